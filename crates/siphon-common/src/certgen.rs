@@ -0,0 +1,267 @@
+//! Generating a private CA and the certificates a first setup needs
+//!
+//! For users without an existing PKI: one self-signed CA issues the client
+//! certificate and, optionally, the server's. The server then trusts the CA
+//! for client authentication and the client trusts it for the server.
+
+use std::fs;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair,
+    KeyUsagePurpose, SanType,
+};
+use rustls::pki_types::ServerName;
+use time::{Duration, OffsetDateTime};
+
+use crate::TunnelError;
+
+/// How long the generated CA is valid
+const CA_VALIDITY: Duration = Duration::days(10 * 365);
+
+/// How long the generated client and server certificates are valid
+const LEAF_VALIDITY: Duration = Duration::days(2 * 365);
+
+/// A CA with the certificates it issued, all PEM-encoded
+pub struct GeneratedCerts {
+    pub ca_cert_pem: String,
+    /// Keep it safe: anyone holding it can issue accepted client certificates
+    pub ca_key_pem: String,
+    pub client_cert_pem: String,
+    pub client_key_pem: String,
+    /// Only generated when server names were given
+    pub server: Option<ServerCert>,
+}
+
+/// Server certificate and key issued by the generated CA
+pub struct ServerCert {
+    pub cert_pem: String,
+    pub key_pem: String,
+}
+
+/// A PEM file to write out; keys are written readable by the owner only
+pub struct PemFile<'a> {
+    pub name: &'static str,
+    pub pem: &'a str,
+    pub private: bool,
+}
+
+impl GeneratedCerts {
+    /// Generate a CA, a client certificate for `client_name` and, if
+    /// `server_names` isn't empty, a server certificate valid for them
+    ///
+    /// Server names that parse as IP addresses become IP SANs, so the server
+    /// can be reached before it has a DNS record.
+    pub fn generate(client_name: &str, server_names: &[String]) -> Result<Self, TunnelError> {
+        let now = OffsetDateTime::now_utc();
+        let err = |e: rcgen::Error| TunnelError::Certificate(e.to_string());
+
+        let ca_key = KeyPair::generate().map_err(err)?;
+        let mut ca_params = CertificateParams::default();
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "Siphon CA");
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        ca_params.not_before = now;
+        ca_params.not_after = now + CA_VALIDITY;
+        let ca_cert = ca_params.self_signed(&ca_key).map_err(err)?;
+        let ca_cert_pem = ca_cert.pem();
+        let ca_key_pem = ca_key.serialize_pem();
+        let issuer = Issuer::new(ca_params, ca_key);
+
+        let client_key = KeyPair::generate().map_err(err)?;
+        let mut client_params = CertificateParams::default();
+        client_params
+            .distinguished_name
+            .push(DnType::CommonName, client_name);
+        client_params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        client_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        client_params.not_before = now;
+        client_params.not_after = now + LEAF_VALIDITY;
+        let client_cert = client_params.signed_by(&client_key, &issuer).map_err(err)?;
+
+        let server = match server_names.first() {
+            None => None,
+            Some(common_name) => {
+                let server_key = KeyPair::generate().map_err(err)?;
+                let mut server_params = CertificateParams::default();
+                server_params
+                    .distinguished_name
+                    .push(DnType::CommonName, common_name.as_str());
+                server_params.subject_alt_names = server_names
+                    .iter()
+                    .map(|name| server_san(name))
+                    .collect::<Result<_, _>>()?;
+                server_params.key_usages = vec![
+                    KeyUsagePurpose::DigitalSignature,
+                    KeyUsagePurpose::KeyEncipherment,
+                ];
+                server_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+                server_params.not_before = now;
+                server_params.not_after = now + LEAF_VALIDITY;
+                let server_cert = server_params.signed_by(&server_key, &issuer).map_err(err)?;
+                Some(ServerCert {
+                    cert_pem: server_cert.pem(),
+                    key_pem: server_key.serialize_pem(),
+                })
+            }
+        };
+
+        Ok(Self {
+            ca_cert_pem,
+            ca_key_pem,
+            client_cert_pem: client_cert.pem(),
+            client_key_pem: client_key.serialize_pem(),
+            server,
+        })
+    }
+
+    /// The CA and server files, which belong with (or on) the server
+    pub fn server_files(&self) -> Vec<PemFile<'_>> {
+        let mut files = vec![
+            PemFile {
+                name: "ca.crt",
+                pem: &self.ca_cert_pem,
+                private: false,
+            },
+            PemFile {
+                name: "ca.key",
+                pem: &self.ca_key_pem,
+                private: true,
+            },
+        ];
+        if let Some(server) = &self.server {
+            files.push(PemFile {
+                name: "server.crt",
+                pem: &server.cert_pem,
+                private: false,
+            });
+            files.push(PemFile {
+                name: "server.key",
+                pem: &server.key_pem,
+                private: true,
+            });
+        }
+        files
+    }
+
+    /// The client certificate and key
+    pub fn client_files(&self) -> Vec<PemFile<'_>> {
+        vec![
+            PemFile {
+                name: "client.crt",
+                pem: &self.client_cert_pem,
+                private: false,
+            },
+            PemFile {
+                name: "client.key",
+                pem: &self.client_key_pem,
+                private: true,
+            },
+        ]
+    }
+}
+
+/// The SAN a server name is verified against: an IP or a DNS name
+fn server_san(name: &str) -> Result<SanType, TunnelError> {
+    let invalid = || TunnelError::Certificate(format!("Invalid server name: {}", name));
+    if let Ok(ip) = name.parse::<IpAddr>() {
+        return Ok(SanType::IpAddress(ip));
+    }
+    ServerName::try_from(name).map_err(|_| invalid())?;
+    Ok(SanType::DnsName(name.try_into().map_err(|_| invalid())?))
+}
+
+/// Write `files` into `dir` (created if needed), returning their paths
+///
+/// Private keys are created readable by the owner only; existing files are
+/// never overwritten, so a CA key can't be lost by running this twice.
+pub fn write_pem_files(dir: &Path, files: &[PemFile<'_>]) -> Result<Vec<PathBuf>, TunnelError> {
+    fs::create_dir_all(dir)?;
+
+    let paths: Vec<PathBuf> = files.iter().map(|file| dir.join(file.name)).collect();
+    if let Some(existing) = paths.iter().find(|path| path.exists()) {
+        return Err(TunnelError::Certificate(format!(
+            "{} already exists, refusing to overwrite it",
+            existing.display()
+        )));
+    }
+
+    for (file, path) in files.iter().zip(&paths) {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(if file.private { 0o600 } else { 0o644 });
+        }
+        options.open(path)?.write_all(file.pem.as_bytes())?;
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{load_client_config_from_pem, load_server_config_from_pem, verify_cert_key_pair};
+
+    #[test]
+    fn test_generated_certs_load() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let certs = GeneratedCerts::generate(
+            "laptop",
+            &["tunnel.example.com".to_string(), "203.0.113.7".to_string()],
+        )
+        .unwrap();
+
+        verify_cert_key_pair(&certs.client_cert_pem, &certs.client_key_pem).unwrap();
+        load_client_config_from_pem(
+            &certs.client_cert_pem,
+            &certs.client_key_pem,
+            &certs.ca_cert_pem,
+        )
+        .unwrap();
+
+        let server = certs.server.as_ref().expect("server certificate");
+        load_server_config_from_pem(&server.cert_pem, &server.key_pem, &certs.ca_cert_pem).unwrap();
+        let der = crate::tls::load_certs_from_pem(&server.cert_pem).unwrap();
+        assert_eq!(
+            crate::extract_sans(&der[0]),
+            vec!["tunnel.example.com", "203.0.113.7"]
+        );
+    }
+
+    #[test]
+    fn test_server_cert_is_optional() {
+        let certs = GeneratedCerts::generate("laptop", &[]).unwrap();
+        assert!(certs.server.is_none());
+        assert_eq!(certs.server_files().len(), 2);
+
+        assert!(GeneratedCerts::generate("laptop", &["bad name".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_write_pem_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let certs = GeneratedCerts::generate("laptop", &[]).unwrap();
+
+        let paths = write_pem_files(dir.path(), &certs.client_files()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&paths[0]).unwrap(),
+            certs.client_cert_pem
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&paths[1]).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // A second run must not clobber the first one's keys
+        assert!(write_pem_files(dir.path(), &certs.client_files()).is_err());
+    }
+}
@@ -12,6 +12,9 @@ pub enum TunnelError {
     #[error("Certificate error: {0}")]
     Certificate(String),
 
+    #[error("Private key is encrypted but no passphrase was provided")]
+    PassphraseRequired,
+
     #[error("Connection closed")]
     ConnectionClosed,
 
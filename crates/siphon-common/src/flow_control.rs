@@ -0,0 +1,230 @@
+//! Credit-based flow control for TCP streams sharing a control connection
+//!
+//! Every stream of a tunnel rides the same TLS connection, so a receiver
+//! waiting for one slow reader would hold up all the others behind it.
+//! Instead each side may only have [`STREAM_WINDOW`] bytes of a stream in
+//! flight: the receiver queues them without waiting and grants credit back
+//! (`TcpWindowUpdate`) as it writes them out, and a sender out of credit
+//! pauses only its own stream.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tokio::sync::{mpsc, Semaphore};
+
+/// Bytes of a stream a sender may have in flight before the receiver grants more
+pub const STREAM_WINDOW: u64 = 256 * 1024;
+
+/// Credit is granted back once at least this many bytes were written out
+const UPDATE_THRESHOLD: u64 = STREAM_WINDOW / 4;
+
+/// Chunks queued for a stream whose peer doesn't take part in flow control
+const BOUNDED_QUEUE_CAPACITY: usize = 32;
+
+/// Credit left to send data on one stream
+#[derive(Debug)]
+pub struct SendWindow {
+    credit: Semaphore,
+}
+
+impl SendWindow {
+    /// A full window, as granted to every new stream
+    pub fn new() -> Self {
+        Self {
+            credit: Semaphore::new(STREAM_WINDOW as usize),
+        }
+    }
+
+    /// Wait until `bytes` may be sent and take them from the window
+    ///
+    /// Returns false once the window is closed.
+    pub async fn reserve(&self, bytes: usize) -> bool {
+        let bytes = bytes.min(STREAM_WINDOW as usize) as u32;
+        match self.credit.acquire_many(bytes).await {
+            Ok(permit) => {
+                permit.forget();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Add credit granted by the peer, never beyond a full window
+    pub fn grant(&self, bytes: u64) {
+        let room = STREAM_WINDOW.saturating_sub(self.credit.available_permits() as u64);
+        self.credit.add_permits(bytes.min(room) as usize);
+    }
+
+    /// Fail pending and future reservations (the stream is gone)
+    pub fn close(&self) {
+        self.credit.close();
+    }
+}
+
+impl Default for SendWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Bytes of one stream written out but not granted back to the sender yet
+#[derive(Debug, Default)]
+pub struct ReceiveWindow {
+    written: u64,
+}
+
+impl ReceiveWindow {
+    /// Record `bytes` written out, returning the credit to grant back once
+    /// enough has built up
+    pub fn written(&mut self, bytes: usize) -> Option<u64> {
+        self.written += bytes as u64;
+        (self.written >= UPDATE_THRESHOLD).then(|| std::mem::take(&mut self.written))
+    }
+}
+
+/// Error pushing data onto a [`StreamQueue`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum StreamQueueError {
+    #[error("stream closed")]
+    Closed,
+    #[error("peer sent more than its flow control window")]
+    Overrun,
+}
+
+/// Sending side of the data waiting to be written to one stream
+#[derive(Debug, Clone)]
+pub enum StreamQueue {
+    /// Peer without flow control: pushing waits while the queue is full
+    Bounded(mpsc::Sender<Bytes>),
+    /// Peer with flow control: its window bounds the queue, so pushing
+    /// never waits, and data beyond the window is refused
+    Windowed {
+        tx: mpsc::UnboundedSender<Bytes>,
+        queued: Arc<AtomicU64>,
+    },
+}
+
+/// Receiving side of a [`StreamQueue`], held by the task writing to the stream
+#[derive(Debug)]
+pub enum StreamQueueReceiver {
+    Bounded(mpsc::Receiver<Bytes>),
+    Windowed {
+        rx: mpsc::UnboundedReceiver<Bytes>,
+        queued: Arc<AtomicU64>,
+    },
+}
+
+/// Create the queue of data to write to a stream, windowed when the peer
+/// takes part in flow control
+pub fn stream_queue(flow_control: bool) -> (StreamQueue, StreamQueueReceiver) {
+    if flow_control {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let queued = Arc::new(AtomicU64::new(0));
+        (
+            StreamQueue::Windowed {
+                tx,
+                queued: queued.clone(),
+            },
+            StreamQueueReceiver::Windowed { rx, queued },
+        )
+    } else {
+        let (tx, rx) = mpsc::channel(BOUNDED_QUEUE_CAPACITY);
+        (StreamQueue::Bounded(tx), StreamQueueReceiver::Bounded(rx))
+    }
+}
+
+impl StreamQueue {
+    /// Queue `data` to be written to the stream
+    pub async fn push(&self, data: Bytes) -> Result<(), StreamQueueError> {
+        match self {
+            StreamQueue::Bounded(tx) => tx.send(data).await.map_err(|_| StreamQueueError::Closed),
+            StreamQueue::Windowed { tx, queued } => {
+                let len = data.len() as u64;
+                if queued.fetch_add(len, Ordering::Relaxed) + len > STREAM_WINDOW {
+                    queued.fetch_sub(len, Ordering::Relaxed);
+                    return Err(StreamQueueError::Overrun);
+                }
+                tx.send(data).map_err(|_| StreamQueueError::Closed)
+            }
+        }
+    }
+}
+
+impl StreamQueueReceiver {
+    /// Next chunk to write, or None once every sender is gone
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        match self {
+            StreamQueueReceiver::Bounded(rx) => rx.recv().await,
+            StreamQueueReceiver::Windowed { rx, queued } => {
+                let data = rx.recv().await?;
+                queued.fetch_sub(data.len() as u64, Ordering::Relaxed);
+                Some(data)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_send_window_waits_for_credit() {
+        let window = Arc::new(SendWindow::new());
+        assert!(window.reserve(STREAM_WINDOW as usize).await);
+
+        let waiter = tokio::spawn({
+            let window = window.clone();
+            async move { window.reserve(1000).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+
+        window.grant(1000);
+        assert!(waiter.await.unwrap());
+
+        // Credit never grows past a full window
+        window.grant(10 * STREAM_WINDOW);
+        assert!(window.reserve(STREAM_WINDOW as usize).await);
+        let waiter = tokio::spawn({
+            let window = window.clone();
+            async move { window.reserve(1).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        window.close();
+        assert!(!waiter.await.unwrap());
+    }
+
+    #[test]
+    fn test_receive_window_batches_updates() {
+        let mut window = ReceiveWindow::default();
+        assert_eq!(window.written(UPDATE_THRESHOLD as usize - 1), None);
+        assert_eq!(window.written(10), Some(UPDATE_THRESHOLD + 9));
+        assert_eq!(window.written(1), None);
+    }
+
+    #[tokio::test]
+    async fn test_windowed_queue_refuses_overrun() {
+        let (queue, mut rx) = stream_queue(true);
+        let chunk = Bytes::from(vec![0u8; (STREAM_WINDOW / 2) as usize]);
+        queue.push(chunk.clone()).await.unwrap();
+        queue.push(chunk.clone()).await.unwrap();
+        assert_eq!(
+            queue.push(Bytes::from_static(&[0])).await,
+            Err(StreamQueueError::Overrun)
+        );
+
+        // Written out data makes room again
+        assert_eq!(rx.recv().await.map(|data| data.len()), Some(chunk.len()));
+        queue.push(Bytes::from_static(&[0])).await.unwrap();
+
+        drop(rx);
+        assert_eq!(
+            queue.push(Bytes::from_static(&[0])).await,
+            Err(StreamQueueError::Closed)
+        );
+    }
+}
@@ -0,0 +1,168 @@
+//! IP addresses and CIDR blocks matched against peer addresses
+//!
+//! Used by the server for its trusted proxies and by the client for its
+//! allowed local targets. IPv4-mapped IPv6 addresses (`::ffff:10.0.0.1`, as
+//! dual-stack sockets report IPv4 peers) are matched as the IPv4 address.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Error parsing an [`IpRange`]
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum IpRangeError {
+    #[error("Invalid IP address in '{0}'")]
+    InvalidAddress(String),
+    #[error("Invalid prefix length in '{0}' (expected 0-{1})")]
+    InvalidPrefix(String, u8),
+}
+
+/// An IP address or CIDR block (`10.0.0.0/8`, `2400:cb00::/32`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    /// Check whether `ip` falls inside this range
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(
+                u32::from(net).into(),
+                u32::from(ip).into(),
+                self.prefix_len,
+                32,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), self.prefix_len, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Compare the top `prefix_len` bits of two `bits`-wide addresses
+fn prefix_matches(net: u128, ip: u128, prefix_len: u8, bits: u8) -> bool {
+    if prefix_len == 0 {
+        return true;
+    }
+    let shift = u32::from(bits - prefix_len);
+    (net >> shift) == (ip >> shift)
+}
+
+impl FromStr for IpRange {
+    type Err = IpRangeError;
+
+    /// Parse `addr/prefix`, or a bare address for a single host
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+
+        let addr: IpAddr = addr
+            .trim()
+            .parse()
+            .map_err(|_| IpRangeError::InvalidAddress(s.to_string()))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max)
+                .ok_or_else(|| IpRangeError::InvalidPrefix(s.to_string(), max))?,
+            None => max,
+        };
+
+        // A block of IPv4-mapped addresses is the IPv4 block it maps
+        if let IpAddr::V6(v6) = addr {
+            if let Some(v4) = v6.to_ipv4_mapped().filter(|_| prefix_len >= 96) {
+                return Ok(Self {
+                    addr: IpAddr::V4(v4),
+                    prefix_len: prefix_len - 96,
+                });
+            }
+        }
+
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(s: &str) -> IpRange {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ipv4_membership() {
+        let range = range("173.245.48.0/20");
+        assert!(range.contains("173.245.48.1".parse().unwrap()));
+        assert!(range.contains("173.245.63.255".parse().unwrap()));
+        assert!(!range.contains("173.245.64.0".parse().unwrap()));
+        assert!(!range.contains("::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_ipv6_membership() {
+        let range = range("2400:cb00::/32");
+        assert!(range.contains("2400:cb00:1::1".parse().unwrap()));
+        assert!(!range.contains("2400:cb01::1".parse().unwrap()));
+        assert!(!range.contains("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_bare_address_is_single_host() {
+        let single = range("127.0.0.1");
+        assert_eq!(single.to_string(), "127.0.0.1/32");
+        assert!(single.contains("127.0.0.1".parse().unwrap()));
+        assert!(!single.contains("127.0.0.2".parse().unwrap()));
+        assert_eq!(range("::1").to_string(), "::1/128");
+    }
+
+    #[test]
+    fn test_zero_prefix_matches_whole_family() {
+        let any = range("0.0.0.0/0");
+        assert!(any.contains("8.8.8.8".parse().unwrap()));
+        assert!(!any.contains("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_ipv4_mapped_addresses() {
+        // Mapped peers match IPv4 blocks
+        let range_v4 = range("10.0.0.0/8");
+        assert!(range_v4.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!range_v4.contains("::ffff:11.1.2.3".parse().unwrap()));
+
+        // Mapped blocks match IPv4 peers
+        let mapped = range("::ffff:10.0.0.0/104");
+        assert_eq!(mapped.to_string(), "10.0.0.0/8");
+        assert!(mapped.contains("10.1.2.3".parse().unwrap()));
+        assert!(mapped.contains("::ffff:10.1.2.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_invalid_ranges() {
+        assert_eq!(
+            "not-an-ip".parse::<IpRange>(),
+            Err(IpRangeError::InvalidAddress("not-an-ip".to_string()))
+        );
+        assert_eq!(
+            "10.0.0.0/33".parse::<IpRange>(),
+            Err(IpRangeError::InvalidPrefix("10.0.0.0/33".to_string(), 32))
+        );
+        assert!("::1/129".parse::<IpRange>().is_err());
+        assert!("10.0.0.0/x".parse::<IpRange>().is_err());
+        assert!("10.0.0.0/-1".parse::<IpRange>().is_err());
+    }
+}
@@ -1,8 +1,20 @@
+mod certgen;
 mod error;
+pub mod flow_control;
+mod ip_range;
+pub mod otel;
+mod socket;
 mod tls;
 
+pub use certgen::{write_pem_files, GeneratedCerts, PemFile, ServerCert};
 pub use error::TunnelError;
+pub use ip_range::{IpRange, IpRangeError};
+pub use socket::{KeepaliveOptions, SocketOptions};
 pub use tls::{
-    load_client_config, load_client_config_from_pem, load_server_config,
-    load_server_config_from_pem, load_server_config_no_client_auth,
+    decrypt_private_key_pem, extract_cn, extract_sans, load_client_config,
+    load_client_config_from_pem, load_client_config_from_pem_skip_server_verify,
+    load_client_config_from_pem_with_policy, load_root_store_from_pem, load_server_config,
+    load_server_config_from_pem, load_server_config_from_pem_with_policy,
+    load_server_config_no_client_auth, tls_probe_client_config, verify_cert_key_pair, TlsPolicy,
+    TlsVersion,
 };
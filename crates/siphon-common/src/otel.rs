@@ -0,0 +1,118 @@
+//! OpenTelemetry spans for the request lifecycle
+//!
+//! The server and client open spans under [`OTEL_TARGET`] around each tunneled
+//! request: `http_request` when a visitor's request arrives, `tunnel_send`
+//! while it goes through the tunnel, and `forward_http` on the client while
+//! the local service answers. Log filters never enable that target, so the
+//! spans cost nothing unless the `otel` feature exports them to an OTLP
+//! collector. The trace context crosses the tunnel as a W3C `traceparent`
+//! header on the request, which the local service also gets.
+
+/// Target of the spans exported to OpenTelemetry
+pub const OTEL_TARGET: &str = "otel";
+
+#[cfg(feature = "otel")]
+pub use exporter::*;
+
+#[cfg(feature = "otel")]
+mod exporter {
+    use std::collections::HashMap;
+
+    use opentelemetry::propagation::TextMapPropagator;
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::{ExporterBuildError, SpanExporter};
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+    use tracing::{Level, Span, Subscriber};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::filter::Targets;
+    use tracing_subscriber::registry::LookupSpan;
+    use tracing_subscriber::Layer;
+
+    use super::OTEL_TARGET;
+
+    /// Collector endpoint for all signals
+    const ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+    /// Collector endpoint for traces only
+    const TRACES_ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT";
+
+    /// Headers carrying the trace context across the tunnel
+    const CONTEXT_HEADERS: [&str; 2] = ["traceparent", "tracestate"];
+
+    /// Span export to an OpenTelemetry collector
+    pub struct Telemetry {
+        provider: SdkTracerProvider,
+    }
+
+    impl Telemetry {
+        /// Export over OTLP/HTTP when `OTEL_EXPORTER_OTLP_ENDPOINT` (or
+        /// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`) is set, None otherwise
+        ///
+        /// The other `OTEL_EXPORTER_OTLP_*` variables (headers, timeout) apply.
+        pub fn from_env(service_name: &'static str) -> Result<Option<Self>, ExporterBuildError> {
+            let configured = |var| std::env::var(var).is_ok_and(|value| !value.is_empty());
+            if !configured(ENDPOINT_ENV) && !configured(TRACES_ENDPOINT_ENV) {
+                return Ok(None);
+            }
+            let exporter = SpanExporter::builder().with_http().build()?;
+            let provider = SdkTracerProvider::builder()
+                .with_batch_exporter(exporter)
+                .with_resource(Resource::builder().with_service_name(service_name).build())
+                .build();
+            Ok(Some(Self::new(provider)))
+        }
+
+        pub fn new(provider: SdkTracerProvider) -> Self {
+            Self { provider }
+        }
+
+        /// Layer turning the lifecycle spans into OpenTelemetry ones
+        pub fn layer<S>(&self) -> impl Layer<S>
+        where
+            S: Subscriber + for<'a> LookupSpan<'a>,
+        {
+            tracing_opentelemetry::layer()
+                .with_tracer(self.provider.tracer("siphon"))
+                .with_filter(Targets::new().with_target(OTEL_TARGET, Level::INFO))
+        }
+
+        /// Export the spans still buffered and stop
+        pub fn shutdown(self) {
+            if let Err(e) = self.provider.shutdown() {
+                tracing::warn!("Failed to flush OpenTelemetry spans: {}", e);
+            }
+        }
+    }
+
+    /// Make `span` a child of the trace context in `headers`, if any
+    ///
+    /// Must be called before the span is first entered.
+    pub fn set_parent_from<'a>(span: &Span, headers: impl IntoIterator<Item = (&'a str, &'a str)>) {
+        let carrier: HashMap<String, String> = headers
+            .into_iter()
+            .map(|(name, value)| (name.to_ascii_lowercase(), value.to_string()))
+            .filter(|(name, _)| CONTEXT_HEADERS.contains(&name.as_str()))
+            .collect();
+        if carrier.is_empty() {
+            return;
+        }
+        let _ = span.set_parent(TraceContextPropagator::new().extract(&carrier));
+    }
+
+    /// Replace the trace context in `headers` with that of `span`
+    pub fn inject_context(span: &Span, headers: &mut Vec<(String, String)>) {
+        let mut carrier = HashMap::new();
+        TraceContextPropagator::new().inject_context(&span.context(), &mut carrier);
+        if carrier.is_empty() {
+            // Not exported: whatever context the request came with goes on as is
+            return;
+        }
+        headers.retain(|(name, _)| {
+            !CONTEXT_HEADERS
+                .iter()
+                .any(|header| name.eq_ignore_ascii_case(header))
+        });
+        headers.extend(carrier.into_iter().filter(|(_, value)| !value.is_empty()));
+    }
+}
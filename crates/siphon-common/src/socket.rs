@@ -0,0 +1,118 @@
+//! TCP socket tuning shared by the server planes and the client
+//!
+//! Tunnel traffic is mostly small, latency-sensitive writes (frames of an
+//! interactive protocol, HTTP headers), which Nagle's algorithm would hold back
+//! until the previous segment is acknowledged. `TCP_NODELAY` is therefore on by
+//! default. Keepalive probes are off unless configured; they help detect peers
+//! that vanished behind a NAT or load balancer without closing the connection.
+
+use std::io;
+use std::time::Duration;
+
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::TcpStream;
+
+/// `SO_KEEPALIVE` parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveOptions {
+    /// Idle time before the first probe
+    pub idle: Duration,
+    /// Time between probes (OS default when None)
+    pub interval: Option<Duration>,
+    /// Unanswered probes before the connection is dropped (OS default when None)
+    pub count: Option<u32>,
+}
+
+/// Options applied to every tunnel TCP socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Disable Nagle's algorithm
+    pub nodelay: bool,
+    /// Enable keepalive probes
+    pub keepalive: Option<KeepaliveOptions>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Set the options on a connected stream
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+
+        if let Some(keepalive) = &self.keepalive {
+            let mut params = TcpKeepalive::new().with_time(keepalive.idle);
+            if let Some(interval) = keepalive.interval {
+                params = params.with_interval(interval);
+            }
+            #[cfg(not(windows))]
+            if let Some(count) = keepalive.count {
+                params = params.with_retries(count);
+            }
+            SockRef::from(stream).set_tcp_keepalive(&params)?;
+        }
+        Ok(())
+    }
+
+    /// Apply the options, logging instead of failing (for freshly accepted streams)
+    pub fn apply_or_warn(&self, stream: &TcpStream) {
+        if let Err(e) = self.apply(stream) {
+            tracing::warn!("Failed to set socket options: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    async fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (client.unwrap(), server.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn test_default_sets_nodelay_only() {
+        let (client, _server) = connected_pair().await;
+        SocketOptions::default().apply(&client).unwrap();
+
+        assert!(client.nodelay().unwrap());
+        assert!(!SockRef::from(&client).keepalive().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_parameters() {
+        let (client, _server) = connected_pair().await;
+        let options = SocketOptions {
+            nodelay: false,
+            keepalive: Some(KeepaliveOptions {
+                idle: Duration::from_secs(30),
+                interval: Some(Duration::from_secs(5)),
+                count: Some(4),
+            }),
+        };
+        options.apply(&client).unwrap();
+
+        let sock = SockRef::from(&client);
+        assert!(!client.nodelay().unwrap());
+        assert!(sock.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(sock.tcp_keepalive_time().unwrap(), Duration::from_secs(30));
+            assert_eq!(
+                sock.tcp_keepalive_interval().unwrap(),
+                Duration::from_secs(5)
+            );
+            assert_eq!(sock.tcp_keepalive_retries().unwrap(), 4);
+        }
+    }
+}
@@ -3,17 +3,22 @@
 //! This module provides a test harness that starts a complete siphon server
 //! with mocked DNS for testing purposes.
 
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::atomic::{AtomicU16, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
+use siphon_common::TlsPolicy;
 use tokio::net::TcpListener;
 use tokio::sync::oneshot;
 use tokio_rustls::TlsAcceptor;
 
 use siphon_server::{
-    new_response_registry, new_tcp_connection_registry, ControlPlane, HttpPlane, PortAllocator,
-    Router, StreamIdGenerator, TcpPlane,
+    new_response_registry, new_tcp_connection_registry, new_ws_connection_registry,
+    BandwidthPolicy, BaseDomains, ControlPlane, ControlPlaneOptions, DnsPropagationCheck,
+    ErrorPages, HttpPlane, HttpPlaneOptions, InjectedHeaders, PortAllocator, Router, StaticRoutes,
+    StreamIdGenerator, StreamMetrics, TcpPlane, TcpPlaneOptions, TrustedProxies, ZoneProviders,
 };
 
 use crate::certificates::TestCertificates;
@@ -34,72 +39,211 @@ pub struct TestServer {
     pub control_addr: SocketAddr,
     /// HTTP plane address
     pub http_addr: SocketAddr,
+    /// Shared SNI-routed TCP port (only with [`StartOptions::tcp_mux`])
+    pub tcp_mux_addr: Option<SocketAddr>,
     /// Base domain for the test server
     pub base_domain: String,
     /// Mock DNS provider for assertions
     pub dns_provider: Arc<MockDnsProvider>,
+    /// Mock DNS provider of each extra base domain (only with
+    /// [`StartOptions::extra_base_domains`])
+    pub zone_dns_providers: HashMap<String, Arc<MockDnsProvider>>,
     /// Certificate set used
     pub certs: Arc<TestCertificates>,
+    /// Control plane counters for responses matching no open stream
+    pub stream_metrics: Arc<StreamMetrics>,
     /// Shutdown signal sender
     shutdown_tx: Option<oneshot::Sender<()>>,
 }
 
+/// Optional server features, set through [`TestServer::start_with`]
+#[derive(Default)]
+pub struct StartOptions {
+    /// Share one SNI-routed port between TCP tunnels
+    pub tcp_mux: bool,
+    /// Upstreams whose forwarded scheme headers are trusted
+    pub trusted_proxies: TrustedProxies,
+    /// Cap on concurrent control plane connections
+    pub max_connections: Option<usize>,
+    /// Cap on concurrent control plane connections per source IP
+    pub max_connections_per_ip: Option<usize>,
+    /// Pages the HTTP plane answers errors with
+    pub error_pages: ErrorPages,
+    /// Per-tunnel bandwidth caps
+    pub bandwidth: BandwidthPolicy,
+    /// Check to wait for before announcing tunnels
+    pub dns_propagation: Option<DnsPropagationCheck>,
+    /// Expect a PROXY protocol header on HTTP and TCP plane connections
+    pub proxy_protocol: bool,
+    /// Leave the IP SANs out of the server certificate
+    pub dns_only_certificate: bool,
+    /// TLS versions and ALPN protocols the control plane accepts
+    pub tls_policy: TlsPolicy,
+    /// How long requests wait for a disconnected tunnel to come back
+    pub reconnect_hold: Option<Duration>,
+    /// How long connections keep their tunnels before being rotated
+    pub max_tunnel_lifetime: Option<Duration>,
+    /// Headers the HTTP plane sets on tunneled responses
+    pub inject_response_headers: InjectedHeaders,
+    /// Name the answering tunnel in an `X-Siphon-Tunnel` response header
+    pub expose_tunnel_header: bool,
+    /// Share identical concurrent GETs, reusing responses for this long
+    pub coalesce_gets: Option<Duration>,
+    /// How long tunnels get to answer each request
+    pub response_timeout: Option<Duration>,
+    /// Serve the HTTP plane over TLS with the server certificate
+    pub https: bool,
+    /// Drop HTTPS connections whose SNI isn't under the base domain
+    pub strict_sni: bool,
+    /// Subdomains proxied straight to an upstream
+    pub static_routes: StaticRoutes,
+    /// Base domains served besides the default one, each with its own DNS
+    pub extra_base_domains: Vec<String>,
+}
+
 impl TestServer {
     /// Start a test server with mock DNS and generated certificates
     pub async fn start() -> Self {
-        let certs = Arc::new(TestCertificates::generate());
+        Self::start_with(|_| {}).await
+    }
+
+    /// Start a test server with the features `configure` turns on
+    ///
+    /// ```ignore
+    /// let server = TestServer::start_with(|options| options.tcp_mux = true).await;
+    /// ```
+    pub async fn start_with(configure: impl FnOnce(&mut StartOptions)) -> Self {
+        let mut options = StartOptions::default();
+        configure(&mut options);
+        Self::start_inner(options).await
+    }
+
+    async fn start_inner(options: StartOptions) -> Self {
+        let certs = Arc::new(TestCertificates::generate_with_ip_sans(
+            !options.dns_only_certificate,
+        ));
         let base_domain = "test.example.com".to_string();
 
         // Build TLS config for control plane (with client auth)
-        let tls_config = siphon_common::load_server_config_from_pem(
+        let tls_config = siphon_common::load_server_config_from_pem_with_policy(
             &certs.server_cert_pem,
             &certs.server_key_pem,
             &certs.ca_cert_pem,
+            &options.tls_policy,
         )
         .expect("Failed to load server TLS config");
 
         let tls_acceptor = TlsAcceptor::from(Arc::new(tls_config));
 
         // Create shared state
-        let router = Router::new();
+        let router = Router::with_reconnect_hold(options.reconnect_hold);
+        for subdomain in options.static_routes.subdomains() {
+            router.reserve(subdomain);
+        }
         let dns_provider = MockDnsProvider::new();
+        let base_domains = BaseDomains::new(
+            std::iter::once(base_domain.as_str())
+                .chain(options.extra_base_domains.iter().map(String::as_str)),
+        )
+        .expect("Invalid base domains");
+        let zone_dns_providers: HashMap<String, Arc<MockDnsProvider>> = options
+            .extra_base_domains
+            .iter()
+            .map(|domain| (domain.clone(), MockDnsProvider::new()))
+            .collect();
+        let mut zone_providers = ZoneProviders::default();
+        for (domain, provider) in &zone_dns_providers {
+            zone_providers.insert(domain.clone(), provider.clone());
+        }
         let response_registry = new_response_registry();
         let tcp_registry = new_tcp_connection_registry();
+        let ws_registry = new_ws_connection_registry();
 
         // Allocate a unique port range for this test server
         let range_index = PORT_RANGE_COUNTER.fetch_add(1, Ordering::Relaxed);
         let start_port = BASE_TCP_PORT + (range_index * PORTS_PER_SERVER);
         let end_port = start_port + PORTS_PER_SERVER;
-        let port_allocator = PortAllocator::new(start_port, end_port);
+        let port_allocator = PortAllocator::with_exclusions(start_port, end_port, &[]);
 
         let stream_id_gen = StreamIdGenerator::new();
 
         // Create planes
-        let tcp_plane = TcpPlane::new(
+        let tcp_plane = TcpPlane::with_options(
             router.clone(),
             port_allocator,
             tcp_registry.clone(),
             stream_id_gen,
+            TcpPlaneOptions {
+                proxy_protocol: options.proxy_protocol,
+                ..Default::default()
+            },
         );
 
-        let control_plane = ControlPlane::new(
+        let tcp_mux_listener = if options.tcp_mux {
+            Some(
+                TcpListener::bind("127.0.0.1:0")
+                    .await
+                    .expect("Failed to bind TCP mux"),
+            )
+        } else {
+            None
+        };
+        let tcp_mux_addr = tcp_mux_listener
+            .as_ref()
+            .map(|listener| listener.local_addr().unwrap());
+
+        let control_plane = ControlPlane::with_options(
             router.clone(),
-            tls_acceptor,
+            tls_acceptor.into(),
             dns_provider.clone(),
-            base_domain.clone(),
+            base_domains.clone(),
             response_registry.clone(),
-            tcp_plane,
+            tcp_plane.clone(),
             tcp_registry,
+            ws_registry.clone(),
+            ControlPlaneOptions {
+                tcp_mux_port: tcp_mux_addr.map(|addr| addr.port()),
+                max_connections: options.max_connections,
+                max_connections_per_ip: options.max_connections_per_ip,
+                bandwidth: options.bandwidth,
+                dns_propagation: options.dns_propagation,
+                zone_providers,
+                require_alpn: !options.tls_policy.alpn_protocols().is_empty(),
+                max_tunnel_lifetime: options.max_tunnel_lifetime,
+                ..Default::default()
+            },
         );
 
-        // HTTP plane without TLS for simplicity in tests
-        let http_plane = HttpPlane::new(
+        // HTTP plane without TLS for simplicity in tests, unless asked for
+        let http_tls_acceptor = options.https.then(|| {
+            let http_tls_config = siphon_common::load_server_config_no_client_auth(
+                &certs.server_cert_pem,
+                &certs.server_key_pem,
+            )
+            .expect("Failed to load HTTP plane TLS config");
+            TlsAcceptor::from(Arc::new(http_tls_config)).into()
+        });
+        let http_plane = HttpPlane::with_options(
             router.clone(),
-            base_domain.clone(),
+            base_domains.clone(),
             response_registry,
-            None, // No TLS for HTTP plane in tests
+            ws_registry,
+            http_tls_acceptor,
+            HttpPlaneOptions {
+                trusted_proxies: options.trusted_proxies,
+                error_pages: options.error_pages,
+                proxy_protocol: options.proxy_protocol,
+                inject_response_headers: options.inject_response_headers,
+                response_timeout: options.response_timeout,
+                strict_sni: options.strict_sni,
+                static_routes: options.static_routes,
+                expose_tunnel_header: options.expose_tunnel_header,
+                coalesce_gets: options.coalesce_gets,
+            },
         );
 
+        let stream_metrics = control_plane.stream_metrics();
+
         // Bind to ephemeral ports
         let control_listener = TcpListener::bind("127.0.0.1:0")
             .await
@@ -137,15 +281,30 @@ impl TestServer {
             }
         });
 
+        // Spawn shared TCP port
+        if let Some(listener) = tcp_mux_listener {
+            tokio::spawn(async move {
+                if let Err(e) = tcp_plane
+                    .run_mux_with_listener(listener, base_domains)
+                    .await
+                {
+                    tracing::error!("TCP mux error: {}", e);
+                }
+            });
+        }
+
         // Give the servers a moment to start
         tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
 
         Self {
             control_addr,
             http_addr,
+            tcp_mux_addr,
             base_domain,
             dns_provider,
+            zone_dns_providers,
             certs,
+            stream_metrics,
             shutdown_tx: Some(shutdown_tx),
         }
     }
@@ -160,6 +319,17 @@ impl TestServer {
         .expect("Failed to load client TLS config")
     }
 
+    /// Get a client TLS config that only offers the TLS versions `policy` allows
+    pub fn client_tls_config_with_policy(&self, policy: &TlsPolicy) -> rustls::ClientConfig {
+        siphon_common::load_client_config_from_pem_with_policy(
+            &self.certs.client_cert_pem,
+            &self.certs.client_key_pem,
+            &self.certs.ca_cert_pem,
+            policy,
+        )
+        .expect("Failed to load client TLS config")
+    }
+
     /// Get the full URL for a subdomain (e.g., "https://myapp.test.example.com")
     pub fn url_for(&self, subdomain: &str) -> String {
         format!("https://{}.{}", subdomain, self.base_domain)
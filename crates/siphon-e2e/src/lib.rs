@@ -8,11 +8,13 @@ pub mod harness;
 pub mod mock_dns;
 pub mod mock_service;
 pub mod mock_tcp_service;
+pub mod mock_ws_service;
 pub mod test_client;
 
 pub use certificates::TestCertificates;
-pub use harness::TestServer;
+pub use harness::{StartOptions, TestServer};
 pub use mock_dns::MockDnsProvider;
 pub use mock_service::MockHttpService;
 pub use mock_tcp_service::{MockTcpService, TcpServiceMode};
+pub use mock_ws_service::MockWsService;
 pub use test_client::TestClient;
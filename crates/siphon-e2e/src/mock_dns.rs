@@ -9,7 +9,7 @@ use std::sync::Arc;
 use async_trait::async_trait;
 use dashmap::DashMap;
 
-use siphon_server::{DnsError, DnsProvider, OriginCertificate};
+use siphon_server::{DnsError, DnsProvider};
 
 /// Mock DNS provider that tracks operations without making real API calls
 pub struct MockDnsProvider {
@@ -109,20 +109,6 @@ impl DnsProvider for MockDnsProvider {
         tracing::debug!("MockDnsProvider: deleted record {}", record_id);
         Ok(())
     }
-
-    async fn create_origin_certificate(
-        &self,
-        _validity_days: u32,
-    ) -> Result<Option<OriginCertificate>, DnsError> {
-        // Tests don't need real Origin CA certificates
-        // Return None to indicate no certificate was created
-        Ok(None)
-    }
-
-    async fn cleanup_old_origin_certificates(&self) -> Result<u32, DnsError> {
-        // No-op for mock
-        Ok(0)
-    }
 }
 
 #[cfg(test)]
@@ -6,7 +6,9 @@
 
 use std::convert::Infallible;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 use bytes::Bytes;
 use http_body_util::{BodyExt, Full};
@@ -45,6 +47,12 @@ pub struct MockHttpService {
     response_body: Arc<RwLock<Vec<u8>>>,
     /// Configurable response headers
     response_headers: Arc<RwLock<Vec<(String, String)>>>,
+    /// Configurable delay before each response
+    response_delay: Arc<RwLock<Duration>>,
+    /// Requests being handled right now, and the most seen at once
+    in_flight: Arc<(AtomicUsize, AtomicUsize)>,
+    /// TCP connections accepted so far
+    accepted: Arc<AtomicUsize>,
 }
 
 impl MockHttpService {
@@ -59,11 +67,17 @@ impl MockHttpService {
         let response_status = Arc::new(RwLock::new(StatusCode::OK));
         let response_body: Arc<RwLock<Vec<u8>>> = Arc::new(RwLock::new(b"OK".to_vec()));
         let response_headers: Arc<RwLock<Vec<(String, String)>>> = Arc::new(RwLock::new(vec![]));
+        let response_delay = Arc::new(RwLock::new(Duration::ZERO));
+        let in_flight = Arc::new((AtomicUsize::new(0), AtomicUsize::new(0)));
+        let accepted = Arc::new(AtomicUsize::new(0));
 
         let requests_clone = requests.clone();
         let status_clone = response_status.clone();
         let body_clone = response_body.clone();
         let headers_clone = response_headers.clone();
+        let delay_clone = response_delay.clone();
+        let in_flight_clone = in_flight.clone();
+        let accepted_clone = accepted.clone();
 
         tokio::spawn(async move {
             loop {
@@ -71,11 +85,14 @@ impl MockHttpService {
                     Ok(conn) => conn,
                     Err(_) => break,
                 };
+                accepted_clone.fetch_add(1, Ordering::SeqCst);
 
                 let requests = requests_clone.clone();
                 let status = status_clone.clone();
                 let body = body_clone.clone();
                 let headers = headers_clone.clone();
+                let delay = delay_clone.clone();
+                let in_flight = in_flight_clone.clone();
 
                 tokio::spawn(async move {
                     let service = service_fn(move |req: Request<Incoming>| {
@@ -83,7 +100,13 @@ impl MockHttpService {
                         let status = status.clone();
                         let body = body.clone();
                         let headers = headers.clone();
+                        let delay = *delay.read();
+                        let in_flight = in_flight.clone();
                         async move {
+                            let (current, max) = &*in_flight;
+                            let now = current.fetch_add(1, Ordering::SeqCst) + 1;
+                            max.fetch_max(now, Ordering::SeqCst);
+
                             // Record the request
                             let method = req.method().to_string();
                             let uri = req.uri().to_string();
@@ -107,6 +130,9 @@ impl MockHttpService {
                                 body: req_body,
                             });
 
+                            tokio::time::sleep(delay).await;
+                            current.fetch_sub(1, Ordering::SeqCst);
+
                             // Build response
                             let resp_status = *status.read();
                             let resp_body = body.read().clone();
@@ -136,6 +162,9 @@ impl MockHttpService {
             response_status,
             response_body,
             response_headers,
+            response_delay,
+            in_flight,
+            accepted,
         }
     }
 
@@ -184,6 +213,21 @@ impl MockHttpService {
         *self.response_headers.write() = headers;
     }
 
+    /// Wait this long before answering each request (a slow local service)
+    pub fn set_response_delay(&self, delay: Duration) {
+        *self.response_delay.write() = delay;
+    }
+
+    /// Most requests that were being handled at the same time
+    pub fn max_concurrent_requests(&self) -> usize {
+        self.in_flight.1.load(Ordering::SeqCst)
+    }
+
+    /// TCP connections accepted so far (requests on a reused connection don't count)
+    pub fn accepted_connections(&self) -> usize {
+        self.accepted.load(Ordering::SeqCst)
+    }
+
     /// Add a single response header
     pub fn add_response_header(&self, name: impl Into<String>, value: impl Into<String>) {
         self.response_headers
@@ -0,0 +1,221 @@
+//! Mock WebSocket service for E2E tests
+//!
+//! This module provides a minimal WebSocket echo server, plus the frame
+//! helpers tests need to talk to it through a tunnel over a raw TCP stream.
+//! Only unfragmented frames with payloads under 64 KiB are supported.
+
+use std::net::SocketAddr;
+
+use base64::Engine;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+/// GUID appended to the client key to derive `Sec-WebSocket-Accept` (RFC 6455)
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Text frame opcode
+pub const OPCODE_TEXT: u8 = 0x1;
+/// Close frame opcode
+pub const OPCODE_CLOSE: u8 = 0x8;
+
+/// A mock WebSocket echo service
+pub struct MockWsService {
+    addr: SocketAddr,
+    /// Shutdown channel
+    shutdown_tx: Option<mpsc::Sender<()>>,
+}
+
+impl MockWsService {
+    /// Start an echo service on an ephemeral port
+    pub async fn start() -> Self {
+        Self::start_with_rejection(None).await
+    }
+
+    /// Start a service that answers every upgrade with `status` instead of switching
+    pub async fn start_rejecting(status: u16) -> Self {
+        Self::start_with_rejection(Some(status)).await
+    }
+
+    async fn start_with_rejection(reject: Option<u16>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind mock WebSocket service");
+        let addr = listener.local_addr().unwrap();
+
+        let (shutdown_tx, mut shutdown_rx) = mpsc::channel::<()>(1);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = shutdown_rx.recv() => {
+                        tracing::debug!("Mock WebSocket service shutting down");
+                        break;
+                    }
+                    result = listener.accept() => {
+                        match result {
+                            Ok((stream, _)) => {
+                                tokio::spawn(async move {
+                                    if let Err(e) = handle_connection(stream, reject).await {
+                                        tracing::debug!("Mock WebSocket connection ended: {}", e);
+                                    }
+                                });
+                            }
+                            Err(e) => {
+                                tracing::error!("WebSocket accept error: {}", e);
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+        });
+
+        Self {
+            addr,
+            shutdown_tx: Some(shutdown_tx),
+        }
+    }
+
+    /// Get the address this service is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Get the address as a string
+    pub fn addr_string(&self) -> String {
+        self.addr.to_string()
+    }
+
+    /// Shutdown the service
+    pub async fn shutdown(&mut self) {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.send(()).await;
+        }
+    }
+}
+
+impl Drop for MockWsService {
+    fn drop(&mut self) {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.try_send(());
+        }
+    }
+}
+
+/// Complete the handshake (or reject it), then echo frames until a close frame
+async fn handle_connection(mut stream: TcpStream, reject: Option<u16>) -> std::io::Result<()> {
+    let head = read_http_head(&mut stream).await?;
+    let key = header_value(&head, "sec-websocket-key").unwrap_or_default();
+
+    if let Some(status) = reject {
+        let body = "upgrade rejected";
+        let response = format!(
+            "HTTP/1.1 {} Rejected\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        return stream.write_all(response.as_bytes()).await;
+    }
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(&key)
+    );
+    stream.write_all(response.as_bytes()).await?;
+
+    loop {
+        let (opcode, payload) = read_frame(&mut stream).await?;
+        stream
+            .write_all(&encode_frame(opcode, &payload, false))
+            .await?;
+        if opcode == OPCODE_CLOSE {
+            return Ok(());
+        }
+    }
+}
+
+/// `Sec-WebSocket-Accept` value for a client's `Sec-WebSocket-Key`
+pub fn accept_key(key: &str) -> String {
+    let digest = ring::digest::digest(
+        &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{}{}", key, WEBSOCKET_GUID).as_bytes(),
+    );
+    base64::engine::general_purpose::STANDARD.encode(digest.as_ref())
+}
+
+/// Read an HTTP/1.1 message head (through the blank line), byte by byte
+///
+/// Reading one byte at a time leaves anything after the head (e.g. the first
+/// frames) in the stream.
+pub async fn read_http_head<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<String> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(reader.read_u8().await?);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// Value of header `name` (case-insensitive) in a message head
+pub fn header_value(head: &str, name: &str) -> Option<String> {
+    head.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| value.trim().to_string())
+    })
+}
+
+/// Encode a final frame; clients must set `masked`, servers must not
+pub fn encode_frame(opcode: u8, payload: &[u8], masked: bool) -> Vec<u8> {
+    let mask_bit = if masked { 0x80 } else { 0 };
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..=125 => frame.push(mask_bit | len as u8),
+        len => {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+    }
+    if masked {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    } else {
+        frame.extend_from_slice(payload);
+    }
+    frame
+}
+
+/// Read one frame, returning its opcode and unmasked payload
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<(u8, Vec<u8>)> {
+    let opcode = reader.read_u8().await? & 0x0f;
+    let second = reader.read_u8().await?;
+    let len = match second & 0x7f {
+        126 => reader.read_u16().await? as usize,
+        127 => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "64-bit frame lengths are not supported",
+            ))
+        }
+        len => len as usize,
+    };
+    let mask = if second & 0x80 != 0 {
+        let mut mask = [0u8; 4];
+        reader.read_exact(&mut mask).await?;
+        Some(mask)
+    } else {
+        None
+    };
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+    if let Some(mask) = mask {
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+    }
+    Ok((opcode, payload))
+}
@@ -6,8 +6,8 @@ use std::collections::HashMap;
 use std::sync::Arc;
 
 use anyhow::Result;
-use bytes::BytesMut;
-use parking_lot::RwLock;
+use bytes::{Bytes, BytesMut};
+use parking_lot::{Mutex, RwLock};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
@@ -15,12 +15,17 @@ use tokio_rustls::client::TlsStream;
 use tokio_rustls::TlsConnector;
 use tokio_util::codec::{Decoder, Encoder};
 
-use siphon_protocol::{ClientMessage, ServerMessage, TunnelCodec, TunnelType};
+use siphon_protocol::{
+    ClientMessage, HttpPolicy, ServerMessage, TunnelCodec, TunnelType, PROTOCOL_VERSION,
+};
 
 use crate::harness::TestServer;
 
 /// Type alias for TCP connection registry to avoid clippy::type_complexity
-type TcpConnectionMap = Arc<RwLock<HashMap<u64, mpsc::Sender<Vec<u8>>>>>;
+type TcpConnectionMap = Arc<RwLock<HashMap<u64, mpsc::Sender<Bytes>>>>;
+
+/// Server messages recorded by a client in tap mode
+type MessageTap = Arc<Mutex<Vec<ServerMessage>>>;
 
 /// A test tunnel client
 pub struct TestClient {
@@ -28,6 +33,10 @@ pub struct TestClient {
     _handle: tokio::task::JoinHandle<Result<()>>,
     /// Sender to signal shutdown
     shutdown_tx: Option<mpsc::Sender<()>>,
+    /// Messages queued for the server (weak so it doesn't keep the connection open)
+    message_tx: mpsc::WeakSender<ClientMessage>,
+    /// Every server message received, when connected with a tap
+    tap: Option<MessageTap>,
     /// The established subdomain (if tunnel was established)
     pub subdomain: Option<String>,
     /// The established URL
@@ -44,24 +53,115 @@ impl TestClient {
         subdomain: Option<String>,
         tunnel_type: TunnelType,
     ) -> Result<Self> {
-        let tls_config = server.client_tls_config();
-        let connector = TlsConnector::from(Arc::new(tls_config));
-
-        // Connect to control plane
-        let tcp_stream = TcpStream::connect(server.control_addr).await?;
-        let server_name = "localhost".try_into()?;
-        let tls_stream = connector.connect(server_name, tcp_stream).await?;
-
-        let (subdomain_result, url_result, tcp_port, handle, shutdown_tx) =
-            run_client(tls_stream, local_addr.to_string(), subdomain, tunnel_type).await?;
-
-        Ok(Self {
-            _handle: handle,
-            shutdown_tx: Some(shutdown_tx),
-            subdomain: subdomain_result,
-            url: url_result,
-            tcp_port,
-        })
+        Self::connect_with_policy(server, local_addr, subdomain, tunnel_type, None).await
+    }
+
+    /// Connect and establish a tunnel with an HTTP method/path policy
+    pub async fn connect_with_policy(
+        server: &TestServer,
+        local_addr: &str,
+        subdomain: Option<String>,
+        tunnel_type: TunnelType,
+        http_policy: Option<HttpPolicy>,
+    ) -> Result<Self> {
+        let tls_stream = connect_tls(server).await?;
+        run_client(
+            tls_stream,
+            local_addr.to_string(),
+            subdomain,
+            tunnel_type,
+            http_policy,
+            None,
+        )
+        .await
+    }
+
+    /// Establish a tunnel that records every server message instead of acting on it
+    ///
+    /// Requests are not forwarded anywhere and stay unanswered unless the test
+    /// replies itself with [`TestClient::send`]. Recorded messages (starting
+    /// with `TunnelEstablished`) are available from
+    /// [`TestClient::received_messages`].
+    pub async fn with_message_tap(
+        server: &TestServer,
+        subdomain: Option<String>,
+        tunnel_type: TunnelType,
+    ) -> Result<Self> {
+        let tls_stream = connect_tls(server).await?;
+        run_client(
+            tls_stream,
+            String::new(),
+            subdomain,
+            tunnel_type,
+            None,
+            Some(Arc::new(Mutex::new(Vec::new()))),
+        )
+        .await
+    }
+
+    /// Server messages received so far (always empty without a tap)
+    pub fn received_messages(&self) -> Vec<ServerMessage> {
+        self.tap
+            .as_ref()
+            .map(|tap| tap.lock().clone())
+            .unwrap_or_default()
+    }
+
+    /// Wait until a received message matches `predicate`, returning it
+    pub async fn wait_for_message(
+        &self,
+        timeout: std::time::Duration,
+        predicate: impl Fn(&ServerMessage) -> bool,
+    ) -> Result<ServerMessage> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            if let Some(msg) = self.received_messages().into_iter().find(|m| predicate(m)) {
+                return Ok(msg);
+            }
+            if tokio::time::Instant::now() >= deadline {
+                anyhow::bail!(
+                    "No matching message within {:?} (received: {:?})",
+                    timeout,
+                    self.received_messages()
+                );
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Send a raw protocol message to the server
+    pub async fn send(&self, msg: ClientMessage) -> Result<()> {
+        let closed = || anyhow::anyhow!("Client connection closed");
+        let tx = self.message_tx.upgrade().ok_or_else(closed)?;
+        tx.send(msg).await.map_err(|_| closed())
+    }
+
+    /// Send only a `Hello` and return the server's answer, then disconnect
+    pub async fn hello(server: &TestServer) -> Result<ServerMessage> {
+        let mut tls_stream = connect_tls(server).await?;
+
+        let mut write_codec = TunnelCodec::<ClientMessage>::new();
+        let mut write_buf = BytesMut::new();
+        write_codec.encode(
+            ClientMessage::Hello {
+                protocol_version: PROTOCOL_VERSION,
+            },
+            &mut write_buf,
+        )?;
+        tls_stream.write_all(&write_buf).await?;
+        tls_stream.flush().await?;
+
+        let mut read_codec = TunnelCodec::<ServerMessage>::new();
+        let mut read_buf = BytesMut::with_capacity(8192);
+        loop {
+            if tls_stream.read_buf(&mut read_buf).await? == 0 {
+                anyhow::bail!("Server disconnected before answering Hello");
+            }
+            if let Some(msg) = read_codec.decode(&mut read_buf)? {
+                tls_stream.shutdown().await?;
+                return Ok(msg);
+            }
+        }
     }
 
     /// Shutdown the client
@@ -81,19 +181,27 @@ impl Drop for TestClient {
     }
 }
 
-/// Run the client, returning tunnel info once established
+/// Open a TLS connection to the server's control plane
+async fn connect_tls(server: &TestServer) -> Result<TlsStream<TcpStream>> {
+    let tls_config = server.client_tls_config();
+    let connector = TlsConnector::from(Arc::new(tls_config));
+
+    let tcp_stream = TcpStream::connect(server.control_addr).await?;
+    let server_name = "localhost".try_into()?;
+    Ok(connector.connect(server_name, tcp_stream).await?)
+}
+
+/// Run the client, returning once the tunnel is established
+///
+/// With a `tap`, server messages are recorded instead of handled.
 async fn run_client(
     tls_stream: TlsStream<TcpStream>,
     local_addr: String,
     subdomain: Option<String>,
     tunnel_type: TunnelType,
-) -> Result<(
-    Option<String>,
-    Option<String>,
-    Option<u16>,
-    tokio::task::JoinHandle<Result<()>>,
-    mpsc::Sender<()>,
-)> {
+    http_policy: Option<HttpPolicy>,
+    tap: Option<MessageTap>,
+) -> Result<TestClient> {
     let (mut read_half, mut write_half) = tokio::io::split(tls_stream);
 
     // Parse local port
@@ -108,6 +216,10 @@ async fn run_client(
         subdomain,
         tunnel_type,
         local_port,
+        http_policy,
+        websocket: false,
+        base_domain: None,
+        flow_control: false,
     };
 
     let mut codec = TunnelCodec::<ClientMessage>::new();
@@ -129,11 +241,15 @@ async fn run_client(
         }
 
         if let Some(msg) = read_codec.decode(&mut read_buf)? {
+            if let Some(tap) = &tap {
+                tap.lock().push(msg.clone());
+            }
             match msg {
                 ServerMessage::TunnelEstablished {
                     subdomain,
                     url,
                     port,
+                    ..
                 } => {
                     tracing::debug!("Tunnel established: {} -> {}", url, local_addr);
                     break (Some(subdomain), Some(url), port);
@@ -158,6 +274,8 @@ async fn run_client(
 
     // Spawn the main client loop
     let tcp_conns = tcp_connections.clone();
+    let message_tx = response_tx.downgrade();
+    let loop_tap = tap.clone();
     let handle = tokio::spawn(async move {
         let http_client = reqwest::Client::new();
         let local_addr = local_addr.clone();
@@ -211,9 +329,12 @@ async fn run_client(
                     // Process messages
                     loop {
                         match read_codec.decode(&mut read_buf) {
-                            Ok(Some(msg)) => {
-                                handle_message(msg, &http_client, &local_addr, &response_tx, &tcp_conns).await;
-                            }
+                            Ok(Some(msg)) => match &loop_tap {
+                                Some(tap) => tap.lock().push(msg),
+                                None => {
+                                    handle_message(msg, &http_client, &local_addr, &response_tx, &tcp_conns).await;
+                                }
+                            },
                             Ok(None) => break,
                             Err(e) => {
                                 tracing::error!("Decode error: {}", e);
@@ -230,7 +351,15 @@ async fn run_client(
         Ok(())
     });
 
-    Ok((subdomain_result, url_result, tcp_port, handle, shutdown_tx))
+    Ok(TestClient {
+        _handle: handle,
+        shutdown_tx: Some(shutdown_tx),
+        message_tx,
+        tap,
+        subdomain: subdomain_result,
+        url: url_result,
+        tcp_port,
+    })
 }
 
 /// Handle a server message
@@ -248,6 +377,7 @@ async fn handle_message(
             uri,
             headers,
             body,
+            scheme,
         } => {
             tracing::debug!("HTTP request {}: {} {}", stream_id, method, uri);
 
@@ -266,11 +396,16 @@ async fn handle_message(
 
             let mut req = http_client.request(method, &url);
 
-            // Add headers
+            // Add headers, with the server-reported scheme replacing any client value
             for (name, value) in headers {
-                if name.to_lowercase() != "host" {
-                    req = req.header(&name, &value);
+                let name_lower = name.to_lowercase();
+                if name_lower == "host" || (scheme.is_some() && name_lower == "x-forwarded-proto") {
+                    continue;
                 }
+                req = req.header(&name, &value);
+            }
+            if let Some(scheme) = scheme {
+                req = req.header("X-Forwarded-Proto", scheme);
             }
 
             // Add body
@@ -308,9 +443,14 @@ async fn handle_message(
                 }
             }
         }
-        ServerMessage::TcpConnect { stream_id } => {
+        ServerMessage::TcpConnect { stream_id, .. } => {
             tracing::debug!("TCP connect {}", stream_id);
 
+            // Register the connection before connecting so data that arrives
+            // in the meantime (e.g. a replayed ClientHello) is buffered
+            let (write_tx, mut write_rx) = mpsc::channel::<Bytes>(32);
+            tcp_connections.write().insert(stream_id, write_tx);
+
             // Connect to local service
             let local_addr = local_addr.to_string();
             let response_tx = response_tx.clone();
@@ -321,12 +461,6 @@ async fn handle_message(
                     Ok(stream) => {
                         let (mut read_half, mut write_half) = stream.into_split();
 
-                        // Channel for writing data to local service
-                        let (write_tx, mut write_rx) = mpsc::channel::<Vec<u8>>(32);
-
-                        // Register the connection
-                        tcp_connections.write().insert(stream_id, write_tx);
-
                         // Spawn write task (receives data from tunnel, writes to local)
                         let tcp_conns = tcp_connections.clone();
                         tokio::spawn(async move {
@@ -352,7 +486,7 @@ async fn handle_message(
                                     break;
                                 }
                                 Ok(n) => {
-                                    let data = buf[..n].to_vec();
+                                    let data = Bytes::copy_from_slice(&buf[..n]);
                                     let msg = ClientMessage::TcpData { stream_id, data };
                                     if response_tx.send(msg).await.is_err() {
                                         break;
@@ -373,6 +507,7 @@ async fn handle_message(
                     }
                     Err(e) => {
                         tracing::error!("Failed to connect to local service: {}", e);
+                        tcp_connections.write().remove(&stream_id);
                         // Send close to indicate connection failed
                         let _ = response_tx
                             .send(ClientMessage::TcpClose { stream_id })
@@ -396,7 +531,21 @@ async fn handle_message(
             tcp_connections.write().remove(&stream_id);
         }
         ServerMessage::Pong { .. } => {}
-        ServerMessage::TunnelEstablished { .. } | ServerMessage::TunnelDenied { .. } => {
+        ServerMessage::TunnelExpired { lifetime_secs } => {
+            // The server closes the connection next
+            tracing::debug!("Tunnel expired after {}s", lifetime_secs);
+        }
+        ServerMessage::WsUpgrade { .. }
+        | ServerMessage::WsData { .. }
+        | ServerMessage::WsClose { .. } => {
+            // Never requested: this client doesn't set `websocket`
+        }
+        ServerMessage::TcpWindowUpdate { .. } => {
+            // Never requested: this client doesn't set `flow_control`
+        }
+        ServerMessage::TunnelEstablished { .. }
+        | ServerMessage::TunnelDenied { .. }
+        | ServerMessage::ServerInfo { .. } => {
             // These should only come once at the start
         }
     }
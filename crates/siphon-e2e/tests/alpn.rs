@@ -0,0 +1,108 @@
+//! Control plane ALPN end-to-end tests
+//!
+//! The control plane only accepts `siphon/1`; clients offer various ALPN
+//! protocols, or none.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use siphon::{ReconnectPolicy, TunnelClient, TunnelExit};
+use siphon_common::TlsPolicy;
+use siphon_e2e::{MockHttpService, TestServer};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+/// Protocol the test server accepts
+const ALPN: &str = "siphon/1";
+
+/// Initialize tracing and crypto provider for tests
+fn init_test() {
+    // Install rustls crypto provider (ignore if already installed)
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    // Initialize tracing
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter("siphon=debug,siphon_e2e=debug")
+        .with_test_writer()
+        .try_init();
+}
+
+async fn start_server() -> TestServer {
+    TestServer::start_with(|options| options.tls_policy = TlsPolicy::default().with_alpn([ALPN]))
+        .await
+}
+
+/// A client offering `alpn` that gives up after its first session
+fn client(server: &TestServer, local: &MockHttpService, alpn: &[&str]) -> TunnelClient {
+    let policy = TlsPolicy::default().with_alpn(alpn.iter().copied());
+    TunnelClient::builder()
+        .server(server.control_addr.to_string())
+        .server_name("localhost")
+        .local(local.addr_string().parse().unwrap())
+        .tls(server.client_tls_config_with_policy(&policy))
+        .reconnect(ReconnectPolicy::never())
+        .build()
+        .expect("Failed to build client")
+}
+
+#[tokio::test]
+async fn test_matching_alpn_negotiated() {
+    init_test();
+
+    let server = start_server().await;
+
+    // The server picks its protocol out of those offered
+    let policy = TlsPolicy::default().with_alpn(["h2", ALPN]);
+    let connector = TlsConnector::from(Arc::new(server.client_tls_config_with_policy(&policy)));
+    let tcp = TcpStream::connect(server.control_addr).await.unwrap();
+    let tls = connector
+        .connect("localhost".try_into().unwrap(), tcp)
+        .await
+        .expect("Handshake failed");
+    assert_eq!(tls.get_ref().1.alpn_protocol(), Some(ALPN.as_bytes()));
+    drop(tls);
+
+    let mock = MockHttpService::start().await;
+    let handle = client(&server, &mock, &["h2", ALPN]).run();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while handle.subdomain().is_none() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("Tunnel not established");
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_mismatched_alpn_refused() {
+    init_test();
+
+    let server = start_server().await;
+    let mock = MockHttpService::start().await;
+    let mut handle = client(&server, &mock, &["h2"]).run();
+
+    // A TLS failure is fatal rather than retried
+    let exit = tokio::time::timeout(Duration::from_secs(5), handle.wait_for_exit())
+        .await
+        .expect("Client did not give up");
+    assert!(matches!(exit, TunnelExit::Fatal(_)), "{:?}", exit);
+    assert!(handle.subdomain().is_none());
+}
+
+#[tokio::test]
+async fn test_client_without_alpn_refused() {
+    init_test();
+
+    let server = start_server().await;
+    let mock = MockHttpService::start().await;
+    let mut handle = client(&server, &mock, &[]).run();
+
+    // The handshake completes, then the server hangs up
+    let exit = tokio::time::timeout(Duration::from_secs(5), handle.wait_for_exit())
+        .await
+        .expect("Client did not give up");
+    assert!(exit.error().is_some(), "{:?}", exit);
+    assert!(handle.subdomain().is_none());
+}
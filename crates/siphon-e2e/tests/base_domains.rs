@@ -0,0 +1,159 @@
+//! Multiple base domain end-to-end tests
+//!
+//! These run tunnels under two base domains on one server and check that
+//! requests reach the right tunnel and records land in the right zone.
+
+use std::time::Duration;
+
+use siphon::{ReconnectPolicy, TunnelClient, TunnelClientBuilder, TunnelExit, TunnelHandle};
+use siphon_e2e::{MockHttpService, TestClient, TestServer};
+use siphon_protocol::ServerMessage;
+
+/// Base domain served besides the harness' default one
+const OTHER_BASE_DOMAIN: &str = "tun.other.org";
+
+/// Initialize tracing and crypto provider for tests
+fn init_test() {
+    // Install rustls crypto provider (ignore if already installed)
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    // Initialize tracing
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter("siphon=debug,siphon_e2e=debug")
+        .with_test_writer()
+        .try_init();
+}
+
+/// A client exposing `local` as `subdomain`, giving up after its first session
+fn client(server: &TestServer, local: &MockHttpService, subdomain: &str) -> TunnelClientBuilder {
+    TunnelClient::builder()
+        .server(server.control_addr.to_string())
+        .server_name("localhost")
+        .local(local.addr_string().parse().unwrap())
+        .tls(server.client_tls_config())
+        .subdomain(subdomain)
+        .reconnect(ReconnectPolicy::never())
+}
+
+/// Run `builder` until the tunnel is up
+async fn start(builder: TunnelClientBuilder) -> TunnelHandle {
+    let handle = builder.build().expect("Failed to build client").run();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while handle.subdomain().is_none() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("Tunnel not established");
+    handle
+}
+
+/// Status of a GET to `host` through the HTTP plane
+async fn status_for(server: &TestServer, host: &str) -> u16 {
+    reqwest::Client::new()
+        .get(format!("http://{}/", server.http_addr))
+        .header("Host", host)
+        .send()
+        .await
+        .expect("HTTP request failed")
+        .status()
+        .as_u16()
+}
+
+#[tokio::test]
+async fn test_tunnels_under_two_base_domains() {
+    init_test();
+
+    let server = TestServer::start_with(|options| {
+        options.extra_base_domains = vec![OTHER_BASE_DOMAIN.to_string()];
+    })
+    .await;
+    let zone_dns = server.zone_dns_providers[OTHER_BASE_DOMAIN].clone();
+    let default_mock = MockHttpService::start().await;
+    let other_mock = MockHttpService::start().await;
+
+    let default_handle = start(client(&server, &default_mock, "alpha")).await;
+    let other_handle =
+        start(client(&server, &other_mock, "beta").base_domain(OTHER_BASE_DOMAIN)).await;
+
+    // Each record is created in its base domain's zone
+    assert!(server.dns_provider.has_record("alpha"));
+    assert!(!server.dns_provider.has_record("beta"));
+    assert!(zone_dns.has_record("beta"));
+    assert!(!zone_dns.has_record("alpha"));
+
+    // Each host reaches its own tunnel, and only under its base domain
+    assert_eq!(status_for(&server, "alpha.test.example.com").await, 200);
+    assert_eq!(status_for(&server, "beta.tun.other.org").await, 200);
+    assert_eq!(default_mock.get_requests().len(), 1);
+    assert_eq!(other_mock.get_requests().len(), 1);
+    assert_eq!(status_for(&server, "beta.test.example.com").await, 404);
+    assert_eq!(status_for(&server, "alpha.tun.other.org").await, 404);
+    assert_eq!(status_for(&server, "alpha.tun.unknown.net").await, 400);
+
+    // Records are deleted from the zone they were created in
+    other_handle.shutdown().await.unwrap();
+    assert!(!zone_dns.has_record("beta"));
+    default_handle.shutdown().await.unwrap();
+    assert!(!server.dns_provider.has_record("alpha"));
+}
+
+#[tokio::test]
+async fn test_unknown_base_domain_denied() {
+    init_test();
+
+    let server = TestServer::start_with(|options| {
+        options.extra_base_domains = vec![OTHER_BASE_DOMAIN.to_string()];
+    })
+    .await;
+    let mock = MockHttpService::start().await;
+
+    let mut handle = client(&server, &mock, "gamma")
+        .base_domain("tun.unknown.net")
+        .build()
+        .expect("Failed to build client")
+        .run();
+    let exit = tokio::time::timeout(Duration::from_secs(5), handle.wait_for_exit())
+        .await
+        .expect("Client did not give up");
+    assert!(matches!(exit, TunnelExit::Fatal(_)), "{:?}", exit);
+    let error = format!("{:#}", exit.error().unwrap());
+    assert!(error.contains("Unknown base domain"), "{}", error);
+}
+
+#[tokio::test]
+async fn test_server_info_lists_base_domains() {
+    init_test();
+
+    let server = TestServer::start_with(|options| {
+        options.extra_base_domains = vec![OTHER_BASE_DOMAIN.to_string()];
+    })
+    .await;
+    match TestClient::hello(&server).await.expect("Hello failed") {
+        ServerMessage::ServerInfo {
+            base_domain,
+            other_base_domains,
+            ..
+        } => {
+            assert_eq!(base_domain, server.base_domain);
+            assert_eq!(other_base_domains, vec![OTHER_BASE_DOMAIN.to_string()]);
+        }
+        other => panic!("Expected ServerInfo, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_client_asks_for_server_info() {
+    init_test();
+
+    let server = TestServer::start_with(|options| {
+        options.extra_base_domains = vec![OTHER_BASE_DOMAIN.to_string()];
+    })
+    .await;
+    let mock = MockHttpService::start().await;
+
+    // The server answers the Hello and then opens the tunnel as usual
+    let handle = start(client(&server, &mock, "delta").server_info(true)).await;
+    assert_eq!(status_for(&server, "delta.test.example.com").await, 200);
+    handle.shutdown().await.unwrap();
+}
@@ -0,0 +1,62 @@
+//! Catch-all tunnel end-to-end tests
+//!
+//! The catch-all tunnel serves every subdomain no other tunnel holds,
+//! through a wildcard DNS record the operator manages.
+
+use std::time::Duration;
+
+use siphon_e2e::{MockHttpService, TestClient, TestServer};
+use siphon_protocol::TunnelType;
+use siphon_server::DnsProvider;
+
+/// Initialize tracing and crypto provider for tests
+fn init_test() {
+    // Install rustls crypto provider (ignore if already installed)
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    // Initialize tracing
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter("siphon=debug,siphon_e2e=debug")
+        .with_test_writer()
+        .try_init();
+}
+
+#[tokio::test]
+async fn test_catch_all_leaves_wildcard_record_alone() {
+    init_test();
+
+    let server = TestServer::start().await;
+    // The operator's wildcard record
+    server.dns_provider.create_record("*", true).await.unwrap();
+    let records = server.dns_provider.get_records();
+
+    let mock = MockHttpService::start().await;
+    mock.set_response_body(b"Caught".to_vec());
+    let client = TestClient::connect(
+        &server,
+        &mock.addr_string(),
+        Some("*".to_string()),
+        TunnelType::Http,
+    )
+    .await
+    .expect("Failed to connect client");
+    assert_eq!(client.subdomain.as_deref(), Some("*"));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // No record of its own
+    assert_eq!(server.dns_provider.get_records(), records);
+
+    let resp = reqwest::Client::new()
+        .get(format!("http://{}/", server.http_addr))
+        .header("Host", server.host_for("anything"))
+        .send()
+        .await
+        .expect("HTTP request failed");
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.text().await.unwrap(), "Caught");
+
+    // Nor does it take the operator's record away when it leaves
+    drop(client);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(server.dns_provider.get_records(), records);
+}
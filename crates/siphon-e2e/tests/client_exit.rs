@@ -0,0 +1,86 @@
+//! Client exit reason end-to-end tests
+//!
+//! These go through the real client and check why it reports having stopped.
+
+use std::time::Duration;
+
+use siphon::{ReconnectPolicy, TunnelClient, TunnelExit};
+use siphon_e2e::{MockHttpService, TestServer};
+
+/// Initialize tracing and crypto provider for tests
+fn init_test() {
+    // Install rustls crypto provider (ignore if already installed)
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    // Initialize tracing
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter("siphon=debug,siphon_e2e=debug")
+        .with_test_writer()
+        .try_init();
+}
+
+/// A client for `subdomain` that reconnects like the CLI does without `--once`
+fn client(server: &TestServer, local: &MockHttpService, subdomain: &str) -> TunnelClient {
+    TunnelClient::builder()
+        .server(server.control_addr.to_string())
+        .server_name("localhost")
+        .local(local.addr_string().parse().unwrap())
+        .tls(server.client_tls_config())
+        .subdomain(subdomain)
+        .reconnect(ReconnectPolicy {
+            delay: Duration::from_millis(50),
+            max_attempts: None,
+        })
+        .build()
+        .expect("Failed to build client")
+}
+
+#[tokio::test]
+async fn test_exit_reports_shutdown() {
+    init_test();
+
+    let server = TestServer::start().await;
+    let mock = MockHttpService::start().await;
+    let handle = client(&server, &mock, "exit-shutdown").run();
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while handle.subdomain().is_none() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("Tunnel not established");
+
+    let exit = handle.stop().await;
+    assert!(matches!(exit, TunnelExit::Shutdown), "{:?}", exit);
+    assert!(exit.error().is_none());
+}
+
+#[tokio::test]
+async fn test_exit_reports_denied_tunnel() {
+    init_test();
+
+    let server = TestServer::start().await;
+    let mock = MockHttpService::start().await;
+
+    // The first client holds the subdomain the second one asks for
+    let first = client(&server, &mock, "exit-taken").run();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while first.subdomain().is_none() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("Tunnel not established");
+
+    let mut second = client(&server, &mock, "exit-taken").run();
+    // A denial is final, even with reconnects left
+    let exit = tokio::time::timeout(Duration::from_secs(5), second.wait_for_exit())
+        .await
+        .expect("Client kept retrying a denied tunnel");
+    assert!(matches!(exit, TunnelExit::Fatal(_)), "{:?}", exit);
+    assert!(exit.is_denied());
+    assert!(exit.into_result().is_err());
+
+    first.shutdown().await.unwrap();
+}
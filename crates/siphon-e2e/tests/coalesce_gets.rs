@@ -0,0 +1,107 @@
+//! GET coalescing end-to-end tests
+//!
+//! Identical GETs fired at once through one tunnel, against a slow local
+//! service, checking how many of them actually reach it.
+
+use std::time::Duration;
+
+use siphon_e2e::{MockHttpService, TestClient, TestServer};
+use siphon_protocol::TunnelType;
+
+/// Concurrent requests fired in each test
+const REQUESTS: usize = 8;
+
+/// Initialize tracing and crypto provider for tests
+fn init_test() {
+    // Install rustls crypto provider (ignore if already installed)
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    // Initialize tracing
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter("siphon=debug,siphon_e2e=debug")
+        .with_test_writer()
+        .try_init();
+}
+
+/// Start a slow local service and a tunnel to it, returning its subdomain
+async fn start_tunnel(server: &TestServer) -> (MockHttpService, TestClient, String) {
+    let mock = MockHttpService::start().await;
+    mock.set_response_body(b"Popular".to_vec());
+    mock.set_response_delay(Duration::from_millis(300));
+
+    let client = TestClient::connect(server, &mock.addr_string(), None, TunnelType::Http)
+        .await
+        .expect("Failed to connect client");
+    let subdomain = client.subdomain.clone().expect("No subdomain assigned");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    (mock, client, subdomain)
+}
+
+/// Fire [`REQUESTS`] identical GETs at once, each with `headers`, checking
+/// they all get the local service's answer
+async fn fire_gets(server: &TestServer, subdomain: &str, headers: &[(&str, &str)]) {
+    let client = reqwest::Client::new();
+    let requests = (0..REQUESTS).map(|_| {
+        let mut request = client
+            .get(format!("http://{}/popular?page=1", server.http_addr))
+            .header("Host", server.host_for(subdomain));
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        tokio::spawn(request.send())
+    });
+    let requests: Vec<_> = requests.collect();
+
+    for request in requests {
+        let resp = request.await.unwrap().expect("HTTP request failed");
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.text().await.unwrap(), "Popular");
+    }
+}
+
+#[tokio::test]
+async fn test_identical_gets_share_one_forward() {
+    init_test();
+
+    let server =
+        TestServer::start_with(|options| options.coalesce_gets = Some(Duration::ZERO)).await;
+    let (mock, _client, subdomain) = start_tunnel(&server).await;
+
+    fire_gets(&server, &subdomain, &[]).await;
+    assert_eq!(mock.get_requests().len(), 1);
+
+    // Without a TTL, a request after the answer goes through again
+    fire_gets(&server, &subdomain, &[]).await;
+    assert_eq!(mock.get_requests().len(), 2);
+}
+
+#[tokio::test]
+async fn test_gets_not_coalesced_by_default() {
+    init_test();
+
+    let server = TestServer::start().await;
+    let (mock, _client, subdomain) = start_tunnel(&server).await;
+
+    fire_gets(&server, &subdomain, &[]).await;
+    assert_eq!(mock.get_requests().len(), REQUESTS);
+}
+
+#[tokio::test]
+async fn test_private_requests_and_responses_not_shared() {
+    init_test();
+
+    let server =
+        TestServer::start_with(|options| options.coalesce_gets = Some(Duration::from_secs(5)))
+            .await;
+    let (mock, _client, subdomain) = start_tunnel(&server).await;
+
+    // Requests with credentials each go through
+    fire_gets(&server, &subdomain, &[("Cookie", "session=1")]).await;
+    assert_eq!(mock.get_requests().len(), REQUESTS);
+
+    // So do those whose response mustn't be stored
+    mock.clear_requests();
+    mock.add_response_header("Cache-Control", "no-store");
+    fire_gets(&server, &subdomain, &[]).await;
+    assert_eq!(mock.get_requests().len(), REQUESTS);
+}
@@ -0,0 +1,78 @@
+//! Connecting to the server by IP address end-to-end tests
+//!
+//! The client is pointed at `127.0.0.1` without `--server-name`, so the
+//! server certificate has to carry a matching IP SAN.
+
+use std::time::Duration;
+
+use siphon::{ReconnectPolicy, TunnelClient, TunnelExit};
+use siphon_e2e::{MockHttpService, TestServer};
+
+/// Initialize tracing and crypto provider for tests
+fn init_test() {
+    // Install rustls crypto provider (ignore if already installed)
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    // Initialize tracing
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter("siphon=debug,siphon_e2e=debug")
+        .with_test_writer()
+        .try_init();
+}
+
+/// A client connecting to the server's IP, verifying the certificate against it
+fn client_by_ip(server: &TestServer, local: &MockHttpService) -> TunnelClient {
+    assert!(server.control_addr.ip().is_loopback());
+    TunnelClient::builder()
+        .server(server.control_addr.to_string())
+        .local(local.addr_string().parse().unwrap())
+        .tls(server.client_tls_config())
+        .reconnect(ReconnectPolicy::never())
+        .build()
+        .expect("Failed to build client")
+}
+
+#[tokio::test]
+async fn test_connect_by_ip_with_ip_san() {
+    init_test();
+
+    let server = TestServer::start().await;
+    let mock = MockHttpService::start().await;
+    let handle = client_by_ip(&server, &mock).run();
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while handle.subdomain().is_none() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("Tunnel not established");
+    let subdomain = handle.subdomain().unwrap();
+
+    let resp = reqwest::Client::new()
+        .get(format!("http://{}/", server.http_addr))
+        .header("Host", server.host_for(&subdomain))
+        .send()
+        .await
+        .expect("HTTP request failed");
+    assert_eq!(resp.status(), 200);
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_connect_by_ip_without_ip_san_fails() {
+    init_test();
+
+    let server = TestServer::start_with(|options| options.dns_only_certificate = true).await;
+    let mock = MockHttpService::start().await;
+    let mut handle = client_by_ip(&server, &mock).run();
+
+    // A certificate only valid for `localhost` doesn't cover the IP
+    let exit = tokio::time::timeout(Duration::from_secs(5), handle.wait_for_exit())
+        .await
+        .expect("Client did not give up");
+    assert!(matches!(exit, TunnelExit::Fatal(_)), "{:?}", exit);
+    let error = format!("{:#}", exit.error().unwrap());
+    assert!(error.contains("not valid for name"), "{}", error);
+}
@@ -0,0 +1,95 @@
+//! Host header forwarding end-to-end tests
+//!
+//! These go through the real client, whose forwarder applies the mode.
+
+use std::time::Duration;
+
+use siphon::{ForwardHost, TunnelClient};
+use siphon_e2e::{MockHttpService, TestServer};
+
+/// Initialize tracing and crypto provider for tests
+fn init_test() {
+    // Install rustls crypto provider (ignore if already installed)
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    // Initialize tracing
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter("siphon=debug,siphon_e2e=debug")
+        .with_test_writer()
+        .try_init();
+}
+
+/// Send one request through a tunnel using `mode`, returning the Host the mock saw
+async fn host_seen_by_local_service(mode: ForwardHost) -> (String, Option<String>) {
+    let server = TestServer::start().await;
+    let mock = MockHttpService::start().await;
+
+    let handle = TunnelClient::builder()
+        .server(server.control_addr.to_string())
+        .server_name("localhost")
+        .local(mock.addr_string().parse().unwrap())
+        .tls(server.client_tls_config())
+        .forward_host(mode)
+        .build()
+        .expect("Failed to build client")
+        .run();
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while handle.metrics().snapshot().tunnel_info.is_none() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("Tunnel not established");
+    let subdomain = handle.subdomain().expect("No subdomain assigned");
+    let public_host = server.host_for(&subdomain);
+
+    let resp = reqwest::Client::new()
+        .get(format!("http://{}/", server.http_addr))
+        .header("Host", &public_host)
+        .send()
+        .await
+        .expect("HTTP request failed");
+    assert_eq!(resp.status(), 200);
+
+    let request = mock.last_request().expect("Mock received no request");
+    let host = request
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("host"))
+        .map(|(_, value)| value.clone());
+
+    handle.shutdown().await.unwrap();
+    (public_host, host)
+}
+
+#[tokio::test]
+async fn test_host_stripped_by_default() {
+    init_test();
+
+    // The local service sees its own address instead
+    let (public_host, host) = host_seen_by_local_service(ForwardHost::default()).await;
+    assert_ne!(host.as_deref(), Some(public_host.as_str()));
+    assert!(
+        host.as_deref().is_some_and(|h| h.starts_with("127.0.0.1:")),
+        "unexpected Host: {:?}",
+        host
+    );
+}
+
+#[tokio::test]
+async fn test_host_preserved() {
+    init_test();
+
+    let (public_host, host) = host_seen_by_local_service(ForwardHost::Preserve).await;
+    assert_eq!(host, Some(public_host));
+}
+
+#[tokio::test]
+async fn test_host_rewritten() {
+    init_test();
+
+    let (_, host) =
+        host_seen_by_local_service(ForwardHost::Rewrite("app.internal".to_string())).await;
+    assert_eq!(host.as_deref(), Some("app.internal"));
+}
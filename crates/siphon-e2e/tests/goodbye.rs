@@ -0,0 +1,125 @@
+//! Client goodbye end-to-end tests
+//!
+//! These check that the server releases a client's subdomain as soon as the
+//! client says goodbye, without waiting for the connection to drop.
+
+use std::time::Duration;
+
+use siphon::{ReconnectPolicy, TunnelClient, TunnelExit, TunnelHandle};
+use siphon_e2e::{MockHttpService, TestClient, TestServer};
+use siphon_protocol::{ClientMessage, TunnelType};
+
+/// Initialize tracing and crypto provider for tests
+fn init_test() {
+    // Install rustls crypto provider (ignore if already installed)
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    // Initialize tracing
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter("siphon=debug,siphon_e2e=debug")
+        .with_test_writer()
+        .try_init();
+}
+
+/// Run a client for `subdomain` that gives up after its first session,
+/// failing if it doesn't get the subdomain
+async fn start_client(
+    server: &TestServer,
+    local: &MockHttpService,
+    subdomain: &str,
+) -> TunnelHandle {
+    let handle = TunnelClient::builder()
+        .server(server.control_addr.to_string())
+        .server_name("localhost")
+        .local(local.addr_string().parse().unwrap())
+        .tls(server.client_tls_config())
+        .subdomain(subdomain)
+        .reconnect(ReconnectPolicy::never())
+        .build()
+        .expect("Failed to build client")
+        .run();
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while handle.subdomain().is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Tunnel not established");
+    handle
+}
+
+#[tokio::test]
+async fn test_subdomain_free_right_after_goodbye() {
+    init_test();
+
+    let server = TestServer::start().await;
+    let mock = MockHttpService::start().await;
+
+    // Each client takes the subdomain the moment the previous one has stopped
+    let mut handle = start_client(&server, &mock, "goodbye").await;
+    for _ in 0..5 {
+        let exit = handle.stop().await;
+        assert!(matches!(exit, TunnelExit::Shutdown), "{:?}", exit);
+        handle = start_client(&server, &mock, "goodbye").await;
+    }
+
+    // The released tunnel no longer serves requests
+    let exit = handle.stop().await;
+    assert!(matches!(exit, TunnelExit::Shutdown), "{:?}", exit);
+    let resp = reqwest::Client::new()
+        .get(format!("http://{}/", server.http_addr))
+        .header("Host", server.host_for("goodbye"))
+        .send()
+        .await
+        .expect("HTTP request failed");
+    assert_eq!(resp.status(), 404);
+}
+
+#[tokio::test]
+async fn test_goodbye_releases_subdomain_before_close() {
+    init_test();
+
+    let server = TestServer::start().await;
+    let mock = MockHttpService::start().await;
+    let first = TestClient::connect(
+        &server,
+        &mock.addr_string(),
+        Some("farewell".to_string()),
+        TunnelType::Http,
+    )
+    .await
+    .expect("Failed to connect");
+    assert!(server.dns_provider.has_record("farewell"));
+
+    // The client keeps its side open: only the goodbye releases the tunnel,
+    // and the server closes the connection once it has
+    first
+        .send(ClientMessage::Goodbye {
+            reason: "restarting".to_string(),
+        })
+        .await
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while first
+            .send(ClientMessage::Ping { timestamp: 0 })
+            .await
+            .is_ok()
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Server did not close the connection after goodbye");
+
+    assert!(!server.dns_provider.has_record("farewell"));
+    let second = TestClient::connect(
+        &server,
+        &mock.addr_string(),
+        Some("farewell".to_string()),
+        TunnelType::Http,
+    )
+    .await
+    .expect("Subdomain still taken after goodbye");
+    assert_eq!(second.subdomain.as_deref(), Some("farewell"));
+}
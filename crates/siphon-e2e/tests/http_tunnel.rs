@@ -1,8 +1,14 @@
 //! HTTP tunnel end-to-end tests
 
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
 use hyper::StatusCode;
 use siphon_e2e::{MockHttpService, TestClient, TestServer};
-use siphon_protocol::TunnelType;
+use siphon_protocol::{ClientMessage, HttpPolicy, ServerMessage, TunnelType, PROTOCOL_VERSION};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
 
 /// Initialize tracing and crypto provider for tests
 fn init_test() {
@@ -244,6 +250,134 @@ async fn test_http_tunnel_preserves_headers() {
     );
 }
 
+/// Collect the last request's headers recorded by the mock, keyed by lowercase name
+fn last_request_headers(mock: &MockHttpService) -> std::collections::HashMap<String, String> {
+    mock.last_request()
+        .expect("No request recorded")
+        .headers
+        .iter()
+        .map(|(k, v)| (k.to_lowercase(), v.clone()))
+        .collect()
+}
+
+#[tokio::test]
+async fn test_forwarded_proto_from_trusted_proxy() {
+    init_test();
+
+    let server = TestServer::start_with(|options| {
+        options.trusted_proxies =
+            siphon_server::TrustedProxies::parse(&["127.0.0.1"]).expect("Invalid trusted proxies");
+    })
+    .await;
+    let mock = MockHttpService::start().await;
+
+    let client = TestClient::connect(&server, &mock.addr_string(), None, TunnelType::Http)
+        .await
+        .expect("Failed to connect client");
+    let subdomain = client.subdomain.clone().expect("No subdomain assigned");
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+
+    // HTTPS at the edge, plain HTTP to the origin
+    let http_client = reqwest::Client::new();
+    let resp = http_client
+        .get(format!("http://{}/", server.http_addr))
+        .header("Host", server.host_for(&subdomain))
+        .header("X-Forwarded-Proto", "https")
+        .send()
+        .await
+        .expect("HTTP request failed");
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        last_request_headers(&mock).get("x-forwarded-proto"),
+        Some(&"https".to_string())
+    );
+
+    let resp = http_client
+        .get(format!("http://{}/", server.http_addr))
+        .header("Host", server.host_for(&subdomain))
+        .header("Forwarded", "for=192.0.2.60;proto=https")
+        .send()
+        .await
+        .expect("HTTP request failed");
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        last_request_headers(&mock).get("x-forwarded-proto"),
+        Some(&"https".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_forwarded_proto_ignored_from_untrusted_peer() {
+    init_test();
+
+    let server = TestServer::start().await;
+    let mock = MockHttpService::start().await;
+
+    let client = TestClient::connect(&server, &mock.addr_string(), None, TunnelType::Http)
+        .await
+        .expect("Failed to connect client");
+    let subdomain = client.subdomain.clone().expect("No subdomain assigned");
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+
+    let resp = reqwest::Client::new()
+        .get(format!("http://{}/", server.http_addr))
+        .header("Host", server.host_for(&subdomain))
+        .header("X-Forwarded-Proto", "https")
+        .send()
+        .await
+        .expect("HTTP request failed");
+    assert_eq!(resp.status(), 200);
+
+    // The spoofed header is replaced by the scheme the server actually saw
+    assert_eq!(
+        last_request_headers(&mock).get("x-forwarded-proto"),
+        Some(&"http".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_proxy_protocol_sets_forwarded_for() {
+    init_test();
+
+    let server = TestServer::start_with(|options| options.proxy_protocol = true).await;
+    let mock = MockHttpService::start().await;
+
+    let client = TestClient::connect(&server, &mock.addr_string(), None, TunnelType::Http)
+        .await
+        .expect("Failed to connect client");
+    let subdomain = client.subdomain.clone().expect("No subdomain assigned");
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+
+    // The balancer's header comes first, then the visitor's request
+    let mut stream = TcpStream::connect(server.http_addr).await.unwrap();
+    let request = format!(
+        "PROXY TCP4 203.0.113.7 10.0.0.1 51234 80\r\nGET / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        server.host_for(&subdomain)
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(
+        tokio::time::Duration::from_secs(5),
+        stream.read_to_end(&mut response),
+    )
+    .await
+    .expect("No response")
+    .unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 200"));
+    assert_eq!(
+        last_request_headers(&mock).get("x-forwarded-for"),
+        Some(&"203.0.113.7".to_string())
+    );
+
+    // Connections without the header are dropped unanswered
+    let result = reqwest::Client::new()
+        .get(format!("http://{}/", server.http_addr))
+        .header("Host", server.host_for(&subdomain))
+        .send()
+        .await;
+    assert!(result.is_err());
+}
+
 #[tokio::test]
 async fn test_http_tunnel_error_response() {
     init_test();
@@ -272,6 +406,41 @@ async fn test_http_tunnel_error_response() {
     assert_eq!(resp.text().await.unwrap(), "Resource not found");
 }
 
+#[tokio::test]
+async fn test_custom_error_page_for_unknown_subdomain() {
+    init_test();
+
+    let mut pages = siphon_server::ErrorPages::default();
+    pages.insert(404, "<h1>{status}</h1><p>{message}</p>");
+    let server = TestServer::start_with(|options| options.error_pages = pages).await;
+
+    let http_client = reqwest::Client::new();
+    let resp = http_client
+        .get(format!("http://{}/", server.http_addr))
+        .header("Host", server.host_for("nosuchtunnel"))
+        .send()
+        .await
+        .expect("HTTP request failed");
+
+    assert_eq!(resp.status(), 404);
+    assert_eq!(resp.headers()["content-type"], "text/html; charset=utf-8");
+    assert_eq!(
+        resp.text().await.unwrap(),
+        "<h1>404</h1><p>Tunnel not found for: nosuchtunnel</p>"
+    );
+
+    // Statuses without a page keep the plain-text body
+    let resp = http_client
+        .get(format!("http://{}/", server.http_addr))
+        .header("Host", "example.org")
+        .send()
+        .await
+        .expect("HTTP request failed");
+    assert_eq!(resp.status(), 400);
+    assert!(resp.headers().get("content-type").is_none());
+    assert_eq!(resp.text().await.unwrap(), "Invalid or missing subdomain");
+}
+
 #[tokio::test]
 async fn test_multiple_tunnels_isolated() {
     init_test();
@@ -335,3 +504,539 @@ async fn test_multiple_tunnels_isolated() {
     // Verify both DNS records were created
     assert_eq!(server.dns_provider.record_count(), 2);
 }
+
+#[tokio::test]
+async fn test_http_policy_blocks_disallowed_requests() {
+    init_test();
+
+    let server = TestServer::start().await;
+    let mock = MockHttpService::start().await;
+
+    let policy = HttpPolicy {
+        allowed_methods: vec!["GET".to_string(), "HEAD".to_string()],
+        allowed_paths: vec!["/public/*".to_string()],
+    };
+    let client = TestClient::connect_with_policy(
+        &server,
+        &mock.addr_string(),
+        Some("readonly".to_string()),
+        TunnelType::Http,
+        Some(policy),
+    )
+    .await
+    .expect("Failed to connect client");
+    assert_eq!(client.subdomain.as_deref(), Some("readonly"));
+
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+
+    let http_client = reqwest::Client::new();
+
+    // Disallowed method is rejected at the server
+    let resp = http_client
+        .post(format!("http://{}/public/form", server.http_addr))
+        .header("Host", server.host_for("readonly"))
+        .body("data")
+        .send()
+        .await
+        .expect("HTTP request failed");
+    assert_eq!(resp.status(), 403);
+
+    // Disallowed path is rejected at the server
+    let resp = http_client
+        .get(format!("http://{}/admin", server.http_addr))
+        .header("Host", server.host_for("readonly"))
+        .send()
+        .await
+        .expect("HTTP request failed");
+    assert_eq!(resp.status(), 403);
+
+    // Dot-segments can't climb out of an allowed path. Sent raw, since
+    // reqwest would resolve them before sending.
+    for path in [
+        "/public/../admin",
+        "/public/%2e%2e/admin",
+        "/public//../admin",
+    ] {
+        let mut stream = TcpStream::connect(server.http_addr).await.unwrap();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path,
+            server.host_for("readonly")
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(
+            tokio::time::Duration::from_secs(5),
+            stream.read_to_end(&mut response),
+        )
+        .await
+        .expect("No response")
+        .unwrap();
+        assert!(
+            response.starts_with(b"HTTP/1.1 403"),
+            "{} was not blocked",
+            path
+        );
+    }
+
+    assert!(
+        mock.get_requests().is_empty(),
+        "Blocked requests must not reach the local service"
+    );
+
+    // Allowed request goes through
+    let resp = http_client
+        .get(format!("http://{}/public/page?x=1", server.http_addr))
+        .header("Host", server.host_for("readonly"))
+        .send()
+        .await
+        .expect("HTTP request failed");
+    assert_eq!(resp.status(), 200);
+    assert_eq!(mock.get_requests().len(), 1);
+}
+
+#[tokio::test]
+async fn test_hello_returns_server_info() {
+    init_test();
+
+    let server = TestServer::start().await;
+
+    match TestClient::hello(&server).await.expect("Hello failed") {
+        ServerMessage::ServerInfo {
+            base_domain,
+            other_base_domains,
+            protocol_version,
+            supported_types,
+            max_tunnels,
+        } => {
+            assert_eq!(base_domain, server.base_domain);
+            assert!(other_base_domains.is_empty());
+            assert_eq!(protocol_version, PROTOCOL_VERSION);
+            assert_eq!(supported_types, vec![TunnelType::Http, TunnelType::Tcp]);
+            assert_eq!(max_tunnels, None);
+        }
+        other => panic!("Expected ServerInfo, got {:?}", other),
+    }
+
+    // A Hello-only connection leaves nothing behind
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+    assert_eq!(server.dns_provider.record_count(), 0);
+
+    let mock = MockHttpService::start().await;
+    let client = TestClient::connect(
+        &server,
+        &mock.addr_string(),
+        Some("after-hello".to_string()),
+        TunnelType::Http,
+    )
+    .await
+    .expect("Failed to connect client after Hello");
+    assert_eq!(client.subdomain.as_deref(), Some("after-hello"));
+}
+
+#[tokio::test]
+async fn test_message_tap_records_server_messages() {
+    init_test();
+
+    let server = TestServer::start().await;
+    let client =
+        TestClient::with_message_tap(&server, Some("tapped".to_string()), TunnelType::Http)
+            .await
+            .expect("Failed to connect client");
+    let timeout = tokio::time::Duration::from_secs(5);
+
+    assert!(matches!(
+        client.received_messages().first(),
+        Some(ServerMessage::TunnelEstablished { subdomain, .. }) if subdomain == "tapped"
+    ));
+
+    // Pings are answered with a Pong echoing the timestamp
+    client
+        .send(ClientMessage::Ping { timestamp: 42 })
+        .await
+        .expect("Failed to send Ping");
+    client
+        .wait_for_message(timeout, |m| {
+            matches!(m, ServerMessage::Pong { timestamp: 42 })
+        })
+        .await
+        .expect("No Pong received");
+
+    // Requests are recorded, not forwarded; the test answers them itself
+    let url = format!("http://{}/observed", server.http_addr);
+    let host = server.host_for("tapped");
+    let request = tokio::spawn(async move {
+        reqwest::Client::new()
+            .get(url)
+            .header("Host", host)
+            .send()
+            .await
+    });
+
+    let ServerMessage::HttpRequest { stream_id, uri, .. } = client
+        .wait_for_message(timeout, |m| matches!(m, ServerMessage::HttpRequest { .. }))
+        .await
+        .expect("No HttpRequest received")
+    else {
+        unreachable!()
+    };
+    assert_eq!(uri, "/observed");
+
+    client
+        .send(ClientMessage::HttpResponse {
+            stream_id,
+            status: 204,
+            headers: vec![],
+            body: vec![],
+        })
+        .await
+        .expect("Failed to send response");
+    let resp = request.await.unwrap().expect("HTTP request failed");
+    assert_eq!(resp.status(), 204);
+}
+
+/// Wait briefly for the server side of a raw connection to close it
+async fn closed_promptly(stream: &mut tokio::net::TcpStream) -> bool {
+    use tokio::io::AsyncReadExt;
+
+    let mut buf = [0u8; 1];
+    matches!(
+        tokio::time::timeout(
+            tokio::time::Duration::from_millis(500),
+            stream.read(&mut buf)
+        )
+        .await,
+        Ok(Ok(0)) | Ok(Err(_))
+    )
+}
+
+#[tokio::test]
+async fn test_connection_cap_rejects_excess() {
+    init_test();
+
+    let server = TestServer::start_with(|options| options.max_connections = Some(3)).await;
+
+    // Raw connections that never start TLS hold their slots
+    let mut held = Vec::new();
+    for _ in 0..3 {
+        held.push(
+            tokio::net::TcpStream::connect(server.control_addr)
+                .await
+                .unwrap(),
+        );
+    }
+
+    let mut excess = tokio::net::TcpStream::connect(server.control_addr)
+        .await
+        .unwrap();
+    assert!(
+        closed_promptly(&mut excess).await,
+        "excess connection was not closed"
+    );
+    assert!(
+        !closed_promptly(&mut held[0]).await,
+        "connection within the cap was closed"
+    );
+
+    // Freeing a slot lets a real client in again
+    held.clear();
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+
+    let mock = MockHttpService::start().await;
+    let client = TestClient::connect(
+        &server,
+        &mock.addr_string(),
+        Some("after-cap".to_string()),
+        TunnelType::Http,
+    )
+    .await
+    .expect("Failed to connect once a slot was free");
+    assert_eq!(client.subdomain.as_deref(), Some("after-cap"));
+}
+
+#[tokio::test]
+async fn test_per_ip_connection_cap() {
+    init_test();
+
+    let server = TestServer::start_with(|options| options.max_connections_per_ip = Some(2)).await;
+
+    let _first = tokio::net::TcpStream::connect(server.control_addr)
+        .await
+        .unwrap();
+    let mut second = tokio::net::TcpStream::connect(server.control_addr)
+        .await
+        .unwrap();
+    let mut third = tokio::net::TcpStream::connect(server.control_addr)
+        .await
+        .unwrap();
+
+    assert!(closed_promptly(&mut third).await);
+    assert!(!closed_promptly(&mut second).await);
+}
+
+/// Resolves from the third lookup on, like a record still propagating
+#[derive(Default)]
+struct PropagatingLookup {
+    calls: AtomicU32,
+}
+
+#[async_trait::async_trait]
+impl siphon_server::NameLookup for PropagatingLookup {
+    async fn resolves(&self, _name: &str) -> bool {
+        self.calls.fetch_add(1, Ordering::Relaxed) + 1 >= 3
+    }
+}
+
+#[tokio::test]
+async fn test_tunnel_announced_after_dns_propagates() {
+    init_test();
+
+    let lookup = Arc::new(PropagatingLookup::default());
+    let check = siphon_server::DnsPropagationCheck::new(lookup.clone()).with_backoff(
+        siphon_server::PropagationBackoff {
+            attempts: 5,
+            initial_delay: std::time::Duration::from_millis(10),
+            max_delay: std::time::Duration::from_millis(20),
+        },
+    );
+    let server = TestServer::start_with(|options| options.dns_propagation = Some(check)).await;
+
+    let mock = MockHttpService::start().await;
+    let client = TestClient::connect(
+        &server,
+        &mock.addr_string(),
+        Some("propagated".to_string()),
+        TunnelType::Http,
+    )
+    .await
+    .expect("Failed to connect client");
+
+    assert_eq!(client.subdomain.as_deref(), Some("propagated"));
+    assert_eq!(
+        lookup.calls.load(Ordering::Relaxed),
+        3,
+        "tunnel announced before its name resolved"
+    );
+}
+
+#[tokio::test]
+async fn test_http_tunnel_injects_response_headers() {
+    init_test();
+
+    let server = TestServer::start_with(|options| {
+        options.inject_response_headers = siphon_server::InjectedHeaders::parse(&[(
+            "X-Frame-Options".to_string(),
+            "DENY".to_string(),
+        )])
+        .expect("Invalid response headers");
+    })
+    .await;
+    let mock = MockHttpService::start().await;
+    mock.set_response_headers(vec![
+        ("X-App-Header".to_string(), "from-app".to_string()),
+        ("X-Frame-Options".to_string(), "SAMEORIGIN".to_string()),
+    ]);
+
+    let client = TestClient::connect(&server, &mock.addr_string(), None, TunnelType::Http)
+        .await
+        .expect("Failed to connect client");
+    let subdomain = client.subdomain.clone().expect("No subdomain assigned");
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+
+    let resp = reqwest::Client::new()
+        .get(format!("http://{}/", server.http_addr))
+        .header("Host", server.host_for(&subdomain))
+        .send()
+        .await
+        .expect("HTTP request failed");
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["x-app-header"], "from-app");
+    // The injected header wins over the app's own
+    let frame_options: Vec<_> = resp.headers().get_all("x-frame-options").iter().collect();
+    assert_eq!(frame_options, vec!["DENY"]);
+}
+
+/// Request `/` through a tunnel whose app tries to set `X-Siphon-Tunnel`
+/// itself, returning the header visitors see
+async fn tunnel_header(server: &TestServer) -> Option<String> {
+    let mock = MockHttpService::start().await;
+    mock.set_response_headers(vec![("X-Siphon-Tunnel".to_string(), "spoofed".to_string())]);
+
+    let client = TestClient::connect(server, &mock.addr_string(), None, TunnelType::Http)
+        .await
+        .expect("Failed to connect client");
+    let subdomain = client.subdomain.clone().expect("No subdomain assigned");
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+
+    let resp = reqwest::Client::new()
+        .get(format!("http://{}/", server.http_addr))
+        .header("Host", server.host_for(&subdomain))
+        .send()
+        .await
+        .expect("HTTP request failed");
+    assert_eq!(resp.status(), 200);
+
+    let values: Vec<_> = resp.headers().get_all("x-siphon-tunnel").iter().collect();
+    assert!(values.len() <= 1, "{:?}", values);
+    values
+        .first()
+        .map(|value| value.to_str().unwrap().replace(&subdomain, "<subdomain>"))
+}
+
+#[tokio::test]
+async fn test_tunnel_header_only_when_enabled() {
+    init_test();
+
+    // Off by default: the app's own header doesn't get through either
+    let server = TestServer::start().await;
+    assert_eq!(tunnel_header(&server).await, None);
+
+    let server = TestServer::start_with(|options| options.expose_tunnel_header = true).await;
+    assert_eq!(
+        tunnel_header(&server).await.as_deref(),
+        Some("<subdomain>@test-client")
+    );
+}
+
+#[tokio::test]
+async fn test_duplicate_foreign_and_orphan_responses_are_dropped() {
+    init_test();
+
+    let server = TestServer::start().await;
+    let owner = TestClient::with_message_tap(&server, Some("owner".to_string()), TunnelType::Http)
+        .await
+        .expect("Failed to connect client");
+    let intruder =
+        TestClient::with_message_tap(&server, Some("intruder".to_string()), TunnelType::Http)
+            .await
+            .expect("Failed to connect client");
+    let timeout = tokio::time::Duration::from_secs(5);
+    let response = |stream_id, body: &[u8]| ClientMessage::HttpResponse {
+        stream_id,
+        status: 200,
+        headers: vec![],
+        body: body.to_vec(),
+    };
+
+    let url = format!("http://{}/", server.http_addr);
+    let host = server.host_for("owner");
+    let request = tokio::spawn(async move {
+        reqwest::Client::new()
+            .get(url)
+            .header("Host", host)
+            .send()
+            .await
+    });
+    let ServerMessage::HttpRequest { stream_id, .. } = owner
+        .wait_for_message(timeout, |m| matches!(m, ServerMessage::HttpRequest { .. }))
+        .await
+        .expect("No HttpRequest received")
+    else {
+        unreachable!()
+    };
+
+    // Another client can't answer a request it wasn't sent
+    intruder
+        .send(response(stream_id, b"forged"))
+        .await
+        .expect("Failed to send response");
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    assert!(!request.is_finished(), "Foreign response was delivered");
+
+    // The owner's first answer wins; a duplicate and an unknown stream are dropped
+    owner
+        .send(response(stream_id, b"genuine"))
+        .await
+        .expect("Failed to send response");
+    owner
+        .send(response(stream_id, b"duplicate"))
+        .await
+        .expect("Failed to send response");
+    owner
+        .send(response(stream_id + 1_000_000, b"orphan"))
+        .await
+        .expect("Failed to send response");
+
+    let resp = request.await.unwrap().expect("HTTP request failed");
+    assert_eq!(resp.text().await.unwrap(), "genuine");
+
+    tokio::time::timeout(timeout, async {
+        while server.stream_metrics.orphan_responses() < 3 {
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Orphan responses not counted");
+    assert_eq!(server.stream_metrics.orphan_responses(), 3);
+
+    // The connection survives and keeps serving
+    owner
+        .send(ClientMessage::Ping { timestamp: 7 })
+        .await
+        .expect("Failed to send Ping");
+    owner
+        .wait_for_message(timeout, |m| {
+            matches!(m, ServerMessage::Pong { timestamp: 7 })
+        })
+        .await
+        .expect("No Pong received");
+}
+
+#[tokio::test]
+async fn test_response_timeout_answers_504() {
+    init_test();
+
+    let server = TestServer::start_with(|options| {
+        options.response_timeout = Some(Duration::from_millis(300))
+    })
+    .await;
+    let client =
+        TestClient::with_message_tap(&server, Some("stalled".to_string()), TunnelType::Http)
+            .await
+            .expect("Failed to connect client");
+
+    let url = format!("http://{}/", server.http_addr);
+    let host = server.host_for("stalled");
+    let started = std::time::Instant::now();
+    let request = tokio::spawn(async move {
+        reqwest::Client::new()
+            .get(url)
+            .header("Host", host)
+            .send()
+            .await
+    });
+
+    // The client receives the request but never answers
+    let ServerMessage::HttpRequest { stream_id, .. } = client
+        .wait_for_message(Duration::from_secs(5), |m| {
+            matches!(m, ServerMessage::HttpRequest { .. })
+        })
+        .await
+        .expect("No HttpRequest received")
+    else {
+        unreachable!()
+    };
+
+    let resp = request.await.unwrap().expect("HTTP request failed");
+    assert_eq!(resp.status(), 504);
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // A response arriving after the timeout has nowhere to go
+    client
+        .send(ClientMessage::HttpResponse {
+            stream_id,
+            status: 200,
+            headers: vec![],
+            body: b"late".to_vec(),
+        })
+        .await
+        .expect("Failed to send response");
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while server.stream_metrics.orphan_responses() < 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Late response not counted");
+}
@@ -0,0 +1,107 @@
+//! Local concurrency limit end-to-end tests
+//!
+//! These go through the real client, which bounds forwards to the local service.
+
+use std::time::Duration;
+
+use siphon::{TunnelClient, TunnelHandle};
+use siphon_e2e::{MockHttpService, TestServer};
+
+/// Initialize tracing and crypto provider for tests
+fn init_test() {
+    // Install rustls crypto provider (ignore if already installed)
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    // Initialize tracing
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter("siphon=debug,siphon_e2e=debug")
+        .with_test_writer()
+        .try_init();
+}
+
+/// Run the real client with `max` concurrent forwards until the tunnel is up
+async fn start_client(
+    server: &TestServer,
+    mock: &MockHttpService,
+    max: usize,
+) -> (TunnelHandle, String) {
+    let handle = TunnelClient::builder()
+        .server(server.control_addr.to_string())
+        .server_name("localhost")
+        .local(mock.addr_string().parse().unwrap())
+        .tls(server.client_tls_config())
+        .max_local_concurrency(max)
+        .build()
+        .expect("Failed to build client")
+        .run();
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while handle.metrics().snapshot().tunnel_info.is_none() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("Tunnel not established");
+
+    let subdomain = handle.subdomain().expect("No subdomain assigned");
+    (handle, subdomain)
+}
+
+/// Send `count` requests at once, returning their statuses
+async fn burst(server: &TestServer, subdomain: &str, count: usize) -> Vec<u16> {
+    let client = reqwest::Client::new();
+    let requests: Vec<_> = (0..count)
+        .map(|i| {
+            tokio::spawn(
+                client
+                    .get(format!("http://{}/slow/{}", server.http_addr, i))
+                    .header("Host", server.host_for(subdomain))
+                    .send(),
+            )
+        })
+        .collect();
+
+    let mut statuses = Vec::new();
+    for request in requests {
+        let resp = request.await.unwrap().expect("HTTP request failed");
+        statuses.push(resp.status().as_u16());
+    }
+    statuses
+}
+
+#[tokio::test]
+async fn test_burst_is_queued_within_limit() {
+    init_test();
+
+    let server = TestServer::start().await;
+    let mock = MockHttpService::start().await;
+    mock.set_response_delay(Duration::from_millis(200));
+    let (handle, subdomain) = start_client(&server, &mock, 2).await;
+
+    // Two forwarded, two queued: all answered, never more than two at once
+    let statuses = burst(&server, &subdomain, 4).await;
+    assert_eq!(statuses, vec![200; 4]);
+    assert_eq!(mock.max_concurrent_requests(), 2);
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_burst_beyond_queue_gets_503() {
+    init_test();
+
+    let server = TestServer::start().await;
+    let mock = MockHttpService::start().await;
+    mock.set_response_delay(Duration::from_millis(300));
+    let (handle, subdomain) = start_client(&server, &mock, 2).await;
+
+    let statuses = burst(&server, &subdomain, 10).await;
+    let ok = statuses.iter().filter(|&&s| s == 200).count();
+    let busy = statuses.iter().filter(|&&s| s == 503).count();
+    assert_eq!(ok + busy, 10, "unexpected statuses: {:?}", statuses);
+    assert!(ok >= 2 && busy >= 1, "unexpected statuses: {:?}", statuses);
+    assert!(mock.max_concurrent_requests() <= 2);
+    assert_eq!(mock.get_requests().len(), ok);
+
+    handle.shutdown().await.unwrap();
+}
@@ -0,0 +1,250 @@
+//! Connection reuse to the local service end-to-end tests
+//!
+//! These go through the real client and count the connections the local
+//! service accepts across several bursts of concurrent requests, and
+//! across reconnects to the server.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use siphon::{LocalPool, ReconnectPolicy, TunnelClient, TunnelHandle};
+use siphon_e2e::{MockHttpService, TestServer};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// Requests sent at once in each burst
+const BURST: usize = 16;
+
+/// Bursts sent one after the other
+const ROUNDS: usize = 3;
+
+/// Initialize tracing and crypto provider for tests
+fn init_test() {
+    // Install rustls crypto provider (ignore if already installed)
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    // Initialize tracing
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter("siphon=debug,siphon_e2e=debug")
+        .with_test_writer()
+        .try_init();
+}
+
+/// Run the real client with `pool` until the tunnel is up
+async fn start_client(
+    server: &TestServer,
+    mock: &MockHttpService,
+    pool: LocalPool,
+) -> (TunnelHandle, String) {
+    let handle = TunnelClient::builder()
+        .server(server.control_addr.to_string())
+        .server_name("localhost")
+        .local(mock.addr_string().parse().unwrap())
+        .tls(server.client_tls_config())
+        .local_pool(pool)
+        .build()
+        .expect("Failed to build client")
+        .run();
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while handle.metrics().snapshot().tunnel_info.is_none() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("Tunnel not established");
+
+    let subdomain = handle.subdomain().expect("No subdomain assigned");
+    (handle, subdomain)
+}
+
+/// Send `ROUNDS` bursts of `BURST` concurrent requests, returning the
+/// connections the local service accepted
+async fn accepted_after_bursts(pool: LocalPool) -> usize {
+    let server = TestServer::start().await;
+    let mock = MockHttpService::start().await;
+    mock.set_response_delay(Duration::from_millis(50));
+    let (handle, subdomain) = start_client(&server, &mock, pool).await;
+
+    let client = reqwest::Client::new();
+    for round in 0..ROUNDS {
+        let requests: Vec<_> = (0..BURST)
+            .map(|i| {
+                tokio::spawn(
+                    client
+                        .get(format!("http://{}/{}/{}", server.http_addr, round, i))
+                        .header("Host", server.host_for(&subdomain))
+                        .send(),
+                )
+            })
+            .collect();
+        for request in requests {
+            let resp = request.await.unwrap().expect("HTTP request failed");
+            assert_eq!(resp.status(), 200);
+        }
+    }
+
+    handle.shutdown().await.unwrap();
+    mock.accepted_connections()
+}
+
+#[tokio::test]
+async fn test_default_pool_reuses_connections_across_bursts() {
+    init_test();
+
+    // Later bursts run on the connections the first one opened
+    let accepted = accepted_after_bursts(LocalPool::default()).await;
+    assert!(
+        accepted <= BURST + 2,
+        "{} connections for {} bursts of {}",
+        accepted,
+        ROUNDS,
+        BURST
+    );
+}
+
+#[tokio::test]
+async fn test_small_pool_churns_connections() {
+    init_test();
+
+    // With one idle connection kept, nearly every request of a burst dials again
+    let accepted = accepted_after_bursts(LocalPool {
+        max_idle: 1,
+        ..Default::default()
+    })
+    .await;
+    assert!(
+        accepted >= ROUNDS * (BURST - 2),
+        "{} connections for {} bursts of {}",
+        accepted,
+        ROUNDS,
+        BURST
+    );
+}
+
+/// TCP relay in front of the control plane whose connections can be cut,
+/// making the client reconnect
+struct ControlRelay {
+    addr: SocketAddr,
+    pipes: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    accept_task: JoinHandle<()>,
+}
+
+impl ControlRelay {
+    async fn start(target: SocketAddr) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pipes: Arc<Mutex<Vec<JoinHandle<()>>>> = Arc::default();
+        let accept_task = tokio::spawn({
+            let pipes = pipes.clone();
+            async move {
+                while let Ok((mut inbound, _)) = listener.accept().await {
+                    let pipe = tokio::spawn(async move {
+                        if let Ok(mut outbound) = TcpStream::connect(target).await {
+                            let _ =
+                                tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+                        }
+                    });
+                    pipes.lock().unwrap().push(pipe);
+                }
+            }
+        });
+        Self {
+            addr,
+            pipes,
+            accept_task,
+        }
+    }
+
+    /// Drop every relayed connection
+    fn cut(&self) {
+        for pipe in self.pipes.lock().unwrap().drain(..) {
+            pipe.abort();
+        }
+    }
+}
+
+impl Drop for ControlRelay {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+        self.cut();
+    }
+}
+
+/// Send a request to `subdomain`, retrying until the tunnel serves it
+async fn get_until_served(server: &TestServer, client: &reqwest::Client, subdomain: &str) {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let resp = client
+                .get(format!("http://{}/after-reconnect", server.http_addr))
+                .header("Host", server.host_for(subdomain))
+                .send()
+                .await;
+            if matches!(&resp, Ok(resp) if resp.status() == 200) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("Tunnel not re-established");
+}
+
+#[tokio::test]
+async fn test_reconnect_keeps_warm_local_connection() {
+    init_test();
+
+    let server = TestServer::start().await;
+    let mock = MockHttpService::start().await;
+    let relay = ControlRelay::start(server.control_addr).await;
+
+    let handle = TunnelClient::builder()
+        .server(relay.addr.to_string())
+        .server_name("localhost")
+        .local(mock.addr_string().parse().unwrap())
+        .tls(server.client_tls_config())
+        .reconnect(ReconnectPolicy {
+            delay: Duration::from_millis(50),
+            max_attempts: None,
+        })
+        .build()
+        .expect("Failed to build client")
+        .run();
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while handle.metrics().snapshot().tunnel_info.is_none() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("Tunnel not established");
+    let subdomain = handle.subdomain().expect("No subdomain assigned");
+
+    let client = reqwest::Client::new();
+    get_until_served(&server, &client, &subdomain).await;
+    assert_eq!(mock.accepted_connections(), 1);
+
+    for cycle in 1..=2 {
+        relay.cut();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while handle.metrics().snapshot().reconnects < cycle {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("Client did not reconnect");
+
+        // The new session keeps the subdomain and the idle local connection
+        assert_eq!(handle.subdomain().as_deref(), Some(subdomain.as_str()));
+        get_until_served(&server, &client, &subdomain).await;
+        assert_eq!(
+            mock.accepted_connections(),
+            1,
+            "local service dialed again after reconnect {}",
+            cycle
+        );
+    }
+
+    handle.shutdown().await.unwrap();
+}
@@ -0,0 +1,128 @@
+//! OpenTelemetry span end-to-end tests
+//!
+//! A request through a tunnel, with the server and the real client in this
+//! process and spans exported to memory, checking their names and how they
+//! nest across the tunnel. Run with `--features otel`.
+
+#![cfg(feature = "otel")]
+
+use std::time::Duration;
+
+use opentelemetry::trace::{SpanId, TraceId};
+use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider, SpanData};
+use siphon::TunnelClient;
+use siphon_common::otel::Telemetry;
+use siphon_e2e::{MockHttpService, TestServer};
+use tracing_subscriber::layer::SubscriberExt;
+
+/// Trace the visitor's request belongs to
+const VISITOR_TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+/// Export spans to memory for the whole test binary
+fn init_test() -> (Telemetry, InMemorySpanExporter) {
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    let exporter = InMemorySpanExporter::default();
+    let provider = SdkTracerProvider::builder()
+        .with_simple_exporter(exporter.clone())
+        .build();
+    let telemetry = Telemetry::new(provider);
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(telemetry.layer()))
+        .expect("Subscriber already set");
+    (telemetry, exporter)
+}
+
+fn span<'a>(spans: &'a [SpanData], name: &str) -> &'a SpanData {
+    spans
+        .iter()
+        .find(|span| span.name == name)
+        .unwrap_or_else(|| panic!("No {} span in {:?}", name, spans))
+}
+
+#[tokio::test]
+async fn test_request_lifecycle_spans() {
+    let (telemetry, exporter) = init_test();
+
+    let server = TestServer::start().await;
+    let mock = MockHttpService::start().await;
+    let handle = TunnelClient::builder()
+        .server(server.control_addr.to_string())
+        .server_name("localhost")
+        .local(mock.addr_string().parse().unwrap())
+        .tls(server.client_tls_config())
+        .build()
+        .expect("Failed to build client")
+        .run();
+    let subdomain = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(subdomain) = handle.subdomain() {
+                return subdomain;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Tunnel not established");
+
+    let resp = reqwest::Client::new()
+        .get(format!("http://{}/traced?page=1", server.http_addr))
+        .header("Host", server.host_for(&subdomain))
+        .header(
+            "traceparent",
+            format!("00-{}-00f067aa0ba902b7-01", VISITOR_TRACE_ID),
+        )
+        .send()
+        .await
+        .expect("HTTP request failed");
+    assert_eq!(resp.status(), 200);
+
+    // The request span closes last, once the response is written
+    let spans = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let spans = exporter.get_finished_spans().unwrap();
+            if spans.iter().any(|span| span.name == "http_request") {
+                return spans;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("No http_request span exported");
+
+    let request = span(&spans, "http_request");
+    let send = span(&spans, "tunnel_send");
+    let forward = span(&spans, "forward_http");
+
+    // One trace, continuing the visitor's, nested request > send > forward
+    let trace_id = TraceId::from_hex(VISITOR_TRACE_ID).unwrap();
+    for span in [request, send, forward] {
+        assert_eq!(span.span_context.trace_id(), trace_id);
+    }
+    assert_eq!(
+        request.parent_span_id,
+        SpanId::from_hex("00f067aa0ba902b7").unwrap()
+    );
+    assert_eq!(send.parent_span_id, request.span_context.span_id());
+    assert_eq!(forward.parent_span_id, send.span_context.span_id());
+
+    // The local service gets the client's span as its parent
+    let traceparent = mock
+        .last_request()
+        .unwrap()
+        .headers
+        .into_iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("traceparent"))
+        .map(|(_, value)| value)
+        .expect("No traceparent forwarded");
+    assert_eq!(
+        traceparent,
+        format!(
+            "00-{}-{}-01",
+            VISITOR_TRACE_ID,
+            forward.span_context.span_id()
+        )
+    );
+
+    handle.shutdown().await.unwrap();
+    telemetry.shutdown();
+}
@@ -0,0 +1,117 @@
+//! Reconnect hold end-to-end tests
+//!
+//! Requests for a subdomain whose tunnel just dropped are held until a tunnel
+//! registers it again, instead of failing right away.
+
+use std::time::Duration;
+
+use siphon_e2e::{MockHttpService, TestClient, TestServer};
+use siphon_protocol::TunnelType;
+
+/// Initialize tracing and crypto provider for tests
+fn init_test() {
+    // Install rustls crypto provider (ignore if already installed)
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    // Initialize tracing
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter("siphon=debug,siphon_e2e=debug")
+        .with_test_writer()
+        .try_init();
+}
+
+/// Open a tunnel for `subdomain`, then drop it and wait until the server has cleaned up
+async fn connect_and_drop(server: &TestServer, mock: &MockHttpService, subdomain: &str) {
+    let mut client = TestClient::connect(
+        server,
+        &mock.addr_string(),
+        Some(subdomain.to_string()),
+        TunnelType::Http,
+    )
+    .await
+    .expect("Failed to connect client");
+    client.shutdown().await;
+
+    // The DNS record goes once the tunnel is unregistered
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while server.dns_provider.has_record(subdomain) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Tunnel was not unregistered");
+}
+
+#[tokio::test]
+async fn test_held_request_succeeds_after_reconnect() {
+    init_test();
+
+    let server =
+        TestServer::start_with(|options| options.reconnect_hold = Some(Duration::from_secs(5)))
+            .await;
+    let mock = MockHttpService::start().await;
+    mock.set_response_body(b"Back again".to_vec());
+    connect_and_drop(&server, &mock, "held-app").await;
+
+    let request = tokio::spawn({
+        let url = format!("http://{}/", server.http_addr);
+        let host = server.host_for("held-app");
+        async move {
+            reqwest::Client::new()
+                .get(url)
+                .header("Host", host)
+                .send()
+                .await
+        }
+    });
+
+    // The request waits instead of getting a 404
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!request.is_finished(), "Request was not held");
+
+    let _client = TestClient::connect(
+        &server,
+        &mock.addr_string(),
+        Some("held-app".to_string()),
+        TunnelType::Http,
+    )
+    .await
+    .expect("Failed to reconnect client");
+
+    let resp = tokio::time::timeout(Duration::from_secs(5), request)
+        .await
+        .expect("Held request was not resumed")
+        .unwrap()
+        .expect("HTTP request failed");
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.text().await.unwrap(), "Back again");
+}
+
+#[tokio::test]
+async fn test_held_request_times_out_with_503() {
+    init_test();
+
+    let server =
+        TestServer::start_with(|options| options.reconnect_hold = Some(Duration::from_millis(300)))
+            .await;
+    let mock = MockHttpService::start().await;
+    connect_and_drop(&server, &mock, "gone-app").await;
+
+    let http_client = reqwest::Client::new();
+    let resp = http_client
+        .get(format!("http://{}/", server.http_addr))
+        .header("Host", server.host_for("gone-app"))
+        .send()
+        .await
+        .expect("HTTP request failed");
+    assert_eq!(resp.status(), 503);
+
+    // Subdomains that were never registered aren't held
+    let resp = http_client
+        .get(format!("http://{}/", server.http_addr))
+        .header("Host", server.host_for("never-seen"))
+        .send()
+        .await
+        .expect("HTTP request failed");
+    assert_eq!(resp.status(), 404);
+}
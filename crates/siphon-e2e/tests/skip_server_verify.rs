@@ -0,0 +1,81 @@
+//! Skipping server certificate verification end-to-end tests
+//!
+//! The client trusts a different CA than the one that signed the server's
+//! certificate, so it only gets through when told not to verify the server.
+
+use std::time::Duration;
+
+use siphon::{ReconnectPolicy, TunnelClient, TunnelExit};
+use siphon_common::TlsPolicy;
+use siphon_e2e::{MockHttpService, TestCertificates, TestServer};
+
+/// Initialize tracing and crypto provider for tests
+fn init_test() {
+    // Install rustls crypto provider (ignore if already installed)
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    // Initialize tracing
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter("siphon=debug,siphon_e2e=debug")
+        .with_test_writer()
+        .try_init();
+}
+
+/// A client using `tls` that gives up after its first session
+fn client(server: &TestServer, local: &MockHttpService, tls: rustls::ClientConfig) -> TunnelClient {
+    TunnelClient::builder()
+        .server(server.control_addr.to_string())
+        .server_name("localhost")
+        .local(local.addr_string().parse().unwrap())
+        .tls(tls)
+        .reconnect(ReconnectPolicy::never())
+        .build()
+        .expect("Failed to build client")
+}
+
+#[tokio::test]
+async fn test_untrusted_server_refused() {
+    init_test();
+
+    let server = TestServer::start().await;
+    let mock = MockHttpService::start().await;
+    let other_ca = TestCertificates::generate().ca_cert_pem;
+    let tls = siphon_common::load_client_config_from_pem(
+        &server.certs.client_cert_pem,
+        &server.certs.client_key_pem,
+        &other_ca,
+    )
+    .unwrap();
+    let mut handle = client(&server, &mock, tls).run();
+
+    let exit = tokio::time::timeout(Duration::from_secs(5), handle.wait_for_exit())
+        .await
+        .expect("Client did not give up");
+    assert!(matches!(exit, TunnelExit::Fatal(_)), "{:?}", exit);
+    assert!(handle.subdomain().is_none());
+}
+
+#[tokio::test]
+async fn test_untrusted_server_accepted_when_skipping_verification() {
+    init_test();
+
+    let server = TestServer::start().await;
+    let mock = MockHttpService::start().await;
+    let tls = siphon_common::load_client_config_from_pem_skip_server_verify(
+        &server.certs.client_cert_pem,
+        &server.certs.client_key_pem,
+        &TlsPolicy::default(),
+    )
+    .unwrap();
+    let handle = client(&server, &mock, tls).run();
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while handle.subdomain().is_none() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("Tunnel not established");
+
+    handle.shutdown().await.unwrap();
+}
@@ -0,0 +1,117 @@
+//! Static route end-to-end tests
+//!
+//! Statically routed subdomains are proxied by the HTTP plane itself, with no
+//! tunnel client connected.
+
+use hyper::StatusCode;
+use siphon_e2e::{MockHttpService, TestClient, TestServer};
+use siphon_protocol::TunnelType;
+
+/// Initialize tracing and crypto provider for tests
+fn init_test() {
+    // Install rustls crypto provider (ignore if already installed)
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    // Initialize tracing
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter("siphon=debug,siphon_e2e=debug")
+        .with_test_writer()
+        .try_init();
+}
+
+/// Routes proxying each subdomain straight to its upstream
+fn static_routes(routes: &[(&str, &str)]) -> siphon_server::StaticRoutes {
+    let routes: Vec<(String, String)> = routes
+        .iter()
+        .map(|(subdomain, upstream)| (subdomain.to_string(), upstream.to_string()))
+        .collect();
+    siphon_server::StaticRoutes::parse(&routes).expect("Invalid static routes")
+}
+
+#[tokio::test]
+async fn test_static_route_proxies_to_upstream() {
+    init_test();
+
+    let upstream = MockHttpService::start().await;
+    upstream.set_response_status(StatusCode::CREATED);
+    upstream.set_response_body("from upstream");
+    upstream.set_response_headers(vec![("X-Upstream".to_string(), "yes".to_string())]);
+    let server = TestServer::start_with(|options| {
+        options.static_routes = static_routes(&[(
+            "grafana",
+            &format!("http://{}/base", upstream.addr_string()),
+        )]);
+    })
+    .await;
+
+    let resp = reqwest::Client::new()
+        .post(format!("http://{}/api/query?from=now", server.http_addr))
+        .header("Host", server.host_for("grafana"))
+        .body("payload")
+        .send()
+        .await
+        .expect("HTTP request failed");
+    assert_eq!(resp.status(), 201);
+    assert_eq!(resp.headers()["x-upstream"], "yes");
+    assert_eq!(resp.text().await.unwrap(), "from upstream");
+
+    let request = upstream.last_request().expect("Upstream got no request");
+    assert_eq!(request.method, "POST");
+    assert_eq!(request.uri, "/base/api/query?from=now");
+    assert_eq!(request.body, b"payload");
+    let header = |name: &str| {
+        request
+            .headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.clone())
+    };
+    assert_eq!(header("x-forwarded-proto").as_deref(), Some("http"));
+    assert_eq!(header("x-forwarded-host"), Some(server.host_for("grafana")));
+    assert_eq!(header("x-forwarded-for").as_deref(), Some("127.0.0.1"));
+}
+
+#[tokio::test]
+async fn test_static_route_subdomain_reserved() {
+    init_test();
+
+    let upstream = MockHttpService::start().await;
+    let server = TestServer::start_with(|options| {
+        options.static_routes =
+            static_routes(&[("grafana", &format!("http://{}", upstream.addr_string()))]);
+    })
+    .await;
+
+    let local = MockHttpService::start().await;
+    let result = TestClient::connect(
+        &server,
+        &local.addr_string(),
+        Some("grafana".to_string()),
+        TunnelType::Http,
+    )
+    .await;
+    assert!(result.is_err(), "Tunnel took a statically routed subdomain");
+}
+
+#[tokio::test]
+async fn test_static_route_upstream_down() {
+    init_test();
+
+    // Bind and drop to get a port nothing listens on
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let server = TestServer::start_with(|options| {
+        options.static_routes = static_routes(&[("gone", &format!("http://{}", addr))]);
+    })
+    .await;
+
+    let resp = reqwest::Client::new()
+        .get(format!("http://{}/", server.http_addr))
+        .header("Host", server.host_for("gone"))
+        .send()
+        .await
+        .expect("HTTP request failed");
+    assert_eq!(resp.status(), 502);
+}
@@ -0,0 +1,86 @@
+//! HTTPS plane SNI check end-to-end tests
+//!
+//! With strict SNI the HTTPS plane only completes handshakes for the base
+//! domain and its subdomains, so scanners connecting to the origin directly
+//! never see the certificate.
+
+use std::net::SocketAddr;
+
+use siphon_e2e::{MockHttpService, TestClient, TestServer};
+use siphon_protocol::TunnelType;
+
+/// Initialize tracing and crypto provider for tests
+fn init_test() {
+    // Install rustls crypto provider (ignore if already installed)
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    // Initialize tracing
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter("siphon=debug,siphon_e2e=debug")
+        .with_test_writer()
+        .try_init();
+}
+
+/// GET `https://{host}/` with `host` resolving to `addr`, whatever certificate comes back
+async fn get(addr: SocketAddr, host: &str) -> reqwest::Result<reqwest::Response> {
+    reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .resolve(host, addr)
+        .build()
+        .unwrap()
+        .get(format!("https://{}:{}/", host, addr.port()))
+        .send()
+        .await
+}
+
+#[tokio::test]
+async fn test_strict_sni_rejects_foreign_server_names() {
+    init_test();
+
+    let server = TestServer::start_with(|options| {
+        options.https = true;
+        options.strict_sni = true;
+    })
+    .await;
+    let mock = MockHttpService::start().await;
+    let _client = TestClient::connect(
+        &server,
+        &mock.addr_string(),
+        Some("app".to_string()),
+        TunnelType::Http,
+    )
+    .await
+    .expect("Failed to connect client");
+
+    let resp = get(server.http_addr, &server.host_for("app"))
+        .await
+        .expect("Tunnel host refused");
+    assert_eq!(resp.status(), 200);
+
+    // Not ours, a lookalike of the base domain, and no SNI at all (by IP)
+    for host in ["origin.example.org", "eviltest.example.com"] {
+        let result = get(server.http_addr, host).await;
+        assert!(result.is_err(), "{} was accepted", host);
+    }
+    let result = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap()
+        .get(format!("https://{}/", server.http_addr))
+        .send()
+        .await;
+    assert!(result.is_err(), "Connection without SNI was accepted");
+}
+
+#[tokio::test]
+async fn test_permissive_sni_by_default() {
+    init_test();
+
+    let server = TestServer::start_with(|options| options.https = true).await;
+
+    // The handshake completes; the plane itself turns the unknown host away
+    let resp = get(server.http_addr, "origin.example.org")
+        .await
+        .expect("Handshake refused");
+    assert_eq!(resp.status(), 400);
+}
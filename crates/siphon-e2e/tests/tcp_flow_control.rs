@@ -0,0 +1,182 @@
+//! TCP flow control end-to-end tests
+//!
+//! These run the real client and open two streams on one TCP tunnel: one
+//! whose reader stops reading, one that echoes. The echo must keep working
+//! while the slow stream is stuck, in both directions.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use siphon::{LocalTarget, TunnelClient, TunnelHandle};
+use siphon_e2e::TestServer;
+use siphon_protocol::TunnelType;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// More than the socket buffers along the way can absorb, so a stream
+/// whose reader stops stays stuck
+const FLOOD: usize = 256 * 1024 * 1024;
+
+/// Initialize tracing and crypto provider for tests
+fn init_test() {
+    // Install rustls crypto provider (ignore if already installed)
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    // Initialize tracing
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter("siphon=info,siphon_e2e=debug")
+        .with_test_writer()
+        .try_init();
+}
+
+/// Write [`FLOOD`] bytes to `stream`, counting them in `sent`
+async fn flood(stream: &mut TcpStream, sent: &AtomicUsize) {
+    let chunk = vec![b'x'; 64 * 1024];
+    while sent.load(Ordering::Relaxed) < FLOOD {
+        if stream.write_all(&chunk).await.is_err() {
+            return;
+        }
+        sent.fetch_add(chunk.len(), Ordering::Relaxed);
+    }
+}
+
+/// Wait until a flood stops making progress, checking it did stop short
+///
+/// Socket buffers grow in bursts, so only a second without progress counts.
+async fn wait_for_stall(sent: &AtomicUsize) {
+    let mut last = usize::MAX;
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let now = sent.load(Ordering::Relaxed);
+        if now == last {
+            break;
+        }
+        last = now;
+    }
+    assert!(last < FLOOD, "the slow stream was not held back");
+}
+
+/// Start a local service whose connections behave according to their first
+/// byte: `S` stops reading, `F` floods the visitor (counting into the
+/// returned counter), anything else is echoed
+async fn start_local_service() -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let flooded = Arc::new(AtomicUsize::new(0));
+    let sent = flooded.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let sent = sent.clone();
+            tokio::spawn(async move {
+                let mut mode = [0u8; 1];
+                if stream.read_exact(&mut mode).await.is_err() {
+                    return;
+                }
+                match mode[0] {
+                    b'S' => {
+                        // Hold the connection open without reading
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                    }
+                    b'F' => flood(&mut stream, &sent).await,
+                    first => {
+                        let _ = stream.write_all(&[first]).await;
+                        let mut buf = [0u8; 1024];
+                        while let Ok(n) = stream.read(&mut buf).await {
+                            if n == 0 || stream.write_all(&buf[..n]).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+            });
+        }
+    });
+    (addr, flooded)
+}
+
+/// Run the real client for a TCP tunnel until it is up, returning its port
+async fn start_client(server: &TestServer, local: SocketAddr) -> (TunnelHandle, u16) {
+    let handle = TunnelClient::builder()
+        .server(server.control_addr.to_string())
+        .server_name("localhost")
+        .local(LocalTarget::Tcp(local.to_string()))
+        .tunnel_type(TunnelType::Tcp)
+        .tls(server.client_tls_config())
+        .build()
+        .expect("Failed to build client")
+        .run();
+
+    let port = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(port) = handle
+                .metrics()
+                .snapshot()
+                .tunnel_info
+                .and_then(|info| info.port)
+            {
+                return port;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("Tunnel not established");
+    (handle, port)
+}
+
+/// Open a stream that echoes and check a round trip completes promptly
+async fn assert_echo(port: u16) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 5];
+    tokio::time::timeout(Duration::from_secs(3), stream.read_exact(&mut buf))
+        .await
+        .expect("Echo stalled behind the slow stream")
+        .unwrap();
+    assert_eq!(&buf, b"hello");
+}
+
+#[tokio::test]
+async fn test_slow_local_reader_does_not_stall_other_streams() {
+    init_test();
+
+    let server = TestServer::start().await;
+    let (local, _) = start_local_service().await;
+    let (handle, port) = start_client(&server, local).await;
+
+    // The local service stops reading this one while the visitor keeps sending
+    let mut slow = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    slow.write_all(b"S").await.unwrap();
+    let sent = Arc::new(AtomicUsize::new(0));
+    let flooding = tokio::spawn({
+        let sent = sent.clone();
+        async move { flood(&mut slow, &sent).await }
+    });
+    wait_for_stall(&sent).await;
+
+    assert_echo(port).await;
+
+    flooding.abort();
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_slow_visitor_does_not_stall_other_streams() {
+    init_test();
+
+    let server = TestServer::start().await;
+    let (local, flooded) = start_local_service().await;
+    let (handle, port) = start_client(&server, local).await;
+
+    // The local service floods this visitor, which never reads
+    let mut slow = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    slow.write_all(b"F").await.unwrap();
+    wait_for_stall(&flooded).await;
+
+    assert_echo(port).await;
+
+    drop(slow);
+    handle.shutdown().await.unwrap();
+}
@@ -271,3 +271,150 @@ async fn test_tcp_tunnel_bidirectional() {
         assert_eq!(&buf[..n], msg.as_bytes());
     }
 }
+
+/// Build a TLS ClientHello carrying `server_name` as SNI
+fn client_hello(server_name: &str) -> Vec<u8> {
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(rustls::RootCertStore::empty())
+        .with_no_client_auth();
+    let name = server_name.to_string().try_into().unwrap();
+    let mut conn = rustls::ClientConnection::new(std::sync::Arc::new(config), name).unwrap();
+
+    let mut hello = Vec::new();
+    conn.write_tls(&mut hello).unwrap();
+    hello
+}
+
+#[tokio::test]
+async fn test_tcp_mux_routes_by_sni() {
+    init_test();
+
+    let server = TestServer::start_with(|options| options.tcp_mux = true).await;
+    let mux_addr = server.tcp_mux_addr.expect("No TCP mux address");
+
+    let mock_alpha = MockTcpService::start().await;
+    let mock_beta = MockTcpService::start().await;
+
+    let client_alpha = TestClient::connect(
+        &server,
+        &mock_alpha.addr_string(),
+        Some("alpha".to_string()),
+        TunnelType::Tcp,
+    )
+    .await
+    .expect("Failed to connect client alpha");
+    let client_beta = TestClient::connect(
+        &server,
+        &mock_beta.addr_string(),
+        Some("beta".to_string()),
+        TunnelType::Tcp,
+    )
+    .await
+    .expect("Failed to connect client beta");
+
+    // Both tunnels are reachable through the shared port
+    assert_eq!(client_alpha.tcp_port, Some(mux_addr.port()));
+    assert_eq!(client_beta.tcp_port, Some(mux_addr.port()));
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    for (subdomain, mock) in [("alpha", &mock_alpha), ("beta", &mock_beta)] {
+        let hello = client_hello(&server.host_for(subdomain));
+
+        let mut stream = TcpStream::connect(mux_addr)
+            .await
+            .expect("Failed to connect to TCP mux");
+        stream.write_all(&hello).await.expect("Failed to write");
+
+        // The echo service returns the ClientHello untouched
+        let mut echoed = vec![0u8; hello.len()];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut echoed))
+            .await
+            .expect("Read timeout")
+            .expect("Failed to read echo response");
+        assert_eq!(echoed, hello);
+
+        drop(stream);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(
+            mock.connection_count(),
+            1,
+            "{} got wrong traffic",
+            subdomain
+        );
+    }
+
+    // Unknown server names are rejected without reaching any service
+    let mut stream = TcpStream::connect(mux_addr)
+        .await
+        .expect("Failed to connect to TCP mux");
+    stream
+        .write_all(&client_hello(&server.host_for("gamma")))
+        .await
+        .expect("Failed to write");
+    let mut buf = [0u8; 16];
+    let n = read_with_timeout(&mut stream, &mut buf, Duration::from_secs(5))
+        .await
+        .expect("Expected connection to be closed");
+    assert_eq!(n, 0);
+    assert_eq!(mock_alpha.connection_count(), 1);
+    assert_eq!(mock_beta.connection_count(), 1);
+}
+
+#[tokio::test]
+async fn test_tcp_tunnel_bandwidth_cap() {
+    init_test();
+
+    const RATE: u64 = 64 * 1024;
+    const TOTAL: usize = 192 * 1024;
+
+    let server = TestServer::start_with(|options| {
+        options.bandwidth.default = siphon_server::BandwidthLimit {
+            ingress: None,
+            egress: Some(RATE),
+        };
+    })
+    .await;
+    let mock = MockTcpService::start().await;
+
+    let client = TestClient::connect(&server, &mock.addr_string(), None, TunnelType::Tcp)
+        .await
+        .expect("Failed to connect client");
+    let tcp_port = client.tcp_port.expect("No TCP port assigned");
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let stream = TcpStream::connect(format!("127.0.0.1:{}", tcp_port))
+        .await
+        .expect("Failed to connect to tunnel port");
+    let (mut reader, mut writer) = stream.into_split();
+
+    // Hand the write half back instead of dropping it: a half-close would
+    // end the tunneled connection before the throttled echo is through
+    let start = std::time::Instant::now();
+    let write_task = tokio::spawn(async move {
+        writer.write_all(&vec![7u8; TOTAL]).await.unwrap();
+        writer
+    });
+
+    let mut echoed = vec![0u8; TOTAL];
+    tokio::time::timeout(Duration::from_secs(15), reader.read_exact(&mut echoed))
+        .await
+        .expect("Read timeout")
+        .expect("Failed to read echo response");
+    let elapsed = start.elapsed();
+    drop(write_task.await.unwrap());
+
+    // One second of burst, then the remaining 128 KiB at 64 KiB/s
+    assert!(
+        elapsed >= Duration::from_millis(1600),
+        "transferred too fast: {:?}",
+        elapsed
+    );
+    assert!(
+        elapsed < Duration::from_secs(6),
+        "transferred too slowly: {:?}",
+        elapsed
+    );
+    assert!(echoed.iter().all(|&b| b == 7));
+}
@@ -0,0 +1,93 @@
+//! Minimum client TLS version end-to-end tests
+//!
+//! The control plane only accepts TLS 1.3; clients are pinned to one version.
+
+use std::time::Duration;
+
+use siphon::{ReconnectPolicy, TunnelClient, TunnelExit};
+use siphon_common::{TlsPolicy, TlsVersion};
+use siphon_e2e::{MockHttpService, TestServer};
+
+/// Initialize tracing and crypto provider for tests
+fn init_test() {
+    // Install rustls crypto provider (ignore if already installed)
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    // Initialize tracing
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter("siphon=debug,siphon_e2e=debug")
+        .with_test_writer()
+        .try_init();
+}
+
+/// A client that only speaks `version` and gives up after its first session
+fn client(server: &TestServer, local: &MockHttpService, version: TlsVersion) -> TunnelClient {
+    TunnelClient::builder()
+        .server(server.control_addr.to_string())
+        .server_name("localhost")
+        .local(local.addr_string().parse().unwrap())
+        .tls(server.client_tls_config_with_policy(&TlsPolicy::only(version)))
+        .reconnect(ReconnectPolicy::never())
+        .build()
+        .expect("Failed to build client")
+}
+
+#[tokio::test]
+async fn test_tls13_client_accepted() {
+    init_test();
+
+    let server = TestServer::start_with(|options| {
+        options.tls_policy = TlsPolicy::min_version(TlsVersion::Tls13);
+    })
+    .await;
+    let mock = MockHttpService::start().await;
+    let handle = client(&server, &mock, TlsVersion::Tls13).run();
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while handle.subdomain().is_none() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("Tunnel not established");
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_tls12_client_refused() {
+    init_test();
+
+    let server = TestServer::start_with(|options| {
+        options.tls_policy = TlsPolicy::min_version(TlsVersion::Tls13);
+    })
+    .await;
+    let mock = MockHttpService::start().await;
+    let mut handle = client(&server, &mock, TlsVersion::Tls12).run();
+
+    // A TLS failure is fatal rather than retried
+    let exit = tokio::time::timeout(Duration::from_secs(5), handle.wait_for_exit())
+        .await
+        .expect("Client did not give up");
+    assert!(matches!(exit, TunnelExit::Fatal(_)), "{:?}", exit);
+    assert!(handle.subdomain().is_none());
+}
+
+#[tokio::test]
+async fn test_tls12_client_accepted_by_default() {
+    init_test();
+
+    let server = TestServer::start().await;
+    let mock = MockHttpService::start().await;
+    let handle = client(&server, &mock, TlsVersion::Tls12).run();
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while handle.subdomain().is_none() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("Tunnel not established");
+
+    handle.shutdown().await.unwrap();
+}
@@ -0,0 +1,123 @@
+//! Maximum tunnel lifetime end-to-end tests
+//!
+//! The server releases a connection's tunnels once they reach their maximum
+//! lifetime; the client reconnects for the same subdomain.
+
+use std::time::Duration;
+
+use siphon::{ReconnectPolicy, TunnelClient};
+use siphon_e2e::{MockHttpService, TestClient, TestServer};
+use siphon_protocol::{ServerMessage, TunnelType};
+
+/// Lifetime given to tunnels in these tests
+const LIFETIME: Duration = Duration::from_secs(1);
+
+/// Initialize tracing and crypto provider for tests
+fn init_test() {
+    // Install rustls crypto provider (ignore if already installed)
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    // Initialize tracing
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter("siphon=debug,siphon_e2e=debug")
+        .with_test_writer()
+        .try_init();
+}
+
+/// IDs of the DNS records of `subdomain`
+fn record_ids(server: &TestServer, subdomain: &str) -> Vec<String> {
+    server
+        .dns_provider
+        .get_records()
+        .into_iter()
+        .filter(|(_, name)| name == subdomain)
+        .map(|(id, _)| id)
+        .collect()
+}
+
+#[tokio::test]
+async fn test_expired_tunnel_is_torn_down() {
+    init_test();
+
+    let server =
+        TestServer::start_with(|options| options.max_tunnel_lifetime = Some(LIFETIME)).await;
+    let client =
+        TestClient::with_message_tap(&server, Some("expiring".to_string()), TunnelType::Http)
+            .await
+            .expect("Failed to connect client");
+    assert!(server.dns_provider.has_record("expiring"));
+
+    // The tunnel is released before the client hears about it
+    client
+        .wait_for_message(Duration::from_secs(5), |m| {
+            matches!(m, ServerMessage::TunnelExpired { .. })
+        })
+        .await
+        .expect("No TunnelExpired received");
+    assert!(!server.dns_provider.has_record("expiring"));
+
+    let resp = reqwest::Client::new()
+        .get(format!("http://{}/", server.http_addr))
+        .header("Host", server.host_for("expiring"))
+        .send()
+        .await
+        .expect("HTTP request failed");
+    assert_eq!(resp.status(), 404);
+}
+
+#[tokio::test]
+async fn test_client_reconnects_after_expiry() {
+    init_test();
+
+    let server =
+        TestServer::start_with(|options| options.max_tunnel_lifetime = Some(LIFETIME)).await;
+    let mock = MockHttpService::start().await;
+    mock.set_response_body(b"Still here".to_vec());
+
+    // Expiry isn't an error: even a client that never retries comes back
+    let handle = TunnelClient::builder()
+        .server(server.control_addr.to_string())
+        .server_name("localhost")
+        .local(mock.addr_string().parse().unwrap())
+        .tls(server.client_tls_config())
+        .reconnect(ReconnectPolicy::never())
+        .build()
+        .expect("Failed to build client")
+        .run();
+
+    let subdomain = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(subdomain) = handle.subdomain() {
+                return subdomain;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Tunnel not established");
+    let first_record = record_ids(&server, &subdomain);
+
+    // A fresh tunnel, with a new DNS record, for the same subdomain
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while handle.metrics().snapshot().reconnects == 0
+            || record_ids(&server, &subdomain).is_empty()
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Client did not reconnect");
+    assert_eq!(handle.subdomain(), Some(subdomain.clone()));
+    assert_ne!(record_ids(&server, &subdomain), first_record);
+
+    let resp = reqwest::Client::new()
+        .get(format!("http://{}/", server.http_addr))
+        .header("Host", server.host_for(&subdomain))
+        .send()
+        .await
+        .expect("HTTP request failed");
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.text().await.unwrap(), "Still here");
+
+    handle.shutdown().await.unwrap();
+}
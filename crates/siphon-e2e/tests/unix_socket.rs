@@ -0,0 +1,145 @@
+//! Unix domain socket target end-to-end tests
+//!
+//! These run the real client with `--local unix:/path` and reach the local
+//! service through the server, over both tunnel types.
+
+#![cfg(unix)]
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use siphon::{LocalTarget, TunnelClient, TunnelHandle};
+use siphon_e2e::TestServer;
+use siphon_protocol::TunnelType;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixListener};
+
+/// Initialize tracing and crypto provider for tests
+fn init_test() {
+    // Install rustls crypto provider (ignore if already installed)
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    // Initialize tracing
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter("siphon=debug,siphon_e2e=debug")
+        .with_test_writer()
+        .try_init();
+}
+
+/// Socket path in the temp dir, unique to this test process and `name`
+fn socket_path(name: &str) -> PathBuf {
+    let path =
+        std::env::temp_dir().join(format!("siphon-e2e-{}-{}.sock", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// Run the real client against `path` until its tunnel is up
+async fn start_client(server: &TestServer, path: PathBuf, tunnel_type: TunnelType) -> TunnelHandle {
+    let handle = TunnelClient::builder()
+        .server(server.control_addr.to_string())
+        .server_name("localhost")
+        .local(LocalTarget::Unix(path))
+        .tunnel_type(tunnel_type)
+        .tls(server.client_tls_config())
+        .build()
+        .expect("Failed to build client")
+        .run();
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while handle.metrics().snapshot().tunnel_info.is_none() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("Tunnel not established");
+    handle
+}
+
+#[tokio::test]
+async fn test_http_tunnel_to_unix_socket() {
+    init_test();
+
+    let server = TestServer::start().await;
+    let path = socket_path("http");
+    let listener = UnixListener::bind(&path).unwrap();
+
+    // Answer each request with its request line
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request);
+                let body = request.lines().next().unwrap_or_default().to_string();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+
+    let handle = start_client(&server, path.clone(), TunnelType::Http).await;
+    let subdomain = handle.subdomain().expect("No subdomain assigned");
+
+    let resp = reqwest::Client::new()
+        .get(format!("http://{}/over/unix?x=1", server.http_addr))
+        .header("Host", server.host_for(&subdomain))
+        .send()
+        .await
+        .expect("HTTP request failed");
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.text().await.unwrap(), "GET /over/unix?x=1 HTTP/1.1");
+
+    handle.shutdown().await.unwrap();
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn test_tcp_tunnel_to_unix_socket() {
+    init_test();
+
+    let server = TestServer::start().await;
+    let path = socket_path("tcp");
+    let listener = UnixListener::bind(&path).unwrap();
+
+    // Echo everything back
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.into_split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+
+    let handle = start_client(&server, path.clone(), TunnelType::Tcp).await;
+    let port = handle
+        .metrics()
+        .snapshot()
+        .tunnel_info
+        .and_then(|info| info.port)
+        .expect("No TCP port assigned");
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    stream.write_all(b"hello over unix").await.unwrap();
+    let mut buf = [0u8; 15];
+    tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut buf))
+        .await
+        .expect("No echo from the Unix socket service")
+        .unwrap();
+    assert_eq!(&buf, b"hello over unix");
+
+    drop(stream);
+    handle.shutdown().await.unwrap();
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,169 @@
+//! WebSocket tunnel end-to-end tests
+//!
+//! These go through the real client, whose forwarder relays upgrades.
+
+use std::time::Duration;
+
+use siphon::{TunnelClient, TunnelHandle};
+use siphon_e2e::mock_ws_service::{
+    accept_key, encode_frame, header_value, read_frame, read_http_head, OPCODE_CLOSE, OPCODE_TEXT,
+};
+use siphon_e2e::{MockWsService, TestServer};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Key from the RFC 6455 handshake example
+const CLIENT_KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+
+/// Initialize tracing and crypto provider for tests
+fn init_test() {
+    // Install rustls crypto provider (ignore if already installed)
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    // Initialize tracing
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter("siphon=debug,siphon_server=debug,siphon_e2e=debug")
+        .with_test_writer()
+        .try_init();
+}
+
+/// Run the real client against `server`, forwarding to `local`, until the tunnel is up
+async fn start_client(server: &TestServer, local: &str) -> (TunnelHandle, String) {
+    let handle = TunnelClient::builder()
+        .server(server.control_addr.to_string())
+        .server_name("localhost")
+        .local(local.parse().unwrap())
+        .tls(server.client_tls_config())
+        .build()
+        .expect("Failed to build client")
+        .run();
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while handle.metrics().snapshot().tunnel_info.is_none() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("Tunnel not established");
+
+    let subdomain = handle.subdomain().expect("No subdomain assigned");
+    (handle, subdomain)
+}
+
+/// Send an upgrade request for `path` to the HTTP plane, returning the stream and response head
+async fn open_websocket(server: &TestServer, subdomain: &str, path: &str) -> (TcpStream, String) {
+    let mut stream = TcpStream::connect(server.http_addr)
+        .await
+        .expect("Failed to connect to HTTP plane");
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        path,
+        server.host_for(subdomain),
+        CLIENT_KEY
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let head = tokio::time::timeout(Duration::from_secs(5), read_http_head(&mut stream))
+        .await
+        .expect("No upgrade response")
+        .expect("Failed to read upgrade response");
+    (stream, head)
+}
+
+#[tokio::test]
+async fn test_websocket_echo() {
+    init_test();
+
+    let server = TestServer::start().await;
+    let mock = MockWsService::start().await;
+    let (handle, subdomain) = start_client(&server, &mock.addr_string()).await;
+
+    let (mut stream, head) = open_websocket(&server, &subdomain, "/chat").await;
+    assert!(
+        head.starts_with("HTTP/1.1 101"),
+        "unexpected response: {}",
+        head
+    );
+    assert_eq!(
+        header_value(&head, "sec-websocket-accept"),
+        Some(accept_key(CLIENT_KEY))
+    );
+
+    // Frames pass through the tunnel untouched, in both directions
+    for message in ["hello", "through the tunnel"] {
+        stream
+            .write_all(&encode_frame(OPCODE_TEXT, message.as_bytes(), true))
+            .await
+            .unwrap();
+        let (opcode, payload) =
+            tokio::time::timeout(Duration::from_secs(5), read_frame(&mut stream))
+                .await
+                .expect("No echo")
+                .unwrap();
+        assert_eq!(opcode, OPCODE_TEXT);
+        assert_eq!(payload, message.as_bytes());
+    }
+
+    // A close handshake ends the connection on both sides
+    stream
+        .write_all(&encode_frame(OPCODE_CLOSE, &[], true))
+        .await
+        .unwrap();
+    let (opcode, _) = read_frame(&mut stream).await.unwrap();
+    assert_eq!(opcode, OPCODE_CLOSE);
+    let mut rest = Vec::new();
+    let n = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest))
+        .await
+        .expect("Connection not closed after close handshake")
+        .unwrap_or(0);
+    assert_eq!(n, 0);
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_websocket_rejected_upgrade_is_relayed() {
+    init_test();
+
+    let server = TestServer::start().await;
+    let mock = MockWsService::start_rejecting(403).await;
+    let (handle, subdomain) = start_client(&server, &mock.addr_string()).await;
+
+    let (mut stream, head) = open_websocket(&server, &subdomain, "/chat").await;
+    assert!(
+        head.starts_with("HTTP/1.1 403"),
+        "unexpected response: {}",
+        head
+    );
+
+    let len: usize = header_value(&head, "content-length")
+        .and_then(|v| v.parse().ok())
+        .expect("No content length");
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await.unwrap();
+    assert_eq!(body, b"upgrade rejected");
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_websocket_local_service_down() {
+    init_test();
+
+    let server = TestServer::start().await;
+    // Reserve a port, then free it so nothing is listening
+    let addr = {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    };
+    let (handle, subdomain) = start_client(&server, &addr.to_string()).await;
+
+    let (_stream, head) = open_websocket(&server, &subdomain, "/chat").await;
+    assert!(
+        head.starts_with("HTTP/1.1 502"),
+        "unexpected response: {}",
+        head
+    );
+
+    handle.shutdown().await.unwrap();
+}
@@ -4,7 +4,7 @@ use thiserror::Error;
 use tokio_util::codec::{Decoder, Encoder};
 
 /// Maximum frame size (16 MB)
-const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+pub(crate) const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
 
 /// Errors that can occur during encoding/decoding
 #[derive(Debug, Error)]
@@ -117,6 +117,10 @@ mod tests {
             subdomain: Some("test".to_string()),
             tunnel_type: TunnelType::Http,
             local_port: 8080,
+            http_policy: None,
+            websocket: false,
+            base_domain: None,
+            flow_control: false,
         };
 
         // Encode
@@ -130,6 +134,7 @@ mod tests {
                 subdomain,
                 tunnel_type,
                 local_port,
+                ..
             } => {
                 assert_eq!(subdomain, Some("test".to_string()));
                 assert_eq!(tunnel_type, TunnelType::Http);
@@ -148,6 +153,7 @@ mod tests {
             uri: "/api/test".to_string(),
             headers: vec![("Host".to_string(), "example.com".to_string())],
             body: vec![],
+            scheme: Some("https".to_string()),
         };
 
         // Encode
@@ -1,5 +1,10 @@
 mod codec;
 mod messages;
+mod read_buffer;
 
 pub use codec::TunnelCodec;
-pub use messages::{ClientMessage, ServerMessage, TunnelType};
+pub use messages::{
+    glob_match, ClientMessage, HttpPolicy, ParseTunnelTypeError, ServerMessage, TunnelType,
+    PROTOCOL_VERSION,
+};
+pub use read_buffer::{ReadBufferPolicy, DEFAULT_READ_BUFFER_INITIAL, DEFAULT_READ_BUFFER_MAX};
@@ -1,3 +1,7 @@
+use std::fmt;
+use std::str::FromStr;
+
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 
 /// Type of tunnel to establish
@@ -10,10 +14,162 @@ pub enum TunnelType {
     Tcp,
 }
 
+/// Version of the control protocol spoken by this build
+///
+/// Bumped when a change is not backward compatible; additive, optional fields
+/// don't require a bump.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+impl TunnelType {
+    /// Every tunnel type, in the order shown to users
+    pub const ALL: [TunnelType; 2] = [TunnelType::Http, TunnelType::Tcp];
+
+    /// Lowercase name, as used on the command line and on the wire
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TunnelType::Http => "http",
+            TunnelType::Tcp => "tcp",
+        }
+    }
+}
+
+impl fmt::Display for TunnelType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Error returned when parsing an unknown tunnel type
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Invalid tunnel type: {0}. Use {valid}", valid = valid_tunnel_types())]
+pub struct ParseTunnelTypeError(String);
+
+/// "'http' or 'tcp'"
+fn valid_tunnel_types() -> String {
+    let names: Vec<String> = TunnelType::ALL.iter().map(|t| format!("'{}'", t)).collect();
+    match names.split_last() {
+        Some((last, [])) => last.clone(),
+        Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+        None => String::new(),
+    }
+}
+
+impl FromStr for TunnelType {
+    type Err = ParseTunnelTypeError;
+
+    /// Parse a tunnel type name (case-insensitive)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TunnelType::ALL
+            .into_iter()
+            .find(|t| t.as_str().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| ParseTunnelTypeError(s.to_string()))
+    }
+}
+
+/// Restricts which HTTP requests the server forwards through a tunnel
+///
+/// Empty lists place no restriction. Requests that don't match are answered
+/// with `403 Forbidden` by the server and never reach the client.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HttpPolicy {
+    /// Allowed methods (case-insensitive, e.g. `GET`, `HEAD`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_methods: Vec<String>,
+    /// Allowed path globs, matched against the path without the query string;
+    /// `*` matches any sequence of characters, including `/`. Paths with a `.`
+    /// or `..` segment, even percent-encoded, never match.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_paths: Vec<String>,
+}
+
+impl HttpPolicy {
+    /// Check whether a request with this method and URI is allowed
+    pub fn allows(&self, method: &str, uri: &str) -> bool {
+        let method_allowed = self.allowed_methods.is_empty()
+            || self
+                .allowed_methods
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(method));
+
+        let path = uri.split(['?', '#']).next().unwrap_or(uri);
+        let path_allowed = self.allowed_paths.is_empty()
+            || (!has_dot_segment(path)
+                && self.allowed_paths.iter().any(|glob| glob_match(glob, path)));
+
+        method_allowed && path_allowed
+    }
+}
+
+/// Whether `path` has a `.` or `..` segment once percent-decoded
+///
+/// The local service may resolve those (`/public/../admin`, `/public/%2e%2e/admin`)
+/// to a path the globs would not have allowed. `\` counts as a separator too.
+fn has_dot_segment(path: &str) -> bool {
+    percent_decode(path)
+        .split(|&b| b == b'/' || b == b'\\')
+        .any(|segment| segment == b"." || segment == b"..")
+}
+
+/// Decode `%XX` escapes, leaving malformed ones as they are
+fn percent_decode(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+/// Match `text` against a glob where `*` matches any sequence of characters
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // split always yields at least one part
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No `*` in the pattern: exact match
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
 /// Messages sent from client to server
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ClientMessage {
+    /// Ask for the server's [`ServerMessage::ServerInfo`] without opening a tunnel
+    Hello {
+        /// Protocol version of the client
+        protocol_version: u32,
+    },
+
     /// Request to establish a tunnel
     RequestTunnel {
         /// Requested subdomain (None = auto-generate)
@@ -22,9 +178,27 @@ pub enum ClientMessage {
         tunnel_type: TunnelType,
         /// Local port description (for display purposes)
         local_port: u16,
+        /// Optional restrictions on forwarded HTTP requests (HTTP tunnels only)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        http_policy: Option<HttpPolicy>,
+        /// Whether the client handles [`ServerMessage::WsUpgrade`]; without
+        /// it, upgrade requests are forwarded as plain HTTP requests
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        websocket: bool,
+        /// Base domain to serve the tunnel under (None = the server's default)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        base_domain: Option<String>,
+        /// Whether the client takes part in `TcpWindowUpdate` flow control
+        /// (TCP tunnels only; see [`ServerMessage::TunnelEstablished`])
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        flow_control: bool,
     },
 
     /// Response data for an HTTP request
+    ///
+    /// Also answers [`ServerMessage::WsUpgrade`]: status 101 means the local
+    /// service accepted the upgrade and `WsData` follows, anything else is
+    /// relayed to the visitor as the final response.
     HttpResponse {
         /// Stream ID this response belongs to
         stream_id: u64,
@@ -41,7 +215,7 @@ pub enum ClientMessage {
         /// Stream ID for this TCP connection
         stream_id: u64,
         /// Data bytes
-        data: Vec<u8>,
+        data: Bytes,
     },
 
     /// TCP connection closed by local service
@@ -50,17 +224,65 @@ pub enum ClientMessage {
         stream_id: u64,
     },
 
+    /// The local service consumed `TcpData` from the server: the server may
+    /// send `bytes` more on this stream (flow-controlled tunnels only)
+    TcpWindowUpdate {
+        /// Stream ID for this TCP connection
+        stream_id: u64,
+        /// Credit granted, in bytes
+        bytes: u64,
+    },
+
+    /// Bytes of an upgraded WebSocket connection, from the local service
+    WsData {
+        /// Stream ID of the upgrade request
+        stream_id: u64,
+        /// Raw bytes (WebSocket frames are passed through as is)
+        data: Bytes,
+    },
+
+    /// WebSocket connection closed by the local service
+    WsClose {
+        /// Stream ID of the upgrade request
+        stream_id: u64,
+    },
+
     /// Keepalive ping
     Ping {
         /// Timestamp for RTT measurement
         timestamp: u64,
     },
+
+    /// The client is closing the connection on purpose
+    ///
+    /// The server releases the connection's tunnels before closing its side,
+    /// so they can be requested again as soon as the connection is gone.
+    Goodbye {
+        /// Why the client is leaving (for logs)
+        reason: String,
+    },
 }
 
 /// Messages sent from server to client
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ServerMessage {
+    /// Answer to [`ClientMessage::Hello`]
+    ServerInfo {
+        /// Domain tunnels are created under (`<subdomain>.<base_domain>`)
+        base_domain: String,
+        /// Other base domains tunnels can ask for
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        other_base_domains: Vec<String>,
+        /// Protocol version of the server
+        protocol_version: u32,
+        /// Tunnel types the server accepts
+        supported_types: Vec<TunnelType>,
+        /// Maximum tunnels a client may open (None = no limit)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_tunnels: Option<u32>,
+    },
+
     /// Tunnel successfully established
     TunnelEstablished {
         /// Assigned subdomain
@@ -69,6 +291,11 @@ pub enum ServerMessage {
         url: String,
         /// Assigned port for TCP tunnels (None for HTTP)
         port: Option<u16>,
+        /// Whether TCP streams of this tunnel are flow-controlled: each side
+        /// only sends data the other granted with `TcpWindowUpdate`, beyond
+        /// an initial window. Only set when the client asked for it.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        flow_control: bool,
     },
 
     /// Tunnel request denied
@@ -89,12 +316,18 @@ pub enum ServerMessage {
         headers: Vec<(String, String)>,
         /// Request body
         body: Vec<u8>,
+        /// Scheme the request arrived with at the edge (`http` or `https`)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        scheme: Option<String>,
     },
 
     /// New TCP connection established
     TcpConnect {
         /// Stream ID for this TCP connection
         stream_id: u64,
+        /// Address of the remote client that opened the connection
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        peer_addr: Option<String>,
     },
 
     /// Incoming TCP data
@@ -102,7 +335,7 @@ pub enum ServerMessage {
         /// Stream ID for this TCP connection
         stream_id: u64,
         /// Data bytes
-        data: Vec<u8>,
+        data: Bytes,
     },
 
     /// TCP connection closed by remote
@@ -111,23 +344,100 @@ pub enum ServerMessage {
         stream_id: u64,
     },
 
+    /// The visitor consumed `TcpData` from the client: the client may send
+    /// `bytes` more on this stream (flow-controlled tunnels only)
+    TcpWindowUpdate {
+        /// Stream ID for this TCP connection
+        stream_id: u64,
+        /// Credit granted, in bytes
+        bytes: u64,
+    },
+
+    /// Incoming WebSocket upgrade request (`GET` with `Upgrade: websocket`)
+    ///
+    /// Answered with [`ClientMessage::HttpResponse`]. Only sent to clients
+    /// that set `websocket` in their tunnel request.
+    WsUpgrade {
+        /// Unique stream ID for this connection
+        stream_id: u64,
+        /// Request URI (path + query)
+        uri: String,
+        /// Request headers, including `Upgrade` and `Sec-WebSocket-*`
+        headers: Vec<(String, String)>,
+        /// Scheme the request arrived with at the edge (`http` or `https`)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        scheme: Option<String>,
+    },
+
+    /// Bytes of an upgraded WebSocket connection, from the visitor
+    WsData {
+        /// Stream ID of the upgrade request
+        stream_id: u64,
+        /// Raw bytes (WebSocket frames are passed through as is)
+        data: Bytes,
+    },
+
+    /// WebSocket connection closed by the visitor
+    WsClose {
+        /// Stream ID of the upgrade request
+        stream_id: u64,
+    },
+
     /// Keepalive pong (response to Ping)
     Pong {
         /// Echo back the timestamp
         timestamp: u64,
     },
+
+    /// The connection's tunnels reached the server's maximum lifetime
+    ///
+    /// They are already released when this is sent; the server then closes
+    /// the connection, and the client should reconnect to get fresh tunnels.
+    TunnelExpired {
+        /// How long the tunnels were kept, in seconds
+        lifetime_secs: u64,
+    },
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_tunnel_type_parse() {
+        assert_eq!("http".parse::<TunnelType>().unwrap(), TunnelType::Http);
+        assert_eq!("TCP".parse::<TunnelType>().unwrap(), TunnelType::Tcp);
+        assert_eq!(" Http ".parse::<TunnelType>().unwrap(), TunnelType::Http);
+
+        let err = "udp".parse::<TunnelType>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid tunnel type: udp. Use 'http' or 'tcp'"
+        );
+    }
+
+    #[test]
+    fn test_tunnel_type_display_roundtrip() {
+        for tunnel_type in TunnelType::ALL {
+            let name = tunnel_type.to_string();
+            assert_eq!(name.parse::<TunnelType>().unwrap(), tunnel_type);
+
+            // Display matches the serde representation
+            let json = serde_json::to_string(&tunnel_type).unwrap();
+            assert_eq!(json, format!("\"{}\"", name));
+        }
+    }
+
     #[test]
     fn test_client_message_serialization() {
         let msg = ClientMessage::RequestTunnel {
             subdomain: Some("myapp".to_string()),
             tunnel_type: TunnelType::Http,
             local_port: 3000,
+            http_policy: None,
+            websocket: true,
+            base_domain: Some("tun.example.org".to_string()),
+            flow_control: false,
         };
         let json = serde_json::to_string(&msg).unwrap();
         let parsed: ClientMessage = serde_json::from_str(&json).unwrap();
@@ -137,21 +447,181 @@ mod tests {
                 subdomain,
                 tunnel_type,
                 local_port,
+                http_policy,
+                websocket,
+                base_domain,
+                flow_control,
             } => {
                 assert_eq!(subdomain, Some("myapp".to_string()));
                 assert_eq!(tunnel_type, TunnelType::Http);
                 assert_eq!(local_port, 3000);
+                assert_eq!(http_policy, None);
+                assert!(websocket);
+                assert_eq!(base_domain.as_deref(), Some("tun.example.org"));
+                assert!(!flow_control);
             }
             _ => panic!("Wrong variant"),
         }
     }
 
+    #[test]
+    fn test_goodbye_serialization() {
+        let msg = ClientMessage::Goodbye {
+            reason: "shutting down".to_string(),
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert_eq!(json, r#"{"type":"goodbye","reason":"shutting down"}"#);
+
+        let parsed: ClientMessage = serde_json::from_str(&json).unwrap();
+        assert!(matches!(parsed, ClientMessage::Goodbye { reason } if reason == "shutting down"));
+    }
+
+    #[test]
+    fn test_tunnel_expired_serialization() {
+        let msg = ServerMessage::TunnelExpired {
+            lifetime_secs: 3600,
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert_eq!(json, r#"{"type":"tunnel_expired","lifetime_secs":3600}"#);
+
+        let parsed: ServerMessage = serde_json::from_str(&json).unwrap();
+        assert!(matches!(
+            parsed,
+            ServerMessage::TunnelExpired {
+                lifetime_secs: 3600
+            }
+        ));
+    }
+
+    #[test]
+    fn test_request_tunnel_without_policy_field() {
+        // Messages from clients predating HTTP policies must still parse
+        let json =
+            r#"{"type":"request_tunnel","subdomain":null,"tunnel_type":"http","local_port":80}"#;
+        let parsed: ClientMessage = serde_json::from_str(json).unwrap();
+        assert!(matches!(
+            parsed,
+            ClientMessage::RequestTunnel {
+                http_policy: None,
+                websocket: false,
+                base_domain: None,
+                flow_control: false,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_tcp_data_serialization() {
+        // Same encoding as the byte vectors data frames used to carry
+        let msg = ServerMessage::TcpData {
+            stream_id: 3,
+            data: Bytes::from_static(b"hi"),
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"tcp_data","stream_id":3,"data":[104,105]}"#
+        );
+
+        match serde_json::from_str(&json).unwrap() {
+            ClientMessage::TcpData { stream_id, data } => {
+                assert_eq!(stream_id, 3);
+                assert_eq!(&data[..], b"hi");
+            }
+            other => panic!("Expected TcpData, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_tcp_window_update_serialization() {
+        let msg = ServerMessage::TcpWindowUpdate {
+            stream_id: 7,
+            bytes: 65536,
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"tcp_window_update","stream_id":7,"bytes":65536}"#
+        );
+
+        let parsed: ClientMessage = serde_json::from_str(&json).unwrap();
+        assert!(matches!(
+            parsed,
+            ClientMessage::TcpWindowUpdate {
+                stream_id: 7,
+                bytes: 65536
+            }
+        ));
+    }
+
+    #[test]
+    fn test_http_policy_methods() {
+        let policy = HttpPolicy {
+            allowed_methods: vec!["GET".to_string(), "head".to_string()],
+            allowed_paths: vec![],
+        };
+        assert!(policy.allows("GET", "/anything"));
+        assert!(policy.allows("HEAD", "/"));
+        assert!(!policy.allows("POST", "/anything"));
+        assert!(HttpPolicy::default().allows("DELETE", "/"));
+    }
+
+    #[test]
+    fn test_http_policy_paths() {
+        let policy = HttpPolicy {
+            allowed_methods: vec![],
+            allowed_paths: vec!["/api/*".to_string(), "/health".to_string()],
+        };
+        assert!(policy.allows("GET", "/api/users/1?verbose=true"));
+        assert!(policy.allows("GET", "/health"));
+        assert!(!policy.allows("GET", "/health/deep"));
+        assert!(!policy.allows("GET", "/admin"));
+    }
+
+    #[test]
+    fn test_http_policy_rejects_dot_segments() {
+        let policy = HttpPolicy {
+            allowed_methods: vec![],
+            allowed_paths: vec!["/public/*".to_string()],
+        };
+        assert!(policy.allows("GET", "/public/page..html"));
+        assert!(policy.allows("GET", "/public/.well-known/x"));
+        assert!(!policy.allows("GET", "/public/../admin"));
+        assert!(!policy.allows("GET", "/public/%2e%2e/admin"));
+        assert!(!policy.allows("GET", "/public/%2E./admin"));
+        assert!(!policy.allows("GET", "/public//../admin"));
+        assert!(!policy.allows("GET", "/public/..%2fadmin"));
+        assert!(!policy.allows("GET", "/public/..\\admin"));
+        assert!(!policy.allows("GET", "/public/./x"));
+        assert!(!policy.allows("GET", "/public/.."));
+        // Without path globs nothing is restricted
+        assert!(HttpPolicy::default().allows("GET", "/public/../admin"));
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("/a%2Fb%2e"), b"/a/b.");
+        assert_eq!(percent_decode("100%"), b"100%");
+        assert_eq!(percent_decode("%zz%4"), b"%zz%4");
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("/static/*.css", "/static/css/site.css"));
+        assert!(glob_match("/a*b*c", "/a-b-c"));
+        assert!(!glob_match("/a*b*c", "/a-c-b"));
+        assert!(!glob_match("/exact", "/exact/more"));
+    }
+
     #[test]
     fn test_server_message_serialization() {
         let msg = ServerMessage::TunnelEstablished {
             subdomain: "myapp".to_string(),
             url: "https://myapp.tunnel.example.com".to_string(),
             port: None,
+            flow_control: false,
         };
         let json = serde_json::to_string(&msg).unwrap();
         let parsed: ServerMessage = serde_json::from_str(&json).unwrap();
@@ -161,6 +631,7 @@ mod tests {
                 subdomain,
                 url,
                 port,
+                ..
             } => {
                 assert_eq!(subdomain, "myapp");
                 assert_eq!(url, "https://myapp.tunnel.example.com");
@@ -0,0 +1,160 @@
+//! Receive buffer sizing for long-lived framed connections
+//!
+//! `read_buf` grows a `BytesMut` to fit the largest frame it has seen and the
+//! buffer never gives that memory back on its own, so a single burst of large
+//! messages would pin up to 16 MB per connection for as long as it stays open.
+//! [`ReadBufferPolicy::reclaim`] swaps an oversized buffer for a fresh small
+//! one once what it holds fits in the configured bound again.
+
+use bytes::BytesMut;
+
+use crate::codec::MAX_FRAME_SIZE;
+
+/// Capacity a connection's read buffer starts with unless configured otherwise
+pub const DEFAULT_READ_BUFFER_INITIAL: usize = 8 * 1024;
+
+/// Capacity kept between frames unless configured otherwise
+pub const DEFAULT_READ_BUFFER_MAX: usize = 256 * 1024;
+
+/// Initial and retained capacity of a connection's read buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadBufferPolicy {
+    /// Capacity allocated up front, and after each reclaim
+    pub initial_capacity: usize,
+    /// Capacity above which a drained buffer is reallocated
+    ///
+    /// Frames larger than this are still received in full; the extra memory
+    /// is only released once they have been decoded.
+    pub max_capacity: usize,
+}
+
+impl Default for ReadBufferPolicy {
+    fn default() -> Self {
+        Self {
+            initial_capacity: DEFAULT_READ_BUFFER_INITIAL,
+            max_capacity: DEFAULT_READ_BUFFER_MAX,
+        }
+    }
+}
+
+impl ReadBufferPolicy {
+    /// Empty buffer with the initial capacity
+    pub fn new_buffer(&self) -> BytesMut {
+        BytesMut::with_capacity(self.initial_capacity)
+    }
+
+    /// Release excess capacity of `buf` after decoding, keeping its contents
+    ///
+    /// Nothing happens while a frame too large for `max_capacity` is still
+    /// being received, so its reservation isn't thrown away on every read.
+    /// Returns whether the buffer was reallocated.
+    pub fn reclaim(&self, buf: &mut BytesMut) -> bool {
+        if buf.capacity() <= self.max_capacity {
+            return false;
+        }
+        let needed = pending_frame_len(buf).max(buf.len());
+        if needed > self.max_capacity {
+            return false;
+        }
+
+        let mut fresh = BytesMut::with_capacity(self.initial_capacity.max(needed));
+        fresh.extend_from_slice(buf);
+        *buf = fresh;
+        true
+    }
+}
+
+/// Size of the partially received frame at the start of `buf` (0 if unknown)
+fn pending_frame_len(buf: &BytesMut) -> usize {
+    match buf.get(..4) {
+        Some(prefix) => {
+            let length = u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize;
+            4 + length.min(MAX_FRAME_SIZE)
+        }
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ClientMessage, TunnelCodec};
+    use tokio_util::codec::{Decoder, Encoder};
+
+    fn large_frame(size: usize) -> BytesMut {
+        let mut codec = TunnelCodec::<ClientMessage>::new();
+        let mut frame = BytesMut::new();
+        codec
+            .encode(
+                ClientMessage::TcpData {
+                    stream_id: 1,
+                    data: vec![7; size].into(),
+                },
+                &mut frame,
+            )
+            .unwrap();
+        frame
+    }
+
+    #[test]
+    fn test_capacity_returns_to_bound_after_large_frames() {
+        let policy = ReadBufferPolicy {
+            initial_capacity: 4 * 1024,
+            max_capacity: 32 * 1024,
+        };
+        let mut codec = TunnelCodec::<ClientMessage>::new();
+        let mut buf = policy.new_buffer();
+        let frame = large_frame(128 * 1024);
+
+        let mut peak = 0;
+        let mut decoded = 0;
+        for _ in 0..10 {
+            // Arrives in socket-sized chunks, like read_buf would deliver it
+            for chunk in frame.chunks(16 * 1024) {
+                buf.extend_from_slice(chunk);
+                while codec.decode(&mut buf).unwrap().is_some() {
+                    decoded += 1;
+                }
+                peak = peak.max(buf.capacity());
+                policy.reclaim(&mut buf);
+            }
+        }
+
+        assert_eq!(decoded, 10);
+        assert!(peak >= frame.len(), "peak {}", peak);
+        assert!(buf.is_empty());
+        assert!(
+            buf.capacity() <= policy.max_capacity,
+            "capacity {}",
+            buf.capacity()
+        );
+    }
+
+    #[test]
+    fn test_reclaim_keeps_pending_data() {
+        let policy = ReadBufferPolicy {
+            initial_capacity: 1024,
+            max_capacity: 4096,
+        };
+        let mut buf = BytesMut::with_capacity(1024 * 1024);
+        buf.extend_from_slice(&large_frame(100)[..50]);
+
+        assert!(policy.reclaim(&mut buf));
+        assert_eq!(buf.capacity(), 1024);
+        assert_eq!(&buf[..], &large_frame(100)[..50]);
+    }
+
+    #[test]
+    fn test_reclaim_spares_frame_in_flight() {
+        let policy = ReadBufferPolicy {
+            initial_capacity: 1024,
+            max_capacity: 4096,
+        };
+        let frame = large_frame(100 * 1024);
+        let mut buf = BytesMut::with_capacity(frame.len());
+        buf.extend_from_slice(&frame[..1000]);
+
+        assert!(!policy.reclaim(&mut buf));
+        assert!(buf.capacity() >= frame.len());
+    }
+}
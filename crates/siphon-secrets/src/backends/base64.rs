@@ -1,19 +1,41 @@
 //! Base64 decoding backend
 
+use std::borrow::Cow;
+
 use base64::Engine;
 
 use crate::error::SecretError;
 
+/// Drop the line breaks and indentation of a value wrapped across lines
+fn strip_whitespace(data: &str) -> Cow<'_, str> {
+    if data.contains(|c: char| c.is_ascii_whitespace()) {
+        Cow::Owned(data.split_ascii_whitespace().collect())
+    } else {
+        Cow::Borrowed(data)
+    }
+}
+
 /// Resolve a secret from base64-encoded data
 pub fn resolve(data: &str) -> Result<String, SecretError> {
     let bytes = base64::engine::general_purpose::STANDARD
-        .decode(data)
+        .decode(strip_whitespace(data).as_bytes())
         .map_err(|e| SecretError::backend("base64", format!("decode error: {}", e)))?;
 
     String::from_utf8(bytes)
         .map_err(|e| SecretError::backend("base64", format!("invalid UTF-8: {}", e)))
 }
 
+/// Append the decoded bytes to `buf`, returning how many were written
+///
+/// Unlike [`resolve`], the result doesn't have to be UTF-8 (e.g. DER data).
+pub fn resolve_into(data: &str, buf: &mut Vec<u8>) -> Result<usize, SecretError> {
+    let start = buf.len();
+    base64::engine::general_purpose::STANDARD
+        .decode_vec(strip_whitespace(data).as_bytes(), buf)
+        .map_err(|e| SecretError::backend("base64", format!("decode error: {}", e)))?;
+    Ok(buf.len() - start)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -34,6 +56,23 @@ mod tests {
         assert_eq!(result, pem);
     }
 
+    #[test]
+    fn test_decode_wrapped_base64() {
+        let pem = "-----BEGIN CERTIFICATE-----\nMIIBszCCAVmgAwIBAgIU\n-----END CERTIFICATE-----\n";
+        let encoded = base64::engine::general_purpose::STANDARD.encode(pem);
+        let wrapped: Vec<&str> = encoded
+            .as_bytes()
+            .chunks(16)
+            .map(|line| std::str::from_utf8(line).unwrap())
+            .collect();
+        let wrapped = format!("\n  {}\r\n", wrapped.join("\n  "));
+
+        assert_eq!(resolve(&wrapped).unwrap(), resolve(&encoded).unwrap());
+        let mut buf = Vec::new();
+        assert_eq!(resolve_into(&wrapped, &mut buf).unwrap(), pem.len());
+        assert_eq!(buf, pem.as_bytes());
+    }
+
     #[test]
     fn test_decode_invalid_base64() {
         let result = resolve("not-valid-base64!!!");
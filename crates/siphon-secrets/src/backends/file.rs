@@ -12,6 +12,18 @@ pub fn resolve(path: &Path) -> Result<String, SecretError> {
     })
 }
 
+/// Append the raw contents of a file to `buf`, returning the number of bytes read
+pub fn resolve_into(path: &Path, buf: &mut Vec<u8>) -> Result<usize, SecretError> {
+    use std::io::Read;
+
+    std::fs::File::open(path)
+        .and_then(|mut file| file.read_to_end(buf))
+        .map_err(|e| SecretError::FileError {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -7,31 +7,99 @@
 
 use crate::error::SecretError;
 
+/// Open the keychain entry for `service/key`, or `service/key?account=name`
+///
+/// Without an account, `key` is the entry's account (user) name. With one,
+/// the account is the user name and `key` becomes the entry's target, e.g.
+/// the Windows credential target name. macOS uses targets to pick a keychain domain instead, so entries with an
+/// account aren't supported there.
+fn entry(service: &str, key: &str, account: Option<&str>) -> Result<keyring::Entry, SecretError> {
+    let entry = match account {
+        None => keyring::Entry::new(service, key),
+        #[cfg(target_os = "macos")]
+        Some(_) => {
+            return Err(SecretError::backend(
+                "keychain",
+                "keychain://service/key?account=name is not supported on macOS; \
+                 use keychain://service/account",
+            ))
+        }
+        #[cfg(not(target_os = "macos"))]
+        Some(account) => keyring::Entry::new_with_target(key, service, account),
+    };
+    entry.map_err(|e| SecretError::backend("keychain", e.to_string()))
+}
+
+/// `service/key` or `service/key?account=name`, for messages
+fn entry_name(service: &str, key: &str, account: Option<&str>) -> String {
+    match account {
+        Some(account) => format!("{}/{}?account={}", service, key, account),
+        None => format!("{}/{}", service, key),
+    }
+}
+
 /// Resolve a secret from the OS keychain
-pub fn resolve(service: &str, key: &str) -> Result<String, SecretError> {
-    let entry = keyring::Entry::new(service, key)
-        .map_err(|e| SecretError::backend("keychain", e.to_string()))?;
+///
+/// A locked keychain is reported as [`SecretError::BackendUnavailable`]
+/// rather than retried here; interactive callers can ask the user to unlock
+/// it and try again (see [`SecretError::is_keychain_unavailable`]).
+pub fn resolve(service: &str, key: &str, account: Option<&str>) -> Result<String, SecretError> {
+    let entry = entry(service, key, account)?;
 
-    entry.get_password().map_err(|e| match e {
-        keyring::Error::NoEntry => {
-            SecretError::NotFound(format!("No keychain entry for {}/{}", service, key))
+    entry
+        .get_password()
+        .map_err(|e| map_error(e, &entry_name(service, key, account)))
+}
+
+/// Whether the error means the keychain exists but is locked or refused access
+fn is_locked(err: &keyring::Error) -> bool {
+    match err {
+        // Secret Service reports a locked collection this way
+        keyring::Error::NoStorageAccess(_) => true,
+        // macOS: errSecInteractionNotAllowed (-25308) / errSecAuthFailed (-25293)
+        keyring::Error::PlatformFailure(inner) => {
+            let message = inner.to_string().to_ascii_lowercase();
+            message.contains("interaction is not allowed")
+                || message.contains("-25308")
+                || message.contains("-25293")
+                || message.contains("locked")
         }
+        _ => false,
+    }
+}
+
+/// Map a keyring error to a secret error
+fn map_error(err: keyring::Error, name: &str) -> SecretError {
+    if is_locked(&err) {
+        return SecretError::unavailable(
+            "keychain",
+            format!(
+                "keychain is locked ({}). Unlock it (e.g. `security unlock-keychain` on macOS) \
+                 before starting, or use env://, file:// or op:// when running unattended",
+                err
+            ),
+        );
+    }
+
+    match err {
+        keyring::Error::NoEntry => SecretError::NotFound(format!("No keychain entry for {}", name)),
         keyring::Error::Ambiguous(creds) => SecretError::backend(
             "keychain",
             format!("Ambiguous entry: {} credentials found", creds.len()),
         ),
-        keyring::Error::NoStorageAccess(inner) => {
-            SecretError::AccessDenied(format!("Cannot access keychain storage: {}", inner))
-        }
-        _ => SecretError::backend("keychain", e.to_string()),
-    })
+        _ => SecretError::backend("keychain", err.to_string()),
+    }
 }
 
 /// Store a secret in the OS keychain (useful for setup)
 #[allow(dead_code)]
-pub fn store(service: &str, key: &str, value: &str) -> Result<(), SecretError> {
-    let entry = keyring::Entry::new(service, key)
-        .map_err(|e| SecretError::backend("keychain", e.to_string()))?;
+pub fn store(
+    service: &str,
+    key: &str,
+    account: Option<&str>,
+    value: &str,
+) -> Result<(), SecretError> {
+    let entry = entry(service, key, account)?;
 
     entry
         .set_password(value)
@@ -40,11 +108,61 @@ pub fn store(service: &str, key: &str, value: &str) -> Result<(), SecretError> {
 
 /// Delete a secret from the OS keychain
 #[allow(dead_code)]
-pub fn delete(service: &str, key: &str) -> Result<(), SecretError> {
-    let entry = keyring::Entry::new(service, key)
-        .map_err(|e| SecretError::backend("keychain", e.to_string()))?;
+pub fn delete(service: &str, key: &str, account: Option<&str>) -> Result<(), SecretError> {
+    let entry = entry(service, key, account)?;
 
     entry
         .delete_credential()
         .map_err(|e| SecretError::backend("keychain", e.to_string()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locked() -> keyring::Error {
+        keyring::Error::PlatformFailure("User interaction is not allowed.".into())
+    }
+
+    #[test]
+    fn test_entry_name() {
+        assert_eq!(entry_name("svc", "key", None), "svc/key");
+        assert_eq!(
+            entry_name("svc", "key", Some("alice")),
+            "svc/key?account=alice"
+        );
+    }
+
+    #[test]
+    fn test_locked_is_unavailable() {
+        for err in [
+            locked(),
+            keyring::Error::NoStorageAccess("collection is locked".into()),
+        ] {
+            let err = map_error(err, "svc/key");
+            match &err {
+                SecretError::BackendUnavailable { backend, message } => {
+                    assert_eq!(backend, "keychain");
+                    assert!(message.contains("locked"), "{}", message);
+                }
+                other => panic!("expected BackendUnavailable, got {:?}", other),
+            }
+            assert!(err.is_keychain_unavailable());
+        }
+    }
+
+    #[test]
+    fn test_other_errors_mapped() {
+        assert!(matches!(
+            map_error(keyring::Error::NoEntry, "svc/key"),
+            SecretError::NotFound(_)
+        ));
+        assert!(matches!(
+            map_error(
+                keyring::Error::PlatformFailure("disk on fire".into()),
+                "svc/key"
+            ),
+            SecretError::BackendError { .. }
+        ));
+    }
+}
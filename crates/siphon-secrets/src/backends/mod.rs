@@ -14,3 +14,6 @@ pub mod keychain;
 
 #[cfg(feature = "onepassword")]
 pub mod onepassword;
+
+#[cfg(feature = "stdin")]
+pub mod stdin;
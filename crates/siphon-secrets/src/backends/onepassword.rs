@@ -5,17 +5,30 @@
 //!
 //! See: https://developer.1password.com/docs/cli
 
-use std::process::Command;
+use std::io::Read;
+use std::process::{Command, Output, Stdio};
+use std::time::{Duration, Instant};
 
 use crate::error::SecretError;
 
+/// How often to check whether the `op` process has exited
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
 /// Resolve a secret from 1Password using the CLI
-pub fn resolve(vault: &str, item: &str, field: &str) -> Result<String, SecretError> {
+///
+/// The `op` process is killed if it doesn't finish within `timeout`
+/// (e.g. waiting on a biometric prompt nobody will answer).
+pub fn resolve(
+    vault: &str,
+    item: &str,
+    field: &str,
+    timeout: Duration,
+) -> Result<String, SecretError> {
     let uri = format!("op://{}/{}/{}", vault, item, field);
 
-    let output = Command::new("op")
-        .args(["read", &uri])
-        .output()
+    let mut command = Command::new("op");
+    command.args(["read", &uri]);
+    let output = output_with_timeout(&mut command, timeout)
         .map_err(|e| {
             if e.kind() == std::io::ErrorKind::NotFound {
                 SecretError::backend(
@@ -25,42 +38,12 @@ pub fn resolve(vault: &str, item: &str, field: &str) -> Result<String, SecretErr
             } else {
                 SecretError::backend("1password", format!("Failed to execute 'op' CLI: {}", e))
             }
-        })?;
+        })?
+        .ok_or_else(|| SecretError::timed_out("1password", timeout))?;
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
-        let error_msg = stderr.trim();
-
-        // Provide helpful error messages for common issues
-        if error_msg.contains("not signed in") || error_msg.contains("session expired") {
-            return Err(SecretError::backend(
-                "1password",
-                "Not signed in to 1Password CLI. Run 'op signin' or 'eval $(op signin)'",
-            ));
-        }
-
-        if error_msg.contains("isn't a vault") || error_msg.contains("vault") {
-            return Err(SecretError::NotFound(format!(
-                "1Password vault '{}' not found",
-                vault
-            )));
-        }
-
-        if error_msg.contains("isn't an item") || error_msg.contains("item") {
-            return Err(SecretError::NotFound(format!(
-                "1Password item '{}/{}' not found",
-                vault, item
-            )));
-        }
-
-        if error_msg.contains("isn't a field") || error_msg.contains("field") {
-            return Err(SecretError::NotFound(format!(
-                "1Password field '{}/{}/{}' not found",
-                vault, item, field
-            )));
-        }
-
-        return Err(SecretError::backend("1password", error_msg));
+        return Err(classify_error(stderr.trim(), vault, item, field));
     }
 
     let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
@@ -74,3 +57,189 @@ pub fn resolve(vault: &str, item: &str, field: &str) -> Result<String, SecretErr
 
     Ok(value)
 }
+
+/// Map a failed `op read` to an error naming what's wrong with the reference
+///
+/// `op` mentions the enclosing vault when an item is missing and the item
+/// when a field is, so only its "isn't a ..." phrasing identifies the
+/// component that doesn't exist.
+fn classify_error(stderr: &str, vault: &str, item: &str, field: &str) -> SecretError {
+    let lower = stderr.to_lowercase();
+
+    if lower.contains("not currently signed in")
+        || lower.contains("not signed in")
+        || lower.contains("session expired")
+        || lower.contains("authorization prompt dismissed")
+    {
+        return SecretError::unavailable(
+            "1password",
+            "Not signed in to 1Password CLI. Run 'op signin' (or 'eval $(op signin)') and try again",
+        );
+    }
+
+    if lower.contains("isn't a field") {
+        return SecretError::NotFound(format!(
+            "1Password field '{}' not found in item '{}/{}'",
+            field, vault, item
+        ));
+    }
+
+    if lower.contains("isn't an item") {
+        return SecretError::NotFound(format!(
+            "1Password item '{}' not found in vault '{}'",
+            item, vault
+        ));
+    }
+
+    if lower.contains("isn't a vault") {
+        return SecretError::NotFound(format!("1Password vault '{}' not found", vault));
+    }
+
+    SecretError::backend("1password", stderr)
+}
+
+/// Run a command to completion like `Command::output`, killing it after `timeout`
+///
+/// Returns `Ok(None)` if the deadline passed.
+fn output_with_timeout(
+    command: &mut Command,
+    timeout: Duration,
+) -> std::io::Result<Option<Output>> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Drain the pipes on threads so a chatty child can't block on a full pipe
+    let stdout = child.stdout.take().map(drain);
+    let stderr = child.stderr.take().map(drain);
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(None);
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    };
+
+    let collect = |handle: Option<std::thread::JoinHandle<Vec<u8>>>| {
+        handle
+            .map(|h| h.join().unwrap_or_default())
+            .unwrap_or_default()
+    };
+    Ok(Some(Output {
+        status,
+        stdout: collect(stdout),
+        stderr: collect(stderr),
+    }))
+}
+
+/// Read a pipe to the end on a background thread
+fn drain<R: Read + Send + 'static>(mut pipe: R) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = pipe.read_to_end(&mut buf);
+        buf
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    /// A fake `op` answering like the real CLI, depending on the reference read
+    const OP_SHIM: &str = r#"#!/bin/sh
+case "$2" in
+  op://signed-out/*)
+    echo '[ERROR] 2024/01/01 00:00:00 You are not currently signed in. Please run `op signin --help` for instructions' >&2
+    exit 1 ;;
+  op://Nowhere/*)
+    echo "[ERROR] 2024/01/01 00:00:00 could not read secret '$2': could not get item: \"Nowhere\" isn't a vault in this account. Specify the vault with its ID or name." >&2
+    exit 1 ;;
+  op://Private/Missing/*)
+    echo "[ERROR] 2024/01/01 00:00:00 could not read secret '$2': could not get item: \"Missing\" isn't an item in the \"Private\" vault. Specify the item with its UUID, name, or domain." >&2
+    exit 1 ;;
+  op://Private/Siphon/password)
+    echo "hunter2" ;;
+  op://Private/Siphon/*)
+    echo "[ERROR] 2024/01/01 00:00:00 could not read secret '$2': \"${2##*/}\" isn't a field in the \"Siphon\" item. This may be because you are trying to access a field not in the default section." >&2
+    exit 1 ;;
+  *)
+    echo "[ERROR] 2024/01/01 00:00:00 unexpected failure" >&2
+    exit 1 ;;
+esac
+"#;
+
+    #[test]
+    fn test_resolve_with_op_shim() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let shim = dir.path().join("op");
+        std::fs::write(&shim, OP_SHIM).unwrap();
+        std::fs::set_permissions(&shim, std::fs::Permissions::from_mode(0o755)).unwrap();
+        // Prepended so other tests still find their tools
+        let path = std::env::var("PATH").unwrap_or_default();
+        std::env::set_var("PATH", format!("{}:{}", dir.path().display(), path));
+
+        let read = |vault, item, field| resolve(vault, item, field, Duration::from_secs(5));
+
+        assert_eq!(read("Private", "Siphon", "password").unwrap(), "hunter2");
+
+        let err = read("Nowhere", "Siphon", "password").unwrap_err();
+        assert!(matches!(err, SecretError::NotFound(_)), "{:?}", err);
+        assert!(err.to_string().contains("vault 'Nowhere'"), "{}", err);
+
+        let err = read("Private", "Missing", "password").unwrap_err();
+        assert!(matches!(err, SecretError::NotFound(_)), "{:?}", err);
+        assert!(err.to_string().contains("item 'Missing'"), "{}", err);
+        assert!(err.to_string().contains("vault 'Private'"), "{}", err);
+
+        let err = read("Private", "Siphon", "token").unwrap_err();
+        assert!(matches!(err, SecretError::NotFound(_)), "{:?}", err);
+        assert!(err.to_string().contains("field 'token'"), "{}", err);
+        assert!(err.to_string().contains("'Private/Siphon'"), "{}", err);
+
+        let err = read("signed-out", "Siphon", "password").unwrap_err();
+        assert!(
+            matches!(err, SecretError::BackendUnavailable { .. }),
+            "{:?}",
+            err
+        );
+        assert!(err.to_string().contains("op signin"), "{}", err);
+
+        let err = read("Private", "Other", "password").unwrap_err();
+        assert!(matches!(err, SecretError::BackendError { .. }), "{:?}", err);
+        assert!(err.to_string().contains("unexpected failure"), "{}", err);
+    }
+
+    #[test]
+    fn test_output_with_timeout_kills_slow_process() {
+        let start = Instant::now();
+        let mut command = Command::new("sleep");
+        command.arg("5");
+
+        let output = output_with_timeout(&mut command, Duration::from_millis(100)).unwrap();
+        assert!(output.is_none());
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_output_with_timeout_collects_output() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo secret; echo oops >&2"]);
+
+        let output = output_with_timeout(&mut command, Duration::from_secs(5))
+            .unwrap()
+            .expect("should finish in time");
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"secret\n");
+        assert_eq!(output.stderr, b"oops\n");
+    }
+}
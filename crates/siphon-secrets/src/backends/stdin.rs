@@ -0,0 +1,102 @@
+//! Standard input backend
+//!
+//! Stdin can only be read once per process. The first `stdin://` reference
+//! reads it to EOF and caches the content; later references with the same
+//! name get the cached value, while a reference with a different name fails
+//! because there is no second stream to read from.
+
+use std::io::{IsTerminal, Read};
+use std::sync::Mutex;
+
+use crate::error::SecretError;
+
+/// A secret read from stdin, keyed by the name of the reference that read it
+struct StdinSecret {
+    name: String,
+    value: String,
+}
+
+static STDIN_SECRET: Mutex<Option<StdinSecret>> = Mutex::new(None);
+
+/// Resolve a secret from standard input (read once, then cached)
+pub fn resolve(name: &str) -> Result<String, SecretError> {
+    resolve_with(&STDIN_SECRET, name, || {
+        let mut stdin = std::io::stdin();
+        if stdin.is_terminal() {
+            return Err(SecretError::backend(
+                "stdin",
+                "stdin is a terminal; pipe the secret in (e.g. `cat client.key | siphon ...`)",
+            ));
+        }
+
+        let mut value = String::new();
+        stdin
+            .read_to_string(&mut value)
+            .map_err(|e| SecretError::backend("stdin", format!("read error: {}", e)))?;
+        Ok(value)
+    })
+}
+
+fn resolve_with(
+    cache: &Mutex<Option<StdinSecret>>,
+    name: &str,
+    read: impl FnOnce() -> Result<String, SecretError>,
+) -> Result<String, SecretError> {
+    let mut cached = cache.lock().unwrap_or_else(|e| e.into_inner());
+
+    if let Some(secret) = cached.as_ref() {
+        if secret.name == name {
+            return Ok(secret.value.clone());
+        }
+        return Err(SecretError::backend(
+            "stdin",
+            format!(
+                "stdin was already consumed by {}; only one distinct stdin reference is supported",
+                display_uri(&secret.name)
+            ),
+        ));
+    }
+
+    let value = read()?;
+    *cached = Some(StdinSecret {
+        name: name.to_string(),
+        value: value.clone(),
+    });
+    Ok(value)
+}
+
+fn display_uri(name: &str) -> String {
+    format!("stdin://{}", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_same_reference_reads_once() {
+        let cache = Mutex::new(None);
+        let mut reads = 0;
+
+        let first = resolve_with(&cache, "", || {
+            reads += 1;
+            Ok("piped-secret".to_string())
+        })
+        .unwrap();
+        let second = resolve_with(&cache, "", || unreachable!()).unwrap();
+
+        assert_eq!(reads, 1);
+        assert_eq!(first, "piped-secret");
+        assert_eq!(second, "piped-secret");
+    }
+
+    #[test]
+    fn test_distinct_reference_errors() {
+        let cache = Mutex::new(None);
+        resolve_with(&cache, "key", || Ok("piped-secret".to_string())).unwrap();
+
+        let result = resolve_with(&cache, "cert", || unreachable!());
+        let message = result.unwrap_err().to_string();
+        assert!(message.contains("stdin://key"));
+    }
+}
@@ -21,6 +21,10 @@ pub enum SecretError {
     #[error("{backend} error: {message}")]
     BackendError { backend: String, message: String },
 
+    /// Backend didn't answer in time or can't be reached right now
+    #[error("{backend} unavailable: {message}")]
+    BackendUnavailable { backend: String, message: String },
+
     /// Permission/access denied
     #[error("Access denied to secret: {0}")]
     AccessDenied(String),
@@ -32,6 +36,10 @@ pub enum SecretError {
     /// Environment variable error
     #[error("Environment variable '{var}' not set")]
     EnvNotSet { var: String },
+
+    /// Secret resolved, but to nothing (e.g. an empty file)
+    #[error("Secret '{0}' resolved to an empty value")]
+    Empty(String),
 }
 
 impl SecretError {
@@ -51,6 +59,25 @@ impl SecretError {
         }
     }
 
+    /// Create a backend unavailable error
+    pub fn unavailable(backend: impl Into<String>, message: impl Into<String>) -> Self {
+        Self::BackendUnavailable {
+            backend: backend.into(),
+            message: message.into(),
+        }
+    }
+
+    /// Create a timeout error for a backend call that exceeded its deadline
+    pub fn timed_out(backend: impl Into<String>, timeout: std::time::Duration) -> Self {
+        Self::unavailable(backend, format!("timed out after {:?}", timeout))
+    }
+
+    /// Whether the OS keychain couldn't be read right now, e.g. because it's
+    /// locked; interactive callers can ask the user to unlock it and retry
+    pub fn is_keychain_unavailable(&self) -> bool {
+        matches!(self, Self::BackendUnavailable { backend, .. } if backend == "keychain")
+    }
+
     /// Create a backend disabled error
     pub fn disabled(backend: impl Into<String>) -> Self {
         Self::BackendDisabled {
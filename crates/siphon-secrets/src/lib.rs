@@ -2,11 +2,19 @@
 //!
 //! This crate provides a unified interface for resolving secrets from various backends:
 //!
-//! - **OS Keychain** (`keychain://service/key`): macOS Keychain, Windows Credential Manager, Linux Secret Service
+//! - **OS Keychain** (`keychain://service/key[?account=name]`): macOS Keychain, Windows Credential Manager, Linux Secret Service
 //! - **1Password CLI** (`op://vault/item/field`): Requires `op` CLI to be installed and authenticated
 //! - **Environment variables** (`env://VAR_NAME`): Read from process environment
 //! - **Files** (`file:///path` or just `/path`): Read content from filesystem
-//! - **Plain values**: Any string without a URI scheme is treated as a literal value
+//! - **Standard input** (`stdin://` or `stdin://name`): Read piped input, **once per process**.
+//!   The first reference reads stdin to EOF; repeated references with the same name reuse
+//!   that value, and a reference with a different name is an error. Fails if stdin is a TTY.
+//! - **Plain values**: Any string without a URI scheme is treated as a literal value, unless it
+//!   looks like a path (starts with `/`, `./`, or contains `.pem`/`.crt`/`.key`). Use
+//!   `plain://value` for literals that could be mistaken for a path
+//!
+//! Resolution is one level deep: a resolved value is never parsed as a URI again,
+//! so an environment variable containing `env://OTHER` resolves to that literal string.
 //!
 //! # Example
 //!
@@ -27,6 +35,7 @@
 //! - `onepassword` (default): Enable 1Password CLI support
 //! - `env` (default): Enable environment variable support
 //! - `file` (default): Enable file reading support
+//! - `stdin` (default): Enable reading a secret from piped standard input
 
 mod backends;
 mod error;
@@ -34,8 +43,8 @@ mod resolver;
 mod uri;
 
 pub use error::SecretError;
-pub use resolver::SecretResolver;
-pub use uri::SecretUri;
+pub use resolver::{SecretResolver, TimeoutConfig, DEFAULT_BACKEND_TIMEOUT};
+pub use uri::{redact_sources, SecretUri};
 
 // Re-export keychain utilities for setup/management
 #[cfg(feature = "keychain")]
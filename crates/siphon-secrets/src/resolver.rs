@@ -1,24 +1,75 @@
 //! Secret resolution dispatcher
 
+use std::time::Duration;
+
 use crate::error::SecretError;
-use crate::uri::SecretUri;
+use crate::uri::{has_secret_scheme, SecretUri};
+
+/// Default per-call deadline for backends that can hang
+pub const DEFAULT_BACKEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Per-call deadlines for backends that talk to another process or service
+///
+/// Local backends (file, env, base64, stdin, plain) are never timed out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeoutConfig {
+    /// OS keychain (may block on an unlock prompt)
+    pub keychain: Duration,
+    /// 1Password CLI subprocess (killed on expiry)
+    pub onepassword: Duration,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            keychain: DEFAULT_BACKEND_TIMEOUT,
+            onepassword: DEFAULT_BACKEND_TIMEOUT,
+        }
+    }
+}
 
 /// Resolves secrets from various backends based on URI scheme
 #[derive(Debug, Default)]
 pub struct SecretResolver {
-    _private: (), // Prevent construction without ::new()
+    #[cfg_attr(
+        not(any(feature = "keychain", feature = "onepassword")),
+        allow(dead_code)
+    )]
+    timeouts: TimeoutConfig,
 }
 
 impl SecretResolver {
     /// Create a new secret resolver
     pub fn new() -> Self {
-        Self { _private: () }
+        Self::default()
+    }
+
+    /// Create a resolver with custom backend deadlines
+    pub fn with_timeouts(timeouts: TimeoutConfig) -> Self {
+        Self { timeouts }
     }
 
     /// Resolve a SecretUri to its actual value
+    ///
+    /// Resolution is exactly one level deep: the value a backend returns is
+    /// opaque, even if it looks like another secret URI (e.g. an env var set
+    /// to `env://OTHER`). Such values are returned verbatim with a warning,
+    /// so self-referential chains can't loop.
     pub fn resolve(&self, uri: &SecretUri) -> Result<String, SecretError> {
         tracing::debug!(backend = uri.backend_name(), "Resolving secret");
 
+        let value = self.resolve_backend(uri)?;
+        if !uri.is_plain() && has_secret_scheme(value.trim_start()) {
+            tracing::warn!(
+                backend = uri.backend_name(),
+                "Resolved secret looks like a secret URI; it is used as-is, not resolved again"
+            );
+        }
+        Ok(value)
+    }
+
+    /// Fetch the value from the backend selected by the URI scheme
+    fn resolve_backend(&self, uri: &SecretUri) -> Result<String, SecretError> {
         match uri {
             SecretUri::Plain(value) => Ok(value.clone()),
 
@@ -35,8 +86,15 @@ impl SecretResolver {
             SecretUri::File { .. } => Err(SecretError::disabled("file")),
 
             #[cfg(feature = "keychain")]
-            SecretUri::Keychain { service, key } => {
-                crate::backends::keychain::resolve(service, key)
+            SecretUri::Keychain {
+                service,
+                key,
+                account,
+            } => {
+                let (service, key, account) = (service.clone(), key.clone(), account.clone());
+                with_deadline("keychain", self.timeouts.keychain, move || {
+                    crate::backends::keychain::resolve(&service, &key, account.as_deref())
+                })
             }
 
             #[cfg(not(feature = "keychain"))]
@@ -44,7 +102,7 @@ impl SecretResolver {
 
             #[cfg(feature = "onepassword")]
             SecretUri::OnePassword { vault, item, field } => {
-                crate::backends::onepassword::resolve(vault, item, field)
+                crate::backends::onepassword::resolve(vault, item, field, self.timeouts.onepassword)
             }
 
             #[cfg(not(feature = "onepassword"))]
@@ -55,19 +113,255 @@ impl SecretResolver {
 
             #[cfg(not(feature = "base64"))]
             SecretUri::Base64 { .. } => Err(SecretError::disabled("base64")),
+
+            #[cfg(feature = "stdin")]
+            SecretUri::Stdin { name } => crate::backends::stdin::resolve(name),
+
+            #[cfg(not(feature = "stdin"))]
+            SecretUri::Stdin { .. } => Err(SecretError::disabled("stdin")),
         }
     }
 
+    /// Resolve a SecretUri, appending its raw bytes to `buf`
+    ///
+    /// Returns the number of bytes appended. The file and base64 backends
+    /// write straight into `buf` without an intermediate `String` (and
+    /// without requiring UTF-8), which avoids copies of large CA bundles;
+    /// other backends go through [`resolve`](Self::resolve). On error `buf`
+    /// is left as it was.
+    pub fn resolve_into(&self, uri: &SecretUri, buf: &mut Vec<u8>) -> Result<usize, SecretError> {
+        tracing::debug!(backend = uri.backend_name(), "Resolving secret");
+
+        let start = buf.len();
+        let result = match uri {
+            SecretUri::Plain(value) => {
+                buf.extend_from_slice(value.as_bytes());
+                Ok(value.len())
+            }
+
+            #[cfg(feature = "file")]
+            SecretUri::File { path } => crate::backends::file::resolve_into(path, buf),
+
+            #[cfg(feature = "base64")]
+            SecretUri::Base64 { data } => crate::backends::base64::resolve_into(data, buf),
+
+            _ => self.resolve_backend(uri).map(|value| {
+                use zeroize::Zeroize;
+
+                let mut value = value;
+                buf.extend_from_slice(value.as_bytes());
+                let len = value.len();
+                value.zeroize();
+                len
+            }),
+        };
+
+        match result {
+            Ok(len) => {
+                let value = &buf[start..];
+                let looks_like_uri = std::str::from_utf8(value)
+                    .is_ok_and(|value| has_secret_scheme(value.trim_start()));
+                if !uri.is_plain() && looks_like_uri {
+                    tracing::warn!(
+                        backend = uri.backend_name(),
+                        "Resolved secret looks like a secret URI; it is used as-is, not resolved again"
+                    );
+                }
+                Ok(len)
+            }
+            Err(e) => {
+                buf.truncate(start);
+                Err(e)
+            }
+        }
+    }
+
+    /// Resolve a SecretUri to its raw bytes (see [`resolve_into`](Self::resolve_into))
+    pub fn resolve_bytes(&self, uri: &SecretUri) -> Result<Vec<u8>, SecretError> {
+        let mut buf = Vec::new();
+        self.resolve_into(uri, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Check that a SecretUri can be resolved, without returning its value
+    ///
+    /// The value is resolved and zeroized immediately, which makes this
+    /// suitable for validating configs (e.g. in CI) without exposing secrets.
+    pub fn check(&self, uri: &SecretUri) -> Result<(), SecretError> {
+        use zeroize::Zeroize;
+
+        let mut value = self.resolve(uri)?;
+        value.zeroize();
+        Ok(())
+    }
+
+    /// Resolve a SecretUri, also returning the name of the backend that served it
+    ///
+    /// Intended for audit logging: the backend name can be logged safely,
+    /// the value must not be.
+    pub fn resolve_with_source(
+        &self,
+        uri: &SecretUri,
+    ) -> Result<(String, &'static str), SecretError> {
+        self.resolve(uri).map(|value| (value, uri.backend_name()))
+    }
+
     /// Resolve a SecretUri, trimming whitespace from the result
     pub fn resolve_trimmed(&self, uri: &SecretUri) -> Result<String, SecretError> {
         self.resolve(uri).map(|s| s.trim().to_string())
     }
+
+    /// Resolve a comma-separated list of secret URIs and concatenate the values
+    ///
+    /// Meant for PEM bundles assembled from several sources (e.g. an
+    /// intermediate and a cross-signed root). Every source must resolve to a
+    /// non-empty value.
+    pub fn resolve_bundle(&self, sources: &str) -> Result<String, SecretError> {
+        let mut bundle = Vec::new();
+        for source in sources.split(',').map(str::trim) {
+            if source.is_empty() {
+                return Err(SecretError::invalid_uri(sources, "empty entry in list"));
+            }
+            let value = self.resolve_trimmed(&source.parse()?)?;
+            if value.is_empty() {
+                return Err(SecretError::Empty(source.to_string()));
+            }
+            bundle.push(value);
+        }
+        Ok(bundle.join("\n"))
+    }
+}
+
+/// Run a blocking backend call on its own thread, giving up after `timeout`
+///
+/// The call can't be cancelled, so on expiry the thread is left to finish in
+/// the background and its result is discarded.
+#[cfg_attr(not(feature = "keychain"), allow(dead_code))]
+fn with_deadline<F>(backend: &str, timeout: Duration, call: F) -> Result<String, SecretError>
+where
+    F: FnOnce() -> Result<String, SecretError> + Send + 'static,
+{
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = tx.send(call());
+    });
+
+    match rx.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+            Err(SecretError::timed_out(backend, timeout))
+        }
+        Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+            Err(SecretError::backend(backend, "backend call panicked"))
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_with_deadline_times_out_slow_backend() {
+        let start = std::time::Instant::now();
+        let result = with_deadline("slow", Duration::from_millis(50), || {
+            std::thread::sleep(Duration::from_secs(2));
+            Ok("too late".to_string())
+        });
+
+        match result {
+            Err(SecretError::BackendUnavailable { backend, message }) => {
+                assert_eq!(backend, "slow");
+                assert!(message.contains("timed out"), "{}", message);
+            }
+            other => panic!("expected timeout, got {:?}", other),
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[cfg(feature = "file")]
+    #[test]
+    fn test_resolve_bundle_concatenates_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("intermediate.pem");
+        let second = dir.path().join("root.pem");
+        std::fs::write(&first, "FIRST\n").unwrap();
+        std::fs::write(&second, "SECOND").unwrap();
+
+        let resolver = SecretResolver::new();
+        let sources = format!("file://{}, file://{}", first.display(), second.display());
+        assert_eq!(resolver.resolve_bundle(&sources).unwrap(), "FIRST\nSECOND");
+
+        // A single source is just that source
+        let single = format!("file://{}", second.display());
+        assert_eq!(resolver.resolve_bundle(&single).unwrap(), "SECOND");
+    }
+
+    #[cfg(feature = "file")]
+    #[test]
+    fn test_resolve_bundle_rejects_empty_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let empty = dir.path().join("empty.pem");
+        std::fs::write(&empty, "  \n").unwrap();
+
+        let resolver = SecretResolver::new();
+        let sources = format!("plain://CA, file://{}", empty.display());
+        assert!(matches!(
+            resolver.resolve_bundle(&sources),
+            Err(SecretError::Empty(source)) if source.ends_with("empty.pem")
+        ));
+        assert!(matches!(
+            resolver.resolve_bundle("plain://CA,"),
+            Err(SecretError::InvalidUri { .. })
+        ));
+    }
+
+    #[cfg(all(feature = "file", feature = "base64"))]
+    #[test]
+    fn test_resolve_into_matches_resolve() {
+        use base64::Engine;
+
+        let bundle = "-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n".repeat(64);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bundle.pem");
+        std::fs::write(&path, &bundle).unwrap();
+        let encoded = base64::engine::general_purpose::STANDARD.encode(&bundle);
+
+        let resolver = SecretResolver::new();
+        for uri in [
+            SecretUri::File { path },
+            SecretUri::Base64 { data: encoded },
+        ] {
+            let expected = resolver.resolve(&uri).unwrap().into_bytes();
+            assert_eq!(resolver.resolve_bytes(&uri).unwrap(), expected);
+
+            // Appends after what the buffer already holds
+            let mut buf = b"prefix".to_vec();
+            let len = resolver.resolve_into(&uri, &mut buf).unwrap();
+            assert_eq!(len, bundle.len());
+            assert_eq!(&buf[..6], b"prefix");
+            assert_eq!(&buf[6..], expected.as_slice());
+        }
+    }
+
+    #[cfg(feature = "file")]
+    #[test]
+    fn test_resolve_into_leaves_buffer_on_error() {
+        let resolver = SecretResolver::new();
+        let uri = SecretUri::File {
+            path: "/definitely/not/a/real/path/12345".into(),
+        };
+        let mut buf = b"kept".to_vec();
+        assert!(resolver.resolve_into(&uri, &mut buf).is_err());
+        assert_eq!(buf, b"kept");
+    }
+
+    #[test]
+    fn test_with_deadline_returns_fast_result() {
+        let result = with_deadline("fast", Duration::from_secs(5), || Ok("value".to_string()));
+        assert_eq!(result.unwrap(), "value");
+    }
+
     #[test]
     fn test_resolve_plain() {
         let resolver = SecretResolver::new();
@@ -76,6 +370,40 @@ mod tests {
         assert_eq!(result, "my-secret");
     }
 
+    #[test]
+    fn test_resolve_plain_uri_is_not_a_path() {
+        let resolver = SecretResolver::new();
+        let uri: SecretUri = "plain://my.key.value".parse().unwrap();
+        assert_eq!(resolver.resolve(&uri).unwrap(), "my.key.value");
+    }
+
+    #[test]
+    fn test_check() {
+        let resolver = SecretResolver::new();
+        assert!(resolver
+            .check(&SecretUri::Plain("my-secret".to_string()))
+            .is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "env")]
+    fn test_check_missing_env() {
+        let resolver = SecretResolver::new();
+        let uri = SecretUri::Env {
+            var_name: "SIPHON_TEST_CHECK_DOES_NOT_EXIST".to_string(),
+        };
+        assert!(resolver.check(&uri).is_err());
+    }
+
+    #[test]
+    fn test_resolve_with_source() {
+        let resolver = SecretResolver::new();
+        let uri = SecretUri::Plain("my-secret".to_string());
+        let (value, source) = resolver.resolve_with_source(&uri).unwrap();
+        assert_eq!(value, "my-secret");
+        assert_eq!(source, "plain");
+    }
+
     #[test]
     #[cfg(feature = "env")]
     fn test_resolve_env() {
@@ -88,4 +416,33 @@ mod tests {
         assert_eq!(result, "env-secret-value");
         std::env::remove_var("TEST_RESOLVER_SECRET");
     }
+
+    #[test]
+    #[cfg(feature = "env")]
+    fn test_resolve_self_referential_env_is_verbatim() {
+        std::env::set_var("TEST_RESOLVER_SELF_REF", "env://TEST_RESOLVER_SELF_REF");
+        let resolver = SecretResolver::new();
+        let uri: SecretUri = "env://TEST_RESOLVER_SELF_REF".parse().unwrap();
+        let result = resolver.resolve(&uri).unwrap();
+        assert_eq!(result, "env://TEST_RESOLVER_SELF_REF");
+        std::env::remove_var("TEST_RESOLVER_SELF_REF");
+    }
+
+    #[test]
+    #[cfg(feature = "file")]
+    fn test_resolve_value_containing_uri_is_verbatim() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token");
+        std::fs::write(&path, "keychain://other/secret\n").unwrap();
+
+        let resolver = SecretResolver::new();
+        let uri = SecretUri::File { path };
+        assert_eq!(
+            resolver.resolve_trimmed(&uri).unwrap(),
+            "keychain://other/secret"
+        );
+
+        let uri: SecretUri = "plain://op://vault/item/field".parse().unwrap();
+        assert_eq!(resolver.resolve(&uri).unwrap(), "op://vault/item/field");
+    }
 }
@@ -8,18 +8,26 @@ use crate::error::SecretError;
 /// Represents a secret reference that can be resolved from various backends.
 ///
 /// Supports the following URI schemes:
-/// - `keychain://service/key` - OS keychain (macOS Keychain, Windows Credential Manager, Linux Secret Service)
+/// - `keychain://service/key` or `keychain://service/key?account=name` - OS keychain (macOS Keychain,
+///   Windows Credential Manager, Linux Secret Service)
 /// - `op://vault/item/field` - 1Password CLI
 /// - `env://VAR_NAME` - Environment variable
 /// - `file:///path/to/file` - File content
+/// - `stdin://` or `stdin://name` - Piped standard input (read once per process)
+/// - `plain://value` - Literal value, never mistaken for a path
 /// - Plain string - Literal value (backwards compatible)
 #[derive(Debug, Clone, PartialEq)]
 pub enum SecretUri {
-    /// Plain text value (no URI scheme, backwards compatible)
+    /// Plain text value: `plain://value`, or no URI scheme (backwards compatible)
     Plain(String),
 
-    /// OS Keychain: `keychain://service/key`
-    Keychain { service: String, key: String },
+    /// OS Keychain: `keychain://service/key`, or `keychain://service/key?account=name`
+    /// for entries that also carry an account name
+    Keychain {
+        service: String,
+        key: String,
+        account: Option<String>,
+    },
 
     /// 1Password CLI: `op://vault/item/field`
     OnePassword {
@@ -36,6 +44,43 @@ pub enum SecretUri {
 
     /// Base64 encoded value: `base64://...`
     Base64 { data: String },
+
+    /// Standard input: `stdin://` or `stdin://name`
+    ///
+    /// Stdin is read to EOF only once. Every reference with the same name
+    /// resolves to that content; a second, differently named reference fails.
+    Stdin { name: String },
+}
+
+/// Parses the part of a URI after its `scheme://` prefix; gets the full URI for errors
+type SchemeParser = fn(uri: &str, rest: &str) -> Result<SecretUri, SecretError>;
+
+/// URI schemes understood by [`SecretUri`], tried in order
+///
+/// Strings matching none of these fall back to a bare file path, then to a
+/// plain value. Adding a backend means adding a row here.
+const SCHEMES: &[(&str, SchemeParser)] = &[
+    ("keychain", parse_keychain_uri),
+    ("op", parse_onepassword_uri),
+    ("env", parse_env_uri),
+    ("file", parse_file_uri),
+    ("base64", parse_base64_uri),
+    ("plain", parse_plain_uri),
+    ("stdin", parse_stdin_uri),
+];
+
+/// Split `s` into a known scheme's parser and the rest of the URI
+fn match_scheme(s: &str) -> Option<(SchemeParser, &str)> {
+    SCHEMES.iter().find_map(|(scheme, parser)| {
+        s.strip_prefix(scheme)
+            .and_then(|rest| rest.strip_prefix("://"))
+            .map(|rest| (*parser, rest))
+    })
+}
+
+/// Check if a string starts with one of the secret URI schemes
+pub(crate) fn has_secret_scheme(s: &str) -> bool {
+    match_scheme(s).is_some()
 }
 
 impl SecretUri {
@@ -44,6 +89,21 @@ impl SecretUri {
         matches!(self, SecretUri::Plain(_))
     }
 
+    /// URI scheme of this reference, without `://` (None for plain values)
+    ///
+    /// Bare file paths report `file`, the scheme they are equivalent to.
+    pub fn scheme(&self) -> Option<&str> {
+        match self {
+            SecretUri::Plain(_) => None,
+            SecretUri::Keychain { .. } => Some("keychain"),
+            SecretUri::OnePassword { .. } => Some("op"),
+            SecretUri::Env { .. } => Some("env"),
+            SecretUri::File { .. } => Some("file"),
+            SecretUri::Base64 { .. } => Some("base64"),
+            SecretUri::Stdin { .. } => Some("stdin"),
+        }
+    }
+
     /// Get the backend name for logging/errors
     pub fn backend_name(&self) -> &'static str {
         match self {
@@ -53,24 +113,54 @@ impl SecretUri {
             SecretUri::Env { .. } => "env",
             SecretUri::File { .. } => "file",
             SecretUri::Base64 { .. } => "base64",
+            SecretUri::Stdin { .. } => "stdin",
         }
     }
+
+    /// Description safe to print: the backend, plus the reference for
+    /// keychain and 1Password entries (which name the secret without revealing it)
+    pub fn redacted(&self) -> String {
+        match self {
+            SecretUri::Keychain {
+                service,
+                key,
+                account: None,
+            } => format!("keychain (keychain://{}/{})", service, key),
+            SecretUri::Keychain {
+                service,
+                key,
+                account: Some(account),
+            } => format!(
+                "keychain (keychain://{}/{}?account={})",
+                service, key, account
+            ),
+            SecretUri::OnePassword { vault, item, field } => {
+                format!("1password (op://{}/{}/{})", vault, item, field)
+            }
+            other => format!("{} (redacted)", other.backend_name()),
+        }
+    }
+}
+
+/// Redacted description of a secret source, or of each source in a
+/// comma-separated bundle
+pub fn redact_sources(sources: &str) -> String {
+    sources
+        .split(',')
+        .map(|source| match source.trim().parse::<SecretUri>() {
+            Ok(uri) => uri.redacted(),
+            Err(_) => "invalid (redacted)".to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 impl FromStr for SecretUri {
     type Err = SecretError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.starts_with("keychain://") {
-            parse_keychain_uri(s)
-        } else if s.starts_with("op://") {
-            parse_onepassword_uri(s)
-        } else if s.starts_with("env://") {
-            parse_env_uri(s)
-        } else if s.starts_with("file://") {
-            parse_file_uri(s)
-        } else if s.starts_with("base64://") {
-            parse_base64_uri(s)
+        if let Some((parser, rest)) = match_scheme(s) {
+            parser(s, rest)
         } else if looks_like_file_path(s) {
             // Treat bare paths as file URIs for convenience
             Ok(SecretUri::File {
@@ -83,27 +173,35 @@ impl FromStr for SecretUri {
     }
 }
 
-/// Parse `keychain://service/key`
-fn parse_keychain_uri(s: &str) -> Result<SecretUri, SecretError> {
-    let rest = s.strip_prefix("keychain://").unwrap();
-    let parts: Vec<&str> = rest.splitn(2, '/').collect();
-
-    if parts.len() != 2 || parts[0].is_empty() || parts[1].is_empty() {
-        return Err(SecretError::invalid_uri(
+/// Parse `keychain://service/key` or `keychain://service/key?account=name`
+///
+/// Everything else after the service, `@` included, is part of the key.
+fn parse_keychain_uri(s: &str, rest: &str) -> Result<SecretUri, SecretError> {
+    let invalid = || {
+        SecretError::invalid_uri(
             s,
-            "keychain URI must be keychain://service/key",
-        ));
+            "keychain URI must be keychain://service/key or keychain://service/key?account=name",
+        )
+    };
+
+    let (service, key) = rest.split_once('/').ok_or_else(invalid)?;
+    let (key, account) = match key.rsplit_once("?account=") {
+        Some((key, account)) => (key, Some(account)),
+        None => (key, None),
+    };
+    if service.is_empty() || key.is_empty() || account.is_some_and(str::is_empty) {
+        return Err(invalid());
     }
 
     Ok(SecretUri::Keychain {
-        service: parts[0].to_string(),
-        key: parts[1].to_string(),
+        service: service.to_string(),
+        key: key.to_string(),
+        account: account.map(str::to_string),
     })
 }
 
 /// Parse `op://vault/item/field`
-fn parse_onepassword_uri(s: &str) -> Result<SecretUri, SecretError> {
-    let rest = s.strip_prefix("op://").unwrap();
+fn parse_onepassword_uri(s: &str, rest: &str) -> Result<SecretUri, SecretError> {
     let parts: Vec<&str> = rest.splitn(3, '/').collect();
 
     if parts.len() != 3 || parts.iter().any(|p| p.is_empty()) {
@@ -121,9 +219,7 @@ fn parse_onepassword_uri(s: &str) -> Result<SecretUri, SecretError> {
 }
 
 /// Parse `env://VAR_NAME`
-fn parse_env_uri(s: &str) -> Result<SecretUri, SecretError> {
-    let var_name = s.strip_prefix("env://").unwrap();
-
+fn parse_env_uri(s: &str, var_name: &str) -> Result<SecretUri, SecretError> {
     if var_name.is_empty() {
         return Err(SecretError::invalid_uri(
             s,
@@ -137,9 +233,7 @@ fn parse_env_uri(s: &str) -> Result<SecretUri, SecretError> {
 }
 
 /// Parse `file:///path/to/file`
-fn parse_file_uri(s: &str) -> Result<SecretUri, SecretError> {
-    let path = s.strip_prefix("file://").unwrap();
-
+fn parse_file_uri(s: &str, path: &str) -> Result<SecretUri, SecretError> {
     if path.is_empty() {
         return Err(SecretError::invalid_uri(s, "file URI must specify a path"));
     }
@@ -150,9 +244,7 @@ fn parse_file_uri(s: &str) -> Result<SecretUri, SecretError> {
 }
 
 /// Parse `base64://...`
-fn parse_base64_uri(s: &str) -> Result<SecretUri, SecretError> {
-    let data = s.strip_prefix("base64://").unwrap();
-
+fn parse_base64_uri(s: &str, data: &str) -> Result<SecretUri, SecretError> {
     if data.is_empty() {
         return Err(SecretError::invalid_uri(
             s,
@@ -165,6 +257,25 @@ fn parse_base64_uri(s: &str) -> Result<SecretUri, SecretError> {
     })
 }
 
+/// Parse `plain://value`, bypassing the file path heuristic
+fn parse_plain_uri(s: &str, value: &str) -> Result<SecretUri, SecretError> {
+    if value.is_empty() {
+        return Err(SecretError::invalid_uri(
+            s,
+            "plain URI must contain a value",
+        ));
+    }
+
+    Ok(SecretUri::Plain(value.to_string()))
+}
+
+/// Parse `stdin://` or `stdin://name`
+fn parse_stdin_uri(_s: &str, name: &str) -> Result<SecretUri, SecretError> {
+    Ok(SecretUri::Stdin {
+        name: name.to_string(),
+    })
+}
+
 /// Check if a string looks like a file path
 fn looks_like_file_path(s: &str) -> bool {
     // Unix absolute path or Windows path or relative path with extension
@@ -200,6 +311,50 @@ mod tests {
             SecretUri::Keychain {
                 service: "myservice".to_string(),
                 key: "mykey".to_string(),
+                account: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_keychain_uri_with_account() {
+        let uri: SecretUri = "keychain://myservice/mykey?account=alice".parse().unwrap();
+        assert_eq!(
+            uri,
+            SecretUri::Keychain {
+                service: "myservice".to_string(),
+                key: "mykey".to_string(),
+                account: Some("alice".to_string()),
+            }
+        );
+
+        let uri: SecretUri = "keychain://svc/team/key?account=ops@example.com"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            uri,
+            SecretUri::Keychain {
+                service: "svc".to_string(),
+                key: "team/key".to_string(),
+                account: Some("ops@example.com".to_string()),
+            }
+        );
+        assert_eq!(
+            uri.redacted(),
+            "keychain (keychain://svc/team/key?account=ops@example.com)"
+        );
+    }
+
+    #[test]
+    fn test_parse_keychain_uri_key_with_at() {
+        // An `@` in the key, e.g. an email address, is not an account
+        let uri: SecretUri = "keychain://siphon/alice@example.com".parse().unwrap();
+        assert_eq!(
+            uri,
+            SecretUri::Keychain {
+                service: "siphon".to_string(),
+                key: "alice@example.com".to_string(),
+                account: None,
             }
         );
     }
@@ -256,10 +411,29 @@ mod tests {
         assert_eq!(uri, SecretUri::Plain("my-secret-token".to_string()));
     }
 
+    #[test]
+    fn test_parse_plain_uri() {
+        // Would be read as a file without the explicit scheme
+        let bare: SecretUri = "my.key.value".parse().unwrap();
+        assert!(matches!(bare, SecretUri::File { .. }));
+
+        let uri: SecretUri = "plain://my.key.value".parse().unwrap();
+        assert_eq!(uri, SecretUri::Plain("my.key.value".to_string()));
+
+        let uri: SecretUri = "plain:///etc/not-a-path.pem".parse().unwrap();
+        assert_eq!(uri, SecretUri::Plain("/etc/not-a-path.pem".to_string()));
+
+        assert!("plain://".parse::<SecretUri>().is_err());
+    }
+
     #[test]
     fn test_invalid_keychain_uri() {
         let result: Result<SecretUri, _> = "keychain://onlyservice".parse();
         assert!(result.is_err());
+        assert!("keychain://svc/key?account=".parse::<SecretUri>().is_err());
+        assert!("keychain://svc/?account=alice"
+            .parse::<SecretUri>()
+            .is_err());
     }
 
     #[test]
@@ -290,4 +464,75 @@ mod tests {
         let result: Result<SecretUri, _> = "base64://".parse();
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_parse_stdin_uri() {
+        let uri: SecretUri = "stdin://".parse().unwrap();
+        assert_eq!(
+            uri,
+            SecretUri::Stdin {
+                name: String::new(),
+            }
+        );
+
+        let uri: SecretUri = "stdin://client-key".parse().unwrap();
+        assert_eq!(
+            uri,
+            SecretUri::Stdin {
+                name: "client-key".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_scheme() {
+        for (input, scheme) in [
+            ("keychain://svc/key", Some("keychain")),
+            ("op://v/i/f", Some("op")),
+            ("env://VAR", Some("env")),
+            ("file:///etc/ca.pem", Some("file")),
+            ("/etc/ca.pem", Some("file")),
+            ("base64://Zm9v", Some("base64")),
+            ("stdin://", Some("stdin")),
+            ("plain://value", None),
+            ("value", None),
+        ] {
+            let uri: SecretUri = input.parse().unwrap();
+            assert_eq!(uri.scheme(), scheme, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_redacted_hides_values() {
+        for (input, redacted) in [
+            (
+                "keychain://siphon/cert",
+                "keychain (keychain://siphon/cert)",
+            ),
+            ("op://Infra/siphon/key", "1password (op://Infra/siphon/key)"),
+            ("env://SIPHON_KEY", "env (redacted)"),
+            ("/etc/siphon/key.pem", "file (redacted)"),
+            ("base64://c2VjcmV0", "base64 (redacted)"),
+            ("hunter2", "plain (redacted)"),
+        ] {
+            let uri: SecretUri = input.parse().unwrap();
+            assert_eq!(uri.redacted(), redacted, "{}", input);
+        }
+
+        assert_eq!(
+            redact_sources("keychain://siphon/ca, plain://secret"),
+            "keychain (keychain://siphon/ca), plain (redacted)"
+        );
+        assert_eq!(redact_sources("env://"), "invalid (redacted)");
+    }
+
+    #[test]
+    fn test_every_scheme_is_recognized() {
+        for (scheme, _) in SCHEMES {
+            assert!(has_secret_scheme(&format!("{}://x", scheme)), "{}", scheme);
+        }
+        // A scheme name alone, or a look-alike prefix, is not a URI
+        assert!(!has_secret_scheme("env:VAR"));
+        assert!(!has_secret_scheme("environment://VAR"));
+    }
 }
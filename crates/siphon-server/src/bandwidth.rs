@@ -0,0 +1,174 @@
+//! Per-tunnel bandwidth caps
+//!
+//! Each tunnel gets its own token buckets, shared by all of its HTTP requests
+//! and TCP connections. Ingress is traffic from visitors into the tunnel,
+//! egress is traffic from the tunnel back out to visitors.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tokio::time::Instant;
+
+/// Rates for one tunnel, in bytes per second (None = unlimited)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BandwidthLimit {
+    pub ingress: Option<u64>,
+    pub egress: Option<u64>,
+}
+
+/// Default limit plus per-client overrides, keyed by client ID (certificate CN)
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthPolicy {
+    pub default: BandwidthLimit,
+    pub per_client: HashMap<String, BandwidthLimit>,
+}
+
+impl BandwidthPolicy {
+    /// Limit applying to tunnels of `client_id`
+    pub fn limit_for(&self, client_id: &str) -> BandwidthLimit {
+        self.per_client
+            .get(client_id)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Token bucket allowing bursts of up to one second's worth of traffic
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Negative when earlier callers are still waiting off a debt
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        Self {
+            bytes_per_sec,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Account for `bytes` of traffic, sleeping until the rate allows it
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.updated).as_secs_f64() * self.bytes_per_sec;
+            bucket.tokens = (bucket.tokens + refill).min(self.bytes_per_sec) - bytes as f64;
+            bucket.updated = now;
+
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / self.bytes_per_sec)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Ingress and egress limiters of one tunnel (cheap to clone)
+#[derive(Debug, Clone, Default)]
+pub struct TunnelLimiters {
+    ingress: Option<Arc<RateLimiter>>,
+    egress: Option<Arc<RateLimiter>>,
+}
+
+impl TunnelLimiters {
+    pub fn new(limit: BandwidthLimit) -> Self {
+        Self {
+            ingress: limit.ingress.map(|rate| Arc::new(RateLimiter::new(rate))),
+            egress: limit.egress.map(|rate| Arc::new(RateLimiter::new(rate))),
+        }
+    }
+
+    /// Wait until `bytes` may enter the tunnel
+    pub async fn ingress(&self, bytes: usize) {
+        if let Some(limiter) = &self.ingress {
+            limiter.acquire(bytes).await;
+        }
+    }
+
+    /// Wait until `bytes` may leave the tunnel
+    pub async fn egress(&self, bytes: usize) {
+        if let Some(limiter) = &self.egress {
+            limiter.acquire(bytes).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_burst_then_throttle() {
+        let limiter = RateLimiter::new(10_000);
+
+        // The first second's worth passes straight away
+        let start = std::time::Instant::now();
+        limiter.acquire(10_000).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        // Then 5 KB at 10 KB/s takes half a second
+        limiter.acquire(5_000).await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1000), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_callers_share_the_rate() {
+        let limiter = Arc::new(RateLimiter::new(10_000));
+        limiter.acquire(10_000).await;
+
+        let start = std::time::Instant::now();
+        let tasks: Vec<_> = (0..4)
+            .map(|_| {
+                let limiter = limiter.clone();
+                tokio::spawn(async move { limiter.acquire(1_000).await })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(350), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn test_per_client_override() {
+        let mut policy = BandwidthPolicy {
+            default: BandwidthLimit {
+                ingress: Some(1_000),
+                egress: None,
+            },
+            ..Default::default()
+        };
+        let fast = BandwidthLimit {
+            ingress: None,
+            egress: Some(1_000_000),
+        };
+        policy.per_client.insert("ci-runner".to_string(), fast);
+
+        assert_eq!(policy.limit_for("ci-runner"), fast);
+        assert_eq!(policy.limit_for("laptop"), policy.default);
+    }
+}
@@ -0,0 +1,136 @@
+//! Base domains tunnels are served under
+//!
+//! One server can serve tunnels under several base domains (e.g.
+//! `tun.a.com` and `tun.b.com`). Subdomains are shared between them: a
+//! tunnel takes its subdomain under a single base domain, the first one
+//! unless it asks for another, and requests for that subdomain under any
+//! other base domain find no tunnel.
+
+use std::fmt;
+
+/// Configured base domains, lowercase, the default one first
+#[derive(Debug, Clone)]
+pub struct BaseDomains(Vec<String>);
+
+impl BaseDomains {
+    /// Validate base domains, the first being the default
+    pub fn new<I, S>(domains: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut parsed: Vec<String> = Vec::new();
+        for domain in domains {
+            let domain = normalize(domain.as_ref());
+            if domain.is_empty() || domain.contains(['/', ':', ' ']) {
+                return Err(format!("Invalid base domain: {:?}", domain));
+            }
+            if parsed.contains(&domain) {
+                return Err(format!("Duplicate base domain: {}", domain));
+            }
+            parsed.push(domain);
+        }
+        if parsed.is_empty() {
+            return Err("At least one base domain is required".to_string());
+        }
+        Ok(Self(parsed))
+    }
+
+    /// Base domain of tunnels that don't ask for one
+    pub fn primary(&self) -> &str {
+        &self.0[0]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(String::as_str)
+    }
+
+    /// The configured base domain `name` refers to, if any
+    pub fn get(&self, name: &str) -> Option<&str> {
+        let name = normalize(name);
+        self.iter().find(|domain| *domain == name)
+    }
+
+    /// Split a lowercase `host` into the first label of its subdomain and the
+    /// base domain it is under (the longest matching one)
+    pub fn split<'a>(&'a self, host: &'a str) -> Option<(&'a str, &'a str)> {
+        self.iter()
+            .filter_map(|domain| {
+                let prefix = host.strip_suffix(domain)?.strip_suffix('.')?;
+                let label = prefix.split('.').next()?;
+                (!label.is_empty()).then_some((label, domain))
+            })
+            .max_by_key(|(_, domain)| domain.len())
+    }
+
+    /// Whether `name` is a base domain or a name under one
+    pub fn covers(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        self.iter().any(|domain| {
+            name == domain
+                || name
+                    .strip_suffix(domain)
+                    .is_some_and(|prefix| prefix.len() > 1 && prefix.ends_with('.'))
+        })
+    }
+}
+
+impl From<String> for BaseDomains {
+    fn from(domain: String) -> Self {
+        Self(vec![normalize(&domain)])
+    }
+}
+
+impl From<&str> for BaseDomains {
+    fn from(domain: &str) -> Self {
+        Self(vec![normalize(domain)])
+    }
+}
+
+/// Base domains, comma-separated
+impl fmt::Display for BaseDomains {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0.join(", "))
+    }
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_host_by_base_domain() {
+        let domains = BaseDomains::new(["tun.a.com", "a.com", "TUN.B.com"]).unwrap();
+        assert_eq!(domains.primary(), "tun.a.com");
+        assert_eq!(domains.split("app.tun.a.com"), Some(("app", "tun.a.com")));
+        assert_eq!(domains.split("app.a.com"), Some(("app", "a.com")));
+        assert_eq!(domains.split("x.app.tun.b.com"), Some(("x", "tun.b.com")));
+        assert_eq!(domains.split("tun.a.com"), Some(("tun", "a.com")));
+        assert_eq!(domains.split("a.com"), None);
+        assert_eq!(domains.split("app.c.com"), None);
+        assert_eq!(domains.split("appa.com"), None);
+    }
+
+    #[test]
+    fn test_base_domain_lookup() {
+        let domains = BaseDomains::new(["tun.a.com", "tun.b.com"]).unwrap();
+        assert_eq!(domains.get("TUN.B.COM."), Some("tun.b.com"));
+        assert_eq!(domains.get("tun.c.com"), None);
+        assert!(domains.covers("app.tun.b.com"));
+        assert!(domains.covers("tun.a.com"));
+        assert!(!domains.covers("evil-tun.a.com"));
+        assert_eq!(domains.to_string(), "tun.a.com, tun.b.com");
+    }
+
+    #[test]
+    fn test_invalid_base_domains_rejected() {
+        assert!(BaseDomains::new(Vec::<String>::new()).is_err());
+        assert!(BaseDomains::new(["tun.a.com", "Tun.A.com"]).is_err());
+        assert!(BaseDomains::new(["https://tun.a.com"]).is_err());
+        assert!(BaseDomains::new([" "]).is_err());
+    }
+}
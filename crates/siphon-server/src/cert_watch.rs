@@ -0,0 +1,241 @@
+//! Reloading TLS certificates when their files change
+//!
+//! Certificates rotated on disk (cert-manager, certbot) would otherwise only
+//! be picked up on restart. A watcher on the files' directories rebuilds the
+//! acceptor once the changes settle, and swaps it in for new handshakes;
+//! connections already established keep the certificate they started with.
+//! A new certificate that fails to load leaves the current one in place.
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+
+/// How long the files must stay untouched before reloading, so a rotation
+/// writing the certificate and key separately is loaded once, complete
+pub const CERT_RELOAD_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// TLS acceptor that can be replaced while the server runs
+#[derive(Clone)]
+pub struct ReloadableAcceptor(Arc<ArcSwap<TlsAcceptor>>);
+
+impl ReloadableAcceptor {
+    pub fn new(acceptor: TlsAcceptor) -> Self {
+        Self(Arc::new(ArcSwap::from_pointee(acceptor)))
+    }
+
+    /// Acceptor for the next handshake
+    pub fn current(&self) -> Arc<TlsAcceptor> {
+        self.0.load_full()
+    }
+
+    pub fn replace(&self, acceptor: TlsAcceptor) {
+        self.0.store(Arc::new(acceptor));
+    }
+}
+
+impl From<TlsAcceptor> for ReloadableAcceptor {
+    fn from(acceptor: TlsAcceptor) -> Self {
+        Self::new(acceptor)
+    }
+}
+
+/// Watches certificate files, reloading an acceptor when they change
+///
+/// Watching stops when this is dropped.
+pub struct CertWatcher {
+    _watcher: RecommendedWatcher,
+    reloader: JoinHandle<()>,
+}
+
+impl CertWatcher {
+    /// Watch `files`, replacing `acceptor` with the result of `load` once
+    /// they haven't changed for `debounce`
+    ///
+    /// `name` identifies the acceptor in logs. The directories are watched
+    /// rather than the files, since rotations often replace a file (or a
+    /// symlink to it) instead of writing to it.
+    pub fn start<F>(
+        name: &'static str,
+        files: &[PathBuf],
+        debounce: Duration,
+        acceptor: ReloadableAcceptor,
+        load: F,
+    ) -> anyhow::Result<Self>
+    where
+        F: Fn() -> anyhow::Result<TlsAcceptor> + Send + Sync + 'static,
+    {
+        let names: HashSet<OsString> = files
+            .iter()
+            .filter_map(|file| file.file_name())
+            .map(ToOwned::to_owned)
+            .collect();
+        let (changes, mut changed) = mpsc::unbounded_channel();
+
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let Ok(event) = event else { return };
+                if matches!(event.kind, EventKind::Access(_)) {
+                    return;
+                }
+                let relevant = event.paths.iter().any(|path| {
+                    path.file_name().is_some_and(|file| {
+                        // Kubernetes mounts swap a `..data` symlink to rotate secrets
+                        names.contains(file) || file.to_string_lossy().starts_with("..")
+                    })
+                });
+                if relevant {
+                    let _ = changes.send(());
+                }
+            })?;
+        let dirs: HashSet<&Path> = files
+            .iter()
+            .map(|file| match file.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            })
+            .collect();
+        for dir in dirs {
+            watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        }
+
+        let load = Arc::new(load);
+        let reloader = tokio::spawn(async move {
+            while changed.recv().await.is_some() {
+                // Wait for the rotation to finish writing
+                loop {
+                    match tokio::time::timeout(debounce, changed.recv()).await {
+                        Ok(Some(())) => continue,
+                        Ok(None) => return,
+                        Err(_) => break,
+                    }
+                }
+
+                let load = load.clone();
+                match tokio::task::spawn_blocking(move || load()).await {
+                    Ok(Ok(new)) => {
+                        acceptor.replace(new);
+                        tracing::info!("Reloaded {} TLS certificate", name);
+                    }
+                    Ok(Err(e)) => tracing::warn!(
+                        "Keeping the current {} TLS certificate, the new one failed to load: {:#}",
+                        name,
+                        e
+                    ),
+                    Err(e) => tracing::warn!("{} TLS certificate reload failed: {}", name, e),
+                }
+            }
+        });
+
+        tracing::info!("Watching {} TLS certificate files for changes", name);
+        Ok(Self {
+            _watcher: watcher,
+            reloader,
+        })
+    }
+}
+
+impl Drop for CertWatcher {
+    fn drop(&mut self) {
+        self.reloader.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use siphon_common::{GeneratedCerts, ServerCert};
+
+    const DEBOUNCE: Duration = Duration::from_millis(100);
+
+    /// Acceptor for the certificate and key currently in `dir`
+    fn load_from(dir: &Path) -> anyhow::Result<TlsAcceptor> {
+        let cert = std::fs::read_to_string(dir.join("server.crt"))?;
+        let key = std::fs::read_to_string(dir.join("server.key"))?;
+        siphon_common::verify_cert_key_pair(&cert, &key)?;
+        let config = siphon_common::load_server_config_no_client_auth(&cert, &key)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    fn server_cert() -> ServerCert {
+        GeneratedCerts::generate("client", &["localhost".to_string()])
+            .unwrap()
+            .server
+            .unwrap()
+    }
+
+    fn write_certs(dir: &Path) {
+        let server = server_cert();
+        std::fs::write(dir.join("server.crt"), server.cert_pem).unwrap();
+        std::fs::write(dir.join("server.key"), server.key_pem).unwrap();
+    }
+
+    /// Wait until `acceptor` no longer holds `old`
+    async fn wait_for_reload(acceptor: &ReloadableAcceptor, old: &Arc<TlsAcceptor>) -> bool {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while Arc::ptr_eq(&acceptor.current(), old) {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    #[tokio::test]
+    async fn test_rewritten_cert_is_reloaded() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let dir = tempfile::tempdir().unwrap();
+        write_certs(dir.path());
+
+        let acceptor = ReloadableAcceptor::new(load_from(dir.path()).unwrap());
+        let files = [dir.path().join("server.crt"), dir.path().join("server.key")];
+        let root = dir.path().to_path_buf();
+        let _watcher = CertWatcher::start("test", &files, DEBOUNCE, acceptor.clone(), move || {
+            load_from(&root)
+        })
+        .unwrap();
+
+        let original = acceptor.current();
+        write_certs(dir.path());
+        assert!(wait_for_reload(&acceptor, &original).await);
+
+        // A broken certificate never replaces a working one
+        let reloaded = acceptor.current();
+        std::fs::write(dir.path().join("server.crt"), "not a certificate").unwrap();
+        tokio::time::sleep(DEBOUNCE * 5).await;
+        assert!(Arc::ptr_eq(&acceptor.current(), &reloaded));
+
+        // Nor does a certificate that doesn't go with the key
+        std::fs::write(dir.path().join("server.crt"), server_cert().cert_pem).unwrap();
+        tokio::time::sleep(DEBOUNCE * 5).await;
+        assert!(Arc::ptr_eq(&acceptor.current(), &reloaded));
+    }
+
+    #[tokio::test]
+    async fn test_unrelated_files_ignored() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let dir = tempfile::tempdir().unwrap();
+        write_certs(dir.path());
+
+        let acceptor = ReloadableAcceptor::new(load_from(dir.path()).unwrap());
+        let files = [dir.path().join("server.crt"), dir.path().join("server.key")];
+        let root = dir.path().to_path_buf();
+        let _watcher = CertWatcher::start("test", &files, DEBOUNCE, acceptor.clone(), move || {
+            load_from(&root)
+        })
+        .unwrap();
+
+        let original = acceptor.current();
+        std::fs::write(dir.path().join("notes.txt"), "hello").unwrap();
+        tokio::time::sleep(DEBOUNCE * 5).await;
+        assert!(Arc::ptr_eq(&acceptor.current(), &original));
+    }
+}
@@ -1,3 +1,6 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
 use async_trait::async_trait;
 use rcgen::{CertificateParams, KeyPair};
 use reqwest::Client;
@@ -5,15 +8,29 @@ use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::config::{DnsTarget, ResolvedCloudflareConfig};
-use crate::dns_provider::{DnsError, DnsProvider, OriginCertificate};
+use crate::dns_provider::{DnsError, DnsProvider, OriginCertificate, OriginCertificateIssuer};
+use crate::metrics::DnsMetrics;
+
+/// Public Cloudflare API endpoint
+pub const DEFAULT_API_BASE: &str = "https://api.cloudflare.com/client/v4";
+
+/// TTL of the records siphon creates (short, since they come and go with tunnels)
+const RECORD_TTL: u32 = 60;
+
+/// Page size when listing DNS records
+const LIST_PAGE_SIZE: usize = 100;
 
 /// Cloudflare API client for DNS and Origin CA management
 pub struct CloudflareClient {
     client: Client,
+    api_base: String,
     api_token: String,
     zone_id: String,
     dns_target: DnsTarget,
     base_domain: String,
+    /// Comment marking the records this server created
+    owner: String,
+    metrics: Arc<DnsMetrics>,
 }
 
 #[derive(Debug, Serialize)]
@@ -24,6 +41,7 @@ struct CreateDnsRecord {
     content: String,
     ttl: u32,
     proxied: bool,
+    comment: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -38,6 +56,41 @@ struct DnsRecord {
     id: String,
 }
 
+/// A DNS record already present in the zone
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExistingRecord {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    pub content: String,
+    #[serde(default)]
+    pub proxied: bool,
+    #[serde(default)]
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListDnsRecordsResponse {
+    success: bool,
+    result: Option<Vec<ExistingRecord>>,
+    errors: Vec<CloudflareApiError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ZoneResponse {
+    success: bool,
+    result: Option<ZoneInfo>,
+    errors: Vec<CloudflareApiError>,
+}
+
+/// The configured zone, as reported by the API
+#[derive(Debug, Clone, Deserialize)]
+pub struct ZoneInfo {
+    pub name: String,
+    #[serde(default)]
+    pub status: String,
+}
+
 #[derive(Debug, Deserialize)]
 struct CloudflareApiError {
     message: String,
@@ -111,15 +164,53 @@ impl CloudflareClient {
     pub fn new(config: &ResolvedCloudflareConfig, base_domain: &str) -> Self {
         Self {
             client: Client::new(),
+            api_base: config.api_base.clone(),
             api_token: config.api_token.clone(),
             zone_id: config.zone_id.clone(),
             dns_target: config.dns_target.clone(),
             base_domain: base_domain.to_string(),
+            owner: format!("siphon:{}", config.instance_id),
+            metrics: Arc::new(DnsMetrics::new()),
+        }
+    }
+
+    /// Client for another base domain, in the Cloudflare zone `zone_id`
+    ///
+    /// Shares this client's credentials, record target and counters.
+    pub fn for_base_domain(&self, base_domain: &str, zone_id: &str) -> Self {
+        Self {
+            client: self.client.clone(),
+            api_base: self.api_base.clone(),
+            api_token: self.api_token.clone(),
+            zone_id: zone_id.to_string(),
+            dns_target: self.dns_target.clone(),
+            base_domain: base_domain.to_string(),
+            owner: self.owner.clone(),
+            metrics: self.metrics.clone(),
         }
     }
 
+    /// Whether this server created `record`
+    ///
+    /// Only the comment tells: Cloudflare reports proxied records with an
+    /// automatic TTL, and other servers or operators may point records at the
+    /// same target.
+    fn is_own_record(&self, record: &ExistingRecord) -> bool {
+        record.comment.as_deref() == Some(self.owner.as_str())
+    }
+
+    /// Counters for DNS record and Origin CA operations
+    pub fn metrics(&self) -> Arc<DnsMetrics> {
+        self.metrics.clone()
+    }
+
     /// Create a DNS record for a subdomain (A record for IP, CNAME for hostname)
     ///
+    /// Idempotent: a record this server created earlier for the name (with
+    /// its `siphon:<instance_id>` comment) is reused, and its duplicates
+    /// removed. Any other record with the name belongs to someone else: it is
+    /// left alone and the record is refused.
+    ///
     /// # Arguments
     /// * `subdomain` - The subdomain to create (e.g., "myapp")
     /// * `proxied` - Whether to proxy through Cloudflare (true for HTTP, false for TCP)
@@ -130,6 +221,19 @@ impl CloudflareClient {
         &self,
         subdomain: &str,
         proxied: bool,
+    ) -> Result<String, CloudflareError> {
+        let result = self.request_create_record(subdomain, proxied).await;
+        match &result {
+            Ok(_) => self.metrics.record_created(),
+            Err(_) => self.metrics.create_failed(),
+        }
+        result
+    }
+
+    async fn request_create_record(
+        &self,
+        subdomain: &str,
+        proxied: bool,
     ) -> Result<String, CloudflareError> {
         let full_name = format!("{}.{}", subdomain, self.base_domain);
 
@@ -138,6 +242,63 @@ impl CloudflareClient {
             DnsTarget::Cname(hostname) => ("CNAME", hostname.clone()),
         };
 
+        // Reuse a record left behind by an earlier run (e.g. a crash between
+        // creating it and registering the tunnel) instead of duplicating it
+        match self.list_records_for_name(&full_name, record_type).await {
+            Ok(existing) => {
+                let (owned, foreign): (Vec<_>, Vec<_>) = existing
+                    .into_iter()
+                    .partition(|record| self.is_own_record(record));
+                // Operators' records (or round-robin sets) are never touched
+                if let Some(record) = foreign.first() {
+                    tracing::warn!(
+                        "DNS record {} ({}) for {} was not created by siphon, refusing the tunnel",
+                        record.id,
+                        record.content,
+                        full_name
+                    );
+                    return Err(CloudflareError::Api(format!(
+                        "DNS record already exists for {}",
+                        full_name
+                    )));
+                }
+
+                if let Some((record, duplicates)) = owned.split_first() {
+                    for duplicate in duplicates {
+                        tracing::warn!(
+                            "Removing duplicate DNS record {} for {}",
+                            duplicate.id,
+                            full_name
+                        );
+                        if let Err(e) = self.request_delete_record(&duplicate.id).await {
+                            tracing::warn!("Failed to remove duplicate DNS record: {}", e);
+                        }
+                    }
+
+                    if record.proxied == proxied && record.content == content {
+                        tracing::info!("Reusing DNS record {} with ID {}", full_name, record.id);
+                    } else {
+                        self.request_update_record(
+                            &record.id,
+                            record_type,
+                            &full_name,
+                            &content,
+                            proxied,
+                        )
+                        .await?;
+                    }
+                    return Ok(record.id.clone());
+                }
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to look up existing DNS records for {}, creating a new one: {}",
+                    full_name,
+                    e
+                );
+            }
+        }
+
         tracing::info!(
             "Creating DNS {} record: {} -> {} (proxied: {})",
             record_type,
@@ -149,16 +310,17 @@ impl CloudflareClient {
         let response = self
             .client
             .post(format!(
-                "https://api.cloudflare.com/client/v4/zones/{}/dns_records",
-                self.zone_id
+                "{}/zones/{}/dns_records",
+                self.api_base, self.zone_id
             ))
             .bearer_auth(&self.api_token)
             .json(&CreateDnsRecord {
                 record_type: record_type.to_string(),
                 name: full_name.clone(),
                 content,
-                ttl: 60, // Short TTL for dynamic records
+                ttl: RECORD_TTL,
                 proxied,
+                comment: self.owner.clone(),
             })
             .send()
             .await?;
@@ -182,15 +344,211 @@ impl CloudflareClient {
         }
     }
 
+    /// Point an existing siphon record at `content`
+    async fn request_update_record(
+        &self,
+        record_id: &str,
+        record_type: &str,
+        full_name: &str,
+        content: &str,
+        proxied: bool,
+    ) -> Result<(), CloudflareError> {
+        tracing::info!(
+            "Updating DNS {} record {}: {} -> {} (proxied: {})",
+            record_type,
+            record_id,
+            full_name,
+            content,
+            proxied
+        );
+
+        let response = self
+            .client
+            .patch(format!(
+                "{}/zones/{}/dns_records/{}",
+                self.api_base, self.zone_id, record_id
+            ))
+            .bearer_auth(&self.api_token)
+            .json(&CreateDnsRecord {
+                record_type: record_type.to_string(),
+                name: full_name.to_string(),
+                content: content.to_string(),
+                ttl: RECORD_TTL,
+                proxied,
+                comment: self.owner.clone(),
+            })
+            .send()
+            .await?;
+
+        let result: DnsRecordResponse = response.json().await?;
+
+        if result.success {
+            Ok(())
+        } else {
+            let error_msg = result
+                .errors
+                .into_iter()
+                .map(|e| e.message)
+                .collect::<Vec<_>>()
+                .join(", ");
+            Err(CloudflareError::Api(format!(
+                "Failed to update record {}: {}",
+                record_id, error_msg
+            )))
+        }
+    }
+
+    /// Fetch the configured zone, checking that the token can read it
+    ///
+    /// Read-only; used to validate credentials without touching any record.
+    pub async fn verify_zone(&self) -> Result<ZoneInfo, CloudflareError> {
+        let response = self
+            .client
+            .get(format!("{}/zones/{}", self.api_base, self.zone_id))
+            .bearer_auth(&self.api_token)
+            .send()
+            .await?;
+
+        let result: ZoneResponse = response.json().await?;
+
+        match result.result {
+            Some(zone) if result.success => Ok(zone),
+            _ => {
+                let error_msg = result
+                    .errors
+                    .into_iter()
+                    .map(|e| e.message)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                Err(CloudflareError::Api(format!(
+                    "Failed to read zone {}: {}",
+                    self.zone_id, error_msg
+                )))
+            }
+        }
+    }
+
+    /// List the zone's DNS records of `record_type` named exactly `full_name`
+    pub async fn list_records_for_name(
+        &self,
+        full_name: &str,
+        record_type: &str,
+    ) -> Result<Vec<ExistingRecord>, CloudflareError> {
+        self.list_records(&[("type", record_type), ("name", full_name)])
+            .await
+    }
+
+    /// List the zone's DNS records matching the `filters` query, across all pages
+    async fn list_records(
+        &self,
+        filters: &[(&str, &str)],
+    ) -> Result<Vec<ExistingRecord>, CloudflareError> {
+        let mut records = Vec::new();
+        for page in 1.. {
+            let response = self
+                .client
+                .get(format!(
+                    "{}/zones/{}/dns_records",
+                    self.api_base, self.zone_id
+                ))
+                .query(filters)
+                .query(&[("page", page), ("per_page", LIST_PAGE_SIZE)])
+                .bearer_auth(&self.api_token)
+                .send()
+                .await?;
+
+            let result: ListDnsRecordsResponse = response.json().await?;
+
+            if !result.success {
+                let error_msg = result
+                    .errors
+                    .into_iter()
+                    .map(|e| e.message)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                return Err(CloudflareError::Api(format!(
+                    "Failed to list DNS records: {}",
+                    error_msg
+                )));
+            }
+
+            let batch = result.result.unwrap_or_default();
+            let last_page = batch.len() < LIST_PAGE_SIZE;
+            records.extend(batch);
+            if last_page {
+                break;
+            }
+        }
+        Ok(records)
+    }
+
+    /// Delete tunnel records left behind by a previous run
+    ///
+    /// A record counts as a tunnel record when it is a direct child of the base
+    /// domain and carries this server's `siphon:<instance_id>` comment. Records
+    /// for subdomains in `keep` are left alone.
+    ///
+    /// # Returns
+    /// The number of records deleted
+    pub async fn cleanup_orphaned_records(
+        &self,
+        keep: &HashSet<String>,
+    ) -> Result<u32, CloudflareError> {
+        let record_type = match &self.dns_target {
+            DnsTarget::Ip(_) => "A",
+            DnsTarget::Cname(_) => "CNAME",
+        };
+        let suffix = format!(".{}", self.base_domain);
+
+        let records = self
+            .list_records(&[("type", record_type), ("comment.exact", &self.owner)])
+            .await?;
+
+        let mut deleted = 0;
+        for record in records {
+            let Some(subdomain) = record.name.strip_suffix(&suffix) else {
+                continue;
+            };
+            let is_tunnel_record = self.is_own_record(&record)
+                && !subdomain.is_empty()
+                && !subdomain.contains('.')
+                && subdomain != "*";
+            if !is_tunnel_record || keep.contains(subdomain) {
+                continue;
+            }
+
+            tracing::info!(
+                "Deleting orphaned DNS record {} ({})",
+                record.name,
+                record.id
+            );
+            match self.request_delete_record(&record.id).await {
+                Ok(()) => deleted += 1,
+                Err(e) => tracing::warn!("Failed to delete orphaned record {}: {}", record.name, e),
+            }
+        }
+
+        Ok(deleted)
+    }
+
     /// Delete a DNS record
     pub async fn delete_record(&self, record_id: &str) -> Result<(), CloudflareError> {
+        let result = self.request_delete_record(record_id).await;
+        match &result {
+            Ok(_) => self.metrics.record_deleted(),
+            Err(_) => self.metrics.delete_failed(),
+        }
+        result
+    }
+
+    async fn request_delete_record(&self, record_id: &str) -> Result<(), CloudflareError> {
         tracing::info!("Deleting DNS record {}", record_id);
 
         let response = self
             .client
             .delete(format!(
-                "https://api.cloudflare.com/client/v4/zones/{}/dns_records/{}",
-                self.zone_id, record_id
+                "{}/zones/{}/dns_records/{}",
+                self.api_base, self.zone_id, record_id
             ))
             .bearer_auth(&self.api_token)
             .send()
@@ -259,7 +617,7 @@ impl CloudflareClient {
         // Use origin-ecc since rcgen generates ECDSA keys by default
         let response = self
             .client
-            .post("https://api.cloudflare.com/client/v4/certificates")
+            .post(format!("{}/certificates", self.api_base))
             .bearer_auth(&self.api_token)
             .json(&CreateOriginCertRequest {
                 csr: csr_pem,
@@ -279,6 +637,7 @@ impl CloudflareClient {
 
             let private_key_pem = key_pair.serialize_pem();
 
+            self.metrics.origin_cert_issued();
             tracing::info!(
                 "Created Origin CA certificate for *.{}, expires: {}",
                 self.base_domain,
@@ -314,8 +673,8 @@ impl CloudflareClient {
         let response = self
             .client
             .get(format!(
-                "https://api.cloudflare.com/client/v4/certificates?zone_id={}",
-                self.zone_id
+                "{}/certificates?zone_id={}",
+                self.api_base, self.zone_id
             ))
             .bearer_auth(&self.api_token)
             .send()
@@ -345,10 +704,7 @@ impl CloudflareClient {
 
         let response = self
             .client
-            .delete(format!(
-                "https://api.cloudflare.com/client/v4/certificates/{}",
-                cert_id
-            ))
+            .delete(format!("{}/certificates/{}", self.api_base, cert_id))
             .bearer_auth(&self.api_token)
             .send()
             .await?;
@@ -356,6 +712,7 @@ impl CloudflareClient {
         let result: RevokeOriginCertResponse = response.json().await?;
 
         if result.success {
+            self.metrics.origin_cert_revoked();
             tracing::info!("Revoked Origin CA certificate {}", cert_id);
             Ok(())
         } else {
@@ -436,13 +793,19 @@ impl DnsProvider for CloudflareClient {
             .map_err(Into::into)
     }
 
+    fn origin_certificates(&self) -> Option<&dyn OriginCertificateIssuer> {
+        Some(self)
+    }
+}
+
+#[async_trait]
+impl OriginCertificateIssuer for CloudflareClient {
     async fn create_origin_certificate(
         &self,
         validity_days: u32,
-    ) -> Result<Option<OriginCertificate>, DnsError> {
+    ) -> Result<OriginCertificate, DnsError> {
         CloudflareClient::create_origin_certificate(self, validity_days)
             .await
-            .map(Some)
             .map_err(Into::into)
     }
 
@@ -452,3 +815,414 @@ impl DnsProvider for CloudflareClient {
             .map_err(Into::into)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+    use wiremock::matchers::{body_partial_json, header, method, path, query_param};
+    use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+    fn client(server: &MockServer, dns_target: DnsTarget) -> CloudflareClient {
+        let config = ResolvedCloudflareConfig {
+            api_token: "test-token".to_string(),
+            zone_id: "zone123".to_string(),
+            dns_target,
+            auto_origin_ca: false,
+            cleanup_orphans_on_start: false,
+            api_base: server.uri(),
+            instance_id: "test-instance".to_string(),
+        };
+        CloudflareClient::new(&config, "tunnel.example.com")
+    }
+
+    #[tokio::test]
+    async fn test_create_record_a() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/zones/zone123/dns_records"))
+            .and(header("authorization", "Bearer test-token"))
+            .and(body_partial_json(json!({
+                "type": "A",
+                "name": "myapp.tunnel.example.com",
+                "content": "203.0.113.7",
+                "ttl": 60,
+                "proxied": true,
+                "comment": "siphon:test-instance",
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "success": true,
+                "result": { "id": "rec-1" },
+                "errors": [],
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let cf = client(&server, DnsTarget::Ip("203.0.113.7".to_string()));
+        let id = cf.create_record("myapp", true).await.unwrap();
+
+        assert_eq!(id, "rec-1");
+        assert_eq!(cf.metrics().snapshot().records_created, 1);
+    }
+
+    #[tokio::test]
+    async fn test_create_record_cname_api_error() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/zones/zone123/dns_records"))
+            .and(body_partial_json(json!({
+                "type": "CNAME",
+                "content": "myapp.up.railway.app",
+                "proxied": false,
+            })))
+            .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+                "success": false,
+                "result": null,
+                "errors": [
+                    { "code": 81057, "message": "Record already exists." },
+                    { "code": 1004, "message": "DNS Validation Error" },
+                ],
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let cf = client(
+            &server,
+            DnsTarget::Cname("myapp.up.railway.app".to_string()),
+        );
+        let err = cf.create_record("myapp", false).await.unwrap_err();
+
+        assert!(
+            matches!(&err, CloudflareError::Api(msg) if msg == "Record already exists., DNS Validation Error"),
+            "{}",
+            err
+        );
+        assert_eq!(cf.metrics().snapshot().create_failures, 1);
+    }
+
+    #[tokio::test]
+    async fn test_create_record_reuses_existing() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/zones/zone123/dns_records"))
+            .and(query_param("type", "A"))
+            .and(query_param("name", "myapp.tunnel.example.com"))
+            .and(header("authorization", "Bearer test-token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "success": true,
+                "result": [{ "id": "rec-old", "content": "203.0.113.7", "ttl": 60, "proxied": false, "comment": "siphon:test-instance" }],
+                "errors": [],
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let cf = client(&server, DnsTarget::Ip("203.0.113.7".to_string()));
+        assert_eq!(cf.create_record("myapp", false).await.unwrap(), "rec-old");
+    }
+
+    #[tokio::test]
+    async fn test_create_record_reuses_proxied_leftover() {
+        // Cloudflare reports proxied records with its automatic TTL of 1
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/zones/zone123/dns_records"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "success": true,
+                "result": [{ "id": "rec-old", "content": "203.0.113.7", "ttl": 1, "proxied": true, "comment": "siphon:test-instance" }],
+                "errors": [],
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        for verb in ["POST", "PATCH", "DELETE"] {
+            Mock::given(method(verb))
+                .respond_with(ResponseTemplate::new(500))
+                .expect(0)
+                .mount(&server)
+                .await;
+        }
+
+        let cf = client(&server, DnsTarget::Ip("203.0.113.7".to_string()));
+        assert_eq!(cf.create_record("myapp", true).await.unwrap(), "rec-old");
+    }
+
+    #[tokio::test]
+    async fn test_create_record_updates_proxying_and_removes_duplicates() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/zones/zone123/dns_records"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "success": true,
+                "result": [
+                    { "id": "rec-stale", "content": "203.0.113.7", "ttl": 1, "proxied": true, "comment": "siphon:test-instance" },
+                    { "id": "rec-dup", "content": "203.0.113.7", "ttl": 60, "proxied": false, "comment": "siphon:test-instance" },
+                ],
+                "errors": [],
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("PATCH"))
+            .and(path("/zones/zone123/dns_records/rec-stale"))
+            .and(body_partial_json(json!({
+                "type": "A",
+                "name": "myapp.tunnel.example.com",
+                "content": "203.0.113.7",
+                "proxied": false,
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "success": true,
+                "result": { "id": "rec-stale" },
+                "errors": [],
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/zones/zone123/dns_records/rec-dup"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "success": true })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let cf = client(&server, DnsTarget::Ip("203.0.113.7".to_string()));
+        assert_eq!(cf.create_record("myapp", false).await.unwrap(), "rec-stale");
+    }
+
+    #[tokio::test]
+    async fn test_create_record_leaves_foreign_records_alone() {
+        for foreign in [
+            // Pointing elsewhere, e.g. an operator's status page
+            json!({ "id": "rec-manual", "content": "198.51.100.1", "ttl": 60, "proxied": true }),
+            // Pointing here with siphon's TTL, but not marked as ours
+            json!({ "id": "rec-manual", "content": "203.0.113.7", "ttl": 60, "proxied": true }),
+            // Created by another siphon server sharing the zone
+            json!({ "id": "rec-other", "content": "203.0.113.7", "ttl": 60, "proxied": true, "comment": "siphon:other-instance" }),
+        ] {
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/zones/zone123/dns_records"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "success": true,
+                    "result": [
+                        foreign,
+                        { "id": "rec-own", "content": "203.0.113.7", "ttl": 60, "proxied": true, "comment": "siphon:test-instance" },
+                    ],
+                    "errors": [],
+                })))
+                .mount(&server)
+                .await;
+            for verb in ["POST", "PATCH", "DELETE"] {
+                Mock::given(method(verb))
+                    .respond_with(ResponseTemplate::new(500))
+                    .expect(0)
+                    .mount(&server)
+                    .await;
+            }
+
+            let cf = client(&server, DnsTarget::Ip("203.0.113.7".to_string()));
+            let err = cf.create_record("status", true).await.unwrap_err();
+            assert!(
+                err.to_string().contains("DNS record already exists"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_orphaned_records() {
+        let server = MockServer::start().await;
+        let record = |id: &str, name: &str, owner: &str| json!({ "id": id, "name": name, "content": "203.0.113.7", "ttl": 1, "proxied": true, "comment": owner });
+        Mock::given(method("GET"))
+            .and(path("/zones/zone123/dns_records"))
+            .and(query_param("type", "A"))
+            .and(query_param("comment.exact", "siphon:test-instance"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "success": true,
+                "result": [
+                    record("orphan", "old-app.tunnel.example.com", "siphon:test-instance"),
+                    record("kept", "live-app.tunnel.example.com", "siphon:test-instance"),
+                    record("apex", "tunnel.example.com", "siphon:test-instance"),
+                    record("wildcard", "*.tunnel.example.com", "siphon:test-instance"),
+                    record("nested", "a.b.tunnel.example.com", "siphon:test-instance"),
+                    record("manual", "www.tunnel.example.com", "managed by ops"),
+                    record("other", "app.tunnel.example.com", "siphon:other-instance"),
+                    record("elsewhere", "app.other.example.com", "siphon:test-instance"),
+                ],
+                "errors": [],
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/zones/zone123/dns_records/orphan"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "success": true })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let cf = client(&server, DnsTarget::Ip("203.0.113.7".to_string()));
+        let keep = HashSet::from(["live-app".to_string()]);
+        assert_eq!(cf.cleanup_orphaned_records(&keep).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_delete_record() {
+        let server = MockServer::start().await;
+        Mock::given(method("DELETE"))
+            .and(path("/zones/zone123/dns_records/rec-1"))
+            .and(header("authorization", "Bearer test-token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "success": true,
+                "result": { "id": "rec-1" },
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/zones/zone123/dns_records/missing"))
+            .respond_with(ResponseTemplate::new(404).set_body_json(json!({
+                "success": false,
+            })))
+            .mount(&server)
+            .await;
+
+        let cf = client(&server, DnsTarget::Ip("203.0.113.7".to_string()));
+        cf.delete_record("rec-1").await.unwrap();
+        assert!(cf.delete_record("missing").await.is_err());
+
+        let snapshot = cf.metrics().snapshot();
+        assert_eq!(snapshot.records_deleted, 1);
+        assert_eq!(snapshot.delete_failures, 1);
+    }
+
+    #[tokio::test]
+    async fn test_create_origin_certificate() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/certificates"))
+            .and(body_partial_json(json!({
+                "hostnames": ["*.tunnel.example.com", "tunnel.example.com"],
+                "request_type": "origin-ecc",
+                "requested_validity": 90,
+            })))
+            .respond_with(|req: &Request| {
+                // The CSR is generated locally, so only check that one was sent
+                let body: serde_json::Value = serde_json::from_slice(&req.body).unwrap();
+                let csr = body["csr"].as_str().unwrap_or_default();
+                if !csr.starts_with("-----BEGIN CERTIFICATE REQUEST-----") {
+                    return ResponseTemplate::new(400);
+                }
+                ResponseTemplate::new(200).set_body_json(json!({
+                    "success": true,
+                    "result": {
+                        "certificate": "-----BEGIN CERTIFICATE-----\ntest\n-----END CERTIFICATE-----\n",
+                        "expires_on": "2030-01-01 00:00:00 +0000 UTC",
+                    },
+                    "errors": [],
+                }))
+            })
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let cf = client(&server, DnsTarget::Ip("203.0.113.7".to_string()));
+        // Issued the way the server asks for it, through the DNS provider
+        let provider: &dyn DnsProvider = &cf;
+        let cert = provider
+            .origin_certificates()
+            .expect("Cloudflare issues origin certificates")
+            .create_origin_certificate(90)
+            .await
+            .unwrap();
+
+        assert!(cert.certificate.starts_with("-----BEGIN CERTIFICATE-----"));
+        assert!(cert.private_key.contains("PRIVATE KEY"));
+        assert_eq!(cert.expires_on, "2030-01-01 00:00:00 +0000 UTC");
+        assert_eq!(cf.metrics().snapshot().origin_certs_issued, 1);
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_old_origin_certificates() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/certificates"))
+            .and(query_param("zone_id", "zone123"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "success": true,
+                "result": [
+                    { "id": "old", "hostnames": ["*.tunnel.example.com"], "expires_on": "2026" },
+                    { "id": "other", "hostnames": ["other.example.com"], "expires_on": "2026" },
+                ],
+                "errors": [],
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/certificates/old"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "success": true,
+                "errors": [],
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let cf = client(&server, DnsTarget::Ip("203.0.113.7".to_string()));
+        assert_eq!(cf.cleanup_old_origin_certificates().await.unwrap(), 1);
+        assert_eq!(cf.metrics().snapshot().origin_certs_revoked, 1);
+    }
+
+    #[tokio::test]
+    async fn test_verify_zone() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/zones/zone123"))
+            .and(header("authorization", "Bearer test-token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "success": true,
+                "result": { "id": "zone123", "name": "example.com", "status": "active" },
+                "errors": [],
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let cf = client(&server, DnsTarget::Ip("203.0.113.7".to_string()));
+        let zone = cf.verify_zone().await.unwrap();
+        assert_eq!(zone.name, "example.com");
+        assert_eq!(zone.status, "active");
+    }
+
+    #[tokio::test]
+    async fn test_verify_zone_bad_token() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/zones/zone123"))
+            .respond_with(ResponseTemplate::new(403).set_body_json(json!({
+                "success": false,
+                "result": null,
+                "errors": [{ "code": 9109, "message": "Invalid access token" }],
+            })))
+            .mount(&server)
+            .await;
+
+        let cf = client(&server, DnsTarget::Ip("203.0.113.7".to_string()));
+        let err = cf.verify_zone().await.unwrap_err().to_string();
+        assert!(err.contains("Invalid access token"), "{}", err);
+    }
+}
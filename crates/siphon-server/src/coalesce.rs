@@ -0,0 +1,392 @@
+//! Coalescing of identical concurrent GET requests
+//!
+//! A popular read endpoint behind a tunnel gets the same request from many
+//! visitors at once, and each one would cross the tunnel and hit the local
+//! service. With coalescing on, the first request goes through the tunnel
+//! and identical ones arriving meanwhile (or shortly after) get a copy of its
+//! response. Requests carrying headers that may make the response specific to
+//! the visitor (credentials, conditionals, anything unknown) or asking not to
+//! be served from a cache, and responses that vary by request or must not be
+//! stored, opt out.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use hyper::header::{
+    ACCEPT, ACCEPT_ENCODING, ACCEPT_LANGUAGE, CACHE_CONTROL, CONTENT_LENGTH, HOST, PRAGMA,
+};
+use hyper::{HeaderMap, Method, Request};
+use tokio::sync::watch;
+
+use crate::state::HttpResponseData;
+
+/// Request headers that leave the response the same for every visitor
+///
+/// Any other header (`Authorization`, `Cookie`, `X-Api-Key`, `Range`,
+/// `If-None-Match`, ...) may carry credentials or select content, so a request
+/// with one goes through on its own. Headers the key includes are listed too.
+const SHAREABLE_HEADERS: &[&str] = &[
+    // Part of the key
+    "host",
+    "accept",
+    "accept-encoding",
+    "accept-language",
+    // Checked separately
+    "cache-control",
+    "pragma",
+    "content-length",
+    // Sent by browsers and proxies alike, with no effect on the content
+    "connection",
+    "user-agent",
+    "referer",
+    "dnt",
+    "upgrade-insecure-requests",
+    "priority",
+    "sec-fetch-dest",
+    "sec-fetch-mode",
+    "sec-fetch-site",
+    "sec-fetch-user",
+    "sec-ch-ua",
+    "sec-ch-ua-mobile",
+    "sec-ch-ua-platform",
+    "via",
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-proto",
+    "x-forwarded-host",
+    "x-real-ip",
+    "cdn-loop",
+    "cf-connecting-ip",
+    "cf-ipcountry",
+    "cf-ray",
+    "cf-visitor",
+    "true-client-ip",
+];
+
+/// Outcome of a request others may be waiting on
+#[derive(Debug)]
+enum Flight {
+    /// Still going through the tunnel
+    Pending,
+    /// Answered with a response that can be shared
+    Shared(Arc<HttpResponseData>),
+    /// Failed or answered with a response for this visitor only
+    NotShared,
+}
+
+/// Requests going through the tunnel, and recently answered ones, by key
+#[derive(Debug, Clone)]
+pub struct Coalescer {
+    /// How long a response is reused once it arrived
+    ttl: Duration,
+    flights: Arc<DashMap<String, watch::Sender<Flight>>>,
+}
+
+impl Coalescer {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            flights: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Run `forward` unless an identical request (same `key`) is in flight or
+    /// was just answered, in which case share its response
+    ///
+    /// Waiters whose request can't share the response forward their own.
+    pub async fn run<F, Fut, E>(&self, key: String, forward: F) -> Result<Arc<HttpResponseData>, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<HttpResponseData, E>>,
+    {
+        let flight = match self.flights.entry(key.clone()) {
+            Entry::Occupied(entry) => Some(entry.get().subscribe()),
+            Entry::Vacant(entry) => {
+                entry.insert(watch::channel(Flight::Pending).0);
+                None
+            }
+        };
+
+        if let Some(mut flight) = flight {
+            // A dropped sender means the first visitor went away before the answer
+            if let Ok(outcome) = flight.wait_for(|f| !matches!(f, Flight::Pending)).await {
+                if let Flight::Shared(response) = &*outcome {
+                    return Ok(response.clone());
+                }
+            }
+            return forward().await.map(Arc::new);
+        }
+
+        // Free the key even if this visitor goes away before the answer
+        let mut landing = Landing {
+            coalescer: self,
+            key,
+            shared: None,
+        };
+        let result = forward().await.map(Arc::new);
+        landing.shared = result
+            .as_ref()
+            .ok()
+            .filter(|response| is_shareable(response))
+            .cloned();
+        result
+    }
+}
+
+/// Publishes the outcome of a request once it's done (or abandoned)
+struct Landing<'a> {
+    coalescer: &'a Coalescer,
+    key: String,
+    shared: Option<Arc<HttpResponseData>>,
+}
+
+impl Drop for Landing<'_> {
+    fn drop(&mut self) {
+        let flights = &self.coalescer.flights;
+        let ttl = self.coalescer.ttl;
+        let outcome = match self.shared.take() {
+            Some(response) => Flight::Shared(response),
+            None => Flight::NotShared,
+        };
+        let reusable = matches!(outcome, Flight::Shared(_)) && !ttl.is_zero();
+
+        let Some(flight) = (match reusable {
+            true => flights.get(&self.key).map(|flight| flight.clone()),
+            false => flights.remove(&self.key).map(|(_, flight)| flight),
+        }) else {
+            return;
+        };
+        flight.send_replace(outcome);
+
+        if reusable {
+            let flights = flights.clone();
+            let key = std::mem::take(&mut self.key);
+            tokio::spawn(async move {
+                tokio::time::sleep(ttl).await;
+                flights.remove_if(&key, |_, current| current.same_channel(&flight));
+            });
+        }
+    }
+}
+
+/// Key under which `req` may share a response, None if it must go through
+/// the tunnel on its own
+pub fn request_key<B>(req: &Request<B>, subdomain: &str, scheme: &str) -> Option<String> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return None;
+    }
+    let headers = req.headers();
+    if headers
+        .keys()
+        .any(|name| !SHAREABLE_HEADERS.contains(&name.as_str()))
+        || headers
+            .get(CONTENT_LENGTH)
+            .is_some_and(|len| len.as_bytes() != b"0")
+    {
+        return None;
+    }
+    if directives(headers, CACHE_CONTROL.as_str())
+        .chain(directives(headers, PRAGMA.as_str()))
+        .any(|directive| directive == "no-cache" || directive == "no-store")
+    {
+        return None;
+    }
+
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("")
+    };
+    Some(
+        [
+            req.method().as_str(),
+            scheme,
+            subdomain,
+            &header(HOST).to_ascii_lowercase(),
+            &req.uri().to_string(),
+            header(ACCEPT),
+            header(ACCEPT_ENCODING),
+            header(ACCEPT_LANGUAGE),
+        ]
+        .join("\n"),
+    )
+}
+
+/// Whether a response may be handed to other visitors
+fn is_shareable(response: &HttpResponseData) -> bool {
+    let values = |wanted: &'static str| {
+        response
+            .headers
+            .iter()
+            .filter(move |(name, _)| name.eq_ignore_ascii_case(wanted))
+            .map(|(_, value)| value.as_str())
+    };
+    values("vary").next().is_none()
+        && values("set-cookie").next().is_none()
+        && !values("cache-control")
+            .flat_map(|value| value.split(','))
+            .map(|directive| directive.trim().to_ascii_lowercase())
+            .any(|directive| directive == "no-store" || directive == "private")
+}
+
+/// Lowercase directives of a comma-separated header
+fn directives<'a>(headers: &'a HeaderMap, name: &'a str) -> impl Iterator<Item = String> + 'a {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim().to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn response(headers: &[(&str, &str)]) -> HttpResponseData {
+        HttpResponseData {
+            status: 200,
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: b"shared".to_vec(),
+            client_id: "client".to_string(),
+        }
+    }
+
+    fn get(uri: &str, headers: &[(&str, &str)]) -> Request<()> {
+        let mut builder = Request::get(uri).header("host", "app.tunnel.example.com");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn test_request_key() {
+        let key = request_key(&get("/a", &[]), "app", "https").unwrap();
+        assert_eq!(
+            request_key(&get("/a", &[]), "app", "https"),
+            Some(key.clone())
+        );
+        assert_ne!(
+            request_key(&get("/b", &[]), "app", "https"),
+            Some(key.clone())
+        );
+        assert_ne!(
+            request_key(&get("/a", &[]), "app", "http"),
+            Some(key.clone())
+        );
+        assert_ne!(
+            request_key(&get("/a", &[("accept", "text/html")]), "app", "https"),
+            Some(key)
+        );
+
+        for headers in [
+            [("authorization", "Bearer x")],
+            [("cookie", "session=1")],
+            [("range", "bytes=0-10")],
+            [("if-none-match", "\"v1\"")],
+            [("transfer-encoding", "chunked")],
+            [("cache-control", "max-age=0, no-cache")],
+            [("pragma", "no-cache")],
+        ] {
+            assert_eq!(request_key(&get("/a", &headers), "app", "https"), None);
+        }
+        let post = Request::post("/a").body(()).unwrap();
+        assert_eq!(request_key(&post, "app", "https"), None);
+    }
+
+    #[test]
+    fn test_unknown_headers_opt_out() {
+        // Two visitors with different API keys must not share a response
+        let alice = get("/a", &[("x-api-key", "alice")]);
+        let bob = get("/a", &[("x-api-key", "bob")]);
+        assert_eq!(request_key(&alice, "app", "https"), None);
+        assert_eq!(request_key(&bob, "app", "https"), None);
+
+        // What browsers and proxies add on their own doesn't
+        let browser = get(
+            "/a",
+            &[
+                ("user-agent", "Mozilla/5.0"),
+                ("sec-fetch-mode", "navigate"),
+                ("x-forwarded-for", "198.51.100.1"),
+                ("cf-connecting-ip", "198.51.100.1"),
+            ],
+        );
+        assert_eq!(
+            request_key(&browser, "app", "https"),
+            request_key(&get("/a", &[]), "app", "https")
+        );
+    }
+
+    #[test]
+    fn test_shareable_responses() {
+        assert!(is_shareable(&response(&[("Cache-Control", "max-age=60")])));
+        assert!(!is_shareable(&response(&[("Vary", "Accept-Encoding")])));
+        assert!(!is_shareable(&response(&[("Set-Cookie", "a=b")])));
+        assert!(!is_shareable(&response(&[("cache-control", "No-Store")])));
+        assert!(!is_shareable(&response(&[(
+            "Cache-Control",
+            "private, max-age=60"
+        )])));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_share_one_forward() {
+        let coalescer = Coalescer::new(Duration::ZERO);
+        let forwarded = AtomicUsize::new(0);
+        let forward = |headers: &'static [(&'static str, &'static str)]| {
+            let forwarded = &forwarded;
+            move || async move {
+                forwarded.fetch_add(1, Ordering::Relaxed);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok::<_, ()>(response(headers))
+            }
+        };
+
+        let run = || coalescer.run("key".to_string(), forward(&[]));
+        let results = tokio::join!(run(), run(), run(), run(), run());
+        for result in [results.0, results.1, results.2, results.3, results.4] {
+            assert_eq!(result.unwrap().body, b"shared");
+        }
+        assert_eq!(forwarded.load(Ordering::Relaxed), 1);
+
+        // Without a TTL the next request goes through again
+        run().await.unwrap();
+        assert_eq!(forwarded.load(Ordering::Relaxed), 2);
+
+        // A response that can't be shared makes the others forward their own
+        let run = || coalescer.run("key".to_string(), forward(&[("Vary", "Cookie")]));
+        let _ = tokio::join!(run(), run(), run());
+        assert_eq!(forwarded.load(Ordering::Relaxed), 5);
+        assert!(coalescer.flights.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_response_reused_within_ttl() {
+        let coalescer = Coalescer::new(Duration::from_millis(100));
+        let forwarded = AtomicUsize::new(0);
+        let forward = || async {
+            forwarded.fetch_add(1, Ordering::Relaxed);
+            Ok::<_, ()>(response(&[]))
+        };
+
+        coalescer.run("key".to_string(), forward).await.unwrap();
+        coalescer.run("key".to_string(), forward).await.unwrap();
+        assert_eq!(forwarded.load(Ordering::Relaxed), 1);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(coalescer.flights.is_empty());
+        coalescer.run("key".to_string(), forward).await.unwrap();
+        assert_eq!(forwarded.load(Ordering::Relaxed), 2);
+    }
+}
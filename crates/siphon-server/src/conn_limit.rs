@@ -0,0 +1,144 @@
+//! Caps on concurrent control plane connections
+//!
+//! Checked right after `accept`, before the TLS handshake, so a connection
+//! flood is shed immediately instead of piling up tasks and file descriptors.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Why a connection was turned away
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum LimitExceeded {
+    #[error("too many concurrent connections (limit {0})")]
+    Total(usize),
+    #[error("too many concurrent connections from this address (limit {0})")]
+    PerIp(usize),
+}
+
+/// Tracks open connections against a global and a per-source-IP cap
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionLimiter {
+    total: Option<(Arc<Semaphore>, usize)>,
+    per_ip: Option<usize>,
+    open_per_ip: Arc<DashMap<IpAddr, usize>>,
+}
+
+impl ConnectionLimiter {
+    /// Create a limiter; `None` leaves that dimension unlimited
+    pub fn new(max_total: Option<usize>, max_per_ip: Option<usize>) -> Self {
+        Self {
+            total: max_total.map(|max| (Arc::new(Semaphore::new(max)), max)),
+            per_ip: max_per_ip,
+            open_per_ip: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Reserve a slot for a connection from `ip`, without waiting
+    ///
+    /// The slot is held until the returned permit is dropped.
+    pub fn try_acquire(&self, ip: IpAddr) -> Result<ConnectionPermit, LimitExceeded> {
+        let total = match &self.total {
+            Some((semaphore, max)) => Some(
+                semaphore
+                    .clone()
+                    .try_acquire_owned()
+                    .map_err(|_| LimitExceeded::Total(*max))?,
+            ),
+            None => None,
+        };
+
+        let per_ip = match self.per_ip {
+            Some(max) => {
+                let mut open = self.open_per_ip.entry(ip).or_insert(0);
+                if *open >= max {
+                    return Err(LimitExceeded::PerIp(max));
+                }
+                *open += 1;
+                Some((ip, self.open_per_ip.clone()))
+            }
+            None => None,
+        };
+
+        Ok(ConnectionPermit {
+            _total: total,
+            per_ip,
+        })
+    }
+}
+
+/// A held connection slot, released on drop
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    _total: Option<OwnedSemaphorePermit>,
+    per_ip: Option<(IpAddr, Arc<DashMap<IpAddr, usize>>)>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        if let Some((ip, open_per_ip)) = &self.per_ip {
+            // Drop the entry at zero so the map doesn't grow with every address seen
+            open_per_ip.remove_if_mut(ip, |_, open| {
+                *open -= 1;
+                *open == 0
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_total_limit() {
+        let limiter = ConnectionLimiter::new(Some(2), None);
+        let a = limiter.try_acquire(ip("10.0.0.1")).unwrap();
+        let _b = limiter.try_acquire(ip("10.0.0.2")).unwrap();
+        assert_eq!(
+            limiter.try_acquire(ip("10.0.0.3")).unwrap_err(),
+            LimitExceeded::Total(2)
+        );
+
+        drop(a);
+        assert!(limiter.try_acquire(ip("10.0.0.3")).is_ok());
+    }
+
+    #[test]
+    fn test_per_ip_limit() {
+        let limiter = ConnectionLimiter::new(None, Some(1));
+        let a = limiter.try_acquire(ip("10.0.0.1")).unwrap();
+        assert_eq!(
+            limiter.try_acquire(ip("10.0.0.1")).unwrap_err(),
+            LimitExceeded::PerIp(1)
+        );
+        assert!(limiter.try_acquire(ip("10.0.0.2")).is_ok());
+
+        drop(a);
+        assert!(limiter.open_per_ip.get(&ip("10.0.0.1")).is_none());
+        assert!(limiter.try_acquire(ip("10.0.0.1")).is_ok());
+    }
+
+    #[test]
+    fn test_per_ip_rejection_keeps_total_slot_free() {
+        let limiter = ConnectionLimiter::new(Some(2), Some(1));
+        let _a = limiter.try_acquire(ip("10.0.0.1")).unwrap();
+        assert!(limiter.try_acquire(ip("10.0.0.1")).is_err());
+        assert!(limiter.try_acquire(ip("10.0.0.2")).is_ok());
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = ConnectionLimiter::default();
+        let permits: Vec<_> = (0..100)
+            .map(|_| limiter.try_acquire(ip("10.0.0.1")).unwrap())
+            .collect();
+        assert_eq!(permits.len(), 100);
+    }
+}
@@ -1,5 +1,7 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use bytes::BytesMut;
@@ -7,48 +9,149 @@ use cuid2::CuidConstructor;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc;
-use tokio_rustls::TlsAcceptor;
 use tokio_util::codec::{Decoder, Encoder};
 
-use siphon_protocol::{ClientMessage, ServerMessage, TunnelCodec, TunnelType};
-
-use crate::dns_provider::DnsProvider;
-use crate::router::{Router, TunnelHandle};
-use crate::state::{HttpResponseData, ResponseRegistry, TcpConnectionRegistry};
+use siphon_common::flow_control::StreamQueueError;
+use siphon_common::SocketOptions;
+use siphon_protocol::{
+    ClientMessage, ReadBufferPolicy, ServerMessage, TunnelCodec, TunnelType, PROTOCOL_VERSION,
+};
+
+use crate::bandwidth::{BandwidthPolicy, TunnelLimiters};
+use crate::base_domains::BaseDomains;
+use crate::cert_watch::ReloadableAcceptor;
+use crate::conn_limit::ConnectionLimiter;
+use crate::dns_propagation::DnsPropagationCheck;
+use crate::dns_provider::{DnsProvider, ZoneProviders};
+use crate::identity::ClientIdentity;
+use crate::metrics::StreamMetrics;
+use crate::router::{Router, TunnelHandle, CATCH_ALL};
+use crate::state::{
+    take_pending_response, HttpResponseData, OrphanResponse, ResponseRegistry,
+    TcpConnectionRegistry, WsConnectionRegistry,
+};
+use crate::subdomain::SubdomainGenerator;
 use crate::tcp_plane::TcpPlane;
+use crate::webhook::{LifecycleEvent, LifecycleEventKind, LifecycleWebhook};
+
+/// How long an expired connection waits for the client to hang up
+const EXPIRY_GRACE: Duration = Duration::from_secs(2);
+
+/// Tunable behavior of the control plane
+#[derive(Debug, Clone, Default)]
+pub struct ControlPlaneOptions {
+    /// Generator for random subdomains when the client doesn't request one
+    pub subdomain_generator: SubdomainGenerator,
+    /// Shared SNI-routed port for TCP tunnels (None = allocate a port per tunnel)
+    pub tcp_mux_port: Option<u16>,
+    /// Notified when tunnels are established and closed
+    pub lifecycle_webhook: Option<LifecycleWebhook>,
+    /// Cap on concurrently open client connections (None = unlimited)
+    pub max_connections: Option<usize>,
+    /// Cap on concurrently open client connections per source IP (None = unlimited)
+    pub max_connections_per_ip: Option<usize>,
+    /// Per-tunnel bandwidth caps, by client
+    pub bandwidth: BandwidthPolicy,
+    /// TCP_NODELAY and keepalive settings for client connections
+    pub socket_options: SocketOptions,
+    /// Initial and retained capacity of each connection's read buffer
+    pub read_buffer: ReadBufferPolicy,
+    /// Wait for new tunnel names to resolve before announcing them (None = announce right away)
+    pub dns_propagation: Option<DnsPropagationCheck>,
+    /// DNS providers of base domains the main provider doesn't manage
+    pub zone_providers: ZoneProviders,
+    /// Refuse clients that negotiate no ALPN protocol (the TLS config lists
+    /// the accepted ones; rustls only refuses clients offering others)
+    pub require_alpn: bool,
+    /// Close connections this long after their first tunnel was set up,
+    /// releasing their tunnels so the client reconnects for fresh ones (None = never)
+    pub max_tunnel_lifetime: Option<Duration>,
+}
 
 /// Control plane server that accepts tunnel client connections via mTLS
 pub struct ControlPlane {
     router: Arc<Router>,
-    tls_acceptor: TlsAcceptor,
+    tls_acceptor: ReloadableAcceptor,
     dns_provider: Arc<dyn DnsProvider>,
-    base_domain: String,
+    base_domains: BaseDomains,
     response_registry: ResponseRegistry,
     tcp_plane: Arc<TcpPlane>,
     tcp_registry: TcpConnectionRegistry,
+    ws_registry: WsConnectionRegistry,
+    connection_limiter: ConnectionLimiter,
+    /// Counters for client messages that match no open stream
+    stream_metrics: Arc<StreamMetrics>,
+    options: ControlPlaneOptions,
 }
 
 impl ControlPlane {
+    #[allow(dead_code, clippy::too_many_arguments)]
     pub fn new(
         router: Arc<Router>,
-        tls_acceptor: TlsAcceptor,
+        tls_acceptor: ReloadableAcceptor,
+        dns_provider: Arc<dyn DnsProvider>,
+        base_domains: BaseDomains,
+        response_registry: ResponseRegistry,
+        tcp_plane: Arc<TcpPlane>,
+        tcp_registry: TcpConnectionRegistry,
+        ws_registry: WsConnectionRegistry,
+    ) -> Arc<Self> {
+        Self::with_options(
+            router,
+            tls_acceptor,
+            dns_provider,
+            base_domains,
+            response_registry,
+            tcp_plane,
+            tcp_registry,
+            ws_registry,
+            ControlPlaneOptions::default(),
+        )
+    }
+
+    /// Create a control plane with non-default options
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_options(
+        router: Arc<Router>,
+        tls_acceptor: ReloadableAcceptor,
         dns_provider: Arc<dyn DnsProvider>,
-        base_domain: String,
+        base_domains: BaseDomains,
         response_registry: ResponseRegistry,
         tcp_plane: Arc<TcpPlane>,
         tcp_registry: TcpConnectionRegistry,
+        ws_registry: WsConnectionRegistry,
+        options: ControlPlaneOptions,
     ) -> Arc<Self> {
+        let connection_limiter =
+            ConnectionLimiter::new(options.max_connections, options.max_connections_per_ip);
         Arc::new(Self {
             router,
             tls_acceptor,
             dns_provider,
-            base_domain,
+            base_domains,
             response_registry,
             tcp_plane,
             tcp_registry,
+            ws_registry,
+            connection_limiter,
+            stream_metrics: Arc::new(StreamMetrics::new()),
+            options,
         })
     }
 
+    /// DNS provider managing the records of `base_domain`
+    fn dns_for(&self, base_domain: &str) -> &Arc<dyn DnsProvider> {
+        self.options
+            .zone_providers
+            .get(base_domain)
+            .unwrap_or(&self.dns_provider)
+    }
+
+    /// Counters for client messages that match no open stream
+    pub fn stream_metrics(&self) -> Arc<StreamMetrics> {
+        self.stream_metrics.clone()
+    }
+
     /// Start listening for tunnel client connections
     pub async fn run(self: Arc<Self>, addr: SocketAddr) -> Result<()> {
         let listener = TcpListener::bind(addr).await?;
@@ -63,9 +166,21 @@ impl ControlPlane {
     pub async fn run_with_listener(self: Arc<Self>, listener: TcpListener) -> Result<()> {
         loop {
             let (stream, peer_addr) = listener.accept().await?;
-            let this = self.clone();
+            self.options.socket_options.apply_or_warn(&stream);
+
+            // Shed excess connections right away instead of queueing them
+            let permit = match self.connection_limiter.try_acquire(peer_addr.ip()) {
+                Ok(permit) => permit,
+                Err(e) => {
+                    tracing::warn!("Rejecting connection from {}: {}", peer_addr, e);
+                    drop(stream);
+                    continue;
+                }
+            };
 
+            let this = self.clone();
             tokio::spawn(async move {
+                let _permit = permit;
                 if let Err(e) = this.handle_connection(stream, peer_addr).await {
                     tracing::error!("Connection error from {}: {}", peer_addr, e);
                 }
@@ -81,12 +196,28 @@ impl ControlPlane {
         tracing::info!("New connection from {}", peer_addr);
 
         // Perform TLS handshake with client cert verification
-        let tls_stream = self.tls_acceptor.accept(stream).await?;
+        let tls_stream = match self.tls_acceptor.current().accept(stream).await {
+            Ok(tls_stream) => tls_stream,
+            Err(e) => {
+                if let Some(hint) = handshake_error_hint(&e) {
+                    tracing::warn!("TLS handshake with {} failed: {}", peer_addr, hint);
+                }
+                return Err(e.into());
+            }
+        };
         tracing::info!("TLS handshake complete with {}", peer_addr);
+        if self.options.require_alpn && tls_stream.get_ref().1.alpn_protocol().is_none() {
+            tracing::warn!(
+                "Refusing {}: it offered no ALPN protocol; check `control_alpn`",
+                peer_addr
+            );
+            return Ok(());
+        }
 
         // Extract client identity from certificate
-        let client_id = extract_client_id(&tls_stream);
-        tracing::info!("Client identified as: {}", client_id);
+        let identity = client_identity(&tls_stream);
+        let client_id = identity.client_id().to_string();
+        tracing::info!("Client identified as: {} ({})", client_id, identity);
 
         // Split the stream for reading and writing
         let (read_half, write_half) = tokio::io::split(tls_stream);
@@ -96,19 +227,23 @@ impl ControlPlane {
 
         // Read loop: process incoming messages from client
         let router = self.router.clone();
-        let dns_provider = self.dns_provider.clone();
-        let base_domain = self.base_domain.clone();
         let client_id_clone = client_id.clone();
         let response_registry = self.response_registry.clone();
+        let stream_metrics = self.stream_metrics.clone();
         let tcp_plane = self.tcp_plane.clone();
         let _tcp_registry = self.tcp_registry.clone();
+        let ws_registry = self.ws_registry.clone();
 
         let mut codec = TunnelCodec::<ClientMessage>::new();
-        let mut read_buf = BytesMut::with_capacity(8192);
+        let read_buffer = self.options.read_buffer;
+        let mut read_buf = read_buffer.new_buffer();
 
         // State for this connection
-        let mut assigned_subdomain: Option<String> = None;
-        let mut assigned_tcp_port: Option<u16> = None;
+        // Public URL of each tunnel registered over this connection
+        let mut assigned_urls: HashMap<String, String> = HashMap::new();
+        // When the connection's tunnels reach their maximum lifetime
+        let mut expires_at: Option<Instant> = None;
+        let mut expired = false;
 
         // Spawn write task
         let write_handle = tokio::spawn(async move {
@@ -131,9 +266,17 @@ impl ControlPlane {
 
         // Read loop
         let mut read_half = read_half;
-        loop {
-            // Read more data
-            match read_half.read_buf(&mut read_buf).await {
+        'session: loop {
+            // Read more data, until the tunnels expire
+            let read = tokio::select! {
+                read = read_half.read_buf(&mut read_buf) => read,
+                _ = sleep_until(expires_at) => {
+                    tracing::info!("Tunnels of {} reached their maximum lifetime", client_id);
+                    expired = true;
+                    break;
+                }
+            };
+            match read {
                 Ok(0) => {
                     tracing::info!("Client {} disconnected", peer_addr);
                     break;
@@ -150,10 +293,35 @@ impl ControlPlane {
                 match codec.decode(&mut read_buf) {
                     Ok(Some(msg)) => {
                         match msg {
+                            ClientMessage::Hello { protocol_version } => {
+                                tracing::debug!(
+                                    "Hello from {} (protocol v{})",
+                                    client_id_clone,
+                                    protocol_version
+                                );
+                                let _ = tx
+                                    .send(ServerMessage::ServerInfo {
+                                        base_domain: self.base_domains.primary().to_string(),
+                                        other_base_domains: self
+                                            .base_domains
+                                            .iter()
+                                            .skip(1)
+                                            .map(str::to_string)
+                                            .collect(),
+                                        protocol_version: PROTOCOL_VERSION,
+                                        supported_types: TunnelType::ALL.to_vec(),
+                                        max_tunnels: None,
+                                    })
+                                    .await;
+                            }
                             ClientMessage::RequestTunnel {
                                 subdomain,
                                 tunnel_type,
                                 local_port,
+                                http_policy,
+                                websocket,
+                                base_domain,
+                                flow_control,
                             } => {
                                 tracing::info!(
                                     "Tunnel request from {}: subdomain={:?}, type={:?}, local_port={}",
@@ -163,40 +331,106 @@ impl ControlPlane {
                                     local_port
                                 );
 
-                                // Generate or validate subdomain
-                                let subdomain = subdomain.unwrap_or_else(|| {
-                                    // Generate random subdomain using cuid2 (always starts with a letter)
-                                    CuidConstructor::new().with_length(8).create_id()
-                                });
+                                // Serve under the requested base domain, or the default one
+                                let base_domain = match base_domain {
+                                    Some(requested) => match self.base_domains.get(&requested) {
+                                        Some(base_domain) => base_domain.to_string(),
+                                        None => {
+                                            let _ = tx
+                                                .send(ServerMessage::TunnelDenied {
+                                                    reason: format!(
+                                                        "Unknown base domain: {}",
+                                                        requested
+                                                    ),
+                                                })
+                                                .await;
+                                            continue;
+                                        }
+                                    },
+                                    None => self.base_domains.primary().to_string(),
+                                };
 
-                                // Validate subdomain format
-                                if !is_valid_subdomain(&subdomain) {
-                                    let _ = tx
-                                        .send(ServerMessage::TunnelDenied {
-                                            reason: "Invalid subdomain format".to_string(),
-                                        })
-                                        .await;
-                                    continue;
+                                // HTTP policies only apply to HTTP tunnels
+                                let http_policy = match http_policy {
+                                    Some(_) if tunnel_type == TunnelType::Tcp => {
+                                        tracing::warn!(
+                                            "Ignoring HTTP policy for TCP tunnel from {}",
+                                            client_id_clone
+                                        );
+                                        None
+                                    }
+                                    policy => policy,
+                                };
+                                let websocket = websocket && tunnel_type == TunnelType::Http;
+                                let flow_control = flow_control && tunnel_type == TunnelType::Tcp;
+                                if let Some(policy) = &http_policy {
+                                    tracing::info!(
+                                        "HTTP policy: methods={:?}, paths={:?}",
+                                        policy.allowed_methods,
+                                        policy.allowed_paths
+                                    );
                                 }
 
+                                // Generate or normalize the requested subdomain
+                                let subdomain = match subdomain {
+                                    Some(requested) if requested.trim() == CATCH_ALL => {
+                                        CATCH_ALL.to_string()
+                                    }
+                                    Some(requested) => match normalize_subdomain(&requested) {
+                                        Some(subdomain) => subdomain,
+                                        None => {
+                                            let _ = tx
+                                                .send(ServerMessage::TunnelDenied {
+                                                    reason: "Invalid subdomain format".to_string(),
+                                                })
+                                                .await;
+                                            continue;
+                                        }
+                                    },
+                                    None => match self
+                                        .options
+                                        .subdomain_generator
+                                        .generate_available(&router)
+                                    {
+                                        Some(subdomain) => subdomain,
+                                        None => {
+                                            let _ = tx
+                                                .send(ServerMessage::TunnelDenied {
+                                                    reason: "Could not generate a free subdomain"
+                                                        .to_string(),
+                                                })
+                                                .await;
+                                            continue;
+                                        }
+                                    },
+                                };
+
                                 // Check availability
                                 if !router.is_available(&subdomain) {
+                                    let reason = if subdomain == CATCH_ALL {
+                                        "A catch-all tunnel is already registered"
+                                    } else {
+                                        "Subdomain already in use"
+                                    };
                                     let _ = tx
                                         .send(ServerMessage::TunnelDenied {
-                                            reason: "Subdomain already in use".to_string(),
+                                            reason: reason.to_string(),
                                         })
                                         .await;
                                     continue;
                                 }
 
-                                // For TCP tunnels, allocate a port first
-                                let tcp_port = if tunnel_type == TunnelType::Tcp {
+                                // For TCP tunnels, allocate a port first (unless they share the mux port).
+                                // Dropping the listener on any failure below releases the port.
+                                let tcp_listener = if tunnel_type == TunnelType::Tcp
+                                    && self.options.tcp_mux_port.is_none()
+                                {
                                     match tcp_plane
                                         .clone()
                                         .allocate_and_listen(subdomain.clone())
                                         .await
                                     {
-                                        Ok(port) => Some(port),
+                                        Ok(listener) => Some(listener),
                                         Err(e) => {
                                             tracing::error!("Failed to allocate TCP port: {}", e);
                                             let _ = tx
@@ -213,28 +447,42 @@ impl ControlPlane {
                                 } else {
                                     None
                                 };
+                                let tcp_port = tcp_listener.as_ref().map(|l| l.port());
 
-                                // Create DNS record
+                                // Create DNS record; the catch-all is reached through a
+                                // wildcard record the operator manages, never touched here
                                 let proxied = tunnel_type == TunnelType::Http;
-                                match dns_provider.create_record(&subdomain, proxied).await {
-                                    Ok(record_id) => {
+                                let dns_record = if subdomain == CATCH_ALL {
+                                    Ok(None)
+                                } else {
+                                    self.dns_for(&base_domain)
+                                        .create_record(&subdomain, proxied)
+                                        .await
+                                        .map(Some)
+                                };
+                                match dns_record {
+                                    Ok(dns_record_id) => {
                                         // Create tunnel handle
                                         let handle = TunnelHandle {
                                             sender: tx.clone(),
                                             client_id: client_id_clone.clone(),
                                             tunnel_type: tunnel_type.clone(),
-                                            dns_record_id: Some(record_id),
+                                            base_domain: base_domain.clone(),
+                                            dns_record_id,
+                                            http_policy,
+                                            websocket,
+                                            flow_control,
+                                            tcp_listener,
+                                            limiters: TunnelLimiters::new(
+                                                self.options.bandwidth.limit_for(&client_id_clone),
+                                            ),
+                                            registered_at: Instant::now(),
                                         };
+                                        let registered_at = handle.registered_at;
 
                                         // Register the tunnel
-                                        if let Err(e) =
-                                            router.register(subdomain.clone(), handle, tcp_port)
-                                        {
+                                        if let Err(e) = router.register(subdomain.clone(), handle) {
                                             tracing::error!("Failed to register tunnel: {}", e);
-                                            // Release TCP port if allocated
-                                            if let Some(port) = tcp_port {
-                                                tcp_plane.release_port(port);
-                                            }
                                             let _ = tx
                                                 .send(ServerMessage::TunnelDenied {
                                                     reason: format!("Registration failed: {}", e),
@@ -242,16 +490,20 @@ impl ControlPlane {
                                                 .await;
                                             continue;
                                         }
-
-                                        assigned_subdomain = Some(subdomain.clone());
-                                        assigned_tcp_port = tcp_port;
+                                        // The connection's lifetime runs from its first tunnel
+                                        if let Some(lifetime) = self.options.max_tunnel_lifetime {
+                                            expires_at.get_or_insert(registered_at + lifetime);
+                                        }
 
                                         let (full_url, response_port) = if tunnel_type
                                             == TunnelType::Http
                                         {
                                             (format!("https://{}.{}", subdomain, base_domain), None)
                                         } else {
-                                            (format!("{}.{}", subdomain, base_domain), tcp_port)
+                                            (
+                                                format!("{}.{}", subdomain, base_domain),
+                                                tcp_port.or(self.options.tcp_mux_port),
+                                            )
                                         };
 
                                         tracing::info!(
@@ -261,20 +513,50 @@ impl ControlPlane {
                                             response_port
                                         );
 
-                                        let _ = tx
-                                            .send(ServerMessage::TunnelEstablished {
-                                                subdomain: subdomain.clone(),
-                                                url: full_url,
-                                                port: response_port,
-                                            })
-                                            .await;
+                                        let public_url = match response_port {
+                                            Some(port) => format!("{}:{}", full_url, port),
+                                            None => full_url.clone(),
+                                        };
+                                        if let Some(webhook) = &self.options.lifecycle_webhook {
+                                            webhook.notify(LifecycleEvent::now(
+                                                LifecycleEventKind::TunnelEstablished,
+                                                subdomain.clone(),
+                                                client_id_clone.clone(),
+                                                public_url.clone(),
+                                            ));
+                                        }
+                                        assigned_urls.insert(subdomain.clone(), public_url);
+
+                                        let established = ServerMessage::TunnelEstablished {
+                                            subdomain: subdomain.clone(),
+                                            url: full_url,
+                                            port: response_port,
+                                            flow_control,
+                                        };
+                                        match &self.options.dns_propagation {
+                                            Some(check) => {
+                                                // Waits in the background so this connection's
+                                                // other tunnels keep being served meanwhile
+                                                let check = check.clone();
+                                                let name = format!("{}.{}", subdomain, base_domain);
+                                                let tx = tx.clone();
+                                                tokio::spawn(async move {
+                                                    if !check.wait_until_resolvable(&name).await {
+                                                        tracing::warn!(
+                                                            "{} does not resolve yet; announcing the tunnel anyway",
+                                                            name
+                                                        );
+                                                    }
+                                                    let _ = tx.send(established).await;
+                                                });
+                                            }
+                                            None => {
+                                                let _ = tx.send(established).await;
+                                            }
+                                        }
                                     }
                                     Err(e) => {
                                         tracing::error!("Failed to create DNS record: {}", e);
-                                        // Release TCP port if allocated
-                                        if let Some(port) = tcp_port {
-                                            tcp_plane.release_port(port);
-                                        }
                                         let _ = tx
                                             .send(ServerMessage::TunnelDenied {
                                                 reason: format!("DNS error: {}", e),
@@ -296,24 +578,38 @@ impl ControlPlane {
                                     status
                                 );
 
-                                // Look up the pending response in the shared registry
-                                if let Some((_, sender)) = response_registry.remove(&stream_id) {
-                                    let response = HttpResponseData {
-                                        status,
-                                        headers,
-                                        body,
-                                    };
-                                    if sender.send(response).is_err() {
+                                // Only the connection the request went to may answer it
+                                match take_pending_response(&response_registry, stream_id, &tx) {
+                                    Ok(sender) => {
+                                        let response = HttpResponseData {
+                                            status,
+                                            headers,
+                                            body,
+                                            client_id: client_id_clone.clone(),
+                                        };
+                                        if sender.send(response).is_err() {
+                                            tracing::warn!(
+                                                "Failed to send response for stream {} (receiver dropped)",
+                                                stream_id
+                                            );
+                                        }
+                                    }
+                                    Err(OrphanResponse::Unknown) => {
+                                        stream_metrics.orphan_response();
                                         tracing::warn!(
-                                            "Failed to send response for stream {} (receiver dropped)",
-                                            stream_id
+                                            "Ignoring response for stream {} from {}: no pending request (duplicate or timed out)",
+                                            stream_id,
+                                            client_id
+                                        );
+                                    }
+                                    Err(OrphanResponse::Foreign) => {
+                                        stream_metrics.orphan_response();
+                                        tracing::warn!(
+                                            "Ignoring response for stream {} from {}: the request was sent to another client",
+                                            stream_id,
+                                            client_id
                                         );
                                     }
-                                } else {
-                                    tracing::warn!(
-                                        "No pending request for stream {} (may have timed out)",
-                                        stream_id
-                                    );
                                 }
                             }
                             ClientMessage::TcpData { stream_id, data } => {
@@ -324,12 +620,23 @@ impl ControlPlane {
                                 );
                                 // Forward to TCP plane
                                 if let Some(writer) = tcp_plane.get_writer(stream_id) {
-                                    if let Err(e) = writer.send(data).await {
-                                        tracing::error!(
-                                            "Failed to forward TCP data to stream {}: {}",
-                                            stream_id,
-                                            e
-                                        );
+                                    match writer.push(data).await {
+                                        Ok(()) => {}
+                                        Err(StreamQueueError::Overrun) => {
+                                            tracing::warn!(
+                                                "Closing TCP stream {}: {} sent more than its window",
+                                                stream_id,
+                                                client_id
+                                            );
+                                            tcp_plane.close_connection(stream_id);
+                                        }
+                                        Err(e) => {
+                                            tracing::error!(
+                                                "Failed to forward TCP data to stream {}: {}",
+                                                stream_id,
+                                                e
+                                            );
+                                        }
                                     }
                                 } else {
                                     tracing::warn!(
@@ -343,9 +650,44 @@ impl ControlPlane {
                                 // Close the TCP connection
                                 tcp_plane.close_connection(stream_id);
                             }
+                            ClientMessage::TcpWindowUpdate { stream_id, bytes } => {
+                                tcp_plane.grant_window(stream_id, bytes);
+                            }
+                            ClientMessage::WsData { stream_id, data } => {
+                                // Forward to the HTTP plane task holding the upgraded connection
+                                let writer = ws_registry.get(&stream_id).map(|w| w.clone());
+                                if let Some(writer) = writer {
+                                    if let Err(e) = writer.send(data).await {
+                                        tracing::error!(
+                                            "Failed to forward WebSocket data to stream {}: {}",
+                                            stream_id,
+                                            e
+                                        );
+                                    }
+                                } else {
+                                    tracing::warn!(
+                                        "No WebSocket connection for stream {} (may have been closed)",
+                                        stream_id
+                                    );
+                                }
+                            }
+                            ClientMessage::WsClose { stream_id } => {
+                                tracing::debug!(
+                                    "WebSocket connection {} closed by client",
+                                    stream_id
+                                );
+                                // Dropping the writer ends the visitor connection
+                                ws_registry.remove(&stream_id);
+                            }
                             ClientMessage::Ping { timestamp } => {
                                 let _ = tx.send(ServerMessage::Pong { timestamp }).await;
                             }
+                            ClientMessage::Goodbye { reason } => {
+                                // Release the tunnels now rather than when the socket closes,
+                                // so the client can reconnect for the same subdomain
+                                tracing::info!("Client {} said goodbye: {}", peer_addr, reason);
+                                break 'session;
+                            }
                         }
                     }
                     Ok(None) => break, // Need more data
@@ -355,26 +697,51 @@ impl ControlPlane {
                     }
                 }
             }
+
+            // Give back memory grown for a burst of large frames
+            read_buffer.reclaim(&mut read_buf);
         }
 
         // Cleanup
         tracing::info!("Cleaning up connection for {}", client_id);
 
-        // Unregister tunnel
-        if let Some(subdomain) = &assigned_subdomain {
-            if let Some(handle) = router.unregister(subdomain) {
-                // Delete DNS record
-                if let Some(record_id) = handle.dns_record_id {
-                    if let Err(e) = dns_provider.delete_record(&record_id).await {
-                        tracing::error!("Failed to delete DNS record: {}", e);
-                    }
+        // Unregister this connection's tunnels (dropping a handle stops its TCP
+        // listener and releases the port)
+        for (subdomain, handle) in router.unregister_connection(&tx) {
+            if let Some(webhook) = &self.options.lifecycle_webhook {
+                webhook.notify(LifecycleEvent::now(
+                    LifecycleEventKind::TunnelClosed,
+                    subdomain.clone(),
+                    client_id.clone(),
+                    assigned_urls.remove(&subdomain).unwrap_or_default(),
+                ));
+            }
+            // Delete DNS record
+            if let Some(record_id) = &handle.dns_record_id {
+                let dns_provider = self.dns_for(&handle.base_domain);
+                if let Err(e) = dns_provider.delete_record(record_id).await {
+                    tracing::error!("Failed to delete DNS record: {}", e);
                 }
             }
         }
 
-        // Release TCP port if allocated
-        if let Some(port) = assigned_tcp_port {
-            tcp_plane.release_port(port);
+        // Only now that the subdomains are free, so the client gets them back
+        if expired {
+            let lifetime_secs = self
+                .options
+                .max_tunnel_lifetime
+                .unwrap_or_default()
+                .as_secs();
+            let _ = tx
+                .send(ServerMessage::TunnelExpired { lifetime_secs })
+                .await;
+            // Give the client a moment to read it and hang up
+            let _ = tokio::time::timeout(EXPIRY_GRACE, async {
+                while matches!(read_half.read_buf(&mut read_buf).await, Ok(n) if n > 0) {
+                    read_buf.clear();
+                }
+            })
+            .await;
         }
 
         write_handle.abort();
@@ -382,46 +749,80 @@ impl ControlPlane {
     }
 }
 
-/// Extract client ID from TLS connection (certificate CN)
-fn extract_client_id<S>(tls_stream: &tokio_rustls::server::TlsStream<S>) -> String {
-    // In a full implementation, we would extract the CN from the client certificate
-    // For now, generate a unique ID
+/// Wait until `deadline`, forever if there is none
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Identity of the client from its TLS certificate
+fn client_identity<S>(tls_stream: &tokio_rustls::server::TlsStream<S>) -> ClientIdentity {
     let (_, server_conn) = tls_stream.get_ref();
 
-    if let Some(certs) = server_conn.peer_certificates() {
-        if let Some(cert) = certs.first() {
-            // Hash the certificate for a stable ID
-            use std::collections::hash_map::DefaultHasher;
-            use std::hash::{Hash, Hasher};
-            let mut hasher = DefaultHasher::new();
-            cert.as_ref().hash(&mut hasher);
-            return format!("client-{:016x}", hasher.finish());
-        }
+    match server_conn
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+    {
+        Some(cert) => ClientIdentity::from_certificate(cert),
+        None => ClientIdentity::anonymous(format!(
+            "unknown-{}",
+            CuidConstructor::new().with_length(8).create_id()
+        )),
     }
-
-    format!(
-        "unknown-{}",
-        CuidConstructor::new().with_length(8).create_id()
-    )
 }
 
-/// Validate subdomain format (alphanumeric and hyphens only)
-fn is_valid_subdomain(subdomain: &str) -> bool {
-    if subdomain.is_empty() || subdomain.len() > 63 {
-        return false;
+/// Explain common mTLS handshake failures in terms an operator can act on
+///
+/// Returns None for errors that aren't about the client certificate, TLS
+/// version or ALPN protocol.
+fn handshake_error_hint(err: &std::io::Error) -> Option<&'static str> {
+    let tls_err = err.get_ref()?.downcast_ref::<rustls::Error>()?;
+
+    match tls_err {
+        rustls::Error::NoCertificatesPresented => {
+            Some("client connected without a certificate; check its `--cert`")
+        }
+        rustls::Error::InvalidCertificate(_) => Some(
+            "client certificate was rejected; check it is signed by the server's CA and not expired",
+        ),
+        rustls::Error::PeerIncompatible(_) => Some(
+            "client does not support a TLS version the server accepts; check `min_client_tls_version`",
+        ),
+        rustls::Error::NoApplicationProtocol => Some(
+            "client offered no ALPN protocol the server accepts; check `control_alpn`",
+        ),
+        _ => None,
     }
+}
 
-    // Must start and end with alphanumeric
-    let chars: Vec<char> = subdomain.chars().collect();
-    if !chars.first().map(|c| c.is_alphanumeric()).unwrap_or(false) {
+/// Validate subdomain format: a lowercase RFC 1123 label
+///
+/// Letters, digits and hyphens only, 1-63 characters, not starting or ending
+/// with a hyphen. Uppercase is rejected rather than folded, so callers
+/// normalize first (see [`normalize_subdomain`]).
+pub(crate) fn is_valid_subdomain(subdomain: &str) -> bool {
+    if subdomain.is_empty() || subdomain.len() > 63 {
         return false;
     }
-    if !chars.last().map(|c| c.is_alphanumeric()).unwrap_or(false) {
+    if subdomain.starts_with('-') || subdomain.ends_with('-') {
         return false;
     }
 
-    // Only alphanumeric and hyphens
-    subdomain.chars().all(|c| c.is_alphanumeric() || c == '-')
+    subdomain
+        .bytes()
+        .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+}
+
+/// Normalize a requested subdomain the way DNS compares names
+///
+/// Hostnames are case-insensitive and visitors' Host headers arrive in any
+/// case, so subdomains are routed in lowercase. Returns `None` if the result
+/// isn't a valid subdomain.
+pub(crate) fn normalize_subdomain(subdomain: &str) -> Option<String> {
+    let subdomain = subdomain.trim().to_ascii_lowercase();
+    is_valid_subdomain(&subdomain).then_some(subdomain)
 }
 
 #[cfg(test)]
@@ -445,5 +846,64 @@ mod tests {
         assert!(!is_valid_subdomain("my_app"));
         assert!(!is_valid_subdomain("my.app"));
         assert!(!is_valid_subdomain(&"a".repeat(64)));
+        assert!(!is_valid_subdomain(".myapp"));
+        assert!(!is_valid_subdomain("myapp."));
+        assert!(!is_valid_subdomain("MyApp"));
+        assert!(!is_valid_subdomain("café"));
+    }
+
+    #[test]
+    fn test_normalize_subdomain() {
+        assert_eq!(normalize_subdomain("MyApp").as_deref(), Some("myapp"));
+        assert_eq!(normalize_subdomain(" my-App ").as_deref(), Some("my-app"));
+        assert_eq!(
+            normalize_subdomain("XN--Caf-DMA").as_deref(),
+            Some("xn--caf-dma")
+        );
+        assert_eq!(normalize_subdomain(&"A".repeat(63)), Some("a".repeat(63)));
+
+        // Nothing left, or still not a label, after normalizing
+        assert_eq!(normalize_subdomain("  "), None);
+        assert_eq!(normalize_subdomain("myapp."), None);
+        assert_eq!(normalize_subdomain("-MyApp"), None);
+        assert_eq!(normalize_subdomain("Café"), None);
+    }
+
+    #[test]
+    fn test_handshake_error_hint() {
+        let no_cert = std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            rustls::Error::NoCertificatesPresented,
+        );
+        assert!(handshake_error_hint(&no_cert)
+            .unwrap()
+            .contains("without a certificate"));
+
+        let expired = std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            rustls::Error::InvalidCertificate(rustls::CertificateError::Expired),
+        );
+        assert!(handshake_error_hint(&expired).unwrap().contains("rejected"));
+
+        let old_tls = std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            rustls::Error::PeerIncompatible(
+                rustls::PeerIncompatible::SupportedVersionsExtensionRequired,
+            ),
+        );
+        assert!(handshake_error_hint(&old_tls)
+            .unwrap()
+            .contains("min_client_tls_version"));
+
+        let wrong_alpn = std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            rustls::Error::NoApplicationProtocol,
+        );
+        assert!(handshake_error_hint(&wrong_alpn)
+            .unwrap()
+            .contains("control_alpn"));
+
+        let other = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        assert!(handshake_error_hint(&other).is_none());
     }
 }
@@ -0,0 +1,213 @@
+//! Waiting for freshly created tunnel records to resolve
+//!
+//! A record isn't visible the moment the provider accepts it, so the first
+//! visitor of a new subdomain can get NXDOMAIN. When enabled, the control plane
+//! looks the name up, retrying with jittered, capped backoff, before it
+//! announces the tunnel to the client.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use hickory_resolver::config::{ResolverConfig, ResolverOpts};
+use hickory_resolver::TokioAsyncResolver;
+use rand::Rng;
+
+/// Resolves names for a [`DnsPropagationCheck`]
+#[async_trait]
+pub trait NameLookup: Send + Sync {
+    /// Whether `name` currently resolves to at least one address
+    async fn resolves(&self, name: &str) -> bool;
+}
+
+/// Lookups through the system's resolvers (Cloudflare's if none are configured)
+pub struct SystemLookup(TokioAsyncResolver);
+
+impl SystemLookup {
+    pub fn new() -> Self {
+        let (config, mut opts) =
+            hickory_resolver::system_conf::read_system_conf().unwrap_or_else(|e| {
+                tracing::debug!("No system resolver config ({}), using Cloudflare DNS", e);
+                (ResolverConfig::cloudflare(), ResolverOpts::default())
+            });
+        // A cached NXDOMAIN would make every retry fail
+        opts.cache_size = 0;
+        Self(TokioAsyncResolver::tokio(config, opts))
+    }
+}
+
+impl Default for SystemLookup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl NameLookup for SystemLookup {
+    async fn resolves(&self, name: &str) -> bool {
+        match self.0.lookup_ip(name).await {
+            Ok(ips) => ips.iter().next().is_some(),
+            Err(e) => {
+                tracing::debug!("Lookup of {} failed: {}", name, e);
+                false
+            }
+        }
+    }
+}
+
+/// How often and how long to retry a lookup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PropagationBackoff {
+    /// Lookups before giving up (at least one is always made)
+    pub attempts: u32,
+    /// Delay after the first failed lookup, doubled after each further one
+    pub initial_delay: Duration,
+    /// Cap on the delay between lookups
+    pub max_delay: Duration,
+}
+
+impl Default for PropagationBackoff {
+    fn default() -> Self {
+        Self {
+            attempts: 6,
+            initial_delay: Duration::from_millis(250),
+            max_delay: Duration::from_secs(4),
+        }
+    }
+}
+
+impl PropagationBackoff {
+    /// Delay after failed lookup `retry` (0-based), scaled by `jitter` in `[0.5, 1.0]`
+    ///
+    /// Jitter keeps clients that reconnect together from querying in lockstep.
+    fn delay(&self, retry: u32, jitter: f64) -> Duration {
+        let exponential = self
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(retry));
+        exponential.min(self.max_delay).mul_f64(jitter)
+    }
+}
+
+/// Waits for a tunnel's DNS name to resolve before it is announced
+#[derive(Clone)]
+pub struct DnsPropagationCheck {
+    lookup: Arc<dyn NameLookup>,
+    backoff: PropagationBackoff,
+}
+
+impl fmt::Debug for DnsPropagationCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DnsPropagationCheck")
+            .field("backoff", &self.backoff)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DnsPropagationCheck {
+    pub fn new(lookup: Arc<dyn NameLookup>) -> Self {
+        Self {
+            lookup,
+            backoff: PropagationBackoff::default(),
+        }
+    }
+
+    /// Check through the system's resolvers
+    pub fn system() -> Self {
+        Self::new(Arc::new(SystemLookup::new()))
+    }
+
+    #[allow(dead_code)]
+    pub fn with_backoff(mut self, backoff: PropagationBackoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Look `name` up until it resolves or the attempts run out
+    ///
+    /// Returns whether it resolved; callers go ahead either way.
+    pub async fn wait_until_resolvable(&self, name: &str) -> bool {
+        let attempts = self.backoff.attempts.max(1);
+        for attempt in 0..attempts {
+            if self.lookup.resolves(name).await {
+                tracing::debug!("{} resolves after {} lookup(s)", name, attempt + 1);
+                return true;
+            }
+            if attempt + 1 < attempts {
+                let jitter = rand::rng().random_range(0.5..=1.0);
+                tokio::time::sleep(self.backoff.delay(attempt, jitter)).await;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Resolves once it has been asked `resolves_after` times
+    struct CountingLookup {
+        calls: AtomicU32,
+        resolves_after: u32,
+    }
+
+    #[async_trait]
+    impl NameLookup for CountingLookup {
+        async fn resolves(&self, _name: &str) -> bool {
+            self.calls.fetch_add(1, Ordering::Relaxed) + 1 >= self.resolves_after
+        }
+    }
+
+    fn check(resolves_after: u32, attempts: u32) -> (DnsPropagationCheck, Arc<CountingLookup>) {
+        let lookup = Arc::new(CountingLookup {
+            calls: AtomicU32::new(0),
+            resolves_after,
+        });
+        let check = DnsPropagationCheck::new(lookup.clone()).with_backoff(PropagationBackoff {
+            attempts,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(2),
+        });
+        (check, lookup)
+    }
+
+    #[test]
+    fn test_delay_doubles_up_to_cap() {
+        let backoff = PropagationBackoff {
+            attempts: 10,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(500),
+        };
+        let delays: Vec<u128> = (0..5).map(|r| backoff.delay(r, 1.0).as_millis()).collect();
+        assert_eq!(delays, vec![100, 200, 400, 500, 500]);
+
+        // Jitter only ever shortens the delay, by at most half
+        assert_eq!(backoff.delay(1, 0.5), Duration::from_millis(100));
+        assert_eq!(backoff.delay(40, 0.5), Duration::from_millis(250));
+    }
+
+    #[tokio::test]
+    async fn test_retries_until_resolvable() {
+        let (check, lookup) = check(3, 5);
+        assert!(
+            check
+                .wait_until_resolvable("myapp.tunnel.example.com")
+                .await
+        );
+        assert_eq!(lookup.calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_attempts() {
+        let (check, lookup) = check(u32::MAX, 4);
+        assert!(
+            !check
+                .wait_until_resolvable("myapp.tunnel.example.com")
+                .await
+        );
+        assert_eq!(lookup.calls.load(Ordering::Relaxed), 4);
+    }
+}
@@ -2,6 +2,10 @@
 //!
 //! This trait allows for different DNS backends (Cloudflare, mock for testing, etc.)
 
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
 use async_trait::async_trait;
 use thiserror::Error;
 
@@ -26,12 +30,13 @@ pub enum DnsError {
     Api(String),
 }
 
-/// Trait for DNS and certificate management providers
+/// Trait for DNS management providers
 ///
 /// This abstraction allows the server to work with different DNS backends,
 /// such as Cloudflare for production or a mock implementation for testing.
+/// Providers that can also issue origin certificates expose them through
+/// [`DnsProvider::origin_certificates`].
 #[async_trait]
-#[allow(dead_code)]
 pub trait DnsProvider: Send + Sync {
     /// Create a DNS record for a subdomain
     ///
@@ -46,17 +51,24 @@ pub trait DnsProvider: Send + Sync {
     /// Delete a DNS record by its ID
     async fn delete_record(&self, record_id: &str) -> Result<(), DnsError>;
 
+    /// Issuer of origin certificates trusted by this provider's proxy, if it has one
+    fn origin_certificates(&self) -> Option<&dyn OriginCertificateIssuer> {
+        None
+    }
+}
+
+/// Issuer of certificates the DNS provider's proxy trusts when connecting to
+/// the origin (e.g. Cloudflare's Origin CA, for Full Strict mode)
+#[async_trait]
+pub trait OriginCertificateIssuer: Send + Sync {
     /// Create an origin certificate for HTTPS
     ///
     /// # Arguments
     /// * `validity_days` - Certificate validity in days
-    ///
-    /// # Returns
-    /// An OriginCertificate if the provider supports it, None otherwise
     async fn create_origin_certificate(
         &self,
         validity_days: u32,
-    ) -> Result<Option<OriginCertificate>, DnsError>;
+    ) -> Result<OriginCertificate, DnsError>;
 
     /// Clean up old origin certificates for this domain
     ///
@@ -64,3 +76,50 @@ pub trait DnsProvider: Send + Sync {
     /// The number of certificates revoked
     async fn cleanup_old_origin_certificates(&self) -> Result<u32, DnsError>;
 }
+
+/// DNS providers of base domains whose records aren't managed by the main
+/// provider (e.g. base domains in another Cloudflare zone)
+#[derive(Clone, Default)]
+pub struct ZoneProviders(HashMap<String, Arc<dyn DnsProvider>>);
+
+impl ZoneProviders {
+    /// Manage the records of `base_domain` with `provider`
+    pub fn insert(&mut self, base_domain: impl Into<String>, provider: Arc<dyn DnsProvider>) {
+        self.0
+            .insert(base_domain.into().to_ascii_lowercase(), provider);
+    }
+
+    /// Provider of `base_domain`, if it has its own
+    pub fn get(&self, base_domain: &str) -> Option<&Arc<dyn DnsProvider>> {
+        self.0.get(base_domain)
+    }
+}
+
+impl fmt::Debug for ZoneProviders {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+/// DNS provider for servers whose DNS is managed externally
+///
+/// Used in `manual` DNS mode, where a wildcard record (or other ingress)
+/// already points at the server. Record operations succeed without doing
+/// anything, so tunnels still register and route by Host header.
+pub struct ManualDnsProvider;
+
+#[async_trait]
+impl DnsProvider for ManualDnsProvider {
+    async fn create_record(&self, subdomain: &str, _proxied: bool) -> Result<String, DnsError> {
+        tracing::debug!(
+            "Manual DNS mode: skipping record creation for {}",
+            subdomain
+        );
+        Ok(format!("manual-{}", subdomain))
+    }
+
+    async fn delete_record(&self, record_id: &str) -> Result<(), DnsError> {
+        tracing::debug!("Manual DNS mode: skipping deletion of {}", record_id);
+        Ok(())
+    }
+}
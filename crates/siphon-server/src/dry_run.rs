@@ -0,0 +1,100 @@
+//! `siphon-server --dry-run`: validate the production config and exit
+//!
+//! Secrets have already been resolved by the time this runs. It then builds
+//! every TLS configuration the server would use and checks the Cloudflare
+//! token with a read-only zone lookup. No port is bound and no DNS record or
+//! Origin CA certificate is created.
+
+use anyhow::Result;
+
+use crate::cloudflare::CloudflareClient;
+use crate::config::{DnsTarget, ResolvedServerConfig};
+
+/// Run the checks, print a summary and fail if any check failed
+pub async fn run(config: &ResolvedServerConfig) -> Result<()> {
+    println!("Base domain:   {}", config.base_domains());
+    println!("Control plane: port {}", config.control_port);
+    println!("HTTP plane:    port {}", config.http_port);
+    match config.tcp_mux_port {
+        Some(port) => println!("TCP tunnels:   shared port {} (SNI)", port),
+        None => println!(
+            "TCP tunnels:   ports {}-{}",
+            config.tcp_port_range.0, config.tcp_port_range.1
+        ),
+    }
+    println!("DNS mode:      {:?}", config.dns_mode);
+    println!();
+
+    let mut failures = 0;
+    let mut report = |name: &str, result: Result<String>| match result {
+        Ok(detail) => println!("  ok    {} ({})", name, detail),
+        Err(e) => {
+            failures += 1;
+            println!("  FAIL  {} ({:#})", name, e);
+        }
+    };
+
+    report(
+        "control plane TLS",
+        siphon_common::load_server_config_from_pem(
+            &config.cert_pem,
+            &config.key_pem,
+            &config.ca_cert_pem,
+        )
+        .map(|_| "certificate, key and client CA loaded".to_string())
+        .map_err(Into::into),
+    );
+
+    if let (Some(cert), Some(key)) = (&config.http_cert_pem, &config.http_key_pem) {
+        report(
+            "HTTP plane TLS",
+            siphon_common::load_server_config_no_client_auth(cert, key)
+                .map(|_| "certificate and key loaded".to_string())
+                .map_err(Into::into),
+        );
+    }
+
+    if let Some(cf) = &config.cloudflare {
+        let client = CloudflareClient::new(cf, &config.base_domain);
+        report(
+            "Cloudflare API token",
+            check_zone(&client, &config.base_domain).await,
+        );
+        for extra in &config.extra_base_domains {
+            if let Some(zone_id) = &extra.zone_id {
+                let client = client.for_base_domain(&extra.domain, zone_id);
+                report(
+                    &format!("Cloudflare zone of {}", extra.domain),
+                    check_zone(&client, &extra.domain).await,
+                );
+            }
+        }
+
+        let target = match &cf.dns_target {
+            DnsTarget::Ip(ip) => format!("A {}", ip),
+            DnsTarget::Cname(host) => format!("CNAME {}", host),
+        };
+        println!("  info  tunnel records would point at {}", target);
+        if cf.auto_origin_ca {
+            println!("  info  an Origin CA certificate would be requested at startup");
+        }
+    }
+
+    println!();
+    if failures > 0 {
+        anyhow::bail!("{} check(s) failed", failures);
+    }
+    println!("Configuration is valid");
+    Ok(())
+}
+
+/// Look up the client's zone and check that `base_domain` belongs to it
+async fn check_zone(client: &CloudflareClient, base_domain: &str) -> Result<String> {
+    let zone = client.verify_zone().await?;
+    let base = base_domain.to_ascii_lowercase();
+    let zone_name = zone.name.to_ascii_lowercase();
+    if base != zone_name && !base.ends_with(&format!(".{}", zone_name)) {
+        anyhow::bail!("base domain {} is not in zone {}", base_domain, zone.name);
+    }
+    Ok(format!("zone {} is {}", zone.name, zone.status))
+}
@@ -0,0 +1,116 @@
+//! Custom HTML pages for errors the HTTP plane answers itself
+//!
+//! Pages are loaded once at startup from a directory of `<status>.html`
+//! files (`404.html`, `502.html`, ...). `{status}` and `{message}` in a page
+//! are replaced with the status code and the plain-text error message.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+
+/// HTML templates keyed by HTTP status code
+#[derive(Debug, Clone, Default)]
+pub struct ErrorPages {
+    pages: HashMap<u16, Arc<str>>,
+}
+
+impl ErrorPages {
+    /// Load every `<status>.html` file in `dir`; other files are ignored
+    pub fn load_dir(dir: &Path) -> anyhow::Result<Self> {
+        let entries = std::fs::read_dir(dir)
+            .with_context(|| format!("Failed to read error page directory {}", dir.display()))?;
+
+        let mut pages = Self::default();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("html") {
+                continue;
+            }
+            let Some(status) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u16>().ok())
+                .filter(|s| (400..600).contains(s))
+            else {
+                tracing::warn!(
+                    "Ignoring error page {}: not named <status>.html",
+                    path.display()
+                );
+                continue;
+            };
+
+            let template = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read error page {}", path.display()))?;
+            pages.insert(status, template);
+        }
+
+        tracing::info!("Loaded {} custom error page(s)", pages.pages.len());
+        Ok(pages)
+    }
+
+    /// Use `template` for responses with `status`
+    pub fn insert(&mut self, status: u16, template: impl Into<Arc<str>>) {
+        self.pages.insert(status, template.into());
+    }
+
+    /// Page for `status` with placeholders filled in, if one is configured
+    pub fn render(&self, status: u16, message: &str) -> Option<String> {
+        let template = self.pages.get(&status)?;
+        Some(
+            template
+                .replace("{status}", &status.to_string())
+                .replace("{message}", &escape_html(message)),
+        )
+    }
+}
+
+/// Escape text for inclusion in HTML (messages can echo the request's Host)
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_substitutes_placeholders() {
+        let mut pages = ErrorPages::default();
+        pages.insert(404, "<h1>{status}</h1><p>{message}</p>");
+
+        assert_eq!(
+            pages.render(404, "Tunnel not found for: <x>").unwrap(),
+            "<h1>404</h1><p>Tunnel not found for: &lt;x&gt;</p>"
+        );
+        assert!(pages.render(502, "Tunnel disconnected").is_none());
+    }
+
+    #[test]
+    fn test_load_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("404.html"), "missing {status}").unwrap();
+        std::fs::write(dir.path().join("502.html"), "down").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "ignored").unwrap();
+        std::fs::write(dir.path().join("oops.html"), "ignored").unwrap();
+
+        let pages = ErrorPages::load_dir(dir.path()).unwrap();
+        assert_eq!(pages.pages.len(), 2);
+        assert_eq!(pages.render(404, "").unwrap(), "missing 404");
+        assert_eq!(pages.render(502, "").unwrap(), "down");
+
+        assert!(ErrorPages::load_dir(&dir.path().join("nope")).is_err());
+    }
+}
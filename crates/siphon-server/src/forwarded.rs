@@ -0,0 +1,184 @@
+//! Scheme detection from `X-Forwarded-Proto` / `Forwarded` headers, and the
+//! `X-Forwarded-For` chain passed on to the client
+//!
+//! These headers are only honored when the connection comes from a trusted
+//! upstream (e.g. Cloudflare's edge), otherwise any client could claim HTTPS
+//! or another address.
+
+use std::fmt;
+use std::net::IpAddr;
+
+use hyper::HeaderMap;
+use siphon_common::IpRange;
+
+/// Upstreams allowed to tell us the original request scheme
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Vec<IpRange>);
+
+impl TrustedProxies {
+    /// Parse a list of IP addresses and CIDR blocks
+    pub fn parse<S: AsRef<str>>(entries: &[S]) -> Result<Self, String> {
+        entries
+            .iter()
+            .map(|entry| {
+                entry
+                    .as_ref()
+                    .parse()
+                    .map_err(|e| format!("Invalid trusted proxy: {}", e))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|range| range.contains(ip))
+    }
+}
+
+impl fmt::Display for TrustedProxies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ranges: Vec<String> = self.0.iter().map(IpRange::to_string).collect();
+        write!(f, "{}", ranges.join(", "))
+    }
+}
+
+/// Read the original scheme from `X-Forwarded-Proto`, falling back to `Forwarded`
+///
+/// Only the first (client-most) value is used. Returns None if neither header
+/// carries `http` or `https`.
+pub fn forwarded_proto(headers: &HeaderMap) -> Option<&'static str> {
+    let from_x_forwarded = headers
+        .get("x-forwarded-proto")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next());
+
+    let from_forwarded = || {
+        let value = headers.get("forwarded")?.to_str().ok()?;
+        let first = value.split(',').next()?;
+        first.split(';').find_map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            key.trim()
+                .eq_ignore_ascii_case("proto")
+                .then(|| value.trim().trim_matches('"'))
+        })
+    };
+
+    let proto = from_x_forwarded.or_else(from_forwarded)?.trim();
+    if proto.eq_ignore_ascii_case("https") {
+        Some("https")
+    } else if proto.eq_ignore_ascii_case("http") {
+        Some("http")
+    } else {
+        None
+    }
+}
+
+/// Append `client` to the `X-Forwarded-For` chain in `headers`
+///
+/// An existing chain is kept only if it comes from a `trusted` upstream;
+/// otherwise it is replaced, so the last entry is always an address we saw.
+pub fn append_forwarded_for(headers: &mut Vec<(String, String)>, client: IpAddr, trusted: bool) {
+    let previous: Vec<String> = headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("x-forwarded-for"))
+        .map(|(_, value)| value.clone())
+        .collect();
+    headers.retain(|(name, _)| !name.eq_ignore_ascii_case("x-forwarded-for"));
+
+    let value = match trusted && !previous.is_empty() {
+        true => format!("{}, {}", previous.join(", "), client),
+        false => client.to_string(),
+    };
+    headers.push(("X-Forwarded-For".to_string(), value));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(*name, value.parse().unwrap());
+        }
+        map
+    }
+
+    #[test]
+    fn test_trusted_proxies_contains() {
+        let proxies = TrustedProxies::parse(&["173.245.48.0/20", "2400:cb00::/32"]).unwrap();
+        assert!(proxies.contains("173.245.48.1".parse().unwrap()));
+        assert!(proxies.contains("::ffff:173.245.48.1".parse().unwrap()));
+        assert!(proxies.contains("2400:cb00:1::1".parse().unwrap()));
+        assert!(!proxies.contains("8.8.8.8".parse().unwrap()));
+        assert_eq!(proxies.to_string(), "173.245.48.0/20, 2400:cb00::/32");
+    }
+
+    #[test]
+    fn test_append_forwarded_for() {
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+        let spoofed = || {
+            vec![
+                ("x-forwarded-for".to_string(), "1.2.3.4".to_string()),
+                ("accept".to_string(), "*/*".to_string()),
+            ]
+        };
+
+        let mut headers = spoofed();
+        append_forwarded_for(&mut headers, client, true);
+        assert_eq!(
+            headers[1],
+            (
+                "X-Forwarded-For".to_string(),
+                "1.2.3.4, 203.0.113.7".to_string()
+            )
+        );
+
+        let mut headers = spoofed();
+        append_forwarded_for(&mut headers, client, false);
+        assert_eq!(
+            headers,
+            vec![
+                ("accept".to_string(), "*/*".to_string()),
+                ("X-Forwarded-For".to_string(), "203.0.113.7".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trusted_proxies_parse_invalid() {
+        assert_eq!(
+            TrustedProxies::parse(&["10.0.0.0/8", "bogus"]).unwrap_err(),
+            "Invalid trusted proxy: Invalid IP address in 'bogus'"
+        );
+        assert!(TrustedProxies::parse(&["10.0.0.0/33"]).is_err());
+    }
+
+    #[test]
+    fn test_forwarded_proto() {
+        assert_eq!(
+            forwarded_proto(&headers(&[("x-forwarded-proto", "https")])),
+            Some("https")
+        );
+        assert_eq!(
+            forwarded_proto(&headers(&[("x-forwarded-proto", "HTTPS, http")])),
+            Some("https")
+        );
+        assert_eq!(
+            forwarded_proto(&headers(&[(
+                "forwarded",
+                "for=192.0.2.60;proto=\"https\";by=203.0.113.43, proto=http"
+            )])),
+            Some("https")
+        );
+        assert_eq!(
+            forwarded_proto(&headers(&[("x-forwarded-proto", "gopher")])),
+            None
+        );
+        assert_eq!(forwarded_proto(&HeaderMap::new()), None);
+    }
+}
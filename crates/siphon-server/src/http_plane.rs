@@ -5,47 +5,127 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Result;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use http_body_util::{BodyExt, Full};
 use hyper::body::Incoming;
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::TcpListener;
-use tokio::sync::oneshot;
-use tokio_rustls::TlsAcceptor;
-
+use reqwest::Url;
+use rustls::server::Acceptor;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::{LazyConfigAcceptor, TlsAcceptor};
+use tracing::Instrument;
+
+#[cfg(feature = "otel")]
+use siphon_common::otel;
+use siphon_common::otel::OTEL_TARGET;
 use siphon_protocol::ServerMessage;
 
-use crate::router::Router;
-use crate::state::ResponseRegistry;
+use crate::bandwidth::TunnelLimiters;
+use crate::base_domains::BaseDomains;
+use crate::cert_watch::ReloadableAcceptor;
+use crate::coalesce::{self, Coalescer};
+use crate::error_pages::ErrorPages;
+use crate::forwarded::{append_forwarded_for, forwarded_proto, TrustedProxies};
+use crate::proxy_protocol::read_proxy_header;
+use crate::response_headers::InjectedHeaders;
+use crate::router::{ReconnectWait, Router};
+use crate::state::{HttpResponseData, PendingResponse, ResponseRegistry, WsConnectionRegistry};
+use crate::static_routes::StaticRoutes;
+use crate::tcp_plane::READ_CHUNK;
+
+/// Tunable behavior of the HTTP plane
+#[derive(Debug, Clone, Default)]
+pub struct HttpPlaneOptions {
+    /// Upstreams whose `X-Forwarded-Proto`/`Forwarded` headers are believed
+    pub trusted_proxies: TrustedProxies,
+    /// HTML pages replacing the plain-text bodies of our own error responses
+    pub error_pages: ErrorPages,
+    /// Expect a PROXY protocol header on every connection and take the
+    /// visitor's address from it (only behind a load balancer sending one)
+    pub proxy_protocol: bool,
+    /// Headers set on every response relayed from a tunnel, replacing the client's
+    pub inject_response_headers: InjectedHeaders,
+    /// How long to wait for a tunnel's response (None = [`DEFAULT_RESPONSE_TIMEOUT`])
+    pub response_timeout: Option<Duration>,
+    /// In TLS mode, drop connections whose SNI isn't the base domain or one of
+    /// its subdomains (no certificate is sent to them)
+    pub strict_sni: bool,
+    /// Subdomains proxied straight to an upstream instead of a tunnel
+    pub static_routes: StaticRoutes,
+    /// Tell visitors which tunnel answered with `X-Siphon-Tunnel:
+    /// <subdomain>@<client_id>` (otherwise the header is stripped)
+    pub expose_tunnel_header: bool,
+    /// Let identical concurrent GETs share one trip through the tunnel,
+    /// reusing the response for this long after it arrived (None = off)
+    pub coalesce_gets: Option<Duration>,
+}
+
+/// Response header naming the tunnel that answered
+const TUNNEL_HEADER: &str = "x-siphon-tunnel";
+
+/// Time a tunnel gets to answer a request before the visitor gets a 504
+pub const DEFAULT_RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
 
 /// HTTP data plane that receives traffic from Cloudflare
 pub struct HttpPlane {
     router: Arc<Router>,
-    base_domain: String,
+    base_domains: BaseDomains,
     stream_id_counter: AtomicU64,
     /// Shared registry for pending responses
     response_registry: ResponseRegistry,
+    /// Shared registry for upgraded WebSocket connections
+    ws_registry: WsConnectionRegistry,
     /// Optional TLS acceptor for HTTPS mode
-    tls_acceptor: Option<TlsAcceptor>,
+    tls_acceptor: Option<ReloadableAcceptor>,
+    /// In-flight GETs others can share (None = coalescing is off)
+    coalescer: Option<Coalescer>,
+    options: HttpPlaneOptions,
 }
 
 impl HttpPlane {
+    #[allow(dead_code)]
     pub fn new(
         router: Arc<Router>,
-        base_domain: String,
+        base_domains: BaseDomains,
         response_registry: ResponseRegistry,
-        tls_acceptor: Option<TlsAcceptor>,
+        ws_registry: WsConnectionRegistry,
+        tls_acceptor: Option<ReloadableAcceptor>,
+    ) -> Arc<Self> {
+        Self::with_options(
+            router,
+            base_domains,
+            response_registry,
+            ws_registry,
+            tls_acceptor,
+            HttpPlaneOptions::default(),
+        )
+    }
+
+    /// Create an HTTP plane with non-default options
+    pub fn with_options(
+        router: Arc<Router>,
+        base_domains: BaseDomains,
+        response_registry: ResponseRegistry,
+        ws_registry: WsConnectionRegistry,
+        tls_acceptor: Option<ReloadableAcceptor>,
+        options: HttpPlaneOptions,
     ) -> Arc<Self> {
         Arc::new(Self {
             router,
-            base_domain,
+            base_domains,
             stream_id_counter: AtomicU64::new(1),
             response_registry,
+            ws_registry,
             tls_acceptor,
+            coalescer: options.coalesce_gets.map(Coalescer::new),
+            options,
         })
     }
 
@@ -62,10 +142,23 @@ impl HttpPlane {
 
         let service = service_fn(move |req| {
             let this = self.clone();
-            async move { this.handle_request(req).await }
+            let span = request_span(&req);
+            async move {
+                let response = this.handle_request(req, peer_addr).await;
+                if let Ok(response) = &response {
+                    tracing::Span::current()
+                        .record("http.response.status_code", response.status().as_u16());
+                }
+                response
+            }
+            .instrument(span)
         });
 
-        if let Err(e) = http1::Builder::new().serve_connection(io, service).await {
+        if let Err(e) = http1::Builder::new()
+            .serve_connection(io, service)
+            .with_upgrades()
+            .await
+        {
             tracing::debug!("HTTP connection error from {}: {}", peer_addr, e);
         }
     }
@@ -89,21 +182,29 @@ impl HttpPlane {
     /// ephemeral port and get the actual address before starting the server.
     pub async fn run_with_listener(self: Arc<Self>, listener: TcpListener) -> Result<()> {
         loop {
-            let (stream, peer_addr) = listener.accept().await?;
+            let (mut stream, peer_addr) = listener.accept().await?;
             tracing::debug!("HTTP connection from {}", peer_addr);
             let this = self.clone();
 
             tokio::spawn(async move {
-                if let Some(ref acceptor) = this.tls_acceptor {
-                    // TLS mode
-                    match acceptor.accept(stream).await {
-                        Ok(tls_stream) => {
-                            this.serve_connection(tls_stream, peer_addr).await;
-                        }
+                // The balancer's header comes before TLS and names the real visitor
+                let peer_addr = if this.options.proxy_protocol {
+                    match read_proxy_header(&mut stream).await {
+                        Ok(source) => source.unwrap_or(peer_addr),
                         Err(e) => {
-                            tracing::warn!("TLS handshake failed from {}: {}", peer_addr, e);
+                            tracing::warn!("Rejected connection from {}: {}", peer_addr, e);
+                            return;
                         }
                     }
+                } else {
+                    peer_addr
+                };
+
+                if let Some(acceptor) = this.tls_acceptor.as_ref().map(|a| a.current()) {
+                    // TLS mode
+                    if let Some(tls_stream) = this.accept_tls(&acceptor, stream, peer_addr).await {
+                        this.serve_connection(tls_stream, peer_addr).await;
+                    }
                 } else {
                     // Plain HTTP mode
                     this.serve_connection(stream, peer_addr).await;
@@ -112,9 +213,53 @@ impl HttpPlane {
         }
     }
 
+    /// TLS handshake, checking the requested server name first with `strict_sni`
+    async fn accept_tls(
+        &self,
+        acceptor: &TlsAcceptor,
+        stream: TcpStream,
+        peer_addr: SocketAddr,
+    ) -> Option<TlsStream<TcpStream>> {
+        let result = if self.options.strict_sni {
+            let start = match LazyConfigAcceptor::new(Acceptor::default(), stream).await {
+                Ok(start) => start,
+                Err(e) => {
+                    tracing::warn!("TLS handshake failed from {}: {}", peer_addr, e);
+                    return None;
+                }
+            };
+            let server_name = start.client_hello().server_name().map(str::to_owned);
+            // A missing SNI (clients connecting by IP) is never allowed
+            let allowed = server_name
+                .as_deref()
+                .is_some_and(|name| self.base_domains.covers(name));
+            if !allowed {
+                // Scanners hitting the origin directly; closing here keeps the certificate private
+                tracing::warn!(
+                    "Rejected TLS connection from {} for server name {:?}",
+                    peer_addr,
+                    server_name
+                );
+                return None;
+            }
+            start.into_stream(acceptor.config().clone()).await
+        } else {
+            acceptor.accept(stream).await
+        };
+
+        match result {
+            Ok(tls_stream) => Some(tls_stream),
+            Err(e) => {
+                tracing::warn!("TLS handshake failed from {}: {}", peer_addr, e);
+                None
+            }
+        }
+    }
+
     async fn handle_request(
         self: Arc<Self>,
         req: Request<Incoming>,
+        peer_addr: SocketAddr,
     ) -> Result<Response<Full<Bytes>>, Infallible> {
         tracing::debug!(
             "HTTP request: {} {} (Host: {:?})",
@@ -123,108 +268,205 @@ impl HttpPlane {
             req.headers().get("host")
         );
 
-        // Extract subdomain from Host header
-        let subdomain = match self.extract_subdomain(&req) {
+        // Extract subdomain and base domain from Host header
+        let (subdomain, base_domain) = match self.extract_subdomain(&req) {
             Some(s) => s,
             None => {
                 tracing::warn!("Request without valid subdomain");
-                return Ok(Response::builder()
-                    .status(StatusCode::BAD_REQUEST)
-                    .body(Full::new(Bytes::from("Invalid or missing subdomain")))
-                    .unwrap());
+                return Ok(
+                    self.error_response(StatusCode::BAD_REQUEST, "Invalid or missing subdomain")
+                );
             }
         };
 
+        tracing::Span::current().record("subdomain", subdomain.as_str());
+
+        // Static routes are served here, no tunnel involved
+        if let Some(upstream) = self.options.static_routes.upstream(&subdomain) {
+            return Ok(self.proxy_static(req, peer_addr, upstream).await);
+        }
+
         tracing::debug!("Forwarding to tunnel: {}", subdomain);
 
         // Find the tunnel for this subdomain
+        // A tunnel that just dropped may be reconnecting: hold the request for it
         let sender = match self.router.get_sender(&subdomain) {
             Some(s) => s,
-            None => {
-                tracing::warn!("No tunnel for subdomain: {}", subdomain);
-                return Ok(Response::builder()
-                    .status(StatusCode::NOT_FOUND)
-                    .body(Full::new(Bytes::from(format!(
-                        "Tunnel not found for: {}",
-                        subdomain
-                    ))))
-                    .unwrap());
-            }
+            None => match self.router.wait_for_reconnect(&subdomain).await {
+                ReconnectWait::Reconnected(s) => {
+                    tracing::debug!("Tunnel for {} is back, resuming held request", subdomain);
+                    s
+                }
+                ReconnectWait::TimedOut => {
+                    tracing::warn!("Tunnel for {} did not reconnect in time", subdomain);
+                    return Ok(self.error_response(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        &format!("Tunnel reconnecting: {}", subdomain),
+                    ));
+                }
+                ReconnectWait::NotRecent => {
+                    tracing::warn!("No tunnel for subdomain: {}", subdomain);
+                    return Ok(self.error_response(
+                        StatusCode::NOT_FOUND,
+                        &format!("Tunnel not found for: {}", subdomain),
+                    ));
+                }
+            },
         };
 
+        // Subdomains are shared by the base domains; a tunnel only answers under its own
+        if self.router.get_base_domain(&subdomain).as_deref() != Some(base_domain.as_str()) {
+            tracing::warn!("No tunnel for {} under {}", subdomain, base_domain);
+            return Ok(self.error_response(
+                StatusCode::NOT_FOUND,
+                &format!("Tunnel not found for: {}", subdomain),
+            ));
+        }
+
+        // Enforce the tunnel's method/path policy before forwarding
+        if let Some(policy) = self.router.get_http_policy(&subdomain) {
+            let path = req.uri().path_and_query().map_or("/", |pq| pq.as_str());
+            if !policy.allows(req.method().as_str(), path) {
+                tracing::debug!(
+                    "Blocked {} {} for {} by HTTP policy",
+                    req.method(),
+                    path,
+                    subdomain
+                );
+                return Ok(self.error_response(StatusCode::FORBIDDEN, "Forbidden by tunnel policy"));
+            }
+        }
+
         // Generate stream ID
         let stream_id = self.next_stream_id();
 
+        let scheme = self.effective_scheme(&req, peer_addr);
+
+        // Clients that don't relay upgrades get them as plain requests below
+        if is_websocket_upgrade(&req) && self.router.supports_websocket(&subdomain) {
+            return Ok(self
+                .handle_upgrade(req, peer_addr, subdomain, sender, stream_id, scheme)
+                .await);
+        }
+
+        // Identical GETs in flight at the same time can share a response
+        let coalesce_key = self
+            .coalescer
+            .as_ref()
+            .and_then(|_| coalesce::request_key(&req, &subdomain, scheme));
+
         // Convert request to protocol message
         let method = req.method().to_string();
         let uri = req.uri().to_string();
 
-        let headers: Vec<(String, String)> = req
-            .headers()
-            .iter()
-            .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("").to_string()))
-            .collect();
+        let headers = self.forward_headers(&req, peer_addr);
 
         // Collect body
         let body = match req.into_body().collect().await {
             Ok(collected) => collected.to_bytes().to_vec(),
             Err(e) => {
                 tracing::error!("Failed to read request body: {}", e);
-                return Ok(Response::builder()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(Full::new(Bytes::from("Failed to read request body")))
-                    .unwrap());
+                return Ok(self.error_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to read request body",
+                ));
             }
         };
 
-        // Create response channel
-        let (response_tx, response_rx) = oneshot::channel();
-
-        // Register pending response in shared registry
-        self.response_registry.insert(stream_id, response_tx);
-
-        // Send request to tunnel
         let msg = ServerMessage::HttpRequest {
             stream_id,
             method,
             uri,
             headers,
             body,
+            scheme: Some(scheme.to_string()),
         };
+        let limiters = self.router.get_limiters(&subdomain);
+        let forward = || {
+            let span = tracing::info_span!(target: OTEL_TARGET, "tunnel_send", stream_id);
+            // The client's span continues this one
+            #[cfg(feature = "otel")]
+            let msg = {
+                let mut msg = msg;
+                if let ServerMessage::HttpRequest { headers, .. } = &mut msg {
+                    otel::inject_context(&span, headers);
+                }
+                msg
+            };
+            self.forward_to_tunnel(&sender, stream_id, msg, &limiters)
+                .instrument(span)
+        };
+        let response_data = match (&self.coalescer, coalesce_key) {
+            (Some(coalescer), Some(key)) => coalescer.run(key, forward).await,
+            _ => forward().await.map(Arc::new),
+        };
+        let response_data = match response_data {
+            Ok(response_data) => Arc::unwrap_or_clone(response_data),
+            Err(response) => return Ok(response),
+        };
+
+        // Build HTTP response
+        let mut builder = Response::builder().status(response_data.status);
 
+        for (name, value) in response_data.headers {
+            builder = builder.header(name, value);
+        }
+
+        let mut response = builder
+            .body(Full::new(Bytes::from(response_data.body)))
+            .unwrap();
+        self.options
+            .inject_response_headers
+            .apply(response.headers_mut());
+        self.tunnel_header(response.headers_mut(), &subdomain, &response_data.client_id);
+        Ok(response)
+    }
+
+    /// Send a request through its tunnel and wait for the response, or the
+    /// error response to give the visitor
+    async fn forward_to_tunnel(
+        &self,
+        sender: &mpsc::Sender<ServerMessage>,
+        stream_id: u64,
+        msg: ServerMessage,
+        limiters: &TunnelLimiters,
+    ) -> Result<HttpResponseData, Response<Full<Bytes>>> {
+        // Bodies are buffered, so bandwidth caps delay them as a whole
+        if let ServerMessage::HttpRequest { body, .. } = &msg {
+            limiters.ingress(body.len()).await;
+        }
+
+        // Create response channel
+        let (response_tx, response_rx) = oneshot::channel();
+
+        // Register pending response in shared registry
+        self.response_registry.insert(
+            stream_id,
+            PendingResponse {
+                sender: response_tx,
+                tunnel: sender.clone(),
+            },
+        );
+
+        // Send request to tunnel
         if let Err(e) = sender.send(msg).await {
             tracing::error!("Failed to send request to tunnel: {}", e);
             // Clean up pending response
             self.response_registry.remove(&stream_id);
 
-            return Ok(Response::builder()
-                .status(StatusCode::BAD_GATEWAY)
-                .body(Full::new(Bytes::from("Tunnel connection lost")))
-                .unwrap());
+            return Err(self.error_response(StatusCode::BAD_GATEWAY, "Tunnel connection lost"));
         }
 
         // Wait for response with timeout
-        let timeout = Duration::from_secs(30);
-        match tokio::time::timeout(timeout, response_rx).await {
+        match tokio::time::timeout(self.response_timeout(), response_rx).await {
             Ok(Ok(response_data)) => {
-                // Build HTTP response
-                let mut builder = Response::builder().status(response_data.status);
-
-                for (name, value) in response_data.headers {
-                    builder = builder.header(name, value);
-                }
-
-                Ok(builder
-                    .body(Full::new(Bytes::from(response_data.body)))
-                    .unwrap())
+                limiters.egress(response_data.body.len()).await;
+                Ok(response_data)
             }
             Ok(Err(_)) => {
                 // Channel closed (tunnel disconnected)
                 tracing::error!("Tunnel disconnected while waiting for response");
-                Ok(Response::builder()
-                    .status(StatusCode::BAD_GATEWAY)
-                    .body(Full::new(Bytes::from("Tunnel disconnected")))
-                    .unwrap())
+                Err(self.error_response(StatusCode::BAD_GATEWAY, "Tunnel disconnected"))
             }
             Err(_) => {
                 // Timeout
@@ -232,30 +474,320 @@ impl HttpPlane {
                 // Clean up pending response
                 self.response_registry.remove(&stream_id);
 
-                Ok(Response::builder()
-                    .status(StatusCode::GATEWAY_TIMEOUT)
-                    .body(Full::new(Bytes::from("Tunnel response timeout")))
-                    .unwrap())
+                Err(self.error_response(StatusCode::GATEWAY_TIMEOUT, "Tunnel response timeout"))
             }
         }
     }
 
-    /// Extract subdomain from Host header
-    fn extract_subdomain(&self, req: &Request<Incoming>) -> Option<String> {
+    /// Proxy a request for a statically routed subdomain to its upstream
+    async fn proxy_static(
+        &self,
+        req: Request<Incoming>,
+        peer_addr: SocketAddr,
+        upstream: &Url,
+    ) -> Response<Full<Bytes>> {
+        let scheme = self.effective_scheme(&req, peer_addr);
+        let mut headers = self.forward_headers(&req, peer_addr);
+        // What the tunnel client adds for tunnels, the plane adds here
+        headers.retain(|(name, _)| name != "x-forwarded-proto" && name != "x-forwarded-host");
+        headers.push(("x-forwarded-proto".to_string(), scheme.to_string()));
+        if let Some(host) = req.headers().get("host").and_then(|h| h.to_str().ok()) {
+            headers.push(("x-forwarded-host".to_string(), host.to_string()));
+        }
+        let method = req.method().clone();
+        let path = req
+            .uri()
+            .path_and_query()
+            .map_or("/", |pq| pq.as_str())
+            .to_string();
+
+        let body = match req.into_body().collect().await {
+            Ok(collected) => collected.to_bytes(),
+            Err(e) => {
+                tracing::error!("Failed to read request body: {}", e);
+                return self.error_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to read request body",
+                );
+            }
+        };
+
+        let forwarded = self
+            .options
+            .static_routes
+            .forward(upstream, method, &path, &headers, body);
+        match tokio::time::timeout(self.response_timeout(), forwarded).await {
+            Ok(Ok(upstream_response)) => {
+                let mut response = Response::new(Full::new(upstream_response.body));
+                *response.status_mut() = upstream_response.status;
+                *response.headers_mut() = upstream_response.headers;
+                self.options
+                    .inject_response_headers
+                    .apply(response.headers_mut());
+                response
+            }
+            Ok(Err(e)) => {
+                tracing::warn!("Static route upstream {} failed: {}", upstream, e);
+                self.error_response(StatusCode::BAD_GATEWAY, "Upstream unreachable")
+            }
+            Err(_) => {
+                tracing::warn!("Timeout waiting for static route upstream {}", upstream);
+                self.error_response(StatusCode::GATEWAY_TIMEOUT, "Upstream response timeout")
+            }
+        }
+    }
+
+    /// Relay a WebSocket upgrade to the tunnel and, once accepted, pipe the connection
+    async fn handle_upgrade(
+        &self,
+        mut req: Request<Incoming>,
+        peer_addr: SocketAddr,
+        subdomain: String,
+        sender: mpsc::Sender<ServerMessage>,
+        stream_id: u64,
+        scheme: &str,
+    ) -> Response<Full<Bytes>> {
+        tracing::debug!("WebSocket upgrade {} for {}", stream_id, subdomain);
+
+        let (response_tx, response_rx) = oneshot::channel();
+        self.response_registry.insert(
+            stream_id,
+            PendingResponse {
+                sender: response_tx,
+                tunnel: sender.clone(),
+            },
+        );
+
+        let msg = ServerMessage::WsUpgrade {
+            stream_id,
+            uri: req.uri().to_string(),
+            headers: self.forward_headers(&req, peer_addr),
+            scheme: Some(scheme.to_string()),
+        };
+        if let Err(e) = sender.send(msg).await {
+            tracing::error!("Failed to send upgrade to tunnel: {}", e);
+            self.response_registry.remove(&stream_id);
+            return self.error_response(StatusCode::BAD_GATEWAY, "Tunnel connection lost");
+        }
+
+        let response_data = match tokio::time::timeout(self.response_timeout(), response_rx).await {
+            Ok(Ok(response_data)) => response_data,
+            Ok(Err(_)) => {
+                tracing::error!("Tunnel disconnected while waiting for upgrade response");
+                return self.error_response(StatusCode::BAD_GATEWAY, "Tunnel disconnected");
+            }
+            Err(_) => {
+                tracing::error!("Timeout waiting for upgrade response");
+                self.response_registry.remove(&stream_id);
+                return self.error_response(StatusCode::GATEWAY_TIMEOUT, "Tunnel response timeout");
+            }
+        };
+
+        let mut builder = Response::builder().status(response_data.status);
+        for (name, value) in response_data.headers {
+            builder = builder.header(name, value);
+        }
+        let mut response = builder
+            .body(Full::new(Bytes::from(response_data.body)))
+            .unwrap();
+        self.tunnel_header(response.headers_mut(), &subdomain, &response_data.client_id);
+
+        // The local service refused: relay its answer as the final response
+        if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+            self.options
+                .inject_response_headers
+                .apply(response.headers_mut());
+            return response;
+        }
+
+        // Register before answering so data the client sends right away isn't dropped
+        let (ws_tx, ws_rx) = mpsc::channel::<Bytes>(32);
+        self.ws_registry.insert(stream_id, ws_tx);
+
+        let ws_registry = self.ws_registry.clone();
+        let limiters = self.router.get_limiters(&subdomain);
+        tokio::spawn(async move {
+            match hyper::upgrade::on(&mut req).await {
+                Ok(upgraded) => {
+                    pipe_websocket(TokioIo::new(upgraded), stream_id, ws_rx, &sender, limiters)
+                        .await;
+                }
+                Err(e) => tracing::warn!("WebSocket upgrade {} failed: {}", stream_id, e),
+            }
+            ws_registry.remove(&stream_id);
+            let _ = sender.send(ServerMessage::WsClose { stream_id }).await;
+        });
+
+        response
+    }
+
+    fn response_timeout(&self) -> Duration {
+        self.options
+            .response_timeout
+            .unwrap_or(DEFAULT_RESPONSE_TIMEOUT)
+    }
+
+    /// Error generated by the plane itself, as HTML if a page is configured for `status`
+    fn error_response(&self, status: StatusCode, message: &str) -> Response<Full<Bytes>> {
+        let builder = Response::builder().status(status);
+        let response = match self.options.error_pages.render(status.as_u16(), message) {
+            Some(page) => builder
+                .header(CONTENT_TYPE, "text/html; charset=utf-8")
+                .body(Full::new(Bytes::from(page))),
+            None => builder.body(Full::new(Bytes::from(message.to_string()))),
+        };
+        response.unwrap()
+    }
+
+    /// Scheme the request originally arrived with at the edge
+    ///
+    /// Forwarding headers are only honored from trusted upstreams; otherwise
+    /// the scheme of our own listener is used.
+    fn effective_scheme(&self, req: &Request<Incoming>, peer_addr: SocketAddr) -> &'static str {
+        if self.options.trusted_proxies.contains(peer_addr.ip()) {
+            if let Some(proto) = forwarded_proto(req.headers()) {
+                return proto;
+            }
+        }
+
+        if self.tls_acceptor.is_some() {
+            "https"
+        } else {
+            "http"
+        }
+    }
+
+    /// Request headers as sent to the client, with the visitor appended to `X-Forwarded-For`
+    fn forward_headers(
+        &self,
+        req: &Request<Incoming>,
+        peer_addr: SocketAddr,
+    ) -> Vec<(String, String)> {
+        let mut headers: Vec<(String, String)> = req
+            .headers()
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("").to_string()))
+            .collect();
+        let trusted = self.options.trusted_proxies.contains(peer_addr.ip());
+        append_forwarded_for(&mut headers, peer_addr.ip(), trusted);
+        headers
+    }
+
+    /// Name the tunnel that answered in `headers` when exposing it, otherwise
+    /// make sure the local service didn't set the header itself
+    fn tunnel_header(&self, headers: &mut HeaderMap, subdomain: &str, client_id: &str) {
+        headers.remove(TUNNEL_HEADER);
+        if !self.options.expose_tunnel_header {
+            return;
+        }
+        match HeaderValue::from_str(&format!("{}@{}", subdomain, client_id)) {
+            Ok(value) => {
+                headers.insert(TUNNEL_HEADER, value);
+            }
+            Err(_) => tracing::warn!("Client ID {:?} is not a valid header value", client_id),
+        }
+    }
+
+    /// Extract the subdomain and the base domain it is under from the Host header
+    fn extract_subdomain(&self, req: &Request<Incoming>) -> Option<(String, String)> {
         let host = req.headers().get("host")?.to_str().ok()?;
 
-        // Remove port if present
-        let host = host.split(':').next()?;
+        // Remove port if present; subdomains are routed in lowercase
+        let host = host.split(':').next()?.to_ascii_lowercase();
+
+        // Only the first part counts (in case of multi-level subdomain)
+        let (subdomain, base_domain) = self.base_domains.split(&host)?;
+        Some((subdomain.to_string(), base_domain.to_string()))
+    }
+}
+
+/// Span of a visitor's request, continuing the trace it came with if any
+fn request_span<B>(req: &Request<B>) -> tracing::Span {
+    let span = tracing::info_span!(
+        target: OTEL_TARGET,
+        "http_request",
+        http.request.method = %req.method(),
+        url.path = req.uri().path(),
+        subdomain = tracing::field::Empty,
+        http.response.status_code = tracing::field::Empty,
+    );
+    #[cfg(feature = "otel")]
+    otel::set_parent_from(
+        &span,
+        req.headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?))),
+    );
+    span
+}
+
+/// Whether a request asks to switch to the WebSocket protocol
+fn is_websocket_upgrade<B>(req: &Request<B>) -> bool {
+    let has_token = |name, token: &str| {
+        req.headers().get_all(name).iter().any(|v| {
+            v.to_str()
+                .is_ok_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+        })
+    };
+    req.method() == hyper::Method::GET
+        && has_token(hyper::header::CONNECTION, "upgrade")
+        && has_token(hyper::header::UPGRADE, "websocket")
+}
 
-        // Check if it ends with our base domain
-        if !host.ends_with(&self.base_domain) {
-            return None;
+/// Pipe an upgraded visitor connection to and from the tunnel until either side closes
+async fn pipe_websocket<S>(
+    stream: S,
+    stream_id: u64,
+    mut ws_rx: mpsc::Receiver<Bytes>,
+    sender: &mpsc::Sender<ServerMessage>,
+    limiters: TunnelLimiters,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut read_half, mut write_half) = tokio::io::split(stream);
+
+    // Client -> visitor; ends when the client closes the stream (writer dropped)
+    let egress = limiters.clone();
+    let write = async move {
+        while let Some(data) = ws_rx.recv().await {
+            egress.egress(data.len()).await;
+            if let Err(e) = write_half.write_all(&data).await {
+                tracing::debug!("Failed to write to WebSocket {}: {}", stream_id, e);
+                break;
+            }
         }
+        let _ = write_half.shutdown().await;
+    };
 
-        // Extract subdomain
-        let subdomain_part = host.strip_suffix(&format!(".{}", self.base_domain))?;
+    // Visitor -> client
+    let read = async {
+        let mut buf = BytesMut::with_capacity(READ_CHUNK);
+        loop {
+            buf.reserve(READ_CHUNK);
+            match read_half.read_buf(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    limiters.ingress(n).await;
+                    let data = buf.split_to(n).freeze();
+                    if sender
+                        .send(ServerMessage::WsData { stream_id, data })
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    tracing::debug!("WebSocket {} read error: {}", stream_id, e);
+                    break;
+                }
+            }
+        }
+    };
 
-        // Return only the first part (in case of multi-level subdomain)
-        Some(subdomain_part.split('.').next()?.to_string())
+    tokio::select! {
+        _ = write => {}
+        _ = read => {}
     }
+    tracing::debug!("WebSocket {} closed", stream_id);
 }
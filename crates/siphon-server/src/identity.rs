@@ -0,0 +1,165 @@
+//! Identity of a connected client, taken from its mTLS certificate
+//!
+//! Computed once per control connection, so every log line and policy
+//! decision for that connection refers to the same names.
+
+use std::fmt;
+
+use rustls::pki_types::CertificateDer;
+use siphon_protocol::glob_match;
+
+/// Names presented by a client certificate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    /// Subject Common Name
+    pub cn: Option<String>,
+    /// Subject Alternative Names (DNS names and IPs as is, others prefixed)
+    pub sans: Vec<String>,
+    id: String,
+}
+
+impl ClientIdentity {
+    /// Read the identity from the leaf certificate the client presented
+    pub fn from_certificate(cert: &CertificateDer<'_>) -> Self {
+        let cn = siphon_common::extract_cn(cert).filter(|cn| !cn.is_empty());
+        let id = match &cn {
+            Some(cn) => cn.clone(),
+            // No CN to go by: hash the certificate for a stable ID
+            None => {
+                use std::collections::hash_map::DefaultHasher;
+                use std::hash::{Hash, Hasher};
+                let mut hasher = DefaultHasher::new();
+                cert.as_ref().hash(&mut hasher);
+                format!("client-{:016x}", hasher.finish())
+            }
+        };
+
+        Self {
+            cn,
+            sans: siphon_common::extract_sans(cert),
+            id,
+        }
+    }
+
+    /// Identity of a client without a certificate, under a generated ID
+    pub fn anonymous(id: String) -> Self {
+        Self {
+            cn: None,
+            sans: Vec::new(),
+            id,
+        }
+    }
+
+    /// Stable identifier for this client: the CN, or a certificate hash without one
+    pub fn client_id(&self) -> &str {
+        &self.id
+    }
+
+    /// Whether the CN or any SAN matches `pattern` (`*` matches any sequence)
+    #[allow(dead_code)]
+    pub fn matches(&self, pattern: &str) -> bool {
+        self.cn
+            .iter()
+            .chain(&self.sans)
+            .any(|name| glob_match(pattern, name))
+    }
+
+    /// Log that this identity failed a check expecting `expected`
+    ///
+    /// Spells out everything the certificate presented, so operators can see
+    /// exactly why it didn't match.
+    #[allow(dead_code)]
+    pub fn log_mismatch(&self, check: &str, expected: &str) {
+        tracing::warn!(
+            "Client {} failed {}: expected a name matching '{}', certificate presented {}",
+            self.id,
+            check,
+            expected,
+            self
+        );
+    }
+}
+
+impl fmt::Display for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.cn {
+            Some(cn) => write!(f, "CN={}", cn)?,
+            None => write!(f, "no CN")?,
+        }
+        if self.sans.is_empty() {
+            write!(f, ", no SANs")
+        } else {
+            write!(f, ", SANs=[{}]", self.sans.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use rcgen::{CertificateParams, DnType, KeyPair, SanType};
+
+    fn certificate(cn: Option<&str>, sans: Vec<SanType>) -> CertificateDer<'static> {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        if let Some(cn) = cn {
+            params.distinguished_name.push(DnType::CommonName, cn);
+        }
+        params.subject_alt_names = sans;
+        params.self_signed(&key).unwrap().der().clone()
+    }
+
+    #[test]
+    fn test_identity_from_certificate() {
+        let cert = certificate(
+            Some("ci-runner"),
+            vec![
+                SanType::DnsName("runner.ci.example.com".try_into().unwrap()),
+                SanType::IpAddress(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 7))),
+                SanType::Rfc822Name("ops@example.com".try_into().unwrap()),
+            ],
+        );
+        let identity = ClientIdentity::from_certificate(&cert);
+
+        assert_eq!(identity.cn.as_deref(), Some("ci-runner"));
+        assert_eq!(identity.client_id(), "ci-runner");
+        assert_eq!(
+            identity.sans,
+            vec!["runner.ci.example.com", "10.0.0.7", "email:ops@example.com"]
+        );
+        assert_eq!(
+            identity.to_string(),
+            "CN=ci-runner, SANs=[runner.ci.example.com, 10.0.0.7, email:ops@example.com]"
+        );
+    }
+
+    #[test]
+    fn test_identity_without_cn_uses_certificate_hash() {
+        let cert = certificate(None, Vec::new());
+        let identity = ClientIdentity::from_certificate(&cert);
+
+        assert_eq!(identity.cn, None);
+        assert!(identity.client_id().starts_with("client-"));
+        assert_eq!(identity, ClientIdentity::from_certificate(&cert));
+        assert_eq!(identity.to_string(), "no CN, no SANs");
+    }
+
+    #[test]
+    fn test_matches_cn_or_san() {
+        let cert = certificate(
+            Some("laptop"),
+            vec![SanType::DnsName(
+                "laptop.team.example.com".try_into().unwrap(),
+            )],
+        );
+        let identity = ClientIdentity::from_certificate(&cert);
+
+        assert!(identity.matches("laptop"));
+        assert!(identity.matches("*.team.example.com"));
+        assert!(!identity.matches("ci-*"));
+    }
+}
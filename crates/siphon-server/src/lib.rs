@@ -3,24 +3,55 @@
 //! This library provides the core components for running a siphon tunnel server.
 //! It can be used to embed a tunnel server in other applications or for testing.
 
+mod bandwidth;
+mod base_domains;
+mod cert_watch;
 mod cloudflare;
+mod coalesce;
 mod config;
+mod conn_limit;
 mod control_plane;
+mod dns_propagation;
 mod dns_provider;
+mod error_pages;
+mod forwarded;
 mod http_plane;
+mod identity;
+mod metrics;
+mod proxy_protocol;
+mod response_headers;
 mod router;
+mod sni;
 mod state;
+mod static_routes;
+mod subdomain;
 mod tcp_plane;
+mod webhook;
 
 // Re-export public types
+pub use bandwidth::{BandwidthLimit, BandwidthPolicy, RateLimiter};
+pub use base_domains::BaseDomains;
+pub use cert_watch::{CertWatcher, ReloadableAcceptor, CERT_RELOAD_DEBOUNCE};
 pub use cloudflare::CloudflareClient;
-pub use config::{ResolvedCloudflareConfig, ServerConfig};
-pub use control_plane::ControlPlane;
-pub use dns_provider::{DnsError, DnsProvider, OriginCertificate};
-pub use http_plane::HttpPlane;
+pub use config::{DnsMode, ResolvedCloudflareConfig, ServerConfig, DEFAULT_ENV_PREFIX};
+pub use control_plane::{ControlPlane, ControlPlaneOptions};
+pub use dns_propagation::{DnsPropagationCheck, NameLookup, PropagationBackoff, SystemLookup};
+pub use dns_provider::{
+    DnsError, DnsProvider, ManualDnsProvider, OriginCertificate, OriginCertificateIssuer,
+    ZoneProviders,
+};
+pub use error_pages::ErrorPages;
+pub use forwarded::TrustedProxies;
+pub use http_plane::{HttpPlane, HttpPlaneOptions};
+pub use identity::ClientIdentity;
+pub use metrics::{DnsMetrics, DnsMetricsSnapshot, StreamMetrics};
+pub use response_headers::InjectedHeaders;
 pub use router::Router;
 pub use state::{
-    new_response_registry, new_tcp_connection_registry, PortAllocator, ResponseRegistry,
-    StreamIdGenerator, TcpConnectionRegistry,
+    new_response_registry, new_tcp_connection_registry, new_ws_connection_registry, PortAllocator,
+    ResponseRegistry, StreamIdGenerator, TcpConnectionRegistry, WsConnectionRegistry,
 };
-pub use tcp_plane::TcpPlane;
+pub use static_routes::StaticRoutes;
+pub use subdomain::SubdomainGenerator;
+pub use tcp_plane::{TcpPlane, TcpPlaneOptions};
+pub use webhook::{LifecycleEvent, LifecycleEventKind, LifecycleWebhook};
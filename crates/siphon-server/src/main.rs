@@ -1,27 +1,61 @@
+use std::collections::HashSet;
+use std::io::IsTerminal;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use siphon_common::{TlsPolicy, TlsVersion};
+use siphon_secrets::SecretError;
 use tokio_rustls::TlsAcceptor;
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
 
+mod bandwidth;
+mod base_domains;
+mod cert_watch;
 mod cloudflare;
+mod coalesce;
 mod config;
+mod conn_limit;
 mod control_plane;
+mod dns_propagation;
 mod dns_provider;
+mod dry_run;
+mod error_pages;
+mod forwarded;
 mod http_plane;
+mod identity;
+mod metrics;
+mod proxy_protocol;
+mod response_headers;
 mod router;
+mod setup_secrets;
+mod sni;
 mod state;
+mod static_routes;
+mod subdomain;
 mod tcp_plane;
+mod webhook;
 
+use cert_watch::{CertWatcher, ReloadableAcceptor, CERT_RELOAD_DEBOUNCE};
 use cloudflare::CloudflareClient;
 use config::ServerConfig;
-use control_plane::ControlPlane;
-use http_plane::HttpPlane;
+use control_plane::{ControlPlane, ControlPlaneOptions};
+use dns_propagation::DnsPropagationCheck;
+use dns_provider::{DnsProvider, ManualDnsProvider, ZoneProviders};
+use http_plane::{HttpPlane, HttpPlaneOptions};
 use router::Router;
-use state::{new_response_registry, new_tcp_connection_registry, PortAllocator, StreamIdGenerator};
-use tcp_plane::TcpPlane;
+use state::{
+    new_response_registry, new_tcp_connection_registry, new_ws_connection_registry, PortAllocator,
+    StreamIdGenerator,
+};
+use subdomain::SubdomainGenerator;
+use tcp_plane::{TcpPlane, TcpPlaneOptions};
+use webhook::LifecycleWebhook;
 
 /// Tunnel server - accepts tunnel connections and routes traffic
 #[derive(Parser, Debug)]
@@ -31,6 +65,105 @@ struct Args {
     /// Path to configuration file
     #[arg(short, long, default_value = "server.toml")]
     config: String,
+
+    /// Validate the configuration (secrets, certificates, Cloudflare access) and exit
+    /// without binding ports or touching DNS
+    #[arg(long)]
+    dry_run: bool,
+
+    /// More log output (-v debug, -vv trace); RUST_LOG takes precedence
+    #[arg(short, long, action = clap::ArgAction::Count, global = true, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Only log warnings and errors
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Store server secrets in the OS keychain and reference them from the config file
+    SetupSecrets {
+        /// Server certificate file (PEM)
+        #[arg(long)]
+        cert: PathBuf,
+
+        /// Server private key file (PEM)
+        #[arg(long)]
+        key: PathBuf,
+
+        /// CA certificate file used to verify clients (PEM)
+        #[arg(long)]
+        ca: PathBuf,
+
+        /// File containing the private key passphrase (for encrypted keys)
+        #[arg(long)]
+        key_passphrase: Option<PathBuf>,
+
+        /// File containing the Cloudflare API token
+        #[arg(long)]
+        cloudflare_token: Option<PathBuf>,
+    },
+
+    /// Inspect the server configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Print the resolved configuration and where each value came from (env, file
+    /// or default); secrets show only their backend
+    Show,
+}
+
+/// How long to give the user to unlock the keychain before the single retry
+const KEYCHAIN_UNLOCK_DELAY: Duration = Duration::from_secs(3);
+
+/// Load and resolve the configuration, giving the user one chance to unlock a
+/// locked keychain when attached to a terminal
+async fn load_config(path: &str) -> Result<config::ResolvedServerConfig> {
+    match ServerConfig::load_and_resolve(path) {
+        Err(e) if is_keychain_unavailable(&e) && is_interactive() => {
+            tracing::warn!(
+                "{:#}. Unlock the keychain now; retrying in {}s...",
+                e,
+                KEYCHAIN_UNLOCK_DELAY.as_secs()
+            );
+            tokio::time::sleep(KEYCHAIN_UNLOCK_DELAY).await;
+            ServerConfig::load_and_resolve(path)
+        }
+        result => result,
+    }
+}
+
+/// Whether a secret failed to resolve because the keychain couldn't be read
+fn is_keychain_unavailable(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<SecretError>()
+            .is_some_and(SecretError::is_keychain_unavailable)
+    })
+}
+
+/// Whether someone is at a terminal to act on prompts
+fn is_interactive() -> bool {
+    std::io::stdin().is_terminal() && std::io::stderr().is_terminal()
+}
+
+/// Log level for our crates: -q warn, default info, -v debug, -vv trace
+fn log_level(verbose: u8, quiet: bool) -> &'static str {
+    match (quiet, verbose) {
+        (true, _) => "warn",
+        (false, 0) => "info",
+        (false, 1) => "debug",
+        (false, _) => "trace",
+    }
 }
 
 #[tokio::main]
@@ -40,42 +173,145 @@ async fn main() -> Result<()> {
         .install_default()
         .expect("Failed to install rustls crypto provider");
 
+    let args = Args::parse();
+
     // Initialize logging
-    // Use RUST_LOG if set, otherwise default to info for our crates
+    // Use RUST_LOG if set, otherwise the level picked with -v/-q for our crates
+    let level = log_level(args.verbose, args.quiet);
     let env_filter = EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| EnvFilter::new("siphon_server=info,siphon_common=info"));
-    tracing_subscriber::fmt().with_env_filter(env_filter).init();
+        .unwrap_or_else(|_| EnvFilter::new(format!("siphon_server={level},siphon_common={level}")));
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(env_filter));
+    // Spans go to the collector named by OTEL_EXPORTER_OTLP_ENDPOINT, if any
+    #[cfg(feature = "otel")]
+    let telemetry = siphon_common::otel::Telemetry::from_env("siphon-server")
+        .context("Failed to set up OpenTelemetry export")?;
+    #[cfg(feature = "otel")]
+    let subscriber = subscriber.with(telemetry.as_ref().map(|telemetry| telemetry.layer()));
+    subscriber.init();
+
+    let show_config = matches!(
+        args.command,
+        Some(Command::Config {
+            action: ConfigCommand::Show
+        })
+    );
+
+    if let Some(Command::SetupSecrets {
+        cert,
+        key,
+        ca,
+        key_passphrase,
+        cloudflare_token,
+    }) = args.command
+    {
+        let files = setup_secrets::SecretFiles {
+            cert,
+            key,
+            ca,
+            key_passphrase,
+            cloudflare_token,
+        };
+        return setup_secrets::run(&files, Path::new(&args.config));
+    }
 
-    let args = Args::parse();
     tracing::info!("Starting tunnel server with config: {}", args.config);
 
     // Load and resolve configuration (resolves all secrets)
-    let config = ServerConfig::load_and_resolve(&args.config)
+    let config = load_config(&args.config)
+        .await
         .with_context(|| format!("Failed to load config from {}", args.config))?;
 
-    tracing::info!("Base domain: {}", config.base_domain);
+    if show_config {
+        print!("{}", config);
+        return Ok(());
+    }
+
+    if args.dry_run {
+        return dry_run::run(&config).await;
+    }
+
+    let base_domains = config.base_domains();
+    tracing::info!("Base domain: {}", base_domains);
     tracing::info!("Control plane port: {}", config.control_port);
     tracing::info!("HTTP plane port: {}", config.http_port);
+    tracing::info!("DNS mode: {:?}", config.dns_mode);
 
     // Load TLS configuration from resolved PEM content
-    let tls_config = siphon_common::load_server_config_from_pem(
-        &config.cert_pem,
-        &config.key_pem,
-        &config.ca_cert_pem,
-    )
-    .context("Failed to load TLS configuration")?;
-
-    let tls_acceptor = TlsAcceptor::from(Arc::new(tls_config));
+    let tls_policy = TlsPolicy::min_version(config.min_client_tls_version)
+        .with_alpn(config.control_alpn.iter().cloned());
+    let tls_acceptor = ReloadableAcceptor::new(
+        control_acceptor(
+            &config.cert_pem,
+            &config.key_pem,
+            &config.ca_cert_pem,
+            &tls_policy,
+        )
+        .context("Failed to load TLS configuration")?,
+    );
+    if config.min_client_tls_version > TlsVersion::Tls12 {
+        tracing::info!(
+            "Control plane only accepts TLS {} or newer",
+            config.min_client_tls_version
+        );
+    }
+    if !config.control_alpn.is_empty() {
+        tracing::info!(
+            "Control plane only accepts ALPN protocols: {}",
+            config.control_alpn.join(", ")
+        );
+    }
 
     // Create shared state
-    let router = Router::new();
-    let cloudflare = Arc::new(CloudflareClient::new(
-        &config.cloudflare,
-        &config.base_domain,
-    ));
+    let router = Router::with_reconnect_hold(config.reconnect_hold);
+    // Statically routed subdomains are never handed to a tunnel
+    for subdomain in config.static_routes.subdomains() {
+        router.reserve(subdomain);
+    }
+    if !config.static_routes.is_empty() {
+        tracing::info!("Static routes: {}", config.static_routes);
+    }
+    let cloudflare = config
+        .cloudflare
+        .as_ref()
+        .map(|cf| Arc::new(CloudflareClient::new(cf, &config.base_domain)));
+    // Extra base domains keep their records in their own zones
+    let mut zone_providers = ZoneProviders::default();
+    let mut zone_clients = Vec::new();
+    if let Some(cloudflare) = &cloudflare {
+        for extra in &config.extra_base_domains {
+            if let Some(zone_id) = &extra.zone_id {
+                let client = Arc::new(cloudflare.for_base_domain(&extra.domain, zone_id));
+                zone_providers.insert(extra.domain.clone(), client.clone());
+                zone_clients.push(client);
+            }
+        }
+    }
+    if config
+        .cloudflare
+        .as_ref()
+        .is_some_and(|cf| cf.cleanup_orphans_on_start)
+    {
+        // No tunnel is registered yet, so every tunnel record is an orphan
+        for client in cloudflare.iter().chain(&zone_clients) {
+            match client.cleanup_orphaned_records(&HashSet::new()).await {
+                Ok(deleted) => tracing::info!("Deleted {} orphaned DNS record(s)", deleted),
+                Err(e) => tracing::warn!("Failed to clean up orphaned DNS records: {}", e),
+            }
+        }
+    }
+    let dns_provider: Arc<dyn DnsProvider> = match &cloudflare {
+        Some(cloudflare) => cloudflare.clone(),
+        None => Arc::new(ManualDnsProvider),
+    };
     let response_registry = new_response_registry();
     let tcp_registry = new_tcp_connection_registry();
-    let port_allocator = PortAllocator::new(config.tcp_port_range.0, config.tcp_port_range.1);
+    let ws_registry = new_ws_connection_registry();
+    let port_allocator = PortAllocator::with_exclusions(
+        config.tcp_port_range.0,
+        config.tcp_port_range.1,
+        &config.excluded_ports,
+    );
     let stream_id_gen = StreamIdGenerator::new();
 
     tracing::info!(
@@ -83,43 +319,90 @@ async fn main() -> Result<()> {
         config.tcp_port_range.0,
         config.tcp_port_range.1
     );
+    if !config.excluded_ports.is_empty() {
+        tracing::info!(
+            "TCP ports left to other services: {:?}",
+            config.excluded_ports
+        );
+    }
+
+    if let Some(port) = config.tcp_mux_port {
+        tracing::info!("TCP tunnels share port {} (routed by TLS SNI)", port);
+    }
+
+    if config.trust_proxy_protocol {
+        tracing::info!("Expecting PROXY protocol headers on HTTP and TCP plane connections");
+    }
 
     // Create planes
-    let tcp_plane = TcpPlane::new(
+    let tcp_plane = TcpPlane::with_options(
         router.clone(),
         port_allocator,
         tcp_registry.clone(),
         stream_id_gen,
+        TcpPlaneOptions {
+            socket_options: config.socket_options,
+            proxy_protocol: config.trust_proxy_protocol,
+        },
     );
 
-    let control_plane = ControlPlane::new(
+    let control_plane = ControlPlane::with_options(
         router.clone(),
-        tls_acceptor,
-        cloudflare.clone(),
-        config.base_domain.clone(),
+        tls_acceptor.clone(),
+        dns_provider.clone(),
+        base_domains.clone(),
         response_registry.clone(),
-        tcp_plane,
+        tcp_plane.clone(),
         tcp_registry,
+        ws_registry.clone(),
+        ControlPlaneOptions {
+            subdomain_generator: SubdomainGenerator::new(config.subdomain_length),
+            tcp_mux_port: config.tcp_mux_port,
+            lifecycle_webhook: config
+                .lifecycle_webhook_url
+                .as_deref()
+                .map(LifecycleWebhook::new),
+            max_connections: config.max_connections,
+            max_connections_per_ip: config.max_connections_per_ip,
+            bandwidth: config.bandwidth.clone(),
+            socket_options: config.socket_options,
+            read_buffer: config.read_buffer,
+            dns_propagation: config
+                .verify_dns_propagation
+                .then(DnsPropagationCheck::system),
+            zone_providers,
+            require_alpn: !config.control_alpn.is_empty(),
+            max_tunnel_lifetime: config.max_tunnel_lifetime,
+        },
     );
 
+    // Provider to request an Origin CA certificate from, if enabled
+    let auto_origin_ca = config
+        .cloudflare
+        .as_ref()
+        .is_some_and(|cf| cf.auto_origin_ca);
+    let origin_ca = dns_provider
+        .origin_certificates()
+        .filter(|_| auto_origin_ca);
+
     // Load HTTP plane TLS config if provided (for Cloudflare Full Strict mode)
     // Priority: manual certs > auto Origin CA > no TLS
     let http_tls_acceptor =
         if let (Some(cert), Some(key)) = (&config.http_cert_pem, &config.http_key_pem) {
             tracing::info!("HTTP plane TLS: using provided certificates");
-            let http_tls_config = siphon_common::load_server_config_no_client_auth(cert, key)
-                .context("Failed to load HTTP plane TLS configuration")?;
-            Some(TlsAcceptor::from(Arc::new(http_tls_config)))
-        } else if config.cloudflare.auto_origin_ca {
+            let acceptor =
+                http_acceptor(cert, key).context("Failed to load HTTP plane TLS configuration")?;
+            Some(ReloadableAcceptor::new(acceptor))
+        } else if let Some(origin_ca) = origin_ca {
             tracing::info!("HTTP plane TLS: generating Cloudflare Origin CA certificate...");
 
             // Clean up old certificates first
-            if let Err(e) = cloudflare.cleanup_old_origin_certificates().await {
+            if let Err(e) = origin_ca.cleanup_old_origin_certificates().await {
                 tracing::warn!("Failed to cleanup old Origin CA certificates: {}", e);
             }
 
             // Generate Origin CA certificate
-            let origin_cert = cloudflare
+            let origin_cert = origin_ca
                 .create_origin_certificate(365) // 1 year validity
                 .await
                 .context("Failed to create Origin CA certificate")?;
@@ -142,19 +425,93 @@ async fn main() -> Result<()> {
             .context("Failed to load Origin CA TLS configuration")?;
 
             tracing::info!("Origin CA TLS configuration loaded successfully");
-            Some(TlsAcceptor::from(Arc::new(http_tls_config)))
+            Some(ReloadableAcceptor::new(TlsAcceptor::from(Arc::new(
+                http_tls_config,
+            ))))
         } else {
             tracing::info!("HTTP plane TLS: disabled (plain HTTP)");
             None
         };
 
-    let http_plane = HttpPlane::new(
+    if !config.trusted_proxies.is_empty() {
+        tracing::info!("Trusting forwarded scheme headers from configured proxies");
+    }
+    let http_plane = HttpPlane::with_options(
         router.clone(),
-        config.base_domain.clone(),
+        base_domains.clone(),
         response_registry,
-        http_tls_acceptor,
+        ws_registry,
+        http_tls_acceptor.clone(),
+        HttpPlaneOptions {
+            trusted_proxies: config.trusted_proxies.clone(),
+            error_pages: config.error_pages.clone(),
+            proxy_protocol: config.trust_proxy_protocol,
+            inject_response_headers: config.inject_response_headers.clone(),
+            response_timeout: Some(config.response_total_timeout),
+            strict_sni: config.http_strict_sni,
+            static_routes: config.static_routes.clone(),
+            expose_tunnel_header: config.expose_tunnel_header,
+            coalesce_gets: config.coalesce_gets.then_some(config.coalesce_ttl),
+        },
     );
 
+    // Reload certificates rotated on disk; watching stops when these are dropped
+    let mut cert_watchers = Vec::new();
+    if config.watch_certs {
+        if let Some(files) = config.cert_sources.control_files() {
+            let sources = config.cert_sources.clone();
+            let watcher = CertWatcher::start(
+                "control plane",
+                &files,
+                CERT_RELOAD_DEBOUNCE,
+                tls_acceptor,
+                move || {
+                    let (cert, key, ca_cert) = sources.resolve_control()?;
+                    control_acceptor(&cert, &key, &ca_cert, &tls_policy)
+                },
+            )
+            .context("Failed to watch control plane certificates")?;
+            cert_watchers.push(watcher);
+        }
+        if let (Some(files), Some(acceptor)) = (config.cert_sources.http_files(), http_tls_acceptor)
+        {
+            let sources = config.cert_sources.clone();
+            let watcher = CertWatcher::start(
+                "HTTP plane",
+                &files,
+                CERT_RELOAD_DEBOUNCE,
+                acceptor,
+                move || {
+                    let (cert, key) = sources
+                        .resolve_http()?
+                        .context("No HTTP plane certificate configured")?;
+                    http_acceptor(&cert, &key)
+                },
+            )
+            .context("Failed to watch HTTP plane certificates")?;
+            cert_watchers.push(watcher);
+        }
+    }
+
+    // Periodically log DNS churn so rate limiting and cleanup failures stand out
+    let dns_metrics = cloudflare.as_ref().map(|cloudflare| cloudflare.metrics());
+    if let Some(metrics_logger) = dns_metrics.clone() {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(300));
+            let mut last = metrics_logger.snapshot();
+            loop {
+                interval.tick().await;
+                let current = metrics_logger.snapshot();
+                if current != last {
+                    metrics_logger.log_summary();
+                    last = current;
+                }
+            }
+        });
+    }
+
+    let stream_metrics = control_plane.stream_metrics();
+
     // Start servers
     // SIPHON_BIND_HOST: use [::] for IPv6/dual-stack, 0.0.0.0 for IPv4 only (default)
     let bind_host = std::env::var("SIPHON_BIND_HOST").unwrap_or_else(|_| "0.0.0.0".to_string());
@@ -164,8 +521,22 @@ async fn main() -> Result<()> {
     tracing::info!("Starting control plane on {}", control_addr);
     tracing::info!("Starting HTTP plane on {}", http_addr);
 
-    // Run both planes concurrently with graceful shutdown
+    // Shared SNI-routed TCP port (optional)
+    let tcp_mux = async {
+        match config.tcp_mux_port {
+            Some(port) => {
+                let addr: SocketAddr = format!("{}:{}", bind_host, port).parse()?;
+                tcp_plane.run_mux(addr, base_domains).await
+            }
+            None => std::future::pending().await,
+        }
+    };
+
+    // Run all planes concurrently with graceful shutdown
     tokio::select! {
+        result = tcp_mux => {
+            tracing::error!("TCP mux stopped: {:?}", result);
+        }
         result = control_plane.run(control_addr) => {
             tracing::error!("Control plane stopped: {:?}", result);
         }
@@ -177,10 +548,49 @@ async fn main() -> Result<()> {
         }
     }
 
+    if let Some(dns_metrics) = dns_metrics {
+        dns_metrics.log_summary();
+    }
+    let orphan_responses = stream_metrics.orphan_responses();
+    if orphan_responses > 0 {
+        tracing::info!(
+            "Dropped {} orphan response(s) from clients",
+            orphan_responses
+        );
+    }
+    drop(cert_watchers);
+    #[cfg(feature = "otel")]
+    if let Some(telemetry) = telemetry {
+        telemetry.shutdown();
+    }
     tracing::info!("Server shutdown complete");
     Ok(())
 }
 
+/// Control plane acceptor (mTLS) for a certificate and the key issued with it
+fn control_acceptor(
+    cert_pem: &str,
+    key_pem: &str,
+    ca_cert_pem: &str,
+    policy: &TlsPolicy,
+) -> Result<TlsAcceptor> {
+    siphon_common::verify_cert_key_pair(cert_pem, key_pem)?;
+    let tls_config = siphon_common::load_server_config_from_pem_with_policy(
+        cert_pem,
+        key_pem,
+        ca_cert_pem,
+        policy,
+    )?;
+    Ok(TlsAcceptor::from(Arc::new(tls_config)))
+}
+
+/// HTTP plane acceptor for a certificate and the key issued with it
+fn http_acceptor(cert_pem: &str, key_pem: &str) -> Result<TlsAcceptor> {
+    siphon_common::verify_cert_key_pair(cert_pem, key_pem)?;
+    let tls_config = siphon_common::load_server_config_no_client_auth(cert_pem, key_pem)?;
+    Ok(TlsAcceptor::from(Arc::new(tls_config)))
+}
+
 /// Wait for shutdown signals (SIGTERM, SIGINT)
 async fn shutdown_signal() {
     use tokio::signal;
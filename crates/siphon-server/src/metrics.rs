@@ -0,0 +1,138 @@
+//! Server-side operational metrics
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters for DNS record churn and Origin CA certificate lifecycle
+///
+/// Every tunnel creates and deletes a DNS record, so these make Cloudflare
+/// rate limiting and cleanup failures visible at a glance.
+#[derive(Debug, Default)]
+pub struct DnsMetrics {
+    records_created: AtomicU64,
+    records_deleted: AtomicU64,
+    create_failures: AtomicU64,
+    delete_failures: AtomicU64,
+    origin_certs_issued: AtomicU64,
+    origin_certs_revoked: AtomicU64,
+}
+
+/// Point-in-time copy of [`DnsMetrics`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DnsMetricsSnapshot {
+    pub records_created: u64,
+    pub records_deleted: u64,
+    pub create_failures: u64,
+    pub delete_failures: u64,
+    pub origin_certs_issued: u64,
+    pub origin_certs_revoked: u64,
+}
+
+impl DnsMetricsSnapshot {
+    /// Records created but not (yet) deleted
+    pub fn active_records(&self) -> u64 {
+        self.records_created.saturating_sub(self.records_deleted)
+    }
+}
+
+impl DnsMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_created(&self) {
+        self.records_created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_deleted(&self) {
+        self.records_deleted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn create_failed(&self) {
+        self.create_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn delete_failed(&self) {
+        self.delete_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn origin_cert_issued(&self) {
+        self.origin_certs_issued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn origin_cert_revoked(&self) {
+        self.origin_certs_revoked.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Take a consistent-enough snapshot of all counters
+    pub fn snapshot(&self) -> DnsMetricsSnapshot {
+        DnsMetricsSnapshot {
+            records_created: self.records_created.load(Ordering::Relaxed),
+            records_deleted: self.records_deleted.load(Ordering::Relaxed),
+            create_failures: self.create_failures.load(Ordering::Relaxed),
+            delete_failures: self.delete_failures.load(Ordering::Relaxed),
+            origin_certs_issued: self.origin_certs_issued.load(Ordering::Relaxed),
+            origin_certs_revoked: self.origin_certs_revoked.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Log a one-line summary of the counters
+    pub fn log_summary(&self) {
+        let s = self.snapshot();
+        tracing::info!(
+            "DNS metrics: {} active records ({} created, {} deleted), {} create failures, {} delete failures, {} origin certs issued, {} revoked",
+            s.active_records(),
+            s.records_created,
+            s.records_deleted,
+            s.create_failures,
+            s.delete_failures,
+            s.origin_certs_issued,
+            s.origin_certs_revoked
+        );
+    }
+}
+
+/// Counters for protocol messages from clients that match no open stream
+#[derive(Debug, Default)]
+pub struct StreamMetrics {
+    orphan_responses: AtomicU64,
+}
+
+impl StreamMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A response was dropped: duplicate, late, unknown or from the wrong client
+    pub fn orphan_response(&self) {
+        self.orphan_responses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn orphan_responses(&self) -> u64 {
+        self.orphan_responses.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dns_metrics_counters() {
+        let metrics = DnsMetrics::new();
+        metrics.record_created();
+        metrics.record_created();
+        metrics.record_deleted();
+        metrics.create_failed();
+        metrics.delete_failed();
+        metrics.origin_cert_issued();
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.records_created, 2);
+        assert_eq!(snapshot.records_deleted, 1);
+        assert_eq!(snapshot.active_records(), 1);
+        assert_eq!(snapshot.create_failures, 1);
+        assert_eq!(snapshot.delete_failures, 1);
+        assert_eq!(snapshot.origin_certs_issued, 1);
+        assert_eq!(snapshot.origin_certs_revoked, 0);
+    }
+}
@@ -0,0 +1,221 @@
+//! PROXY protocol (v1 and v2) header parsing
+//!
+//! A TCP load balancer in front of the planes hides the visitor's address;
+//! with the PROXY protocol it sends a header carrying it before the
+//! connection's own bytes. Only enable this behind such a balancer: anyone
+//! reaching the port directly could otherwise claim any address.
+//!
+//! The header is read exactly, byte for byte where needed, so nothing after
+//! it (a TLS ClientHello, or nothing at all for server-speaks-first
+//! protocols) is consumed.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How long a connection has to send its PROXY header
+pub const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest v1 header, CRLF included
+const V1_MAX_LEN: usize = 107;
+
+/// Signature opening every v2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Read and strip the PROXY header at the start of `stream`
+///
+/// Returns the source address it carries, or `None` when it doesn't carry
+/// one (v1 `UNKNOWN`, v2 `LOCAL` health checks, Unix socket addresses), in
+/// which case the connection's own peer address applies. A missing or
+/// malformed header is an error.
+pub async fn read_proxy_header<S>(stream: &mut S) -> Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    tokio::time::timeout(PROXY_HEADER_TIMEOUT, read_header(stream))
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out waiting for PROXY header"))?
+}
+
+async fn read_header<S>(stream: &mut S) -> Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    // Both versions are longer than this, so it never reads past the header
+    let mut header = vec![0u8; 8];
+    stream
+        .read_exact(&mut header)
+        .await
+        .context("connection closed before PROXY header")?;
+
+    if header.starts_with(b"PROXY ") {
+        while !header.ends_with(b"\r\n") {
+            if header.len() >= V1_MAX_LEN {
+                bail!("PROXY v1 header too long");
+            }
+            header.push(stream.read_u8().await?);
+        }
+        return parse_v1(&header);
+    }
+
+    if header[..] == V2_SIGNATURE[..8] {
+        header.resize(16, 0);
+        stream.read_exact(&mut header[8..]).await?;
+        let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+        header.resize(16 + len, 0);
+        stream.read_exact(&mut header[16..]).await?;
+        return parse_v2(&header);
+    }
+
+    bail!("missing PROXY header")
+}
+
+/// Parse a complete v1 line, e.g. `PROXY TCP4 203.0.113.7 10.0.0.1 51234 443\r\n`
+pub fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.strip_suffix("\r\n"))
+        .context("malformed PROXY v1 header")?;
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src.parse().context("invalid PROXY v1 source address")?;
+            if ip.is_ipv4() != (*family == "TCP4") {
+                bail!("PROXY v1 source address doesn't match {}", family);
+            }
+            let port: u16 = src_port.parse().context("invalid PROXY v1 source port")?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => bail!("malformed PROXY v1 header"),
+    }
+}
+
+/// Parse a complete v2 header (signature, fixed part and addresses)
+pub fn parse_v2(header: &[u8]) -> Result<Option<SocketAddr>> {
+    if header.len() < 16 || header[..12] != V2_SIGNATURE {
+        bail!("malformed PROXY v2 header");
+    }
+    let version_command = header[12];
+    if version_command >> 4 != 2 {
+        bail!(
+            "unsupported PROXY protocol version {}",
+            version_command >> 4
+        );
+    }
+    let addresses = &header[16..];
+
+    match (version_command & 0x0f, header[13]) {
+        // LOCAL: the balancer's own connection (health check), no address
+        (0x0, _) => Ok(None),
+        // PROXY over TCP or UDP, IPv4
+        (0x1, 0x11 | 0x12) => {
+            let addr = addresses
+                .get(..12)
+                .context("truncated PROXY v2 IPv4 addresses")?;
+            let ip = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
+            let port = u16::from_be_bytes([addr[8], addr[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // PROXY over TCP or UDP, IPv6
+        (0x1, 0x21 | 0x22) => {
+            let addr = addresses
+                .get(..36)
+                .context("truncated PROXY v2 IPv6 addresses")?;
+            let ip: [u8; 16] = addr[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([addr[32], addr[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        // Unix sockets or unspecified family: nothing usable
+        (0x1, _) => Ok(None),
+        (command, _) => bail!("unsupported PROXY v2 command {}", command),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn test_parse_v1() {
+        assert_eq!(
+            parse_v1(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443\r\n").unwrap(),
+            Some("203.0.113.7:51234".parse().unwrap())
+        );
+        assert_eq!(
+            parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n").unwrap(),
+            Some("[2001:db8::1]:4000".parse().unwrap())
+        );
+        assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+    }
+
+    #[test]
+    fn test_parse_v1_rejects_malformed() {
+        for line in [
+            &b"PROXY TCP4 203.0.113.7 10.0.0.1 51234\r\n"[..],
+            b"PROXY TCP4 not-an-ip 10.0.0.1 51234 443\r\n",
+            b"PROXY TCP6 203.0.113.7 10.0.0.1 51234 443\r\n",
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 99999 443\r\n",
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443",
+        ] {
+            assert!(
+                parse_v1(line).is_err(),
+                "{:?}",
+                String::from_utf8_lossy(line)
+            );
+        }
+    }
+
+    fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let mut addresses = vec![203, 0, 113, 7, 10, 0, 0, 1];
+        addresses.extend_from_slice(&51234u16.to_be_bytes());
+        addresses.extend_from_slice(&443u16.to_be_bytes());
+        assert_eq!(
+            parse_v2(&v2_header(0x1, 0x11, &addresses)).unwrap(),
+            Some("203.0.113.7:51234".parse().unwrap())
+        );
+
+        // Health checks from the balancer itself carry no address
+        assert_eq!(parse_v2(&v2_header(0x0, 0x00, &[])).unwrap(), None);
+
+        assert!(parse_v2(&v2_header(0x1, 0x11, &addresses[..6])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_strips_only_the_header() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 80\r\nGET / HTTP/1.1\r\n")
+            .await
+            .unwrap();
+
+        let source = read_proxy_header(&mut server).await.unwrap();
+        assert_eq!(source, Some("203.0.113.7:51234".parse().unwrap()));
+
+        let mut rest = vec![0u8; 16];
+        server.read_exact(&mut rest).await.unwrap();
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_read_rejects_missing_header() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        assert!(read_proxy_header(&mut server).await.is_err());
+    }
+}
@@ -0,0 +1,119 @@
+//! Headers the HTTP plane adds to every tunneled response
+//!
+//! Meant for CORS or security headers (`X-Frame-Options`, ...) that should
+//! apply to every tunnel without touching the local apps. An injected header
+//! replaces any header of the same name the client sent.
+
+use std::fmt;
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+
+/// Headers set on responses relayed from tunnels, in configuration order
+#[derive(Debug, Clone, Default)]
+pub struct InjectedHeaders {
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl InjectedHeaders {
+    /// Validate configured `(name, value)` pairs
+    ///
+    /// Hop-by-hop headers describe a single connection rather than the
+    /// response, so they are skipped with a warning.
+    pub fn parse(pairs: &[(String, String)]) -> Result<Self, String> {
+        let mut headers = Vec::with_capacity(pairs.len());
+        for (name, value) in pairs {
+            let header_name = HeaderName::from_bytes(name.trim().as_bytes())
+                .map_err(|_| format!("Invalid response header name: {:?}", name))?;
+            let header_value = HeaderValue::from_str(value.trim())
+                .map_err(|_| format!("Invalid value for response header {}", name))?;
+            if is_hop_by_hop(header_name.as_str()) {
+                tracing::warn!("Not injecting hop-by-hop response header {}", header_name);
+                continue;
+            }
+            headers.push((header_name, header_value));
+        }
+        Ok(Self { headers })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    /// Set every injected header on `headers`, replacing values already there
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        for (name, value) in &self.headers {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+}
+
+/// Header names, comma-separated
+impl fmt::Display for InjectedHeaders {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = self.headers.iter().map(|(name, _)| name.as_str()).collect();
+        f.write_str(&names.join(", "))
+    }
+}
+
+/// Hop-by-hop headers (lowercase names), which only make sense for one connection
+pub(crate) fn is_hop_by_hop(name: &str) -> bool {
+    matches!(
+        name,
+        "connection"
+            | "keep-alive"
+            | "proxy-authenticate"
+            | "proxy-authorization"
+            | "te"
+            | "trailers"
+            | "transfer-encoding"
+            | "upgrade"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_injected_headers_replace_client_headers() {
+        let injected = InjectedHeaders::parse(&pairs(&[
+            ("X-Frame-Options", "DENY"),
+            ("Access-Control-Allow-Origin", "*"),
+        ]))
+        .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-frame-options", HeaderValue::from_static("SAMEORIGIN"));
+        headers.insert("content-type", HeaderValue::from_static("text/plain"));
+        injected.apply(&mut headers);
+
+        assert_eq!(headers["x-frame-options"], "DENY");
+        assert_eq!(headers["access-control-allow-origin"], "*");
+        assert_eq!(headers["content-type"], "text/plain");
+        assert_eq!(
+            injected.to_string(),
+            "x-frame-options, access-control-allow-origin"
+        );
+    }
+
+    #[test]
+    fn test_hop_by_hop_headers_skipped() {
+        let injected =
+            InjectedHeaders::parse(&pairs(&[("Connection", "close"), ("Keep-Alive", "5")]))
+                .unwrap();
+        assert!(injected.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_headers_rejected() {
+        assert!(InjectedHeaders::parse(&pairs(&[("Bad Name", "x")])).is_err());
+        assert!(InjectedHeaders::parse(&pairs(&[("X-Ok", "line\nbreak")])).is_err());
+    }
+}
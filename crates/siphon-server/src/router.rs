@@ -1,20 +1,42 @@
-use dashmap::DashMap;
-use siphon_protocol::{ServerMessage, TunnelType};
+use dashmap::mapref::one::Ref;
+use dashmap::{DashMap, DashSet};
+use siphon_protocol::{HttpPolicy, ServerMessage, TunnelType};
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Notify};
+
+use crate::bandwidth::TunnelLimiters;
+use crate::tcp_plane::TcpListenerHandle;
+
+/// Subdomain a catch-all tunnel is registered under: it serves every
+/// subdomain no other tunnel is registered for
+pub const CATCH_ALL: &str = "*";
 
 /// Handle to a tunnel connection
 pub struct TunnelHandle {
     /// Channel to send messages to this tunnel
     pub sender: mpsc::Sender<ServerMessage>,
     /// Client identifier (from certificate CN)
-    #[allow(dead_code)]
     pub client_id: String,
     /// Type of tunnel
-    #[allow(dead_code)]
     pub tunnel_type: TunnelType,
+    /// Base domain the tunnel's subdomain is served under
+    pub base_domain: String,
     /// Cloudflare DNS record ID (for cleanup)
     pub dns_record_id: Option<String>,
+    /// Restrictions on forwarded HTTP requests (None = allow all)
+    pub http_policy: Option<HttpPolicy>,
+    /// Whether the client relays WebSocket upgrades (HTTP tunnels only)
+    pub websocket: bool,
+    /// Whether TCP streams are flow-controlled with window updates (TCP
+    /// tunnels only)
+    pub flow_control: bool,
+    /// Dedicated TCP port listener, stopped when the tunnel is unregistered
+    pub tcp_listener: Option<TcpListenerHandle>,
+    /// Bandwidth caps shared by all of the tunnel's traffic
+    pub limiters: TunnelLimiters,
+    /// When the tunnel was set up
+    pub registered_at: Instant,
 }
 
 /// Routes incoming requests to appropriate tunnel connections
@@ -23,34 +45,58 @@ pub struct Router {
     routes: DashMap<String, TunnelHandle>,
     /// TCP port -> subdomain mapping (for TCP tunnels)
     tcp_ports: DashMap<u16, String>,
+    /// How long requests for a departed subdomain wait for it to come back
+    reconnect_hold: Option<Duration>,
+    /// Subdomains unregistered within the last `reconnect_hold`, with when
+    /// they left and the notify their held requests wait on
+    departed: DashMap<String, (Instant, Arc<Notify>)>,
+    /// Subdomains served without a tunnel (static routes), never registered
+    reserved: DashSet<String>,
+}
+
+/// Outcome of waiting for a departed subdomain's tunnel to come back
+pub enum ReconnectWait {
+    /// The subdomain wasn't registered recently (or holding is disabled)
+    NotRecent,
+    /// A tunnel re-registered the subdomain in time
+    Reconnected(mpsc::Sender<ServerMessage>),
+    /// The hold expired without a tunnel coming back
+    TimedOut,
 }
 
 impl Router {
+    #[allow(dead_code)]
     pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Create a router that lets requests wait up to `hold` for a recently
+    /// unregistered subdomain to be registered again (None = never wait)
+    pub fn with_reconnect_hold(hold: Option<Duration>) -> Arc<Self> {
         Arc::new(Self {
-            routes: DashMap::new(),
-            tcp_ports: DashMap::new(),
+            reconnect_hold: hold.filter(|hold| !hold.is_zero()),
+            ..Self::default()
         })
     }
 
     /// Register a new tunnel
-    pub fn register(
-        &self,
-        subdomain: String,
-        handle: TunnelHandle,
-        tcp_port: Option<u16>,
-    ) -> Result<(), RouterError> {
+    pub fn register(&self, subdomain: String, handle: TunnelHandle) -> Result<(), RouterError> {
         // Check if subdomain is already taken
-        if self.routes.contains_key(&subdomain) {
+        if !self.is_available(&subdomain) {
             return Err(RouterError::SubdomainTaken(subdomain));
         }
 
         // Register TCP port if applicable
-        if let Some(port) = tcp_port {
+        if let Some(port) = handle.tcp_listener.as_ref().map(TcpListenerHandle::port) {
             self.tcp_ports.insert(port, subdomain.clone());
         }
 
-        self.routes.insert(subdomain, handle);
+        self.routes.insert(subdomain.clone(), handle);
+
+        // Wake requests held for this subdomain; they pick up the new route
+        if let Some((_, (_, notify))) = self.departed.remove(&subdomain) {
+            notify.notify_waiters();
+        }
         Ok(())
     }
 
@@ -59,15 +105,142 @@ impl Router {
         if let Some((_, handle)) = self.routes.remove(subdomain) {
             // Remove TCP port mapping if exists
             self.tcp_ports.retain(|_, v| v != subdomain);
+            self.record_departure(subdomain);
             Some(handle)
         } else {
             None
         }
     }
 
+    /// Remember that `subdomain` just left, forgetting departures older than the hold
+    fn record_departure(&self, subdomain: &str) {
+        let Some(hold) = self.reconnect_hold else {
+            return;
+        };
+        self.departed
+            .retain(|_, (departed_at, _)| departed_at.elapsed() < hold);
+        self.departed
+            .entry(subdomain.to_string())
+            .and_modify(|(departed_at, _)| *departed_at = Instant::now())
+            .or_insert_with(|| (Instant::now(), Arc::new(Notify::new())));
+    }
+
+    /// Wait up to the reconnect hold for a recently unregistered subdomain to come back
+    ///
+    /// Held requests only keep a clone of the subdomain's notify; the entry
+    /// itself is dropped when the subdomain is registered again or, once the
+    /// hold has passed, by the next departure.
+    pub async fn wait_for_reconnect(&self, subdomain: &str) -> ReconnectWait {
+        let Some(hold) = self.reconnect_hold else {
+            return ReconnectWait::NotRecent;
+        };
+        let notify = match self.departed.get(subdomain) {
+            Some(entry) if entry.0.elapsed() < hold => entry.1.clone(),
+            _ => return ReconnectWait::NotRecent,
+        };
+
+        // Enable before re-checking, so a registration in between isn't missed
+        let notified = notify.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        if let Some(sender) = self.get_sender(subdomain) {
+            return ReconnectWait::Reconnected(sender);
+        }
+        if tokio::time::timeout(hold, notified).await.is_err() {
+            return ReconnectWait::TimedOut;
+        }
+        match self.get_sender(subdomain) {
+            Some(sender) => ReconnectWait::Reconnected(sender),
+            None => ReconnectWait::TimedOut,
+        }
+    }
+
+    /// Unregister every tunnel of a client
+    ///
+    /// Returns the removed handles so the caller can delete their DNS records;
+    /// dropping them stops their TCP listeners and releases the ports.
+    #[allow(dead_code)]
+    pub fn unregister_by_client(&self, client_id: &str) -> Vec<TunnelHandle> {
+        self.unregister_where(|h| h.client_id == client_id)
+            .into_iter()
+            .map(|(_, handle)| handle)
+            .collect()
+    }
+
+    /// Unregister every tunnel served over the control connection behind `sender`
+    ///
+    /// Several connections can share a client ID (same certificate), so a
+    /// connection going away only takes down its own tunnels.
+    pub fn unregister_connection(
+        &self,
+        sender: &mpsc::Sender<ServerMessage>,
+    ) -> Vec<(String, TunnelHandle)> {
+        self.unregister_where(|h| h.sender.same_channel(sender))
+    }
+
+    fn unregister_where(
+        &self,
+        pred: impl Fn(&TunnelHandle) -> bool,
+    ) -> Vec<(String, TunnelHandle)> {
+        let subdomains: Vec<String> = self
+            .routes
+            .iter()
+            .filter(|r| pred(r.value()))
+            .map(|r| r.key().clone())
+            .collect();
+
+        subdomains
+            .into_iter()
+            .filter_map(|subdomain| {
+                let handle = self.unregister(&subdomain)?;
+                Some((subdomain, handle))
+            })
+            .collect()
+    }
+
+    /// The tunnel serving `subdomain`: its own, or else the catch-all one
+    fn route(&self, subdomain: &str) -> Option<Ref<'_, String, TunnelHandle>> {
+        self.routes
+            .get(subdomain)
+            .or_else(|| self.routes.get(CATCH_ALL))
+    }
+
     /// Get a sender for a subdomain
     pub fn get_sender(&self, subdomain: &str) -> Option<mpsc::Sender<ServerMessage>> {
-        self.routes.get(subdomain).map(|h| h.sender.clone())
+        self.route(subdomain).map(|h| h.sender.clone())
+    }
+
+    /// Get the base domain a subdomain is served under
+    pub fn get_base_domain(&self, subdomain: &str) -> Option<String> {
+        self.route(subdomain).map(|h| h.base_domain.clone())
+    }
+
+    /// Get the tunnel type registered for a subdomain
+    pub fn get_tunnel_type(&self, subdomain: &str) -> Option<TunnelType> {
+        self.route(subdomain).map(|h| h.tunnel_type.clone())
+    }
+
+    /// Get the HTTP policy for a subdomain, if it has one
+    pub fn get_http_policy(&self, subdomain: &str) -> Option<HttpPolicy> {
+        self.route(subdomain).and_then(|h| h.http_policy.clone())
+    }
+
+    /// Whether the tunnel for a subdomain relays WebSocket upgrades
+    pub fn supports_websocket(&self, subdomain: &str) -> bool {
+        self.route(subdomain).is_some_and(|h| h.websocket)
+    }
+
+    /// Whether the tunnel for a subdomain flow-controls its TCP streams
+    pub fn uses_flow_control(&self, subdomain: &str) -> bool {
+        self.route(subdomain).is_some_and(|h| h.flow_control)
+    }
+
+    /// Get the bandwidth limiters for a subdomain (unlimited if unknown)
+    pub fn get_limiters(&self, subdomain: &str) -> TunnelLimiters {
+        self.route(subdomain)
+            .map(|h| h.limiters.clone())
+            .unwrap_or_default()
     }
 
     /// Get subdomain for a TCP port
@@ -76,9 +249,15 @@ impl Router {
         self.tcp_ports.get(&port).map(|s| s.clone())
     }
 
-    /// Check if a subdomain is available
+    /// Check if a subdomain is available (the catch-all tunnel doesn't take
+    /// subdomains, only serves them while they're free)
     pub fn is_available(&self, subdomain: &str) -> bool {
-        !self.routes.contains_key(subdomain)
+        !self.routes.contains_key(subdomain) && !self.reserved.contains(subdomain)
+    }
+
+    /// Keep `subdomain` from ever being registered by a tunnel
+    pub fn reserve(&self, subdomain: &str) {
+        self.reserved.insert(subdomain.to_string());
     }
 
     /// List all active subdomains
@@ -93,6 +272,9 @@ impl Default for Router {
         Self {
             routes: DashMap::new(),
             tcp_ports: DashMap::new(),
+            reconnect_hold: None,
+            departed: DashMap::new(),
+            reserved: DashSet::new(),
         }
     }
 }
@@ -102,3 +284,203 @@ pub enum RouterError {
     #[error("Subdomain already taken: {0}")]
     SubdomainTaken(String),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle(client_id: &str, sender: &mpsc::Sender<ServerMessage>) -> TunnelHandle {
+        TunnelHandle {
+            sender: sender.clone(),
+            client_id: client_id.to_string(),
+            tunnel_type: TunnelType::Http,
+            base_domain: "tunnel.example.com".to_string(),
+            dns_record_id: Some(format!("record-{}", client_id)),
+            http_policy: None,
+            websocket: false,
+            flow_control: false,
+            tcp_listener: None,
+            limiters: TunnelLimiters::default(),
+            registered_at: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn test_unregister_by_client() {
+        let router = Router::new();
+        let (tx, _rx) = mpsc::channel(1);
+        for subdomain in ["one", "two", "three"] {
+            router
+                .register(subdomain.to_string(), handle("laptop", &tx))
+                .unwrap();
+        }
+        router
+            .register("other".to_string(), handle("ci-runner", &tx))
+            .unwrap();
+
+        let removed = router.unregister_by_client("laptop");
+        assert_eq!(removed.len(), 3);
+        assert!(removed.iter().all(|h| h.client_id == "laptop"));
+        assert!(removed
+            .iter()
+            .all(|h| h.dns_record_id.as_deref() == Some("record-laptop")));
+
+        assert_eq!(router.list_subdomains(), vec!["other".to_string()]);
+        assert!(router.unregister_by_client("laptop").is_empty());
+    }
+
+    #[test]
+    fn test_reserved_subdomain_cannot_be_registered() {
+        let router = Router::new();
+        let (tx, _rx) = mpsc::channel(1);
+        router.reserve("grafana");
+
+        assert!(!router.is_available("grafana"));
+        assert!(router
+            .register("grafana".to_string(), handle("laptop", &tx))
+            .is_err());
+        assert!(router.get_sender("grafana").is_none());
+    }
+
+    #[test]
+    fn test_exact_match_beats_catch_all() {
+        let router = Router::new();
+        let (exact_tx, _exact_rx) = mpsc::channel(1);
+        let (catch_all_tx, _catch_all_rx) = mpsc::channel(1);
+        router
+            .register(CATCH_ALL.to_string(), handle("wildcard", &catch_all_tx))
+            .unwrap();
+
+        // Only a registered tunnel takes a subdomain
+        assert!(router.is_available("myapp"));
+        router
+            .register("myapp".to_string(), handle("exact", &exact_tx))
+            .unwrap();
+
+        assert!(router.get_sender("myapp").unwrap().same_channel(&exact_tx));
+    }
+
+    #[test]
+    fn test_unknown_subdomain_hits_catch_all() {
+        let router = Router::new();
+        let (tx, _rx) = mpsc::channel(1);
+        assert!(router.get_sender("tenant-1").is_none());
+
+        router
+            .register(CATCH_ALL.to_string(), handle("wildcard", &tx))
+            .unwrap();
+        assert!(router.get_sender("tenant-1").unwrap().same_channel(&tx));
+        assert_eq!(
+            router.get_base_domain("tenant-2").as_deref(),
+            Some("tunnel.example.com")
+        );
+
+        router.unregister(CATCH_ALL);
+        assert!(router.get_sender("tenant-1").is_none());
+    }
+
+    #[test]
+    fn test_second_catch_all_denied() {
+        let router = Router::new();
+        let (tx, _rx) = mpsc::channel(1);
+        router
+            .register(CATCH_ALL.to_string(), handle("first", &tx))
+            .unwrap();
+        assert!(!router.is_available(CATCH_ALL));
+        assert!(matches!(
+            router.register(CATCH_ALL.to_string(), handle("second", &tx)),
+            Err(RouterError::SubdomainTaken(_))
+        ));
+    }
+
+    #[test]
+    fn test_unregister_connection_keeps_other_connections() {
+        let router = Router::new();
+        let (first, _rx1) = mpsc::channel(1);
+        let (second, _rx2) = mpsc::channel(1);
+        router
+            .register("a".to_string(), handle("laptop", &first))
+            .unwrap();
+        router
+            .register("b".to_string(), handle("laptop", &first))
+            .unwrap();
+        router
+            .register("c".to_string(), handle("laptop", &second))
+            .unwrap();
+
+        let mut removed: Vec<String> = router
+            .unregister_connection(&first)
+            .into_iter()
+            .map(|(subdomain, _)| subdomain)
+            .collect();
+        removed.sort();
+        assert_eq!(removed, vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(router.list_subdomains(), vec!["c".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_held_request_resumes_on_reregister() {
+        let router = Router::with_reconnect_hold(Some(Duration::from_secs(5)));
+        let (first, _rx1) = mpsc::channel(1);
+        let (second, _rx2) = mpsc::channel(1);
+        router
+            .register("app".to_string(), handle("laptop", &first))
+            .unwrap();
+        router.unregister("app");
+
+        let waiter = {
+            let router = router.clone();
+            tokio::spawn(async move { router.wait_for_reconnect("app").await })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        router
+            .register("app".to_string(), handle("laptop", &second))
+            .unwrap();
+
+        match waiter.await.unwrap() {
+            ReconnectWait::Reconnected(sender) => assert!(sender.same_channel(&second)),
+            _ => panic!("held request was not resumed"),
+        }
+        assert!(router.departed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_hold_expires() {
+        let router = Router::with_reconnect_hold(Some(Duration::from_millis(50)));
+        let (tx, _rx) = mpsc::channel(1);
+        router
+            .register("app".to_string(), handle("laptop", &tx))
+            .unwrap();
+        router.unregister("app");
+
+        assert!(matches!(
+            router.wait_for_reconnect("app").await,
+            ReconnectWait::TimedOut
+        ));
+        // Past the hold, the subdomain no longer counts as recent
+        assert!(matches!(
+            router.wait_for_reconnect("app").await,
+            ReconnectWait::NotRecent
+        ));
+        assert!(matches!(
+            router.wait_for_reconnect("never-seen").await,
+            ReconnectWait::NotRecent
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_no_hold_by_default() {
+        let router = Router::new();
+        let (tx, _rx) = mpsc::channel(1);
+        router
+            .register("app".to_string(), handle("laptop", &tx))
+            .unwrap();
+        router.unregister("app");
+
+        assert!(router.departed.is_empty());
+        assert!(matches!(
+            router.wait_for_reconnect("app").await,
+            ReconnectWait::NotRecent
+        ));
+    }
+}
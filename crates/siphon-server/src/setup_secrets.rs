@@ -0,0 +1,164 @@
+//! `siphon-server setup-secrets`: store server secrets in the OS keychain
+//!
+//! Reads the certificate, key, CA and (optionally) the Cloudflare API token
+//! from files, stores them under the `siphon-server` keychain service, and
+//! points the config file at the resulting `keychain://` references.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use toml_edit::{value, DocumentMut, Item, Table};
+
+/// Keychain service the server secrets are stored under
+pub const KEYCHAIN_SERVICE: &str = "siphon-server";
+
+/// Files to import into the keychain
+#[derive(Debug)]
+pub struct SecretFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub ca: PathBuf,
+    pub key_passphrase: Option<PathBuf>,
+    pub cloudflare_token: Option<PathBuf>,
+}
+
+/// Store the secrets in the keychain and update the config file to reference them
+pub fn run(files: &SecretFiles, config_path: &Path) -> Result<()> {
+    let mut config = load_config(config_path)?;
+
+    let mut entries = vec![
+        ("cert", "cert", &files.cert),
+        ("key", "key", &files.key),
+        ("ca_cert", "ca", &files.ca),
+    ];
+    if let Some(path) = &files.key_passphrase {
+        entries.push(("key_passphrase", "key-passphrase", path));
+    }
+
+    for (field, name, path) in entries {
+        let uri = store_file(name, path)?;
+        config[field] = value(uri);
+    }
+
+    if let Some(path) = &files.cloudflare_token {
+        let uri = store_file("cloudflare-token", path)?;
+        let cloudflare = config
+            .entry("cloudflare")
+            .or_insert_with(|| Item::Table(Table::new()))
+            .as_table_like_mut()
+            .context("'cloudflare' in the config file is not a table")?;
+        cloudflare.insert("api_token", value(uri));
+    }
+
+    std::fs::write(config_path, config.to_string())
+        .with_context(|| format!("Failed to write {}", config_path.display()))?;
+
+    println!("Updated {}", config_path.display());
+    if !config.contains_key("base_domain") {
+        println!("Set base_domain (and the rest of the settings) before starting the server");
+    }
+
+    Ok(())
+}
+
+/// Load an existing config file for editing, or start an empty one
+///
+/// Only the secret fields are replaced, so the rest of the file keeps its
+/// comments and formatting.
+fn load_config(path: &Path) -> Result<DocumentMut> {
+    if !path.exists() {
+        return Ok(DocumentMut::new());
+    }
+
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    content
+        .parse()
+        .with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+/// Store a file's content in the keychain and verify it can be read back
+fn store_file(name: &str, path: &Path) -> Result<String> {
+    let value = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let value = value.trim();
+    if value.is_empty() {
+        anyhow::bail!("{} is empty", path.display());
+    }
+
+    siphon_secrets::keychain::store(KEYCHAIN_SERVICE, name, None, value)
+        .with_context(|| format!("Failed to store {} in the keychain", name))?;
+
+    let stored =
+        siphon_secrets::keychain::resolve(KEYCHAIN_SERVICE, name, None).with_context(|| {
+            format!(
+                "Stored {} but could not read it back from the keychain",
+                name
+            )
+        })?;
+    if stored.trim() != value {
+        anyhow::bail!("Keychain returned a different value for {}", name);
+    }
+
+    println!(
+        "Stored {} in keychain ({}/{})",
+        name, KEYCHAIN_SERVICE, name
+    );
+    Ok(format!("keychain://{}/{}", KEYCHAIN_SERVICE, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_config_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = load_config(&dir.path().join("server.toml")).unwrap();
+        assert!(config.is_empty());
+    }
+
+    #[test]
+    fn test_load_config_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.toml");
+        std::fs::write(&path, "base_domain = \"tunnel.example.com\"\n").unwrap();
+
+        let config = load_config(&path).unwrap();
+        assert_eq!(config["base_domain"].as_str(), Some("tunnel.example.com"));
+    }
+
+    #[test]
+    fn test_edit_keeps_comments() {
+        let mut config: DocumentMut = "# Public hostname\nbase_domain = \"tunnel.example.com\"\ncert = \"/etc/siphon/cert.pem\" # old\n\n[cloudflare]\n# Zone for base_domain\nzone_id = \"abc\"\n"
+            .parse()
+            .unwrap();
+        config["cert"] = value("keychain://siphon-server/cert");
+        config["cloudflare"].as_table_like_mut().unwrap().insert(
+            "api_token",
+            value("keychain://siphon-server/cloudflare-token"),
+        );
+
+        let content = config.to_string();
+        assert!(
+            content.contains("# Public hostname\nbase_domain"),
+            "{}",
+            content
+        );
+        assert!(
+            content.contains("# Zone for base_domain\nzone_id"),
+            "{}",
+            content
+        );
+        assert!(
+            content.contains("cert = \"keychain://siphon-server/cert\""),
+            "{}",
+            content
+        );
+        assert!(
+            content.contains("api_token = \"keychain://siphon-server/cloudflare-token\""),
+            "{}",
+            content
+        );
+    }
+}
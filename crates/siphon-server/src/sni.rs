@@ -0,0 +1,182 @@
+//! Minimal TLS ClientHello parser for SNI-based routing
+//!
+//! Only reads as much of the first TLS record as needed to find the
+//! `server_name` extension; the bytes are forwarded untouched afterwards.
+
+/// Largest ClientHello record we are willing to buffer (TLS record header + max plaintext)
+pub const MAX_CLIENT_HELLO_LEN: usize = 5 + 16 * 1024;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// Outcome of parsing the start of a TLS stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SniResult {
+    /// The server name requested by the client (lowercased)
+    Found(String),
+    /// More bytes are needed to finish parsing the ClientHello
+    Incomplete,
+    /// Valid ClientHello without a server name
+    Missing,
+    /// Not a TLS ClientHello
+    Invalid,
+}
+
+/// Extract the SNI host name from the beginning of a TLS stream
+pub fn parse_sni(buf: &[u8]) -> SniResult {
+    // TLS record header: type(1) version(2) length(2)
+    if buf.len() < 5 {
+        return SniResult::Incomplete;
+    }
+    if buf[0] != CONTENT_TYPE_HANDSHAKE {
+        return SniResult::Invalid;
+    }
+    let record_len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    if 5 + record_len > MAX_CLIENT_HELLO_LEN {
+        return SniResult::Invalid;
+    }
+    let Some(record) = buf.get(5..5 + record_len) else {
+        return SniResult::Incomplete;
+    };
+
+    match parse_client_hello(record) {
+        Some(Some(name)) => SniResult::Found(name),
+        Some(None) => SniResult::Missing,
+        None => SniResult::Invalid,
+    }
+}
+
+/// Parse a ClientHello handshake message, returning None if malformed
+fn parse_client_hello(record: &[u8]) -> Option<Option<String>> {
+    let mut r = Reader(record);
+
+    if r.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    let body_len = r.u24()?;
+    let mut r = Reader(r.take(body_len)?);
+
+    r.take(2)?; // client_version
+    r.take(32)?; // random
+    let session_id_len = r.u8()? as usize;
+    r.take(session_id_len)?;
+    let cipher_suites_len = r.u16()? as usize;
+    r.take(cipher_suites_len)?;
+    let compression_len = r.u8()? as usize;
+    r.take(compression_len)?;
+
+    if r.0.is_empty() {
+        // No extensions at all
+        return Some(None);
+    }
+
+    let extensions_len = r.u16()? as usize;
+    let mut extensions = Reader(r.take(extensions_len)?);
+    while !extensions.0.is_empty() {
+        let ext_type = extensions.u16()?;
+        let ext_len = extensions.u16()? as usize;
+        let ext_data = extensions.take(ext_len)?;
+        if ext_type == EXTENSION_SERVER_NAME {
+            return parse_server_name(ext_data).map(Some);
+        }
+    }
+
+    Some(None)
+}
+
+/// Parse the server_name extension body
+fn parse_server_name(data: &[u8]) -> Option<String> {
+    let mut r = Reader(data);
+    let list_len = r.u16()? as usize;
+    let mut list = Reader(r.take(list_len)?);
+
+    while !list.0.is_empty() {
+        let name_type = list.u8()?;
+        let name_len = list.u16()? as usize;
+        let name = list.take(name_len)?;
+        if name_type == NAME_TYPE_HOST_NAME {
+            let name = std::str::from_utf8(name).ok()?;
+            return Some(name.to_ascii_lowercase());
+        }
+    }
+
+    None
+}
+
+/// Cursor over a byte slice with big-endian integer reads
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    /// Produce a real ClientHello for `server_name` using rustls
+    fn client_hello(server_name: &str) -> Vec<u8> {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        let name = server_name.to_string().try_into().unwrap();
+        let mut conn = rustls::ClientConnection::new(Arc::new(config), name).unwrap();
+
+        let mut hello = Vec::new();
+        conn.write_tls(&mut hello).unwrap();
+        hello
+    }
+
+    #[test]
+    fn test_parse_sni_from_client_hello() {
+        let hello = client_hello("App.Tunnel.Example.com");
+        assert_eq!(
+            parse_sni(&hello),
+            SniResult::Found("app.tunnel.example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_sni_incomplete() {
+        let hello = client_hello("app.tunnel.example.com");
+        assert_eq!(parse_sni(&hello[..3]), SniResult::Incomplete);
+        assert_eq!(parse_sni(&hello[..hello.len() - 1]), SniResult::Incomplete);
+    }
+
+    #[test]
+    fn test_parse_sni_missing() {
+        // IP addresses are never sent as SNI
+        let hello = client_hello("127.0.0.1");
+        assert_eq!(parse_sni(&hello), SniResult::Missing);
+    }
+
+    #[test]
+    fn test_parse_sni_not_tls() {
+        assert_eq!(parse_sni(b"GET / HTTP/1.1\r\n\r\n"), SniResult::Invalid);
+    }
+}
@@ -1,34 +1,83 @@
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
+use bytes::Bytes;
 use dashmap::DashMap;
 use parking_lot::RwLock;
+use siphon_common::flow_control::{SendWindow, StreamQueue};
+use siphon_protocol::ServerMessage;
 use tokio::sync::{mpsc, oneshot};
 
 /// Data for an HTTP response from a tunnel client
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct HttpResponseData {
     pub status: u16,
     pub headers: Vec<(String, String)>,
     pub body: Vec<u8>,
+    /// Client whose connection answered
+    pub client_id: String,
+}
+
+/// An HTTP request waiting for its response
+pub struct PendingResponse {
+    /// Delivers the response to the waiting HTTP handler
+    pub sender: oneshot::Sender<HttpResponseData>,
+    /// Control connection the request was sent over, the only one allowed to answer
+    pub tunnel: mpsc::Sender<ServerMessage>,
 }
 
 /// Shared registry for pending HTTP responses
-/// Maps stream_id -> response sender channel
-pub type ResponseRegistry = Arc<DashMap<u64, oneshot::Sender<HttpResponseData>>>;
+/// Maps stream_id -> pending response
+pub type ResponseRegistry = Arc<DashMap<u64, PendingResponse>>;
 
 /// Create a new response registry
 pub fn new_response_registry() -> ResponseRegistry {
     Arc::new(DashMap::new())
 }
 
+/// Why a response from a client can't be delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrphanResponse {
+    /// Nothing waits for the stream: already answered, timed out, or never sent
+    Unknown,
+    /// The request was sent over another client's connection
+    Foreign,
+}
+
+/// Take the pending response for `stream_id` if `tunnel` is the connection it was sent over
+///
+/// Stream IDs are shared by all tunnels, so a response is only accepted from
+/// the connection that got the request; anything else is left untouched.
+pub fn take_pending_response(
+    registry: &ResponseRegistry,
+    stream_id: u64,
+    tunnel: &mpsc::Sender<ServerMessage>,
+) -> Result<oneshot::Sender<HttpResponseData>, OrphanResponse> {
+    match registry.remove_if(&stream_id, |_, pending| pending.tunnel.same_channel(tunnel)) {
+        Some((_, pending)) => Ok(pending.sender),
+        None if registry.contains_key(&stream_id) => Err(OrphanResponse::Foreign),
+        None => Err(OrphanResponse::Unknown),
+    }
+}
+
 /// Handle to a TCP connection's write half and associated data
 pub struct TcpConnectionHandle {
-    pub writer: mpsc::Sender<Vec<u8>>,
+    pub writer: StreamQueue,
+    /// Credit left to send to the client (flow-controlled tunnels only)
+    pub send_window: Option<Arc<SendWindow>>,
     #[allow(dead_code)]
     pub subdomain: String,
 }
 
+impl Drop for TcpConnectionHandle {
+    /// A connection leaving the registry is closed: stop waiting for credit
+    fn drop(&mut self) {
+        if let Some(window) = &self.send_window {
+            window.close();
+        }
+    }
+}
+
 /// Shared registry for TCP connections
 /// Maps stream_id -> TCP connection handle
 pub type TcpConnectionRegistry = Arc<DashMap<u64, TcpConnectionHandle>>;
@@ -38,27 +87,41 @@ pub fn new_tcp_connection_registry() -> TcpConnectionRegistry {
     Arc::new(DashMap::new())
 }
 
+/// Shared registry for upgraded WebSocket connections
+/// Maps stream_id -> sender of bytes to write to the visitor
+pub type WsConnectionRegistry = Arc<DashMap<u64, mpsc::Sender<Bytes>>>;
+
+/// Create a new WebSocket connection registry
+pub fn new_ws_connection_registry() -> WsConnectionRegistry {
+    Arc::new(DashMap::new())
+}
+
 /// Port allocator for TCP tunnels
 pub struct PortAllocator {
     start: u16,
     end: u16,
     allocated: RwLock<std::collections::HashSet<u16>>,
+    /// Ports in the range left to other services, never allocated
+    reserved: RwLock<std::collections::HashSet<u16>>,
 }
 
 impl PortAllocator {
-    pub fn new(start: u16, end: u16) -> Arc<Self> {
+    /// Allocator for the range, skipping the `excluded` ports in it
+    pub fn with_exclusions(start: u16, end: u16, excluded: &[u16]) -> Arc<Self> {
         Arc::new(Self {
             start,
             end,
             allocated: RwLock::new(std::collections::HashSet::new()),
+            reserved: RwLock::new(excluded.iter().copied().collect()),
         })
     }
 
     /// Allocate the next available port
     pub fn allocate(&self) -> Option<u16> {
         let mut allocated = self.allocated.write();
+        let reserved = self.reserved.read();
         for port in self.start..=self.end {
-            if !allocated.contains(&port) {
+            if !allocated.contains(&port) && !reserved.contains(&port) {
                 allocated.insert(port);
                 return Some(port);
             }
@@ -66,7 +129,22 @@ impl PortAllocator {
         None
     }
 
-    /// Release a port back to the pool
+    /// Never allocate `port` again
+    ///
+    /// A tunnel already holding it keeps it until released.
+    pub fn reserve(&self, port: u16) {
+        self.reserved.write().insert(port);
+    }
+
+    /// Allocate the next available port, released again when the lease is dropped
+    pub fn lease(self: &Arc<Self>) -> Option<PortLease> {
+        self.allocate().map(|port| PortLease {
+            port,
+            allocator: self.clone(),
+        })
+    }
+
+    /// Release a port back to the pool (reserved ports stay out of it)
     pub fn release(&self, port: u16) {
         let mut allocated = self.allocated.write();
         allocated.remove(&port);
@@ -79,6 +157,27 @@ impl PortAllocator {
     }
 }
 
+/// A port taken from a [`PortAllocator`]
+///
+/// The port is released on drop, so whoever owns the lease (e.g. a listener
+/// task) gives it back however it ends, including by panicking.
+pub struct PortLease {
+    port: u16,
+    allocator: Arc<PortAllocator>,
+}
+
+impl PortLease {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl Drop for PortLease {
+    fn drop(&mut self) {
+        self.allocator.release(self.port);
+    }
+}
+
 /// Global stream ID counter shared across all planes
 pub struct StreamIdGenerator {
     counter: AtomicU64,
@@ -103,3 +202,100 @@ impl Default for StreamIdGenerator {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_pending_response_checks_connection() {
+        let registry = new_response_registry();
+        let (owner, _owner_rx) = mpsc::channel(1);
+        let (other, _other_rx) = mpsc::channel(1);
+        let (response_tx, _response_rx) = oneshot::channel();
+        registry.insert(
+            7,
+            PendingResponse {
+                sender: response_tx,
+                tunnel: owner.clone(),
+            },
+        );
+
+        // Another client can't answer it, and doesn't take it away from the owner
+        assert_eq!(
+            take_pending_response(&registry, 7, &other).err(),
+            Some(OrphanResponse::Foreign)
+        );
+        assert!(take_pending_response(&registry, 7, &owner).is_ok());
+
+        // A second answer finds nothing waiting
+        assert_eq!(
+            take_pending_response(&registry, 7, &owner).err(),
+            Some(OrphanResponse::Unknown)
+        );
+        assert_eq!(
+            take_pending_response(&registry, 8, &owner).err(),
+            Some(OrphanResponse::Unknown)
+        );
+    }
+
+    #[test]
+    fn test_port_lease_released_on_drop() {
+        let allocator = PortAllocator::with_exclusions(40000, 40001, &[]);
+
+        let first = allocator.lease().unwrap();
+        let second = allocator.lease().unwrap();
+        assert_eq!((first.port(), second.port()), (40000, 40001));
+        assert!(allocator.lease().is_none());
+
+        drop(first);
+        assert!(!allocator.is_allocated(40000));
+        assert_eq!(allocator.lease().map(|l| l.port()), Some(40000));
+    }
+
+    #[test]
+    fn test_excluded_ports_never_allocated() {
+        let allocator = PortAllocator::with_exclusions(40000, 40005, &[40001, 40003, 40004, 41000]);
+
+        let ports: Vec<u16> = std::iter::from_fn(|| allocator.allocate()).collect();
+        assert_eq!(ports, [40000, 40002, 40005]);
+
+        // Releasing an excluded port doesn't put it in the pool
+        allocator.release(40003);
+        assert!(allocator.allocate().is_none());
+
+        allocator.release(40002);
+        assert_eq!(allocator.allocate(), Some(40002));
+    }
+
+    #[test]
+    fn test_reserved_port_not_reallocated() {
+        let allocator = PortAllocator::with_exclusions(40000, 40002, &[]);
+        let held = allocator.allocate().unwrap();
+
+        allocator.reserve(40001);
+        allocator.reserve(held);
+        assert_eq!(allocator.allocate(), Some(40002));
+        assert!(allocator.allocate().is_none());
+
+        // The tunnel that had it gives it back, but nobody gets it again
+        allocator.release(held);
+        assert!(!allocator.is_allocated(held));
+        assert!(allocator.allocate().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_port_lease_released_when_task_panics() {
+        let allocator = PortAllocator::with_exclusions(40000, 40000, &[]);
+        let lease = allocator.lease().unwrap();
+
+        let task = tokio::spawn(async move {
+            let _lease = lease;
+            panic!("listener task failed");
+        });
+        assert!(task.await.unwrap_err().is_panic());
+
+        assert!(!allocator.is_allocated(40000));
+        assert!(allocator.lease().is_some());
+    }
+}
@@ -0,0 +1,175 @@
+//! Subdomains proxied straight to a fixed upstream, without a tunnel client
+//!
+//! For services that already run on a reachable server: the HTTP plane
+//! forwards their requests itself, next to the tunnels. Statically routed
+//! subdomains are reserved in the router so no tunnel can claim them.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use bytes::Bytes;
+use hyper::header::{HeaderMap, CONTENT_LENGTH};
+use hyper::{Method, StatusCode};
+use reqwest::Url;
+
+use crate::control_plane::normalize_subdomain;
+use crate::response_headers::is_hop_by_hop;
+
+/// Configured `subdomain -> upstream` routes and the client that serves them
+#[derive(Debug, Clone, Default)]
+pub struct StaticRoutes {
+    routes: HashMap<String, Url>,
+    client: reqwest::Client,
+}
+
+/// An upstream's answer, ready to be relayed to the visitor
+pub struct UpstreamResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl StaticRoutes {
+    /// Validate configured `(subdomain, upstream URL)` pairs
+    pub fn parse(routes: &[(String, String)]) -> Result<Self, String> {
+        let mut parsed = HashMap::with_capacity(routes.len());
+        for (subdomain, upstream) in routes {
+            let subdomain = normalize_subdomain(subdomain)
+                .ok_or_else(|| format!("Invalid static route subdomain: {:?}", subdomain))?;
+            let url = Url::parse(upstream.trim())
+                .map_err(|e| format!("Invalid upstream for {}: {}", subdomain, e))?;
+            if !matches!(url.scheme(), "http" | "https") || !url.has_host() {
+                return Err(format!(
+                    "Invalid upstream for {}: expected an http:// or https:// URL",
+                    subdomain
+                ));
+            }
+            if parsed.insert(subdomain.clone(), url).is_some() {
+                return Err(format!("Duplicate static route for {}", subdomain));
+            }
+        }
+
+        // Upstream redirects are the visitor's to follow, not ours
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(|e| format!("Failed to build static route client: {}", e))?;
+        Ok(Self {
+            routes: parsed,
+            client,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// Statically routed subdomains
+    pub fn subdomains(&self) -> impl Iterator<Item = &str> {
+        self.routes.keys().map(String::as_str)
+    }
+
+    /// Upstream serving `subdomain`, if it is statically routed
+    pub fn upstream(&self, subdomain: &str) -> Option<&Url> {
+        self.routes.get(subdomain)
+    }
+
+    /// Send a visitor's request to `upstream`, appending its path and query
+    /// to the upstream's path
+    pub async fn forward(
+        &self,
+        upstream: &Url,
+        method: Method,
+        path_and_query: &str,
+        headers: &[(String, String)],
+        body: Bytes,
+    ) -> Result<UpstreamResponse, reqwest::Error> {
+        let mut url = upstream.to_string();
+        if url.ends_with('/') {
+            url.pop();
+        }
+        url.push_str(path_and_query);
+
+        let mut request = self.client.request(method, url).body(body);
+        for (name, value) in headers {
+            // The upstream's own Host, and connection-level headers, come from reqwest
+            if name == "host" || is_hop_by_hop(name) {
+                continue;
+            }
+            request = request.header(name, value);
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        let mut headers = response.headers().clone();
+        let hop_by_hop: Vec<_> = headers
+            .keys()
+            .filter(|name| is_hop_by_hop(name.as_str()))
+            .cloned()
+            .collect();
+        for name in hop_by_hop {
+            headers.remove(name);
+        }
+        // The body is re-sent whole, with its own length
+        headers.remove(CONTENT_LENGTH);
+        let body = response.bytes().await?;
+        Ok(UpstreamResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+/// `subdomain -> upstream` pairs, comma-separated
+impl fmt::Display for StaticRoutes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut routes: Vec<_> = self
+            .routes
+            .iter()
+            .map(|(subdomain, url)| format!("{} -> {}", subdomain, url))
+            .collect();
+        routes.sort();
+        f.write_str(&routes.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routes(routes: &[(&str, &str)]) -> Result<StaticRoutes, String> {
+        let routes: Vec<(String, String)> = routes
+            .iter()
+            .map(|(subdomain, upstream)| (subdomain.to_string(), upstream.to_string()))
+            .collect();
+        StaticRoutes::parse(&routes)
+    }
+
+    #[test]
+    fn test_parse_static_routes() {
+        let parsed = routes(&[
+            ("Grafana", "http://10.0.0.5:3000"),
+            ("docs", "https://docs.internal/site/"),
+        ])
+        .unwrap();
+        assert_eq!(
+            parsed.upstream("grafana").map(Url::as_str),
+            Some("http://10.0.0.5:3000/")
+        );
+        assert!(parsed.upstream("other").is_none());
+        assert_eq!(
+            parsed.to_string(),
+            "docs -> https://docs.internal/site/, grafana -> http://10.0.0.5:3000/"
+        );
+    }
+
+    #[test]
+    fn test_invalid_static_routes_rejected() {
+        assert!(routes(&[("a.b", "http://host")]).is_err());
+        assert!(routes(&[("-app", "http://host")]).is_err());
+        assert!(routes(&[("app", "ftp://host")]).is_err());
+        assert!(routes(&[("app", "not a url")]).is_err());
+        assert!(routes(&[("app", "http://a"), ("APP", "http://b")]).is_err());
+    }
+}
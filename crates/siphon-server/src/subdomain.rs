@@ -0,0 +1,145 @@
+//! Random subdomain generation
+
+use rand::Rng;
+
+use crate::router::Router;
+
+/// Crockford base32 alphabet (lowercase, without the ambiguous i, l, o, u)
+const ALPHABET: &[u8] = b"0123456789abcdefghjkmnpqrstvwxyz";
+
+/// Letters from the alphabet, used for the first character so that
+/// generated subdomains never start with a digit
+const LETTERS: &[u8] = b"abcdefghjkmnpqrstvwxyz";
+
+/// Default length of generated subdomains
+pub const DEFAULT_SUBDOMAIN_LENGTH: usize = 8;
+
+/// Minimum length of generated subdomains (keeps the collision rate sane)
+pub const MIN_SUBDOMAIN_LENGTH: usize = 4;
+
+/// Maximum length of a DNS label
+pub const MAX_SUBDOMAIN_LENGTH: usize = 63;
+
+/// Number of candidates to try before giving up on finding a free subdomain
+const MAX_ATTEMPTS: usize = 16;
+
+/// Generates random, DNS-valid subdomains
+#[derive(Debug, Clone)]
+pub struct SubdomainGenerator {
+    length: usize,
+}
+
+impl SubdomainGenerator {
+    /// Create a generator producing subdomains of the given length
+    ///
+    /// The length is clamped to the valid range for a DNS label.
+    pub fn new(length: usize) -> Self {
+        Self {
+            length: length.clamp(MIN_SUBDOMAIN_LENGTH, MAX_SUBDOMAIN_LENGTH),
+        }
+    }
+
+    /// Generate a random subdomain candidate drawing from `rng`
+    pub fn generate(&self, rng: &mut impl Rng) -> String {
+        let mut subdomain = String::with_capacity(self.length);
+
+        subdomain.push(LETTERS[rng.random_range(0..LETTERS.len())] as char);
+        for _ in 1..self.length {
+            subdomain.push(ALPHABET[rng.random_range(0..ALPHABET.len())] as char);
+        }
+
+        subdomain
+    }
+
+    /// Generate a subdomain that is not currently registered in the router
+    ///
+    /// Returns `None` if no free candidate was found after several attempts.
+    pub fn generate_available(&self, router: &Router) -> Option<String> {
+        self.generate_available_from(router, &mut rand::rng())
+    }
+
+    /// [`Self::generate_available`] drawing candidates from `rng`
+    fn generate_available_from(&self, router: &Router, rng: &mut impl Rng) -> Option<String> {
+        (0..MAX_ATTEMPTS)
+            .map(|_| self.generate(rng))
+            .find(|candidate| router.is_available(candidate))
+    }
+}
+
+impl Default for SubdomainGenerator {
+    fn default() -> Self {
+        Self::new(DEFAULT_SUBDOMAIN_LENGTH)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::control_plane::is_valid_subdomain;
+
+    #[test]
+    fn test_generated_subdomains_are_valid_and_unique() {
+        let generator = SubdomainGenerator::default();
+        let mut rng = rand::rng();
+        let mut seen = HashSet::new();
+
+        for _ in 0..10_000 {
+            let subdomain = generator.generate(&mut rng);
+            assert_eq!(subdomain.len(), DEFAULT_SUBDOMAIN_LENGTH);
+            assert!(is_valid_subdomain(&subdomain), "invalid: {}", subdomain);
+            assert!(subdomain.as_bytes()[0].is_ascii_lowercase());
+            assert!(seen.insert(subdomain), "duplicate subdomain generated");
+        }
+    }
+
+    #[test]
+    fn test_length_is_clamped() {
+        assert_eq!(SubdomainGenerator::new(1).length, MIN_SUBDOMAIN_LENGTH);
+        assert_eq!(SubdomainGenerator::new(100).length, MAX_SUBDOMAIN_LENGTH);
+        assert_eq!(
+            SubdomainGenerator::new(12).generate(&mut rand::rng()).len(),
+            12
+        );
+    }
+
+    /// The first `n` candidates a generator seeded with `seed` produces
+    fn candidates(generator: &SubdomainGenerator, seed: u64, n: usize) -> Vec<String> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..n).map(|_| generator.generate(&mut rng)).collect()
+    }
+
+    #[test]
+    fn test_generate_available_skips_taken() {
+        let router = Router::new();
+        let generator = SubdomainGenerator::new(MIN_SUBDOMAIN_LENGTH);
+        let candidates = candidates(&generator, 7, 4);
+        for taken in &candidates[..3] {
+            router.reserve(taken);
+        }
+
+        let subdomain = generator
+            .generate_available_from(&router, &mut StdRng::seed_from_u64(7))
+            .unwrap();
+        assert_eq!(subdomain, candidates[3]);
+        assert!(router.is_available(&subdomain));
+    }
+
+    #[test]
+    fn test_generate_available_gives_up_when_all_taken() {
+        let router = Router::new();
+        let generator = SubdomainGenerator::new(MIN_SUBDOMAIN_LENGTH);
+        for taken in candidates(&generator, 7, MAX_ATTEMPTS) {
+            router.reserve(&taken);
+        }
+
+        assert_eq!(
+            generator.generate_available_from(&router, &mut StdRng::seed_from_u64(7)),
+            None
+        );
+    }
+}
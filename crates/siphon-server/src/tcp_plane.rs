@@ -1,48 +1,164 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
+use bytes::BytesMut;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
 
-use siphon_protocol::ServerMessage;
+use siphon_common::flow_control::{stream_queue, ReceiveWindow, SendWindow, StreamQueue};
+use siphon_common::SocketOptions;
+use siphon_protocol::{ServerMessage, TunnelType};
 
+use crate::base_domains::BaseDomains;
+use crate::proxy_protocol::read_proxy_header;
 use crate::router::Router;
+use crate::sni::{parse_sni, SniResult, MAX_CLIENT_HELLO_LEN};
 use crate::state::{PortAllocator, StreamIdGenerator, TcpConnectionHandle, TcpConnectionRegistry};
 
+/// How long a client on the shared TCP port has to send its TLS ClientHello
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most bytes read from a visitor connection into one data frame
+pub(crate) const READ_CHUNK: usize = 8192;
+
+/// Listener for a tunnel's dedicated TCP port
+///
+/// Dropping the handle stops the listener. The port goes back to the
+/// allocator once the listener task has ended, however it ended.
+pub struct TcpListenerHandle {
+    port: u16,
+    task: AbortHandle,
+}
+
+impl TcpListenerHandle {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl Drop for TcpListenerHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Tunable behavior of the TCP plane
+#[derive(Debug, Clone, Default)]
+pub struct TcpPlaneOptions {
+    /// Applied to every visitor connection
+    pub socket_options: SocketOptions,
+    /// Expect a PROXY protocol header on every connection and take the
+    /// visitor's address from it (only behind a load balancer sending one)
+    pub proxy_protocol: bool,
+}
+
 /// TCP data plane for direct TCP tunnel connections
 pub struct TcpPlane {
     router: Arc<Router>,
     port_allocator: Arc<PortAllocator>,
     tcp_registry: TcpConnectionRegistry,
     stream_id_gen: Arc<StreamIdGenerator>,
+    options: TcpPlaneOptions,
 }
 
 impl TcpPlane {
+    #[allow(dead_code)]
     pub fn new(
         router: Arc<Router>,
         port_allocator: Arc<PortAllocator>,
         tcp_registry: TcpConnectionRegistry,
         stream_id_gen: Arc<StreamIdGenerator>,
+    ) -> Arc<Self> {
+        Self::with_socket_options(
+            router,
+            port_allocator,
+            tcp_registry,
+            stream_id_gen,
+            SocketOptions::default(),
+        )
+    }
+
+    /// Create a TCP plane applying `socket_options` to every visitor connection
+    #[allow(dead_code)]
+    pub fn with_socket_options(
+        router: Arc<Router>,
+        port_allocator: Arc<PortAllocator>,
+        tcp_registry: TcpConnectionRegistry,
+        stream_id_gen: Arc<StreamIdGenerator>,
+        socket_options: SocketOptions,
+    ) -> Arc<Self> {
+        Self::with_options(
+            router,
+            port_allocator,
+            tcp_registry,
+            stream_id_gen,
+            TcpPlaneOptions {
+                socket_options,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Create a TCP plane with non-default options
+    pub fn with_options(
+        router: Arc<Router>,
+        port_allocator: Arc<PortAllocator>,
+        tcp_registry: TcpConnectionRegistry,
+        stream_id_gen: Arc<StreamIdGenerator>,
+        options: TcpPlaneOptions,
     ) -> Arc<Self> {
         Arc::new(Self {
             router,
             port_allocator,
             tcp_registry,
             stream_id_gen,
+            options,
         })
     }
 
+    /// The visitor's address: the connection's peer, or the one in its PROXY header
+    ///
+    /// Fails on a missing or malformed header when the PROXY protocol is expected.
+    async fn visitor_addr(
+        &self,
+        stream: &mut TcpStream,
+        peer_addr: SocketAddr,
+    ) -> Result<SocketAddr> {
+        if !self.options.proxy_protocol {
+            return Ok(peer_addr);
+        }
+        Ok(read_proxy_header(stream).await?.unwrap_or(peer_addr))
+    }
+
     /// Allocate a port and start listening for TCP connections
-    pub async fn allocate_and_listen(self: Arc<Self>, subdomain: String) -> Result<u16> {
-        let port = self
-            .port_allocator
-            .allocate()
-            .ok_or_else(|| anyhow::anyhow!("No available ports"))?;
+    pub async fn allocate_and_listen(
+        self: Arc<Self>,
+        subdomain: String,
+    ) -> Result<TcpListenerHandle> {
+        let (lease, addr, listener) = loop {
+            let lease = self
+                .port_allocator
+                .lease()
+                .ok_or_else(|| anyhow::anyhow!("No available ports"))?;
 
-        let addr: SocketAddr = format!("0.0.0.0:{}", port).parse()?;
-        let listener = TcpListener::bind(addr).await?;
+            let addr: SocketAddr = format!("0.0.0.0:{}", lease.port()).parse()?;
+            match TcpListener::bind(addr).await {
+                Ok(listener) => break (lease, addr, listener),
+                // Another service holds the port: leave it to that one from now on
+                Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+                    tracing::warn!(
+                        "TCP port {} is in use, no longer allocating it",
+                        addr.port()
+                    );
+                    self.port_allocator.reserve(addr.port());
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let port = lease.port();
 
         tracing::info!(
             "TCP plane listening on {} for subdomain {}",
@@ -53,8 +169,9 @@ impl TcpPlane {
         let this = self.clone();
         let subdomain_clone = subdomain.clone();
 
-        // Spawn listener task
-        tokio::spawn(async move {
+        // Spawn listener task, which owns the port lease
+        let task = tokio::spawn(async move {
+            let _lease = lease;
             loop {
                 match listener.accept().await {
                     Ok((stream, peer_addr)) => {
@@ -66,7 +183,22 @@ impl TcpPlane {
                         let this = this.clone();
                         let subdomain = subdomain_clone.clone();
                         tokio::spawn(async move {
-                            if let Err(e) = this.handle_tcp_connection(stream, subdomain).await {
+                            let mut stream = stream;
+                            let peer_addr = match this.visitor_addr(&mut stream, peer_addr).await {
+                                Ok(addr) => addr,
+                                Err(e) => {
+                                    tracing::warn!(
+                                        "Rejected TCP connection from {}: {}",
+                                        peer_addr,
+                                        e
+                                    );
+                                    return;
+                                }
+                            };
+                            if let Err(e) = this
+                                .handle_tcp_connection(stream, peer_addr, subdomain, Vec::new())
+                                .await
+                            {
                                 tracing::error!("TCP connection error: {}", e);
                             }
                         });
@@ -79,15 +211,110 @@ impl TcpPlane {
             }
         });
 
-        Ok(port)
+        Ok(TcpListenerHandle {
+            port,
+            task: task.abort_handle(),
+        })
+    }
+
+    /// Serve all TCP tunnels on one shared port, routing by TLS SNI
+    ///
+    /// Clients must speak TLS and send `<subdomain>.<base_domain>` as the
+    /// server name; the stream itself is forwarded untouched.
+    pub async fn run_mux(
+        self: Arc<Self>,
+        addr: SocketAddr,
+        base_domains: BaseDomains,
+    ) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        tracing::info!("TCP mux listening on {}", addr);
+        self.run_mux_with_listener(listener, base_domains).await
+    }
+
+    /// Serve the shared SNI-routed TCP port from a pre-bound listener
+    pub async fn run_mux_with_listener(
+        self: Arc<Self>,
+        listener: TcpListener,
+        base_domains: BaseDomains,
+    ) -> Result<()> {
+        let base_domains = Arc::new(base_domains);
+        loop {
+            let (stream, peer_addr) = listener.accept().await?;
+            let this = self.clone();
+            let base_domains = base_domains.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = this
+                    .handle_mux_connection(stream, peer_addr, &base_domains)
+                    .await
+                {
+                    tracing::warn!("TCP mux connection from {} failed: {}", peer_addr, e);
+                }
+            });
+        }
+    }
+
+    /// Read the ClientHello of a shared-port connection and hand it to its tunnel
+    async fn handle_mux_connection(
+        self: Arc<Self>,
+        mut stream: TcpStream,
+        peer_addr: SocketAddr,
+        base_domains: &BaseDomains,
+    ) -> Result<()> {
+        // The balancer's header comes before the ClientHello
+        let peer_addr = self.visitor_addr(&mut stream, peer_addr).await?;
+
+        let mut initial = Vec::with_capacity(1024);
+        let server_name = tokio::time::timeout(CLIENT_HELLO_TIMEOUT, async {
+            loop {
+                match parse_sni(&initial) {
+                    SniResult::Found(name) => return Ok(name),
+                    SniResult::Missing => anyhow::bail!("ClientHello has no server name"),
+                    SniResult::Invalid => anyhow::bail!("not a TLS ClientHello"),
+                    SniResult::Incomplete => {}
+                }
+                if initial.len() >= MAX_CLIENT_HELLO_LEN
+                    || stream.read_buf(&mut initial).await? == 0
+                {
+                    anyhow::bail!("connection closed before ClientHello completed");
+                }
+            }
+        })
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out waiting for ClientHello"))??;
+
+        let (subdomain, base_domain) = base_domains
+            .split(&server_name)
+            .filter(|(subdomain, base_domain)| {
+                server_name.len() == subdomain.len() + 1 + base_domain.len()
+            })
+            .ok_or_else(|| anyhow::anyhow!("server name {} is not a tunnel", server_name))?;
+        let subdomain = subdomain.to_string();
+
+        if self.router.get_tunnel_type(&subdomain) != Some(TunnelType::Tcp)
+            || self.router.get_base_domain(&subdomain).as_deref() != Some(base_domain)
+        {
+            anyhow::bail!("no TCP tunnel for {}", server_name);
+        }
+
+        tracing::info!("TCP mux connection for subdomain {}", subdomain);
+        self.handle_tcp_connection(stream, peer_addr, subdomain, initial)
+            .await
     }
 
     /// Handle an incoming TCP connection
+    ///
+    /// `peer_addr` is the visitor's address reported to the client. `initial`
+    /// holds bytes already read from the stream (e.g. a peeked ClientHello)
+    /// that are sent to the tunnel before anything else.
     async fn handle_tcp_connection(
         self: Arc<Self>,
         stream: TcpStream,
+        peer_addr: SocketAddr,
         subdomain: String,
+        initial: Vec<u8>,
     ) -> Result<()> {
+        self.options.socket_options.apply_or_warn(&stream);
         let stream_id = self.stream_id_gen.next();
         tracing::debug!("New TCP stream {} for subdomain {}", stream_id, subdomain);
 
@@ -100,24 +327,33 @@ impl TcpPlane {
             }
         };
 
+        let peer_addr = Some(peer_addr.to_string());
+        let limiters = self.router.get_limiters(&subdomain);
+        let flow_control = self.router.uses_flow_control(&subdomain);
+
         // Split the stream
         let (mut read_half, mut write_half) = stream.into_split();
 
-        // Create channel for writing data back to this TCP connection
-        let (write_tx, mut write_rx) = mpsc::channel::<Vec<u8>>(32);
+        // Queue for writing data back to this TCP connection
+        let (write_tx, mut write_rx) = stream_queue(flow_control);
+        let send_window = flow_control.then(|| Arc::new(SendWindow::new()));
 
         // Register this connection
         self.tcp_registry.insert(
             stream_id,
             TcpConnectionHandle {
                 writer: write_tx,
+                send_window: send_window.clone(),
                 subdomain: subdomain.clone(),
             },
         );
 
         // Send TcpConnect to client
         if let Err(e) = tunnel_sender
-            .send(ServerMessage::TcpConnect { stream_id })
+            .send(ServerMessage::TcpConnect {
+                stream_id,
+                peer_addr,
+            })
             .await
         {
             tracing::error!("Failed to send TcpConnect: {}", e);
@@ -125,15 +361,40 @@ impl TcpPlane {
             return Ok(());
         }
 
+        // Replay bytes consumed while routing the connection
+        limiters.ingress(initial.len()).await;
+        if !initial.is_empty()
+            && (!reserve(&send_window, initial.len()).await
+                || tunnel_sender
+                    .send(ServerMessage::TcpData {
+                        stream_id,
+                        data: initial.into(),
+                    })
+                    .await
+                    .is_err())
+        {
+            self.tcp_registry.remove(&stream_id);
+            return Ok(());
+        }
+
         // Spawn write task (receives data from tunnel client, writes to TCP)
         let tcp_registry = self.tcp_registry.clone();
         let tunnel_sender_clone = tunnel_sender.clone();
+        let egress = limiters.clone();
         let write_task = tokio::spawn(async move {
+            let mut window = ReceiveWindow::default();
             while let Some(data) = write_rx.recv().await {
+                egress.egress(data.len()).await;
                 if let Err(e) = write_half.write_all(&data).await {
                     tracing::error!("Failed to write to TCP stream {}: {}", stream_id, e);
                     break;
                 }
+                // Let the client send more once the visitor took what it sent
+                if let Some(bytes) = window.written(data.len()).filter(|_| flow_control) {
+                    let _ = tunnel_sender_clone
+                        .send(ServerMessage::TcpWindowUpdate { stream_id, bytes })
+                        .await;
+                }
             }
             // Connection closed, send TcpClose
             let _ = tunnel_sender_clone
@@ -142,17 +403,25 @@ impl TcpPlane {
             tcp_registry.remove(&stream_id);
         });
 
-        // Read from TCP, send to tunnel
-        let mut buf = vec![0u8; 8192];
+        // Read from TCP, send to tunnel. The buffer is reused across reads:
+        // frames share it without a copy, and `reserve` reclaims it once they
+        // have been sent.
+        let mut buf = BytesMut::with_capacity(READ_CHUNK);
         loop {
-            match read_half.read(&mut buf).await {
+            buf.reserve(READ_CHUNK);
+            match read_half.read_buf(&mut buf).await {
                 Ok(0) => {
                     // EOF
                     tracing::debug!("TCP stream {} closed by remote", stream_id);
                     break;
                 }
                 Ok(n) => {
-                    let data = buf[..n].to_vec();
+                    limiters.ingress(n).await;
+                    // Out of credit: only this stream waits for the client
+                    if !reserve(&send_window, n).await {
+                        break;
+                    }
+                    let data = buf.split_to(n).freeze();
                     if let Err(e) = tunnel_sender
                         .send(ServerMessage::TcpData { stream_id, data })
                         .await
@@ -180,14 +449,20 @@ impl TcpPlane {
         Ok(())
     }
 
-    /// Release a port when tunnel is closed
-    pub fn release_port(&self, port: u16) {
-        self.port_allocator.release(port);
+    /// Get write queue for a stream
+    pub fn get_writer(&self, stream_id: u64) -> Option<StreamQueue> {
+        self.tcp_registry.get(&stream_id).map(|h| h.writer.clone())
     }
 
-    /// Get write channel for a stream
-    pub fn get_writer(&self, stream_id: u64) -> Option<mpsc::Sender<Vec<u8>>> {
-        self.tcp_registry.get(&stream_id).map(|h| h.writer.clone())
+    /// Credit a stream with `bytes` more it may send to the client
+    pub fn grant_window(&self, stream_id: u64, bytes: u64) {
+        if let Some(window) = self
+            .tcp_registry
+            .get(&stream_id)
+            .and_then(|h| h.send_window.clone())
+        {
+            window.grant(bytes);
+        }
     }
 
     /// Close a TCP connection
@@ -198,3 +473,94 @@ impl TcpPlane {
         }
     }
 }
+
+/// Take `bytes` from the stream's send window, if it has one
+///
+/// False once the stream is closed.
+async fn reserve(window: &Option<Arc<SendWindow>>, bytes: usize) -> bool {
+    match window {
+        Some(window) => window.reserve(bytes).await,
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::state::new_tcp_connection_registry;
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("0.0.0.0:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    #[tokio::test]
+    async fn test_port_reusable_after_listener_ends() {
+        let port = free_port();
+        let allocator = PortAllocator::with_exclusions(port, port, &[]);
+        let plane = TcpPlane::new(
+            Router::new(),
+            allocator.clone(),
+            new_tcp_connection_registry(),
+            StreamIdGenerator::new(),
+        );
+
+        let listener = plane
+            .clone()
+            .allocate_and_listen("first".to_string())
+            .await
+            .unwrap();
+        assert_eq!(listener.port(), port);
+        assert!(plane
+            .clone()
+            .allocate_and_listen("second".to_string())
+            .await
+            .is_err());
+
+        // Kill the listener task without going through tunnel cleanup
+        drop(listener);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while allocator.is_allocated(port) {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("port was not released");
+
+        // Both the allocator slot and the socket are free again
+        let listener = plane
+            .allocate_and_listen("second".to_string())
+            .await
+            .unwrap();
+        assert_eq!(listener.port(), port);
+    }
+
+    #[tokio::test]
+    async fn test_port_in_use_elsewhere_is_reserved() {
+        let other_service = std::net::TcpListener::bind("0.0.0.0:0").unwrap();
+        let port = other_service.local_addr().unwrap().port();
+        let allocator = PortAllocator::with_exclusions(port, port, &[]);
+        let plane = TcpPlane::new(
+            Router::new(),
+            allocator.clone(),
+            new_tcp_connection_registry(),
+            StreamIdGenerator::new(),
+        );
+
+        let err = plane
+            .allocate_and_listen("app".to_string())
+            .await
+            .err()
+            .expect("listened on a port in use");
+        assert!(err.to_string().contains("No available ports"), "{}", err);
+
+        // The port stays out of the pool even once the other service is gone
+        drop(other_service);
+        assert!(!allocator.is_allocated(port));
+        assert_eq!(allocator.allocate(), None);
+    }
+}
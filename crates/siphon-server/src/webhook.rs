@@ -0,0 +1,185 @@
+//! Best-effort tunnel lifecycle notifications (e.g. for a Slack or ChatOps hook)
+//!
+//! Each event is POSTed as JSON on a background task, so a slow or broken
+//! webhook never delays tunnel setup or teardown.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use reqwest::Client;
+use serde::Serialize;
+
+/// Per-attempt request timeout
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Delay before the single retry of a failed delivery
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// What happened to the tunnel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LifecycleEventKind {
+    TunnelEstablished,
+    TunnelClosed,
+}
+
+/// JSON body sent to the webhook
+#[derive(Debug, Clone, Serialize)]
+pub struct LifecycleEvent {
+    pub event: LifecycleEventKind,
+    pub subdomain: String,
+    pub client_id: String,
+    /// Public URL (`https://<subdomain>.<base_domain>`, or `host:port` for TCP)
+    pub url: String,
+    /// Unix time in seconds
+    pub timestamp: u64,
+}
+
+impl LifecycleEvent {
+    /// Build an event stamped with the current time
+    pub fn now(
+        event: LifecycleEventKind,
+        subdomain: impl Into<String>,
+        client_id: impl Into<String>,
+        url: impl Into<String>,
+    ) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Self {
+            event,
+            subdomain: subdomain.into(),
+            client_id: client_id.into(),
+            url: url.into(),
+            timestamp,
+        }
+    }
+}
+
+/// Posts tunnel lifecycle events to a configured URL
+#[derive(Debug, Clone)]
+pub struct LifecycleWebhook {
+    client: Client,
+    url: String,
+}
+
+impl LifecycleWebhook {
+    pub fn new(url: impl Into<String>) -> Self {
+        let client = Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        Self {
+            client,
+            url: url.into(),
+        }
+    }
+
+    /// Deliver an event in the background, retrying once on failure
+    pub fn notify(&self, event: LifecycleEvent) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            if let Err(first) = this.send(&event).await {
+                tracing::debug!("Lifecycle webhook failed, retrying: {}", first);
+                tokio::time::sleep(RETRY_DELAY).await;
+                if let Err(e) = this.send(&event).await {
+                    tracing::warn!(
+                        "Failed to deliver {:?} event for {} to the lifecycle webhook: {}",
+                        event.event,
+                        event.subdomain,
+                        e
+                    );
+                }
+            }
+        });
+    }
+
+    /// POST one event, treating non-2xx responses as failures
+    async fn send(&self, event: &LifecycleEvent) -> Result<(), reqwest::Error> {
+        self.client
+            .post(&self.url)
+            .json(event)
+            .send()
+            .await?
+            .error_for_status()
+            .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+    use wiremock::matchers::{body_partial_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// Wait until the mock has seen `count` requests
+    async fn wait_for_requests(server: &MockServer, count: usize) -> Vec<wiremock::Request> {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let requests = server.received_requests().await.unwrap_or_default();
+                if requests.len() >= count {
+                    return requests;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("webhook was not called")
+    }
+
+    #[tokio::test]
+    async fn test_notify_posts_event() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/hook"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&server)
+            .await;
+
+        let webhook = LifecycleWebhook::new(format!("{}/hook", server.uri()));
+        let event = LifecycleEvent::now(
+            LifecycleEventKind::TunnelEstablished,
+            "myapp",
+            "client-1",
+            "https://myapp.tunnel.example.com",
+        );
+        webhook.notify(event.clone());
+
+        let requests = wait_for_requests(&server, 1).await;
+        let body: serde_json::Value = serde_json::from_slice(&requests[0].body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "event": "tunnel_established",
+                "subdomain": "myapp",
+                "client_id": "client-1",
+                "url": "https://myapp.tunnel.example.com",
+                "timestamp": event.timestamp,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_notify_retries_once() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({ "event": "tunnel_closed" })))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&server)
+            .await;
+
+        let webhook = LifecycleWebhook::new(server.uri());
+        webhook.notify(LifecycleEvent::now(
+            LifecycleEventKind::TunnelClosed,
+            "myapp",
+            "client-1",
+            "myapp.tunnel.example.com:30001",
+        ));
+
+        wait_for_requests(&server, 2).await;
+        tokio::time::sleep(RETRY_DELAY * 2).await;
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,71 @@
+//! Color output control
+//!
+//! Colors are disabled by `--no-color` or a non-empty `NO_COLOR` environment
+//! variable (see <https://no-color.org>).
+
+use ratatui::buffer::Buffer;
+use ratatui::style::{Color, Modifier};
+
+/// Decide whether to use colors, given the `--no-color` flag
+pub fn color_enabled(no_color_flag: bool) -> bool {
+    !no_color_flag && !std::env::var("NO_COLOR").is_ok_and(|v| !v.is_empty())
+}
+
+/// Enable or disable ANSI colors for all terminal output written through crossterm
+///
+/// This covers the setup wizard and the escape sequences emitted by the
+/// dashboard backend.
+pub fn set_color_output(enabled: bool) {
+    crossterm::style::force_color_output(enabled);
+}
+
+/// Whether crossterm output currently includes colors
+pub fn color_output_enabled() -> bool {
+    !crossterm::style::Colored::ansi_color_disabled_memoized()
+}
+
+/// Strip colors from a rendered frame, keeping text modifiers
+///
+/// Cells that relied on a background color are shown reversed so they stay
+/// visible in monochrome.
+pub(crate) fn to_monochrome(buf: &mut Buffer) {
+    for cell in buf.content.iter_mut() {
+        if cell.bg != Color::Reset {
+            cell.modifier.insert(Modifier::REVERSED);
+        }
+        cell.fg = Color::Reset;
+        cell.bg = Color::Reset;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ratatui::layout::Rect;
+    use ratatui::style::Style;
+
+    #[test]
+    fn test_color_enabled_flag() {
+        assert!(!color_enabled(true));
+    }
+
+    #[test]
+    fn test_to_monochrome() {
+        let mut buf = Buffer::empty(Rect::new(0, 0, 3, 1));
+        buf.set_string(
+            0,
+            0,
+            "ab",
+            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+        );
+        buf[(2, 0)].set_bg(Color::Green);
+
+        to_monochrome(&mut buf);
+
+        assert_eq!(buf[(0, 0)].fg, Color::Reset);
+        assert!(buf[(0, 0)].modifier.contains(Modifier::BOLD));
+        assert_eq!(buf[(2, 0)].bg, Color::Reset);
+        assert!(buf[(2, 0)].modifier.contains(Modifier::REVERSED));
+    }
+}
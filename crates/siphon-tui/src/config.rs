@@ -1,12 +1,20 @@
 //! Siphon configuration management
 //!
-//! Handles loading and saving configuration to `~/.config/siphon/config.toml`
+//! Handles loading and saving configuration to `~/.config/siphon/config.toml`,
+//! and named profiles to `~/.config/siphon/profiles/<name>.toml`. The
+//! directory can be moved with `SIPHON_CONFIG_DIR`.
+//!
+//! String fields support `${VAR}` and `${VAR:-default}` interpolation from the
+//! process environment when loaded (`$$` is a literal `$`).
 //!
 //! Note: Only connection settings are stored in config. Runtime options like
 //! local address, subdomain, and tunnel type are provided via CLI arguments.
 
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+/// Environment variable overriding the config directory
+pub const CONFIG_DIR_ENV: &str = "SIPHON_CONFIG_DIR";
 
 /// Siphon client configuration (connection settings only)
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -14,23 +22,59 @@ pub struct SiphonConfig {
     /// Tunnel server address (host:port)
     pub server_addr: String,
 
+    /// Name to verify the server certificate against, if it differs from the
+    /// host in `server_addr` (e.g. when connecting through a bastion or by IP)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String>,
+
     /// Client certificate reference (keychain://siphon/cert, file path, etc.)
     pub cert: String,
 
     /// Client private key reference (keychain://siphon/key, file path, etc.)
     pub key: String,
 
-    /// CA certificate reference (keychain://siphon/ca, file path, etc.)
+    /// CA certificate reference (keychain://siphon/ca, file path, etc.), or
+    /// several comma-separated references whose PEMs are concatenated
     pub ca_cert: String,
+
+    /// Passphrase reference for an encrypted private key (keychain://siphon/key-pass, etc.)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_passphrase: Option<String>,
+
+    /// Local addresses `--local` may point at: CIDRs (`10.0.0.0/8`, `::1/128`)
+    /// or globs on the target (`localhost:*`, `unix:/run/*.sock`). Empty allows any.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_local_targets: Vec<String>,
+
+    /// Host header sent to the local service: `strip` (default), `preserve`
+    /// the public one, or `rewrite:<host>`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forward_host: Option<String>,
+
+    /// Requests forwarded to the local service at once (default: 64, 0 = unlimited)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_local_concurrency: Option<usize>,
+
+    /// Idle connections kept to the local service for reuse (default: 64, 0 = none)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_pool_max_idle: Option<usize>,
+
+    /// Seconds an idle connection to the local service is kept (default: 90)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_pool_idle_timeout_secs: Option<u64>,
 }
 
 impl SiphonConfig {
-    /// Get the default config directory path (~/.config/siphon)
+    /// Get the config directory path: `SIPHON_CONFIG_DIR` if set, else
+    /// ~/.config/siphon
     pub fn config_dir() -> PathBuf {
-        dirs::home_dir()
-            .unwrap_or_else(|| PathBuf::from("."))
-            .join(".config")
-            .join("siphon")
+        match std::env::var_os(CONFIG_DIR_ENV) {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => dirs::home_dir()
+                .unwrap_or_else(|| PathBuf::from("."))
+                .join(".config")
+                .join("siphon"),
+        }
     }
 
     /// Get the default config file path
@@ -43,11 +87,53 @@ impl SiphonConfig {
         Self::default_path().exists()
     }
 
-    /// Load configuration from a specific path
+    /// Load configuration from a specific path, interpolating environment variables
     pub fn load(path: &PathBuf) -> anyhow::Result<Self> {
         let content = std::fs::read_to_string(path)?;
         let config: Self = toml::from_str(&content)?;
-        Ok(config)
+        config.interpolate_env()
+    }
+
+    /// Substitute `${VAR}` references in string fields from the process environment
+    ///
+    /// Secret references using the `env://` backend are left untouched since
+    /// they are already resolved from the environment.
+    fn interpolate_env(self) -> anyhow::Result<Self> {
+        let lookup = |name: &str| std::env::var(name).ok();
+        let field = |name: &str, value: String| -> anyhow::Result<String> {
+            if value.starts_with("env://") {
+                return Ok(value);
+            }
+            interpolate(&value, lookup)
+                .map_err(|e| anyhow::anyhow!("Invalid value for '{}': {}", name, e))
+        };
+
+        Ok(Self {
+            server_addr: field("server_addr", self.server_addr)?,
+            server_name: self
+                .server_name
+                .map(|value| field("server_name", value))
+                .transpose()?,
+            cert: field("cert", self.cert)?,
+            key: field("key", self.key)?,
+            ca_cert: field("ca_cert", self.ca_cert)?,
+            key_passphrase: self
+                .key_passphrase
+                .map(|value| field("key_passphrase", value))
+                .transpose()?,
+            allowed_local_targets: self
+                .allowed_local_targets
+                .into_iter()
+                .map(|value| field("allowed_local_targets", value))
+                .collect::<anyhow::Result<_>>()?,
+            forward_host: self
+                .forward_host
+                .map(|value| field("forward_host", value))
+                .transpose()?,
+            max_local_concurrency: self.max_local_concurrency,
+            local_pool_max_idle: self.local_pool_max_idle,
+            local_pool_idle_timeout_secs: self.local_pool_idle_timeout_secs,
+        })
     }
 
     /// Load configuration from the default location
@@ -83,6 +169,40 @@ impl SiphonConfig {
         self.save(&Self::default_path())
     }
 
+    /// Get the directory holding named profiles (~/.config/siphon/profiles)
+    pub fn profiles_dir() -> PathBuf {
+        Self::config_dir().join("profiles")
+    }
+
+    /// Get the config file path for a named profile
+    pub fn profile_path(name: &str) -> anyhow::Result<PathBuf> {
+        validate_profile_name(name)?;
+        Ok(Self::profiles_dir().join(format!("{}.toml", name)))
+    }
+
+    /// Load configuration from a named profile
+    pub fn load_profile(name: &str) -> anyhow::Result<Self> {
+        let path = Self::profile_path(name)?;
+        if !path.exists() {
+            anyhow::bail!(
+                "Profile '{}' not found. Run 'siphon setup --profile {}' to create it",
+                name,
+                name
+            );
+        }
+        Self::load(&path)
+    }
+
+    /// Save configuration as a named profile
+    pub fn save_profile(&self, name: &str) -> anyhow::Result<()> {
+        self.save(&Self::profile_path(name)?)
+    }
+
+    /// List the names of available profiles, sorted alphabetically
+    pub fn list_profiles() -> anyhow::Result<Vec<String>> {
+        list_profiles_in(&Self::profiles_dir())
+    }
+
     /// Validate the configuration
     pub fn validate(&self) -> Result<(), Vec<String>> {
         let mut errors = Vec::new();
@@ -111,6 +231,83 @@ impl SiphonConfig {
     }
 }
 
+/// Expand `${VAR}` and `${VAR:-default}` references using `lookup`
+///
+/// `$$` produces a literal `$`, and a `$` not followed by `{` is kept as is.
+/// The default applies when the variable is unset or empty.
+fn interpolate(value: &str, lookup: impl Fn(&str) -> Option<String>) -> anyhow::Result<String> {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(pos) = rest.find('$') {
+        result.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+
+        if let Some(after) = rest.strip_prefix('$') {
+            result.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let end = after
+                .find('}')
+                .ok_or_else(|| anyhow::anyhow!("unterminated '${{' in \"{}\"", value))?;
+            let expr = &after[..end];
+            let (name, default) = match expr.split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (expr, None),
+            };
+            if name.is_empty() {
+                anyhow::bail!("empty variable name in \"{}\"", value);
+            }
+
+            match (lookup(name).filter(|v| !v.is_empty()), default) {
+                (Some(v), _) => result.push_str(&v),
+                (None, Some(default)) => result.push_str(default),
+                (None, None) => anyhow::bail!(
+                    "environment variable {} is not set (use ${{{}:-default}} for a fallback)",
+                    name,
+                    name
+                ),
+            }
+            rest = &after[end + 1..];
+        } else {
+            result.push('$');
+        }
+    }
+
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Reject profile names that would escape the profiles directory
+fn validate_profile_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() {
+        anyhow::bail!("Profile name cannot be empty");
+    }
+
+    if name.starts_with('.') || name.contains(['/', '\\']) {
+        anyhow::bail!("Invalid profile name: {}", name);
+    }
+
+    Ok(())
+}
+
+/// List profile names (file stems of `*.toml` files) in a directory
+fn list_profiles_in(dir: &Path) -> anyhow::Result<Vec<String>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut profiles: Vec<String> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "toml"))
+        .filter_map(|path| path.file_stem().map(|s| s.to_string_lossy().into_owned()))
+        .collect();
+
+    profiles.sort();
+    Ok(profiles)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -136,9 +333,16 @@ mod tests {
     fn test_config_roundtrip() {
         let config = SiphonConfig {
             server_addr: "tunnel.example.com:4443".to_string(),
+            server_name: None,
             cert: "keychain://siphon/cert".to_string(),
             key: "keychain://siphon/key".to_string(),
             ca_cert: "keychain://siphon/ca".to_string(),
+            key_passphrase: None,
+            allowed_local_targets: Vec::new(),
+            forward_host: None,
+            max_local_concurrency: None,
+            local_pool_max_idle: None,
+            local_pool_idle_timeout_secs: None,
         };
 
         let temp_file = tempfile::NamedTempFile::new().unwrap();
@@ -150,4 +354,114 @@ mod tests {
         assert_eq!(loaded.server_addr, config.server_addr);
         assert_eq!(loaded.cert, config.cert);
     }
+
+    #[test]
+    fn test_interpolate() {
+        let lookup = |name: &str| match name {
+            "REGION" => Some("eu".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+
+        assert_eq!(
+            interpolate("tunnel.${REGION}.example.com:4443", lookup).unwrap(),
+            "tunnel.eu.example.com:4443"
+        );
+        assert_eq!(interpolate("no vars", lookup).unwrap(), "no vars");
+        assert_eq!(interpolate("lone $ sign", lookup).unwrap(), "lone $ sign");
+    }
+
+    #[test]
+    fn test_interpolate_defaults() {
+        let lookup = |name: &str| (name == "EMPTY").then(String::new);
+
+        assert_eq!(interpolate("${MISSING:-us}", lookup).unwrap(), "us");
+        assert_eq!(interpolate("${EMPTY:-us}", lookup).unwrap(), "us");
+        assert_eq!(interpolate("${MISSING:-}", lookup).unwrap(), "");
+
+        let err = interpolate("${MISSING}", lookup).unwrap_err().to_string();
+        assert!(err.contains("MISSING"));
+        assert!(interpolate("${UNTERMINATED", lookup).is_err());
+        assert!(interpolate("${}", lookup).is_err());
+    }
+
+    #[test]
+    fn test_interpolate_escaped_dollar() {
+        let lookup = |_: &str| Some("x".to_string());
+
+        assert_eq!(interpolate("$${VAR}", lookup).unwrap(), "${VAR}");
+        assert_eq!(interpolate("cost: $$5", lookup).unwrap(), "cost: $5");
+    }
+
+    #[test]
+    fn test_interpolate_env_skips_env_uris() {
+        let config = SiphonConfig {
+            server_addr: "tunnel.example.com:4443".to_string(),
+            server_name: None,
+            cert: "env://${SIPHON_TEST_UNSET_CERT}".to_string(),
+            key: "keychain://siphon/key".to_string(),
+            ca_cert: "keychain://siphon/ca".to_string(),
+            key_passphrase: None,
+            allowed_local_targets: Vec::new(),
+            forward_host: None,
+            max_local_concurrency: None,
+            local_pool_max_idle: None,
+            local_pool_idle_timeout_secs: None,
+        };
+
+        let config = config.interpolate_env().unwrap();
+        assert_eq!(config.cert, "env://${SIPHON_TEST_UNSET_CERT}");
+    }
+
+    #[test]
+    fn test_profile_name_validation() {
+        assert!(validate_profile_name("work").is_ok());
+        assert!(validate_profile_name("").is_err());
+        assert!(validate_profile_name("../evil").is_err());
+        assert!(validate_profile_name(".hidden").is_err());
+    }
+
+    #[test]
+    fn test_config_dir_override() {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_var(CONFIG_DIR_ENV, dir.path());
+
+        assert_eq!(SiphonConfig::config_dir(), dir.path());
+        assert_eq!(SiphonConfig::default_path(), dir.path().join("config.toml"));
+        assert_eq!(
+            SiphonConfig::profile_path("work").unwrap(),
+            dir.path().join("profiles").join("work.toml")
+        );
+
+        let config = SiphonConfig {
+            server_addr: "tunnel.example.com:4443".to_string(),
+            ..Default::default()
+        };
+        config.save_default().unwrap();
+        config.save_profile("work").unwrap();
+        assert!(SiphonConfig::exists());
+        assert_eq!(
+            SiphonConfig::load_default().unwrap().server_addr,
+            "tunnel.example.com:4443"
+        );
+        assert_eq!(SiphonConfig::list_profiles().unwrap(), vec!["work"]);
+
+        std::env::set_var(CONFIG_DIR_ENV, "");
+        assert!(SiphonConfig::config_dir().ends_with(".config/siphon"));
+        std::env::remove_var(CONFIG_DIR_ENV);
+    }
+
+    #[test]
+    fn test_list_profiles_in() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("work.toml"), "").unwrap();
+        std::fs::write(dir.path().join("personal.toml"), "").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "").unwrap();
+
+        let profiles = list_profiles_in(dir.path()).unwrap();
+        assert_eq!(profiles, vec!["personal", "work"]);
+
+        let missing = list_profiles_in(&dir.path().join("missing")).unwrap();
+        assert!(missing.is_empty());
+    }
 }
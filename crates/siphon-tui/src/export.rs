@@ -0,0 +1,270 @@
+//! CSV export of the live request log, and Prometheus text for the counters
+//!
+//! Uses full RFC 3339 timestamps, independent of the dashboard's short
+//! `%H:%M:%S` display format, so the output can be loaded into other tools.
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use crate::metrics::{MetricsSnapshot, RequestLogEntry};
+
+/// Header row written before the first entry
+pub const REQUESTS_CSV_HEADER: &str = "timestamp,method,uri,status,duration_ms,bytes";
+
+/// Write `entries` as CSV, header row first
+pub fn write_requests_csv<'a, W, I>(mut writer: W, entries: I) -> io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a RequestLogEntry>,
+{
+    writeln!(writer, "{}", REQUESTS_CSV_HEADER)?;
+    for entry in entries {
+        writeln!(
+            writer,
+            "{},{},{},{},{},{}",
+            entry.timestamp.to_rfc3339(),
+            escape_field(&entry.method),
+            escape_field(&entry.uri),
+            entry.status,
+            entry.duration.as_millis(),
+            entry.bytes,
+        )?;
+    }
+    Ok(())
+}
+
+/// Render `entries` as a CSV string
+pub fn requests_to_csv<'a, I>(entries: I) -> String
+where
+    I: IntoIterator<Item = &'a RequestLogEntry>,
+{
+    let mut out = Vec::new();
+    write_requests_csv(&mut out, entries).expect("writing to a Vec cannot fail");
+    String::from_utf8(out).expect("CSV output is built from UTF-8 strings")
+}
+
+/// Render `snapshot` in the Prometheus text exposition format
+///
+/// Counters cover the whole run, across reconnects. Response time quantiles
+/// are computed over the last 1000 requests, like the dashboard's.
+pub fn metrics_to_prometheus(snapshot: &MetricsSnapshot) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, f64)]| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{}{} {}", name, labels, value);
+        }
+    };
+
+    if let Some(uptime) = snapshot.uptime {
+        metric(
+            "siphon_tunnel_uptime_seconds",
+            "gauge",
+            "Time since the tunnel was last established",
+            &[("", uptime.as_secs_f64())],
+        );
+    }
+    metric(
+        "siphon_reconnects_total",
+        "counter",
+        "Reconnections to the server",
+        &[("", snapshot.reconnects as f64)],
+    );
+    if let Some(rtt) = snapshot.last_ping_rtt {
+        metric(
+            "siphon_ping_rtt_seconds",
+            "gauge",
+            "Round-trip time of the last ping to the server",
+            &[("", rtt.as_secs_f64())],
+        );
+    }
+    metric(
+        "siphon_requests_total",
+        "counter",
+        "HTTP requests forwarded to the local service",
+        &[("", snapshot.total_requests as f64)],
+    );
+    let status = &snapshot.status_distribution;
+    metric(
+        "siphon_responses_total",
+        "counter",
+        "HTTP responses by status class",
+        &[
+            ("{class=\"2xx\"}", status.code_2xx as f64),
+            ("{class=\"3xx\"}", status.code_3xx as f64),
+            ("{class=\"4xx\"}", status.code_4xx as f64),
+            ("{class=\"5xx\"}", status.code_5xx as f64),
+        ],
+    );
+    let times = &snapshot.response_times;
+    let quantiles: Vec<(&str, f64)> = [
+        ("{quantile=\"0.5\"}", times.p50),
+        ("{quantile=\"0.95\"}", times.p95),
+        ("{quantile=\"0.99\"}", times.p99),
+    ]
+    .into_iter()
+    .filter_map(|(labels, value)| value.map(|d| (labels, d.as_secs_f64())))
+    .collect();
+    if !quantiles.is_empty() {
+        metric(
+            "siphon_response_time_seconds",
+            "gauge",
+            "Response time quantiles over recent requests",
+            &quantiles,
+        );
+    }
+    metric(
+        "siphon_errors_total",
+        "counter",
+        "Errors forwarding to the local service",
+        &[("", snapshot.error_count as f64)],
+    );
+    metric(
+        "siphon_bytes_in_total",
+        "counter",
+        "Bytes received through the tunnel",
+        &[("", snapshot.bytes_in as f64)],
+    );
+    metric(
+        "siphon_bytes_out_total",
+        "counter",
+        "Bytes sent through the tunnel",
+        &[("", snapshot.bytes_out as f64)],
+    );
+    metric(
+        "siphon_active_connections",
+        "gauge",
+        "Open TCP connections to the local service",
+        &[("", snapshot.active_connections as f64)],
+    );
+    metric(
+        "siphon_connections_total",
+        "counter",
+        "TCP connections to the local service",
+        &[("", snapshot.total_connections as f64)],
+    );
+    out
+}
+
+/// Quote a field if it contains a separator, quote or line break (RFC 4180)
+fn escape_field(value: &str) -> std::borrow::Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\"")).into()
+    } else {
+        value.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    /// Split one CSV record, undoing `escape_field`
+    fn parse_record(line: &str) -> Vec<String> {
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut chars = line.chars().peekable();
+        let mut quoted = false;
+
+        while let Some(c) = chars.next() {
+            match (c, quoted) {
+                ('"', true) if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                ('"', _) => quoted = !quoted,
+                (',', false) => fields.push(std::mem::take(&mut field)),
+                (c, _) => field.push(c),
+            }
+        }
+        fields.push(field);
+        fields
+    }
+
+    fn entry(method: &str, uri: &str, status: u16, millis: u64, bytes: usize) -> RequestLogEntry {
+        RequestLogEntry {
+            timestamp: chrono::Local::now(),
+            method: method.to_string(),
+            uri: uri.to_string(),
+            status,
+            duration: Duration::from_millis(millis),
+            bytes,
+        }
+    }
+
+    #[test]
+    fn test_requests_csv_round_trip() {
+        let entries = vec![
+            entry("GET", "/", 200, 12, 512),
+            entry("POST", "/search?q=a,b", 201, 1500, 0),
+            entry("GET", "/say?\"hi\"", 404, 3, 9),
+        ];
+
+        let csv = requests_to_csv(&entries);
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some(REQUESTS_CSV_HEADER));
+
+        let records: Vec<_> = lines.map(parse_record).collect();
+        assert_eq!(records.len(), entries.len());
+
+        for (record, entry) in records.iter().zip(&entries) {
+            assert_eq!(record.len(), 6, "{:?}", record);
+            let timestamp = chrono::DateTime::parse_from_rfc3339(&record[0]).unwrap();
+            assert_eq!(timestamp, entry.timestamp);
+            assert_eq!(record[1], entry.method);
+            assert_eq!(record[2], entry.uri);
+            assert_eq!(record[3], entry.status.to_string());
+            assert_eq!(record[4], entry.duration.as_millis().to_string());
+            assert_eq!(record[5], entry.bytes.to_string());
+        }
+    }
+
+    #[test]
+    fn test_metrics_to_prometheus() {
+        let metrics = crate::metrics::MetricsCollector::new();
+        metrics.record_request_start();
+        metrics.record_request_complete(
+            200,
+            Duration::from_millis(20),
+            10,
+            "GET".into(),
+            "/".into(),
+        );
+        metrics.record_request_start();
+        metrics.record_request_complete(
+            503,
+            Duration::from_millis(40),
+            0,
+            "GET".into(),
+            "/".into(),
+        );
+        metrics.record_bytes_in(1234);
+        metrics.record_reconnect();
+
+        let text = metrics_to_prometheus(&metrics.snapshot());
+        let value = |series: &str| {
+            text.lines()
+                .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+                .unwrap_or_else(|| panic!("{} missing from:\n{}", series, text))
+                .to_string()
+        };
+
+        assert_eq!(value("siphon_requests_total"), "2");
+        assert_eq!(value("siphon_responses_total{class=\"2xx\"}"), "1");
+        assert_eq!(value("siphon_responses_total{class=\"5xx\"}"), "1");
+        assert_eq!(value("siphon_bytes_in_total"), "1234");
+        assert_eq!(value("siphon_reconnects_total"), "1");
+        assert!(text.contains("# TYPE siphon_requests_total counter\n"));
+        // Never connected, never pinged: those series are left out rather than reported as 0
+        assert!(!text.contains("siphon_tunnel_uptime_seconds"));
+        assert!(!text.contains("siphon_ping_rtt_seconds"));
+    }
+
+    #[test]
+    fn test_requests_csv_empty() {
+        assert_eq!(requests_to_csv(&[]), format!("{}\n", REQUESTS_CSV_HEADER));
+    }
+}
@@ -3,13 +3,19 @@
 //! This crate provides:
 //! - Real-time metrics dashboard with graphs
 //! - Interactive setup wizard for configuration
+//! - CSV export of the live request log and Prometheus text for the counters
 
+pub mod color;
 pub mod config;
+pub mod export;
 pub mod metrics;
 pub mod setup;
 pub mod ui;
 
 pub use config::SiphonConfig;
-pub use metrics::{MetricsCollector, MetricsSnapshot, TunnelInfo};
+pub use metrics::{
+    Alert, Annotation, AnomalyThresholds, FatalError, MetricsCollector, MetricsSnapshot,
+    RateBaseline, TunnelInfo,
+};
 pub use setup::SetupWizard;
 pub use ui::TuiApp;
@@ -2,16 +2,80 @@
 
 use parking_lot::RwLock;
 use siphon_protocol::TunnelType;
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-/// Maximum samples to keep in time-series data (60 seconds at 1 sample/sec)
-const HISTORY_SIZE: usize = 60;
+/// Default samples to keep in time-series data (60 seconds at 1 sample/sec)
+pub const DEFAULT_HISTORY_SIZE: usize = 60;
+
+/// Default time between two time-series samples
+pub const DEFAULT_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Samples averaged for the current request rate
+const RATE_SAMPLES: usize = 10;
 
 /// Maximum recent requests to display in live log
 const MAX_RECENT_REQUESTS: usize = 100;
 
+/// Maximum closed TCP connections to display in live log
+const MAX_RECENT_TCP_CONNECTIONS: usize = 100;
+
+/// Maximum annotations kept for the request rate graph
+const MAX_ANNOTATIONS: usize = 50;
+
+/// Past samples needed before the request rate baseline is trusted
+/// (fewer when the history window is shorter)
+const MIN_BASELINE_SAMPLES: usize = 10;
+
+/// Floor of the baseline's standard deviation, in requests per sample, so a
+/// perfectly flat baseline doesn't flag a single extra request
+const MIN_BASELINE_STDDEV: f64 = 1.0;
+
+/// Requests needed in the recent samples before their 5xx share is judged
+const MIN_ERROR_RATE_REQUESTS: u64 = 5;
+
+/// When the dashboard flags traffic as abnormal
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AnomalyThresholds {
+    /// Standard deviations above the baseline a sample's request count must
+    /// reach to count as a spike (0 disables)
+    pub spike_sigma: f64,
+    /// Share of 5xx responses among recent requests, from 0 to 1, that
+    /// raises an alert (0 disables)
+    pub max_5xx_ratio: f64,
+}
+
+impl Default for AnomalyThresholds {
+    fn default() -> Self {
+        Self {
+            spike_sigma: 3.0,
+            max_5xx_ratio: 0.25,
+        }
+    }
+}
+
+/// Mean and standard deviation of requests per sample over the history
+/// window, excluding the latest sample
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateBaseline {
+    pub mean: f64,
+    pub stddev: f64,
+}
+
+/// Abnormal traffic the dashboard should call out
+#[derive(Debug, Clone, PartialEq)]
+pub enum Alert {
+    /// The latest sample's request count is far above the baseline
+    RateSpike {
+        requests: u64,
+        baseline: RateBaseline,
+        sigmas: f64,
+    },
+    /// Too many recent requests answered with a 5xx
+    ServerErrors { ratio: f64 },
+}
+
 /// Thread-safe metrics collector that can be updated from async tasks
 #[derive(Clone)]
 pub struct MetricsCollector {
@@ -24,6 +88,11 @@ pub struct MetricsState {
     pub tunnel_info: Option<TunnelInfo>,
     pub connected_at: Option<Instant>,
 
+    // Control connection health
+    pub reconnects: u64,
+    pub last_server_message_at: Option<Instant>,
+    pub last_ping_rtt: Option<Duration>,
+
     // Request metrics
     pub total_requests: u64,
     pub requests_in_progress: u64,
@@ -41,22 +110,41 @@ pub struct MetricsState {
     // Error tracking
     pub error_count: u64,
     pub last_error: Option<String>,
+    pub fatal_error: Option<FatalError>,
+    /// Requests that couldn't be forwarded, by failure category
+    pub forward_errors: BTreeMap<&'static str, u64>,
 
     // Recent requests for live log
     pub recent_requests: VecDeque<RequestLogEntry>,
 
+    // Recently closed TCP connections for live log
+    pub recent_tcp_connections: VecDeque<TcpConnectionLogEntry>,
+
+    // App-level events marked on the request rate graph
+    annotations: VecDeque<(u64, Annotation)>,
+
     // Time-series data for graphs (rolling windows)
     pub request_rate_history: VecDeque<u64>,
     pub response_time_p50_history: VecDeque<u64>,
     pub response_time_p99_history: VecDeque<u64>,
     pub bytes_in_rate_history: VecDeque<u64>,
     pub bytes_out_rate_history: VecDeque<u64>,
+    server_error_history: VecDeque<u64>,
 
     // Counters for rate calculation (reset each second)
     requests_this_second: u64,
+    server_errors_this_second: u64,
     bytes_in_this_second: u64,
     bytes_out_this_second: u64,
     last_tick: Instant,
+    /// Graph samples taken so far, to place annotations on the graph
+    samples_taken: u64,
+
+    // Time-series window
+    history_size: usize,
+    sample_interval: Duration,
+
+    anomaly_thresholds: AnomalyThresholds,
 }
 
 /// Information about the established tunnel
@@ -68,6 +156,19 @@ pub struct TunnelInfo {
     pub tunnel_type: TunnelType,
 }
 
+/// An unrecoverable error to show in the dashboard before exiting
+#[derive(Debug, Clone)]
+pub struct FatalError {
+    /// Diagnostic code (e.g., "siphon::tls::expired")
+    pub code: Option<String>,
+    /// Full error message (may span multiple lines)
+    pub message: String,
+    /// Suggestion for fixing the problem
+    pub help: Option<String>,
+    /// Link to relevant documentation
+    pub url: Option<String>,
+}
+
 /// Distribution of HTTP status codes
 #[derive(Debug, Clone, Default)]
 pub struct StatusCodeDistribution {
@@ -99,11 +200,38 @@ pub struct RequestLogEntry {
     pub bytes: usize,
 }
 
+/// Entry in the live TCP connection log, recorded when a connection closes
+#[derive(Debug, Clone)]
+pub struct TcpConnectionLogEntry {
+    pub timestamp: chrono::DateTime<chrono::Local>,
+    /// Remote address of the public client, if the server reported it
+    pub peer: Option<String>,
+    /// Bytes received from the tunnel and written to the local service
+    pub bytes_in: u64,
+    /// Bytes read from the local service and sent through the tunnel
+    pub bytes_out: u64,
+    pub duration: Duration,
+}
+
+/// An app-level event (deploy, feature flag flip, ...) marked on the dashboard
+#[derive(Debug, Clone)]
+pub struct Annotation {
+    pub timestamp: chrono::DateTime<chrono::Local>,
+    pub label: String,
+    /// Graph samples taken since the annotation was added; 0 while it still
+    /// falls in the sample being collected
+    pub samples_ago: u64,
+}
+
 /// Immutable snapshot of metrics for rendering
 #[derive(Debug, Clone)]
 pub struct MetricsSnapshot {
     pub tunnel_info: Option<TunnelInfo>,
     pub uptime: Option<Duration>,
+    pub reconnects: u64,
+    /// Time since the server last sent anything on the control connection
+    pub since_last_server_message: Option<Duration>,
+    pub last_ping_rtt: Option<Duration>,
     pub total_requests: u64,
     pub requests_per_second: f64,
     pub status_distribution: StatusCodeDistribution,
@@ -114,9 +242,20 @@ pub struct MetricsSnapshot {
     pub bytes_out: u64,
     pub error_count: u64,
     pub last_error: Option<String>,
+    pub fatal_error: Option<FatalError>,
+    pub forward_errors: BTreeMap<&'static str, u64>,
     pub recent_requests: Vec<RequestLogEntry>,
-
-    // Graph data
+    pub recent_tcp_connections: Vec<TcpConnectionLogEntry>,
+    /// Oldest first
+    pub annotations: Vec<Annotation>,
+    /// Requests per sample before the latest one, once enough samples were taken
+    pub request_rate_baseline: Option<RateBaseline>,
+    /// Abnormal traffic in the latest samples
+    pub alerts: Vec<Alert>,
+
+    // Graph data (`history_size` samples, `sample_interval` apart)
+    pub history_size: usize,
+    pub sample_interval: Duration,
     pub request_rate_history: Vec<u64>,
     pub response_time_p50_history: Vec<u64>,
     pub response_time_p99_history: Vec<u64>,
@@ -124,11 +263,14 @@ pub struct MetricsSnapshot {
     pub bytes_out_rate_history: Vec<u64>,
 }
 
-impl Default for MetricsState {
-    fn default() -> Self {
+impl MetricsState {
+    fn new(history_size: usize, sample_interval: Duration) -> Self {
         Self {
             tunnel_info: None,
             connected_at: None,
+            reconnects: 0,
+            last_server_message_at: None,
+            last_ping_rtt: None,
             total_requests: 0,
             requests_in_progress: 0,
             status_codes: StatusCodeDistribution::default(),
@@ -139,28 +281,119 @@ impl Default for MetricsState {
             bytes_out: 0,
             error_count: 0,
             last_error: None,
+            fatal_error: None,
+            forward_errors: BTreeMap::new(),
             recent_requests: VecDeque::with_capacity(MAX_RECENT_REQUESTS),
-            request_rate_history: VecDeque::with_capacity(HISTORY_SIZE),
-            response_time_p50_history: VecDeque::with_capacity(HISTORY_SIZE),
-            response_time_p99_history: VecDeque::with_capacity(HISTORY_SIZE),
-            bytes_in_rate_history: VecDeque::with_capacity(HISTORY_SIZE),
-            bytes_out_rate_history: VecDeque::with_capacity(HISTORY_SIZE),
+            recent_tcp_connections: VecDeque::with_capacity(MAX_RECENT_TCP_CONNECTIONS),
+            annotations: VecDeque::new(),
+            request_rate_history: VecDeque::with_capacity(history_size),
+            response_time_p50_history: VecDeque::with_capacity(history_size),
+            response_time_p99_history: VecDeque::with_capacity(history_size),
+            bytes_in_rate_history: VecDeque::with_capacity(history_size),
+            bytes_out_rate_history: VecDeque::with_capacity(history_size),
+            server_error_history: VecDeque::with_capacity(history_size),
             requests_this_second: 0,
+            server_errors_this_second: 0,
             bytes_in_this_second: 0,
             bytes_out_this_second: 0,
             last_tick: Instant::now(),
+            samples_taken: 0,
+            history_size,
+            sample_interval,
+            anomaly_thresholds: AnomalyThresholds::default(),
         }
     }
+
+    /// Baseline of the request rate history, the latest sample left out
+    fn request_rate_baseline(&self) -> Option<RateBaseline> {
+        let past = self.request_rate_history.len().checked_sub(1)?;
+        let needed = MIN_BASELINE_SAMPLES.min(self.history_size - 1).max(2);
+        if past < needed {
+            return None;
+        }
+        let samples = self.request_rate_history.iter().take(past);
+        let mean = samples.clone().sum::<u64>() as f64 / past as f64;
+        let variance = samples
+            .map(|&requests| (requests as f64 - mean).powi(2))
+            .sum::<f64>()
+            / past as f64;
+        Some(RateBaseline {
+            mean,
+            stddev: variance.sqrt(),
+        })
+    }
+
+    /// Alerts raised by the latest samples
+    fn alerts(&self, baseline: Option<RateBaseline>) -> Vec<Alert> {
+        let thresholds = self.anomaly_thresholds;
+        let mut alerts = Vec::new();
+
+        if let (Some(baseline), Some(&requests)) = (baseline, self.request_rate_history.back()) {
+            let sigmas =
+                (requests as f64 - baseline.mean) / baseline.stddev.max(MIN_BASELINE_STDDEV);
+            if thresholds.spike_sigma > 0.0 && sigmas >= thresholds.spike_sigma {
+                alerts.push(Alert::RateSpike {
+                    requests,
+                    baseline,
+                    sigmas,
+                });
+            }
+        }
+
+        let requests: u64 = self
+            .request_rate_history
+            .iter()
+            .rev()
+            .take(RATE_SAMPLES)
+            .sum();
+        let errors: u64 = self
+            .server_error_history
+            .iter()
+            .rev()
+            .take(RATE_SAMPLES)
+            .sum();
+        if thresholds.max_5xx_ratio > 0.0 && requests >= MIN_ERROR_RATE_REQUESTS {
+            let ratio = errors as f64 / requests as f64;
+            if ratio >= thresholds.max_5xx_ratio {
+                alerts.push(Alert::ServerErrors { ratio });
+            }
+        }
+
+        alerts
+    }
+}
+
+impl Default for MetricsState {
+    fn default() -> Self {
+        Self::new(DEFAULT_HISTORY_SIZE, DEFAULT_SAMPLE_INTERVAL)
+    }
 }
 
 impl MetricsCollector {
     /// Create a new metrics collector
     pub fn new() -> Self {
+        Self::with_window(DEFAULT_HISTORY_SIZE, DEFAULT_SAMPLE_INTERVAL)
+    }
+
+    /// Create a metrics collector keeping `samples` graph points, one per `interval`
+    ///
+    /// The graphs then cover `samples * interval` (60 x 1s by default).
+    pub fn with_window(samples: usize, interval: Duration) -> Self {
         Self {
-            inner: Arc::new(RwLock::new(MetricsState::default())),
+            inner: Arc::new(RwLock::new(MetricsState::new(samples.max(1), interval))),
         }
     }
 
+    /// Set when the dashboard flags request rate spikes and 5xx bursts
+    pub fn set_anomaly_thresholds(&self, thresholds: AnomalyThresholds) {
+        self.inner.write().anomaly_thresholds = thresholds;
+    }
+
+    /// Time between two graph samples; call [`tick`](Self::tick) at this rate
+    pub fn sample_interval(&self) -> Duration {
+        self.inner.read().sample_interval
+    }
+
     /// Set tunnel information when connection is established
     pub fn set_tunnel_info(&self, info: TunnelInfo) {
         let mut state = self.inner.write();
@@ -168,6 +401,21 @@ impl MetricsCollector {
         state.connected_at = Some(Instant::now());
     }
 
+    /// Record a new attempt to connect after the control connection was lost
+    pub fn record_reconnect(&self) {
+        self.inner.write().reconnects += 1;
+    }
+
+    /// Record a message received from the server on the control connection
+    pub fn record_server_message(&self) {
+        self.inner.write().last_server_message_at = Some(Instant::now());
+    }
+
+    /// Record the round-trip time of a ping answered by the server
+    pub fn record_ping_rtt(&self, rtt: Duration) {
+        self.inner.write().last_ping_rtt = Some(rtt);
+    }
+
     /// Record the start of an HTTP request
     pub fn record_request_start(&self) {
         let mut state = self.inner.write();
@@ -195,7 +443,10 @@ impl MetricsCollector {
             200..=299 => state.status_codes.code_2xx += 1,
             300..=399 => state.status_codes.code_3xx += 1,
             400..=499 => state.status_codes.code_4xx += 1,
-            _ => state.status_codes.code_5xx += 1,
+            _ => {
+                state.status_codes.code_5xx += 1;
+                state.server_errors_this_second += 1;
+            }
         }
 
         // Store response time
@@ -231,6 +482,31 @@ impl MetricsCollector {
         state.active_tcp_connections = state.active_tcp_connections.saturating_sub(1);
     }
 
+    /// Record a TCP connection being closed, with its totals for the live log
+    pub fn record_tcp_connection_closed(
+        &self,
+        peer: Option<String>,
+        bytes_in: u64,
+        bytes_out: u64,
+        duration: Duration,
+    ) {
+        let mut state = self.inner.write();
+        state.active_tcp_connections = state.active_tcp_connections.saturating_sub(1);
+
+        state
+            .recent_tcp_connections
+            .push_back(TcpConnectionLogEntry {
+                timestamp: chrono::Local::now(),
+                peer,
+                bytes_in,
+                bytes_out,
+                duration,
+            });
+        if state.recent_tcp_connections.len() > MAX_RECENT_TCP_CONNECTIONS {
+            state.recent_tcp_connections.pop_front();
+        }
+    }
+
     /// Record bytes received (inbound)
     pub fn record_bytes_in(&self, bytes: u64) {
         let mut state = self.inner.write();
@@ -252,33 +528,78 @@ impl MetricsCollector {
         state.last_error = Some(error);
     }
 
-    /// Tick the metrics collector (call once per second to update history)
+    /// Record a request that couldn't be forwarded to the local service
+    ///
+    /// `category` groups failures for the error breakdown (e.g. `refused`, `timeout`).
+    pub fn record_forward_error(&self, category: &'static str, error: String) {
+        let mut state = self.inner.write();
+        state.error_count += 1;
+        state.last_error = Some(error);
+        *state.forward_errors.entry(category).or_default() += 1;
+    }
+
+    /// Record an unrecoverable error (the dashboard shows it and freezes)
+    pub fn set_fatal_error(&self, error: FatalError) {
+        let mut state = self.inner.write();
+        state.error_count += 1;
+        state.last_error = Some(error.message.clone());
+        state.fatal_error = Some(error);
+    }
+
+    /// Mark an app-level event (a deploy, a feature flag flip, ...) on the
+    /// request rate graph
+    ///
+    /// Only the most recent annotations are kept.
+    pub fn add_annotation(&self, label: String) {
+        let mut state = self.inner.write();
+        let sample = state.samples_taken;
+        state.annotations.push_back((
+            sample,
+            Annotation {
+                timestamp: chrono::Local::now(),
+                label,
+                samples_ago: 0,
+            },
+        ));
+        if state.annotations.len() > MAX_ANNOTATIONS {
+            state.annotations.pop_front();
+        }
+    }
+
+    /// Tick the metrics collector (call once per sample interval to update history)
     pub fn tick(&self) {
         let mut state = self.inner.write();
 
-        // Calculate time since last tick
+        // Calculate time since last tick (10% slack for timer jitter)
         let elapsed = state.last_tick.elapsed();
-        if elapsed < Duration::from_millis(900) {
+        if elapsed < state.sample_interval.mul_f64(0.9) {
             return; // Too soon, skip
         }
+        let history_size = state.history_size;
 
         // Update request rate history
         let requests_this_sec = state.requests_this_second;
         state.request_rate_history.push_back(requests_this_sec);
-        if state.request_rate_history.len() > HISTORY_SIZE {
+        if state.request_rate_history.len() > history_size {
             state.request_rate_history.pop_front();
         }
 
+        let server_errors_this_sec = state.server_errors_this_second;
+        state.server_error_history.push_back(server_errors_this_sec);
+        if state.server_error_history.len() > history_size {
+            state.server_error_history.pop_front();
+        }
+
         // Update bytes rate history
         let bytes_in_this_sec = state.bytes_in_this_second;
         state.bytes_in_rate_history.push_back(bytes_in_this_sec);
-        if state.bytes_in_rate_history.len() > HISTORY_SIZE {
+        if state.bytes_in_rate_history.len() > history_size {
             state.bytes_in_rate_history.pop_front();
         }
 
         let bytes_out_this_sec = state.bytes_out_this_second;
         state.bytes_out_rate_history.push_back(bytes_out_this_sec);
-        if state.bytes_out_rate_history.len() > HISTORY_SIZE {
+        if state.bytes_out_rate_history.len() > history_size {
             state.bytes_out_rate_history.pop_front();
         }
 
@@ -287,22 +608,24 @@ impl MetricsCollector {
         state
             .response_time_p50_history
             .push_back(p50.map(|d| d.as_millis() as u64).unwrap_or(0));
-        if state.response_time_p50_history.len() > HISTORY_SIZE {
+        if state.response_time_p50_history.len() > history_size {
             state.response_time_p50_history.pop_front();
         }
 
         state
             .response_time_p99_history
             .push_back(p99.map(|d| d.as_millis() as u64).unwrap_or(0));
-        if state.response_time_p99_history.len() > HISTORY_SIZE {
+        if state.response_time_p99_history.len() > history_size {
             state.response_time_p99_history.pop_front();
         }
 
         // Reset per-second counters
         state.requests_this_second = 0;
+        state.server_errors_this_second = 0;
         state.bytes_in_this_second = 0;
         state.bytes_out_this_second = 0;
         state.last_tick = Instant::now();
+        state.samples_taken += 1;
     }
 
     /// Get an immutable snapshot of current metrics for rendering
@@ -311,21 +634,34 @@ impl MetricsCollector {
 
         let uptime = state.connected_at.map(|t| t.elapsed());
 
-        // Calculate requests per second (average over last 10 seconds)
-        let recent_requests: u64 = state.request_rate_history.iter().rev().take(10).sum();
-        let sample_count = state.request_rate_history.len().min(10) as f64;
-        let requests_per_second = if sample_count > 0.0 {
-            recent_requests as f64 / sample_count
+        // Calculate requests per second (average over the last 10 samples)
+        let recent_requests: u64 = state
+            .request_rate_history
+            .iter()
+            .rev()
+            .take(RATE_SAMPLES)
+            .sum();
+        let sampled = state.request_rate_history.len().min(RATE_SAMPLES) as f64
+            * state.sample_interval.as_secs_f64();
+        let requests_per_second = if sampled > 0.0 {
+            recent_requests as f64 / sampled
         } else {
             0.0
         };
+        let history_size = state.history_size;
 
         // Calculate response time stats
         let response_times = calculate_response_time_stats(&state.response_times);
 
+        let request_rate_baseline = state.request_rate_baseline();
+        let alerts = state.alerts(request_rate_baseline);
+
         MetricsSnapshot {
             tunnel_info: state.tunnel_info.clone(),
             uptime,
+            reconnects: state.reconnects,
+            since_last_server_message: state.last_server_message_at.map(|t| t.elapsed()),
+            last_ping_rtt: state.last_ping_rtt,
             total_requests: state.total_requests,
             requests_per_second,
             status_distribution: state.status_codes.clone(),
@@ -336,14 +672,29 @@ impl MetricsCollector {
             bytes_out: state.bytes_out,
             error_count: state.error_count,
             last_error: state.last_error.clone(),
+            fatal_error: state.fatal_error.clone(),
+            forward_errors: state.forward_errors.clone(),
             recent_requests: state.recent_requests.iter().cloned().collect(),
-
-            // Graph data - pad to fixed HISTORY_SIZE for consistent chart rendering
-            request_rate_history: pad_history(&state.request_rate_history, HISTORY_SIZE),
-            response_time_p50_history: pad_history(&state.response_time_p50_history, HISTORY_SIZE),
-            response_time_p99_history: pad_history(&state.response_time_p99_history, HISTORY_SIZE),
-            bytes_in_rate_history: pad_history(&state.bytes_in_rate_history, HISTORY_SIZE),
-            bytes_out_rate_history: pad_history(&state.bytes_out_rate_history, HISTORY_SIZE),
+            recent_tcp_connections: state.recent_tcp_connections.iter().cloned().collect(),
+            annotations: state
+                .annotations
+                .iter()
+                .map(|(sample, annotation)| Annotation {
+                    samples_ago: state.samples_taken - sample,
+                    ..annotation.clone()
+                })
+                .collect(),
+            request_rate_baseline,
+            alerts,
+
+            // Graph data - pad to the fixed history size for consistent chart rendering
+            history_size,
+            sample_interval: state.sample_interval,
+            request_rate_history: pad_history(&state.request_rate_history, history_size),
+            response_time_p50_history: pad_history(&state.response_time_p50_history, history_size),
+            response_time_p99_history: pad_history(&state.response_time_p99_history, history_size),
+            bytes_in_rate_history: pad_history(&state.bytes_in_rate_history, history_size),
+            bytes_out_rate_history: pad_history(&state.bytes_out_rate_history, history_size),
         }
     }
 }
@@ -493,4 +844,226 @@ mod tests {
         metrics.record_tcp_disconnect();
         assert_eq!(metrics.snapshot().active_connections, 1);
     }
+
+    #[test]
+    fn test_tcp_connection_log() {
+        let metrics = MetricsCollector::new();
+
+        metrics.record_tcp_connect();
+        metrics.record_tcp_connection_closed(
+            Some("203.0.113.7:51234".into()),
+            512,
+            2048,
+            Duration::from_secs(3),
+        );
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.active_connections, 0);
+        assert_eq!(snapshot.total_connections, 1);
+        assert_eq!(snapshot.recent_tcp_connections.len(), 1);
+
+        let entry = &snapshot.recent_tcp_connections[0];
+        assert_eq!(entry.peer.as_deref(), Some("203.0.113.7:51234"));
+        assert_eq!(entry.bytes_in, 512);
+        assert_eq!(entry.bytes_out, 2048);
+        assert_eq!(entry.duration, Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_history_window_rolls() {
+        // Zero interval so every tick takes a sample
+        let metrics = MetricsCollector::with_window(10, Duration::ZERO);
+
+        for i in 1..=15u16 {
+            for _ in 0..i {
+                metrics.record_request_complete(
+                    200,
+                    Duration::from_millis(1),
+                    0,
+                    "GET".into(),
+                    "/".into(),
+                );
+            }
+            metrics.tick();
+        }
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.history_size, 10);
+        assert_eq!(
+            snapshot.request_rate_history,
+            (6..=15).collect::<Vec<u64>>()
+        );
+        assert_eq!(snapshot.bytes_in_rate_history.len(), 10);
+    }
+
+    #[test]
+    fn test_history_padded_to_window() {
+        let metrics = MetricsCollector::with_window(10, Duration::ZERO);
+        metrics.record_request_complete(200, Duration::from_millis(1), 0, "GET".into(), "/".into());
+        metrics.tick();
+
+        let history = metrics.snapshot().request_rate_history;
+        assert_eq!(history.len(), 10);
+        assert_eq!(history[9], 1);
+        assert!(history[..9].iter().all(|&v| v == 0));
+    }
+
+    #[test]
+    fn test_control_link_metrics() {
+        let metrics = MetricsCollector::new();
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.last_ping_rtt, None);
+        assert_eq!(snapshot.since_last_server_message, None);
+        assert_eq!(snapshot.reconnects, 0);
+
+        metrics.record_ping_rtt(Duration::from_millis(42));
+        metrics.record_ping_rtt(Duration::from_millis(17));
+        metrics.record_server_message();
+        metrics.record_reconnect();
+        metrics.record_reconnect();
+
+        // Only the latest RTT is kept
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.last_ping_rtt, Some(Duration::from_millis(17)));
+        assert!(snapshot
+            .since_last_server_message
+            .is_some_and(|since| since < Duration::from_secs(5)));
+        assert_eq!(snapshot.reconnects, 2);
+    }
+
+    #[test]
+    fn test_fatal_error() {
+        let metrics = MetricsCollector::new();
+        assert!(metrics.snapshot().fatal_error.is_none());
+
+        metrics.set_fatal_error(FatalError {
+            code: Some("siphon::tls::expired".into()),
+            message: "Certificate has expired".into(),
+            help: Some("Renew your certificate".into()),
+            url: None,
+        });
+
+        let snapshot = metrics.snapshot();
+        let fatal = snapshot.fatal_error.expect("fatal error should be set");
+        assert_eq!(fatal.message, "Certificate has expired");
+        assert_eq!(snapshot.error_count, 1);
+    }
+
+    #[test]
+    fn test_annotations_in_snapshot() {
+        let metrics = MetricsCollector::with_window(3, Duration::ZERO);
+        metrics.add_annotation("deploy v2".into());
+        metrics.tick();
+        metrics.add_annotation("flag on".into());
+
+        let snapshot = metrics.snapshot();
+        let annotations: Vec<_> = snapshot
+            .annotations
+            .iter()
+            .map(|a| (a.label.as_str(), a.samples_ago))
+            .collect();
+        assert_eq!(annotations, vec![("deploy v2", 1), ("flag on", 0)]);
+
+        // Only the most recent ones are kept
+        for i in 0..MAX_ANNOTATIONS {
+            metrics.add_annotation(format!("event {}", i));
+        }
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.annotations.len(), MAX_ANNOTATIONS);
+        assert_eq!(snapshot.annotations[0].label, "event 0");
+    }
+
+    /// Take one sample per entry of `samples`, each `(requests, 5xx among them)`
+    fn record_samples(metrics: &MetricsCollector, samples: &[(u64, u64)]) {
+        for &(requests, errors) in samples {
+            for i in 0..requests {
+                let status = if i < errors { 503 } else { 200 };
+                metrics.record_request_complete(
+                    status,
+                    Duration::from_millis(1),
+                    0,
+                    "GET".into(),
+                    "/".into(),
+                );
+            }
+            metrics.tick();
+        }
+    }
+
+    #[test]
+    fn test_request_rate_spike_alert() {
+        let metrics = MetricsCollector::with_window(30, Duration::ZERO);
+        let steady: Vec<(u64, u64)> = (0..20).map(|i| (8 + i % 5, 0)).collect();
+        record_samples(&metrics, &steady);
+
+        let snapshot = metrics.snapshot();
+        let baseline = snapshot
+            .request_rate_baseline
+            .expect("baseline after 20 samples");
+        assert!((baseline.mean - 10.0).abs() < 0.5, "{:?}", baseline);
+        assert!(snapshot.alerts.is_empty(), "{:?}", snapshot.alerts);
+
+        record_samples(&metrics, &[(60, 0)]);
+        let alerts = metrics.snapshot().alerts;
+        assert!(
+            matches!(alerts.as_slice(), [Alert::RateSpike { requests: 60, sigmas, .. }] if *sigmas > 3.0),
+            "{:?}",
+            alerts
+        );
+
+        // Back to normal once the spike is part of the baseline
+        record_samples(&metrics, &[(10, 0)]);
+        assert!(metrics.snapshot().alerts.is_empty());
+
+        // Disabled
+        metrics.set_anomaly_thresholds(AnomalyThresholds {
+            spike_sigma: 0.0,
+            ..Default::default()
+        });
+        record_samples(&metrics, &[(500, 0)]);
+        assert!(metrics.snapshot().alerts.is_empty());
+    }
+
+    #[test]
+    fn test_no_baseline_until_enough_samples() {
+        let metrics = MetricsCollector::with_window(60, Duration::ZERO);
+        record_samples(&metrics, &[(1, 0), (1, 0), (100, 0)]);
+
+        let snapshot = metrics.snapshot();
+        assert!(snapshot.request_rate_baseline.is_none());
+        assert!(snapshot.alerts.is_empty());
+    }
+
+    #[test]
+    fn test_server_error_alert() {
+        let metrics = MetricsCollector::with_window(30, Duration::ZERO);
+        metrics.set_anomaly_thresholds(AnomalyThresholds {
+            spike_sigma: 0.0,
+            max_5xx_ratio: 0.2,
+        });
+        record_samples(&metrics, &[(10, 1), (10, 0)]);
+        assert!(metrics.snapshot().alerts.is_empty());
+
+        record_samples(&metrics, &[(10, 8)]);
+        let alerts = metrics.snapshot().alerts;
+        assert!(
+            matches!(alerts.as_slice(), [Alert::ServerErrors { ratio }] if (*ratio - 0.3).abs() < 1e-9),
+            "{:?}",
+            alerts
+        );
+    }
+
+    #[test]
+    fn test_forward_error_categories() {
+        let metrics = MetricsCollector::new();
+        metrics.record_forward_error("refused", "connection refused".into());
+        metrics.record_forward_error("refused", "connection refused".into());
+        metrics.record_forward_error("timeout", "timed out".into());
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.error_count, 3);
+        assert_eq!(snapshot.last_error.as_deref(), Some("timed out"));
+        assert_eq!(snapshot.forward_errors.get("refused"), Some(&2));
+        assert_eq!(snapshot.forward_errors.get("timeout"), Some(&1));
+    }
 }
@@ -11,15 +11,19 @@ use crossterm::{
 use ratatui::{prelude::*, Terminal};
 use std::io;
 use std::time::Duration;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 
 use super::dashboard::Dashboard;
+use crate::color::{color_output_enabled, to_monochrome};
 use crate::metrics::MetricsCollector;
 
 /// Main TUI application
 pub struct TuiApp {
     metrics: MetricsCollector,
     shutdown_tx: mpsc::Sender<()>,
+    stop_rx: Option<watch::Receiver<bool>>,
+    /// Render in color (false renders monochrome, see [`crate::color`])
+    color: bool,
 }
 
 impl TuiApp {
@@ -28,9 +32,20 @@ impl TuiApp {
         Self {
             metrics,
             shutdown_tx,
+            stop_rx: None,
+            color: color_output_enabled(),
         }
     }
 
+    /// Exit the event loop once the given flag becomes true
+    ///
+    /// Lets the caller close the dashboard (restoring the terminal) when the
+    /// tunnel ends on its own rather than through a key press.
+    pub fn with_stop_signal(mut self, stop_rx: watch::Receiver<bool>) -> Self {
+        self.stop_rx = Some(stop_rx);
+        self
+    }
+
     /// Run the TUI event loop (blocking)
     pub async fn run(self) -> io::Result<()> {
         // Setup terminal
@@ -62,13 +77,21 @@ impl TuiApp {
         terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
     ) -> io::Result<()> {
         let tick_rate = Duration::from_millis(100);
+        let sample_interval = self.metrics.sample_interval();
         let mut last_tick = std::time::Instant::now();
         let mut clipboard = Clipboard::new().ok();
         let mut copy_feedback: Option<(std::time::Instant, bool)> = None;
+        // Snapshot frozen at the moment a fatal error was reported
+        let mut frozen: Option<crate::metrics::MetricsSnapshot> = None;
 
         loop {
-            // Tick metrics for time-series updates (once per second)
-            if last_tick.elapsed() >= Duration::from_secs(1) {
+            // Stop when requested by the caller
+            if self.stop_rx.as_ref().is_some_and(|rx| *rx.borrow()) {
+                return Ok(());
+            }
+
+            // Tick metrics for time-series updates (once per sample interval)
+            if frozen.is_none() && last_tick.elapsed() >= sample_interval {
                 self.metrics.tick();
                 last_tick = std::time::Instant::now();
             }
@@ -80,15 +103,37 @@ impl TuiApp {
                 }
             }
 
-            // Draw UI
-            let snapshot = self.metrics.snapshot();
+            // Draw UI (graphs stay frozen once a fatal error is shown)
+            let snapshot = match &frozen {
+                Some(snapshot) => snapshot.clone(),
+                None => {
+                    let snapshot = self.metrics.snapshot();
+                    if snapshot.fatal_error.is_some() {
+                        frozen = Some(snapshot.clone());
+                    }
+                    snapshot
+                }
+            };
             let feedback = copy_feedback.map(|(_, success)| success);
-            terminal.draw(|f| Dashboard::render(f, &snapshot, feedback))?;
+            terminal.draw(|f| {
+                Dashboard::render(f, &snapshot, feedback);
+                if !self.color {
+                    to_monochrome(f.buffer_mut());
+                }
+            })?;
 
             // Handle events with timeout
             let timeout = tick_rate.saturating_sub(last_tick.elapsed());
             if crossterm::event::poll(timeout)? {
                 match event::read()? {
+                    // Any key dismisses the fatal error overlay and exits
+                    Event::Key(key)
+                        if key.kind == KeyEventKind::Press && snapshot.fatal_error.is_some() =>
+                    {
+                        let _ = self.shutdown_tx.send(()).await;
+                        return Ok(());
+                    }
+                    #[allow(clippy::collapsible_match)]
                     Event::Key(key) => {
                         if key.kind == KeyEventKind::Press {
                             match key.code {
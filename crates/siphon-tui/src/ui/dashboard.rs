@@ -7,13 +7,15 @@ use ratatui::{
     text::{Line, Span},
     widgets::{
         Axis, Bar, BarChart, BarGroup, Block, Borders, Cell, Chart, Clear, Dataset, GraphType,
-        Paragraph, Row, Sparkline, Table,
+        Paragraph, Row, Sparkline, Table, Wrap,
     },
     Frame,
 };
 use std::time::Duration;
 
-use crate::metrics::MetricsSnapshot;
+use siphon_protocol::TunnelType;
+
+use crate::metrics::{Alert, FatalError, MetricsSnapshot};
 
 /// Dashboard renderer
 pub struct Dashboard;
@@ -25,6 +27,17 @@ impl Dashboard {
         // Clear entire frame to prevent artifacts on resize
         frame.render_widget(Clear, frame.area());
 
+        // Alert banner above everything while traffic looks abnormal
+        let mut area = frame.area();
+        if !snapshot.alerts.is_empty() {
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Length(1), Constraint::Min(0)])
+                .split(area);
+            Self::render_alerts(frame, chunks[0], &snapshot.alerts);
+            area = chunks[1];
+        }
+
         // Main layout: 4 rows
         let main_chunks = Layout::default()
             .direction(Direction::Vertical)
@@ -34,10 +47,16 @@ impl Dashboard {
                 Constraint::Length(8),  // Middle bottom: Status codes + Throughput
                 Constraint::Min(8),     // Bottom: Live log
             ])
-            .split(frame.area());
+            .split(area);
 
-        // Header: Tunnel info panel
-        Self::render_tunnel_info(frame, main_chunks[0], snapshot, copy_feedback);
+        // Header: Tunnel info panel + control connection health
+        let header_chunks = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(40), Constraint::Length(24)])
+            .split(main_chunks[0]);
+
+        Self::render_tunnel_info(frame, header_chunks[0], snapshot, copy_feedback);
+        Self::render_link(frame, header_chunks[1], snapshot);
 
         // Middle top: 2-column layout
         let top_chunks = Layout::default()
@@ -57,8 +76,123 @@ impl Dashboard {
         Self::render_status_codes(frame, bottom_chunks[0], snapshot);
         Self::render_throughput(frame, bottom_chunks[1], snapshot);
 
-        // Bottom: Live request log
-        Self::render_live_log(frame, main_chunks[3], snapshot);
+        // Bottom: Live request log (or connection log for TCP tunnels)
+        let is_tcp = snapshot
+            .tunnel_info
+            .as_ref()
+            .is_some_and(|info| info.tunnel_type == TunnelType::Tcp);
+        if is_tcp {
+            Self::render_tcp_log(frame, main_chunks[3], snapshot);
+        } else {
+            Self::render_live_log(frame, main_chunks[3], snapshot);
+        }
+
+        // Fatal error overlay on top of everything
+        if let Some(ref error) = snapshot.fatal_error {
+            Self::render_fatal_error(frame, error);
+        }
+    }
+
+    fn render_alerts(frame: &mut Frame, area: Rect, alerts: &[Alert]) {
+        let style = Style::default()
+            .fg(Color::White)
+            .bg(Color::Red)
+            .add_modifier(Modifier::BOLD);
+
+        let mut spans = vec![Span::styled(" ⚠ ", style)];
+        for (i, alert) in alerts.iter().enumerate() {
+            if i > 0 {
+                spans.push(Span::styled("  │  ", style));
+            }
+            let text = match alert {
+                Alert::RateSpike {
+                    requests,
+                    baseline,
+                    sigmas,
+                } => format!(
+                    "Request spike: {} in the last sample vs {:.1} ± {:.1} usual ({:.1}σ)",
+                    requests, baseline.mean, baseline.stddev, sigmas
+                ),
+                Alert::ServerErrors { ratio } => {
+                    format!(
+                        "Server errors: {:.0}% of recent requests got a 5xx",
+                        ratio * 100.0
+                    )
+                }
+            };
+            spans.push(Span::styled(text, style));
+        }
+
+        frame.render_widget(Paragraph::new(Line::from(spans)).style(style), area);
+    }
+
+    fn render_fatal_error(frame: &mut Frame, error: &FatalError) {
+        let mut text = Vec::new();
+
+        if let Some(ref code) = error.code {
+            text.push(Line::from(Span::styled(
+                code.as_str(),
+                Style::default().fg(Color::DarkGray),
+            )));
+            text.push(Line::from(""));
+        }
+
+        for line in error.message.lines() {
+            text.push(Line::from(Span::styled(
+                line,
+                Style::default()
+                    .fg(Color::White)
+                    .add_modifier(Modifier::BOLD),
+            )));
+        }
+
+        if let Some(ref help) = error.help {
+            text.push(Line::from(""));
+            text.push(Line::from(vec![
+                Span::styled("Help: ", Style::default().fg(Color::Yellow)),
+                Span::raw(help.as_str()),
+            ]));
+        }
+
+        if let Some(ref url) = error.url {
+            text.push(Line::from(vec![
+                Span::styled("More info: ", Style::default().fg(Color::Gray)),
+                Span::styled(
+                    url.as_str(),
+                    Style::default()
+                        .fg(Color::Cyan)
+                        .add_modifier(Modifier::UNDERLINED),
+                ),
+            ]));
+        }
+
+        text.push(Line::from(""));
+        text.push(Line::from(Span::styled(
+            "Press any key to exit",
+            Style::default().fg(Color::DarkGray),
+        )));
+
+        let area = frame.area();
+        let width = (area.width * 7 / 10).max(40).min(area.width);
+        // Borders plus some slack for wrapped lines
+        let height = (text.len() as u16 + 4).min(area.height);
+        let popup = Rect {
+            x: area.x + (area.width - width) / 2,
+            y: area.y + (area.height - height) / 2,
+            width,
+            height,
+        };
+
+        let block = Block::default()
+            .title(" Fatal Error ")
+            .title_style(Style::default().fg(Color::Red).add_modifier(Modifier::BOLD))
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(Color::Red));
+
+        let para = Paragraph::new(text).block(block).wrap(Wrap { trim: false });
+
+        frame.render_widget(Clear, popup);
+        frame.render_widget(para, popup);
     }
 
     fn render_tunnel_info(
@@ -89,7 +223,7 @@ impl Dashboard {
                 .map(format_duration)
                 .unwrap_or_else(|| "N/A".to_string());
 
-            let tunnel_type = format!("{:?}", info.tunnel_type);
+            let tunnel_type = info.tunnel_type.to_string();
 
             // Build helper line with copy feedback if present
             let helper_line = match copy_feedback {
@@ -166,9 +300,54 @@ impl Dashboard {
         }
     }
 
+    fn render_link(frame: &mut Frame, area: Rect, snapshot: &MetricsSnapshot) {
+        let block = Block::default()
+            .title(" Link ")
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(Color::Cyan));
+
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+        frame.render_widget(Clear, inner);
+
+        let rtt = snapshot
+            .last_ping_rtt
+            .map(format_duration_ms)
+            .unwrap_or_else(|| "N/A".to_string());
+        let last_message = snapshot
+            .since_last_server_message
+            .map(|since| format!("{} ago", format_duration(since)))
+            .unwrap_or_else(|| "never".to_string());
+        let reconnects_color = if snapshot.reconnects > 0 {
+            Color::Yellow
+        } else {
+            Color::White
+        };
+
+        let text = vec![
+            Line::from(vec![
+                Span::styled("RTT: ", Style::default().fg(Color::Gray)),
+                Span::styled(rtt, Style::default().fg(Color::Cyan)),
+            ]),
+            Line::from(vec![
+                Span::styled("Last msg: ", Style::default().fg(Color::Gray)),
+                Span::raw(last_message),
+            ]),
+            Line::from(vec![
+                Span::styled("Reconnects: ", Style::default().fg(Color::Gray)),
+                Span::styled(
+                    format_number(snapshot.reconnects),
+                    Style::default().fg(reconnects_color),
+                ),
+            ]),
+        ];
+
+        frame.render_widget(Paragraph::new(text), inner);
+    }
+
     fn render_request_rate(frame: &mut Frame, area: Rect, snapshot: &MetricsSnapshot) {
         let block = Block::default()
-            .title(" Request Rate (last 60s) ")
+            .title(format!(" Request Rate (last {}) ", format_window(snapshot)))
             .borders(Borders::ALL);
 
         let inner = block.inner(area);
@@ -192,9 +371,10 @@ impl Dashboard {
         // Clear area first to prevent rendering artifacts
         frame.render_widget(Clear, chunks[0]);
         frame.render_widget(sparkline, chunks[0]);
+        Self::render_annotation_markers(frame, chunks[0], snapshot);
 
         // Stats line
-        let stats = Line::from(vec![
+        let mut stats = vec![
             Span::styled("Total: ", Style::default().fg(Color::Gray)),
             Span::styled(
                 format_number(snapshot.total_requests),
@@ -206,15 +386,56 @@ impl Dashboard {
                 format!("{:.1} req/s", snapshot.requests_per_second),
                 Style::default().fg(Color::Cyan),
             ),
-        ]);
+        ];
+        if let Some(annotation) = snapshot.annotations.last() {
+            stats.push(Span::raw("  │  "));
+            stats.push(Span::styled(
+                format!(
+                    "{} {}",
+                    annotation.timestamp.format("%H:%M:%S"),
+                    annotation.label
+                ),
+                Style::default().fg(Color::Yellow),
+            ));
+        }
 
-        let stats_para = Paragraph::new(stats);
+        let stats_para = Paragraph::new(Line::from(stats));
         frame.render_widget(stats_para, chunks[1]);
     }
 
+    /// Draw a vertical marker over the sample each annotation falls in
+    fn render_annotation_markers(frame: &mut Frame, area: Rect, snapshot: &MetricsSnapshot) {
+        let samples = snapshot.request_rate_history.len() as u64;
+        let style = Style::default().fg(Color::Yellow);
+
+        for annotation in &snapshot.annotations {
+            // One in the sample still being collected goes on the newest bar
+            let Some(index) = samples.checked_sub(annotation.samples_ago.max(1)) else {
+                continue;
+            };
+            if index >= u64::from(area.width) {
+                continue;
+            }
+            let x = area.x + index as u16;
+
+            for y in area.top()..area.bottom() {
+                if let Some(cell) = frame.buffer_mut().cell_mut((x, y)) {
+                    // Keep the bar, only recolor it
+                    if cell.symbol() == " " {
+                        cell.set_symbol("│");
+                    }
+                    cell.set_style(style);
+                }
+            }
+        }
+    }
+
     fn render_response_times(frame: &mut Frame, area: Rect, snapshot: &MetricsSnapshot) {
         let block = Block::default()
-            .title(" Response Times (last 60s) ")
+            .title(format!(
+                " Response Times (last {}) ",
+                format_window(snapshot)
+            ))
             .borders(Borders::ALL);
 
         let inner = block.inner(area);
@@ -271,7 +492,7 @@ impl Dashboard {
         let chart = Chart::new(datasets)
             .x_axis(
                 Axis::default()
-                    .bounds([0.0, 60.0])
+                    .bounds([0.0, snapshot.history_size as f64])
                     .labels(vec![Line::from("")]),
             )
             .y_axis(Axis::default().bounds([0.0, max_time]).labels(y_labels));
@@ -486,6 +707,64 @@ impl Dashboard {
 
         frame.render_widget(table, inner);
     }
+
+    fn render_tcp_log(frame: &mut Frame, area: Rect, snapshot: &MetricsSnapshot) {
+        let block = Block::default()
+            .title(format!(
+                " Live Connections ({} open, {} total) ",
+                snapshot.active_connections, snapshot.total_connections
+            ))
+            .borders(Borders::ALL);
+
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+
+        // Table header
+        let header = Row::new(vec![
+            Cell::from("Closed"),
+            Cell::from("Peer"),
+            Cell::from("Duration"),
+            Cell::from("In"),
+            Cell::from("Out"),
+        ])
+        .style(Style::default().add_modifier(Modifier::BOLD))
+        .bottom_margin(0);
+
+        // Table rows (most recent first)
+        let rows: Vec<Row> = snapshot
+            .recent_tcp_connections
+            .iter()
+            .rev()
+            .take(inner.height.saturating_sub(1) as usize)
+            .map(|conn| {
+                Row::new(vec![
+                    Cell::from(conn.timestamp.format("%H:%M:%S").to_string()),
+                    Cell::from(truncate(conn.peer.as_deref().unwrap_or("-"), 40)),
+                    Cell::from(format_duration_ms(conn.duration)),
+                    Cell::from(Span::styled(
+                        format_bytes(conn.bytes_in),
+                        Style::default().fg(Color::Cyan),
+                    )),
+                    Cell::from(Span::styled(
+                        format_bytes(conn.bytes_out),
+                        Style::default().fg(Color::Magenta),
+                    )),
+                ])
+            })
+            .collect();
+
+        let widths = [
+            Constraint::Length(10),
+            Constraint::Min(20),
+            Constraint::Length(10),
+            Constraint::Length(10),
+            Constraint::Length(10),
+        ];
+
+        let table = Table::new(rows, widths).header(header);
+
+        frame.render_widget(table, inner);
+    }
 }
 
 // Helper functions
@@ -501,6 +780,18 @@ fn format_duration(d: Duration) -> String {
     }
 }
 
+/// Time span covered by the graphs, e.g. "60s", "10m" or "2h"
+fn format_window(snapshot: &MetricsSnapshot) -> String {
+    let secs = (snapshot.sample_interval * snapshot.history_size as u32).as_secs();
+    if secs >= 3600 && secs.is_multiple_of(3600) {
+        format!("{}h", secs / 3600)
+    } else if secs > 60 && secs.is_multiple_of(60) {
+        format!("{}m", secs / 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
 fn format_duration_ms(d: Duration) -> String {
     let ms = d.as_millis();
     if ms < 1000 {
@@ -0,0 +1,330 @@
+//! Debug logging of forwarded request and response bodies (`--dump-bodies`)
+//!
+//! Sensitive headers, JSON fields, form fields and query parameters are
+//! replaced with a marker before anything is logged. Other text is logged as
+//! is (capped), binary bodies only by size.
+
+use serde_json::Value;
+
+/// Replacement for redacted values
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Bodies longer than this are truncated in the log by default
+pub const DEFAULT_MAX_BODY_BYTES: usize = 4096;
+
+/// Headers redacted unless configured otherwise
+const DEFAULT_REDACT_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+];
+
+/// JSON, form and query keys redacted unless configured otherwise
+const DEFAULT_REDACT_KEYS: &[&str] = &[
+    "password",
+    "passwd",
+    "secret",
+    "token",
+    "access_token",
+    "refresh_token",
+    "api_key",
+    "apikey",
+    "client_secret",
+];
+
+/// What to redact and how much of each body to log
+#[derive(Debug, Clone)]
+pub struct BodyDump {
+    /// Longest body excerpt logged, in bytes
+    pub max_body_bytes: usize,
+    /// Header names whose values are redacted (case-insensitive)
+    pub redact_headers: Vec<String>,
+    /// JSON object keys (at any depth), form fields and query parameters whose
+    /// values are redacted (case-insensitive)
+    pub redact_keys: Vec<String>,
+}
+
+impl Default for BodyDump {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            redact_headers: DEFAULT_REDACT_HEADERS
+                .iter()
+                .map(|h| h.to_string())
+                .collect(),
+            redact_keys: DEFAULT_REDACT_KEYS.iter().map(|k| k.to_string()).collect(),
+        }
+    }
+}
+
+impl BodyDump {
+    /// Log a request about to be forwarded
+    pub fn log_request(&self, method: &str, uri: &str, headers: &[(String, String)], body: &[u8]) {
+        tracing::debug!(
+            "Request {} {}\n  headers: {:?}\n  body: {}",
+            method,
+            self.uri(uri),
+            self.headers(headers),
+            self.body(headers, body)
+        );
+    }
+
+    /// Log the local service's response
+    pub fn log_response(
+        &self,
+        method: &str,
+        uri: &str,
+        status: u16,
+        headers: &[(String, String)],
+        body: &[u8],
+    ) {
+        tracing::debug!(
+            "Response {} {} -> {}\n  headers: {:?}\n  body: {}",
+            method,
+            self.uri(uri),
+            status,
+            self.headers(headers),
+            self.body(headers, body)
+        );
+    }
+
+    /// Headers with sensitive values replaced
+    fn headers(&self, headers: &[(String, String)]) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(|(name, value)| {
+                let redact = self
+                    .redact_headers
+                    .iter()
+                    .any(|h| h.eq_ignore_ascii_case(name));
+                let value = if redact { REDACTED } else { value.as_str() };
+                (name.clone(), value.to_string())
+            })
+            .collect()
+    }
+
+    /// URI with sensitive query parameters replaced
+    fn uri(&self, uri: &str) -> String {
+        match uri.split_once('?') {
+            Some((path, query)) => format!("{}?{}", path, self.redact_form(query)),
+            None => uri.to_string(),
+        }
+    }
+
+    /// Printable, redacted and capped form of a body
+    fn body(&self, headers: &[(String, String)], body: &[u8]) -> String {
+        if body.is_empty() {
+            return "<empty>".to_string();
+        }
+
+        let text = match serde_json::from_slice::<Value>(body) {
+            Ok(mut json) => {
+                self.redact_json(&mut json);
+                json.to_string()
+            }
+            Err(_) => match std::str::from_utf8(body) {
+                Ok(text) if is_form(headers) || looks_like_form(text) => self.redact_form(text),
+                Ok(text) => text.to_string(),
+                Err(_) => return format!("<{} bytes of binary data>", body.len()),
+            },
+        };
+
+        truncate(text, self.max_body_bytes)
+    }
+
+    /// Replace the values of sensitive fields in a `a=1&b=2` string, leaving
+    /// the rest as it was encoded
+    fn redact_form(&self, form: &str) -> String {
+        form.split('&')
+            .map(|pair| {
+                let (key, _) = form_urlencoded::parse(pair.as_bytes())
+                    .next()
+                    .unwrap_or_default();
+                if self
+                    .redact_keys
+                    .iter()
+                    .any(|k| k.eq_ignore_ascii_case(&key))
+                {
+                    let raw_key = pair.split_once('=').map_or(pair, |(key, _)| key);
+                    format!("{}={}", raw_key, REDACTED)
+                } else {
+                    pair.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+
+    /// Replace the values of sensitive keys, at any depth
+    fn redact_json(&self, value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    if self.redact_keys.iter().any(|k| k.eq_ignore_ascii_case(key)) {
+                        *value = Value::String(REDACTED.to_string());
+                    } else {
+                        self.redact_json(value);
+                    }
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|item| self.redact_json(item)),
+            _ => {}
+        }
+    }
+}
+
+/// Whether the headers declare a URL-encoded form body
+fn is_form(headers: &[(String, String)]) -> bool {
+    headers.iter().any(|(name, value)| {
+        name.eq_ignore_ascii_case("content-type")
+            && value.split(';').next().is_some_and(|mime| {
+                mime.trim()
+                    .eq_ignore_ascii_case("application/x-www-form-urlencoded")
+            })
+    })
+}
+
+/// Whether untyped text parses as `key=value` pairs
+fn looks_like_form(text: &str) -> bool {
+    !text.contains(char::is_whitespace)
+        && text
+            .split('&')
+            .all(|pair| pair.split_once('=').is_some_and(|(key, _)| !key.is_empty()))
+}
+
+/// Cut `text` to at most `max` bytes (on a char boundary), noting the full size
+fn truncate(mut text: String, max: usize) -> String {
+    if text.len() <= max {
+        return text;
+    }
+    let total = text.len();
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
+    format!("{}... ({} bytes total)", text, total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(name: &str, value: &str) -> (String, String) {
+        (name.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn test_redacts_headers() {
+        let dump = BodyDump::default();
+        let headers = dump.headers(&[
+            header("Authorization", "Bearer abc"),
+            header("content-type", "application/json"),
+            header("Cookie", "session=xyz"),
+        ]);
+        assert_eq!(
+            headers,
+            vec![
+                header("Authorization", REDACTED),
+                header("content-type", "application/json"),
+                header("Cookie", REDACTED),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_redacts_json_keys_at_any_depth() {
+        let dump = BodyDump::default();
+        let body = br#"{"user":"ada","Password":"hunter2","nested":{"token":"t"},"list":[{"api_key":"k","id":1}]}"#;
+
+        let logged: Value = serde_json::from_str(&dump.body(&[], body)).unwrap();
+        assert_eq!(
+            logged,
+            serde_json::json!({
+                "user": "ada",
+                "Password": REDACTED,
+                "nested": { "token": REDACTED },
+                "list": [{ "api_key": REDACTED, "id": 1 }],
+            })
+        );
+    }
+
+    #[test]
+    fn test_custom_redaction_lists() {
+        let dump = BodyDump {
+            redact_headers: vec!["x-session".to_string()],
+            redact_keys: vec!["ssn".to_string()],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            dump.headers(&[header("X-Session", "s"), header("Authorization", "a")]),
+            vec![header("X-Session", REDACTED), header("Authorization", "a")]
+        );
+        let logged: Value =
+            serde_json::from_str(&dump.body(&[], br#"{"ssn":"123","password":"p"}"#)).unwrap();
+        assert_eq!(
+            logged,
+            serde_json::json!({ "ssn": REDACTED, "password": "p" })
+        );
+    }
+
+    #[test]
+    fn test_redacts_form_bodies() {
+        let dump = BodyDump::default();
+        let form = [header(
+            "Content-Type",
+            "application/x-www-form-urlencoded; charset=utf-8",
+        )];
+        assert_eq!(
+            dump.body(&form, b"user=ada&Password=hunter%202&next=%2Fhome"),
+            "user=ada&Password=[REDACTED]&next=%2Fhome"
+        );
+        // Recognized without a Content-Type too
+        assert_eq!(
+            dump.body(&[], b"grant_type=password&client_secret=s"),
+            "grant_type=password&client_secret=[REDACTED]"
+        );
+        // Percent-encoded keys are matched decoded
+        assert_eq!(
+            dump.body(&form, b"api%5Fkey=k&flag"),
+            "api%5Fkey=[REDACTED]&flag"
+        );
+        assert_eq!(dump.body(&[], b"token is secret"), "token is secret");
+    }
+
+    #[test]
+    fn test_redacts_query_parameters() {
+        let dump = BodyDump::default();
+        assert_eq!(
+            dump.uri("/callback?token=abc&page=2&API_KEY=k"),
+            "/callback?token=[REDACTED]&page=2&API_KEY=[REDACTED]"
+        );
+        assert_eq!(dump.uri("/search?q=token"), "/search?q=token");
+        assert_eq!(dump.uri("/plain"), "/plain");
+    }
+
+    #[test]
+    fn test_binary_and_text_bodies() {
+        let dump = BodyDump::default();
+        assert_eq!(
+            dump.body(&[], &[0xff, 0xfe, 0x00]),
+            "<3 bytes of binary data>"
+        );
+        assert_eq!(dump.body(&[], b"plain text"), "plain text");
+        assert_eq!(dump.body(&[], b""), "<empty>");
+    }
+
+    #[test]
+    fn test_truncates_long_bodies() {
+        let dump = BodyDump {
+            max_body_bytes: 4,
+            ..Default::default()
+        };
+        assert_eq!(dump.body(&[], b"abcdefgh"), "abcd... (8 bytes total)");
+        // Never splits a multi-byte character
+        assert_eq!(dump.body(&[], "ééé".as_bytes()), "éé... (6 bytes total)");
+    }
+}
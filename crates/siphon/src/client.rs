@@ -0,0 +1,605 @@
+//! Programmatic entry point for running a tunnel
+//!
+//! The `siphon` binary is a thin wrapper around [`TunnelClient`]; embedders
+//! get the same connect, reconnect and subdomain-stickiness behavior without
+//! going through the CLI.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use siphon_common::SocketOptions;
+use siphon_protocol::{HttpPolicy, ReadBufferPolicy, TunnelType};
+use siphon_tui::MetricsCollector;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
+
+use crate::body_dump::BodyDump;
+use crate::connector::{AssignedSubdomain, TunnelConnection, TunnelDenied, TunnelExpired};
+use crate::forwarder::{ForwardHost, HttpForwarder, LocalPool, RetryPolicy};
+use crate::local_concurrency::LocalConcurrency;
+use crate::local_target::LocalTarget;
+use crate::server_name::tls_server_name;
+
+/// Delay between reconnect attempts unless configured otherwise
+pub const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// What to do after a session ends with an error
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// Wait before each reconnect attempt
+    pub delay: Duration,
+    /// Reconnect attempts allowed over the client's lifetime (`None` for unlimited)
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            delay: DEFAULT_RECONNECT_DELAY,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Give up after the first error (tunnels the server expired are still renewed)
+    pub fn never() -> Self {
+        Self {
+            max_attempts: Some(0),
+            ..Default::default()
+        }
+    }
+}
+
+/// Why a tunnel stopped for good
+#[derive(Debug)]
+pub enum TunnelExit {
+    /// The server closed the tunnel normally
+    Closed,
+    /// Shutdown was requested through the handle (or it was dropped)
+    Shutdown,
+    /// The server refused the tunnel, or an error the fatal-error check
+    /// deemed permanent
+    Fatal(anyhow::Error),
+    /// Reconnect attempts ran out; the error that ended the last session
+    GaveUp { attempts: u32, error: anyhow::Error },
+}
+
+impl TunnelExit {
+    /// The error that stopped the tunnel, if any
+    pub fn error(&self) -> Option<&anyhow::Error> {
+        match self {
+            Self::Closed | Self::Shutdown => None,
+            Self::Fatal(error) | Self::GaveUp { error, .. } => Some(error),
+        }
+    }
+
+    /// Whether the server refused the tunnel (see [`TunnelDenied`])
+    pub fn is_denied(&self) -> bool {
+        self.error().is_some_and(|e| e.is::<TunnelDenied>())
+    }
+
+    /// `Ok` for a normal close or shutdown, the error otherwise
+    pub fn into_result(self) -> Result<()> {
+        match self {
+            Self::Closed | Self::Shutdown => Ok(()),
+            Self::Fatal(error) | Self::GaveUp { error, .. } => Err(error),
+        }
+    }
+}
+
+/// Decides whether an error is worth reconnecting after
+type FatalErrorFn = Arc<dyn Fn(&anyhow::Error) -> bool + Send + Sync>;
+
+/// Default fatal-error check: TLS errors won't go away by reconnecting
+fn is_tls_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        // Handshake failures arrive wrapped in an io::Error, whose source skips them
+        let wrapped = cause
+            .downcast_ref::<std::io::Error>()
+            .and_then(|e| e.get_ref());
+        cause.is::<rustls::Error>() || wrapped.is_some_and(|inner| inner.is::<rustls::Error>())
+    })
+}
+
+/// What to expose and how, fixed for the lifetime of the client
+#[derive(Clone)]
+struct TunnelSpec {
+    local_target: LocalTarget,
+    subdomain: Option<String>,
+    base_domain: Option<String>,
+    tunnel_type: TunnelType,
+    http_policy: Option<HttpPolicy>,
+    /// Built once and shared by every session, so reconnects keep its warm
+    /// connections to the local service
+    http_forwarder: HttpForwarder,
+    socket_options: SocketOptions,
+    forward_host: ForwardHost,
+    read_buffer: ReadBufferPolicy,
+    local_concurrency: LocalConcurrency,
+    server_info: bool,
+}
+
+/// Builder for [`TunnelClient`]
+///
+/// `server`, `local` and `tls` are required, everything else has a default.
+#[derive(Default)]
+pub struct TunnelClientBuilder {
+    server_addr: Option<String>,
+    server_name: Option<String>,
+    local_target: Option<LocalTarget>,
+    subdomain: Option<String>,
+    base_domain: Option<String>,
+    tunnel_type: Option<TunnelType>,
+    http_policy: Option<HttpPolicy>,
+    retry: RetryPolicy,
+    body_dump: Option<BodyDump>,
+    socket_options: SocketOptions,
+    insecure_local_tls: bool,
+    forward_host: ForwardHost,
+    read_buffer: ReadBufferPolicy,
+    max_local_concurrency: Option<usize>,
+    local_pool: LocalPool,
+    tls_config: Option<Arc<rustls::ClientConfig>>,
+    metrics: Option<MetricsCollector>,
+    reconnect: ReconnectPolicy,
+    is_fatal: Option<FatalErrorFn>,
+    server_info: bool,
+}
+
+impl TunnelClientBuilder {
+    /// Tunnel server address (`host:port`)
+    pub fn server(mut self, addr: impl Into<String>) -> Self {
+        self.server_addr = Some(addr.into());
+        self
+    }
+
+    /// Name to verify the server certificate against (defaults to the server host)
+    pub fn server_name(mut self, name: impl Into<String>) -> Self {
+        self.server_name = Some(name.into());
+        self
+    }
+
+    /// Local service to forward to
+    pub fn local(mut self, target: LocalTarget) -> Self {
+        self.local_target = Some(target);
+        self
+    }
+
+    /// Subdomain to request (auto-generated if not set)
+    pub fn subdomain(mut self, subdomain: impl Into<String>) -> Self {
+        self.subdomain = Some(subdomain.into());
+        self
+    }
+
+    /// Base domain to serve the tunnel under, for servers with several
+    /// (defaults to the server's first)
+    pub fn base_domain(mut self, base_domain: impl Into<String>) -> Self {
+        self.base_domain = Some(base_domain.into());
+        self
+    }
+
+    /// Tunnel type (defaults to HTTP)
+    pub fn tunnel_type(mut self, tunnel_type: TunnelType) -> Self {
+        self.tunnel_type = Some(tunnel_type);
+        self
+    }
+
+    /// Method/path allowlist enforced by the server (HTTP tunnels only)
+    pub fn http_policy(mut self, policy: HttpPolicy) -> Self {
+        self.http_policy = Some(policy);
+        self
+    }
+
+    /// Retry of requests that fail to reach the local service
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Log redacted HTTP request and response bodies at debug level
+    pub fn dump_bodies(mut self, body_dump: BodyDump) -> Self {
+        self.body_dump = Some(body_dump);
+        self
+    }
+
+    /// TCP_NODELAY and keepalive for the server and local connections
+    /// (defaults to nodelay on, keepalive off)
+    pub fn socket_options(mut self, options: SocketOptions) -> Self {
+        self.socket_options = options;
+        self
+    }
+
+    /// Skip certificate verification of an `https://` local target
+    ///
+    /// Only affects requests to the local service; the connection to the
+    /// tunnel server is always verified.
+    pub fn insecure_local_tls(mut self, insecure: bool) -> Self {
+        self.insecure_local_tls = insecure;
+        self
+    }
+
+    /// Host header sent to the local service: stripped (default), the public
+    /// one preserved for virtual-host routing, or a fixed value
+    pub fn forward_host(mut self, forward_host: ForwardHost) -> Self {
+        self.forward_host = forward_host;
+        self
+    }
+
+    /// Ask the server to describe itself before each tunnel request, and log
+    /// its answer
+    ///
+    /// Off by default: servers predating the exchange drop the connection.
+    pub fn server_info(mut self, enabled: bool) -> Self {
+        self.server_info = enabled;
+        self
+    }
+
+    /// Initial and retained capacity of the buffer server messages are read into
+    pub fn read_buffer(mut self, policy: ReadBufferPolicy) -> Self {
+        self.read_buffer = policy;
+        self
+    }
+
+    /// Requests forwarded to the local service at once (defaults to
+    /// [`DEFAULT_MAX_LOCAL_CONCURRENCY`](crate::DEFAULT_MAX_LOCAL_CONCURRENCY), 0 = unlimited)
+    ///
+    /// As many again wait briefly for a slot; requests beyond that get a 503.
+    pub fn max_local_concurrency(mut self, max: usize) -> Self {
+        self.max_local_concurrency = Some(max);
+        self
+    }
+
+    /// Idle connections kept open to the local service (see [`LocalPool`])
+    pub fn local_pool(mut self, pool: LocalPool) -> Self {
+        self.local_pool = pool;
+        self
+    }
+
+    /// mTLS configuration used to connect to the server
+    pub fn tls(mut self, config: impl Into<Arc<rustls::ClientConfig>>) -> Self {
+        self.tls_config = Some(config.into());
+        self
+    }
+
+    /// Collector to report into (a fresh one is created if not set)
+    pub fn metrics(mut self, metrics: MetricsCollector) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Reconnect behavior after errors (defaults to retrying forever every 5s)
+    pub fn reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = policy;
+        self
+    }
+
+    /// Errors for which reconnecting is pointless (defaults to TLS errors)
+    pub fn fatal_error(
+        mut self,
+        is_fatal: impl Fn(&anyhow::Error) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.is_fatal = Some(Arc::new(is_fatal));
+        self
+    }
+
+    /// Validate the settings and create the client
+    pub fn build(self) -> Result<TunnelClient> {
+        let server_addr = self.server_addr.context("Server address required")?;
+        let local_target = self.local_target.context("Local target required")?;
+        let tls_config = self.tls_config.context("TLS configuration required")?;
+        let server_name = tls_server_name(&server_addr, self.server_name.as_deref())?;
+
+        let tunnel_type = self.tunnel_type.unwrap_or(TunnelType::Http);
+        if self.http_policy.is_some() && tunnel_type != TunnelType::Http {
+            anyhow::bail!("An HTTP policy only applies to HTTP tunnels");
+        }
+        if matches!(local_target, LocalTarget::Https(_)) && tunnel_type != TunnelType::Http {
+            anyhow::bail!("An https:// local target only applies to HTTP tunnels");
+        }
+        if self.insecure_local_tls && !matches!(local_target, LocalTarget::Https(_)) {
+            anyhow::bail!("Insecure local TLS requires an https:// local target");
+        }
+        if self.insecure_local_tls {
+            tracing::warn!(
+                "Certificate verification is disabled for {}; local traffic can be intercepted",
+                local_target
+            );
+        }
+
+        let http_forwarder = HttpForwarder::new(local_target.clone())
+            .with_retry(self.retry)
+            .with_body_dump(self.body_dump)
+            .with_socket_options(self.socket_options)
+            .with_insecure_local_tls(self.insecure_local_tls)
+            .with_local_pool(self.local_pool)
+            .with_forward_host(self.forward_host.clone());
+
+        Ok(TunnelClient {
+            server_addr,
+            server_name,
+            connector: TlsConnector::from(tls_config),
+            tunnel: TunnelSpec {
+                local_target,
+                subdomain: self.subdomain,
+                base_domain: self.base_domain,
+                tunnel_type,
+                http_policy: self.http_policy,
+                http_forwarder,
+                socket_options: self.socket_options,
+                forward_host: self.forward_host,
+                read_buffer: self.read_buffer,
+                local_concurrency: self
+                    .max_local_concurrency
+                    .map(LocalConcurrency::new)
+                    .unwrap_or_default(),
+                server_info: self.server_info,
+            },
+            metrics: self.metrics.unwrap_or_default(),
+            reconnect: self.reconnect,
+            is_fatal: self.is_fatal.unwrap_or_else(|| Arc::new(is_tls_error)),
+            assigned: AssignedSubdomain::default(),
+        })
+    }
+}
+
+/// A configured tunnel client, started with [`TunnelClient::run`]
+pub struct TunnelClient {
+    server_addr: String,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+    tunnel: TunnelSpec,
+    metrics: MetricsCollector,
+    reconnect: ReconnectPolicy,
+    is_fatal: FatalErrorFn,
+    /// Keeps the assigned subdomain across reconnects
+    assigned: AssignedSubdomain,
+}
+
+impl fmt::Debug for TunnelClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TunnelClient")
+            .field("server_addr", &self.server_addr)
+            .field("local_target", &self.tunnel.local_target)
+            .field("reconnect", &self.reconnect)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TunnelClient {
+    pub fn builder() -> TunnelClientBuilder {
+        TunnelClientBuilder::default()
+    }
+
+    /// Start the tunnel on a background task
+    ///
+    /// Must be called within a Tokio runtime.
+    pub fn run(self) -> TunnelHandle {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let metrics = self.metrics.clone();
+        let assigned = self.assigned.clone();
+        let task = tokio::spawn(self.reconnect_loop(shutdown_rx));
+
+        TunnelHandle {
+            metrics,
+            assigned,
+            shutdown_tx,
+            task: Some(task),
+        }
+    }
+
+    /// Run sessions until the tunnel closes normally, a fatal error occurs,
+    /// reconnect attempts run out or shutdown is requested
+    async fn reconnect_loop(self, mut shutdown: watch::Receiver<bool>) -> TunnelExit {
+        tracing::info!(
+            "Connecting to {} to expose {}",
+            self.server_addr,
+            self.tunnel.local_target
+        );
+
+        let mut attempts = 0;
+        loop {
+            tracing::info!("Connecting to {}...", self.server_addr);
+
+            // Sessions watch for shutdown themselves, to say goodbye to the server
+            let result = self.run_tunnel(&shutdown).await;
+            if stop_requested(&shutdown) {
+                return TunnelExit::Shutdown;
+            }
+
+            let e = match result {
+                Ok(()) => {
+                    tracing::info!("Tunnel closed normally");
+                    return TunnelExit::Closed;
+                }
+                Err(e) => e,
+            };
+
+            // Rotation rather than failure: get a fresh tunnel right away
+            if e.is::<TunnelExpired>() {
+                tracing::info!("{}, reconnecting", e);
+                self.metrics.record_reconnect();
+                continue;
+            }
+
+            // Asking again would get the same answer
+            if e.is::<TunnelDenied>() || (self.is_fatal)(&e) {
+                return TunnelExit::Fatal(e);
+            }
+            tracing::error!("Tunnel error: {}", e);
+            self.metrics.record_error(format!("Tunnel error: {}", e));
+
+            if self
+                .reconnect
+                .max_attempts
+                .is_some_and(|max| attempts >= max)
+            {
+                return TunnelExit::GaveUp { attempts, error: e };
+            }
+            attempts += 1;
+
+            tracing::info!("Reconnecting in {:?}...", self.reconnect.delay);
+            tokio::select! {
+                _ = tokio::time::sleep(self.reconnect.delay) => {}
+                _ = shutdown.wait_for(|stop| *stop) => return TunnelExit::Shutdown,
+            }
+            self.metrics.record_reconnect();
+        }
+    }
+
+    /// Run one tunnel session, re-requesting the previously assigned subdomain
+    ///
+    /// If the server refuses the remembered subdomain (e.g. it was taken while we
+    /// were disconnected), a fresh one is requested instead.
+    async fn run_tunnel(&self, shutdown: &watch::Receiver<bool>) -> Result<()> {
+        let remembered = match self.tunnel.subdomain {
+            Some(_) => None,
+            None => self.assigned.get(),
+        };
+        let requested = self.tunnel.subdomain.clone().or_else(|| remembered.clone());
+
+        let result = self.run_session(requested, shutdown).await;
+
+        match (result, remembered) {
+            (Err(e), Some(previous)) if e.is::<TunnelDenied>() => {
+                tracing::warn!(
+                    "Previous subdomain {} is no longer available ({}), requesting a new one",
+                    previous,
+                    e
+                );
+                self.run_session(None, shutdown).await
+            }
+            (result, _) => result,
+        }
+    }
+
+    /// Connect to the server, request a tunnel for `subdomain` and serve it
+    /// until disconnection or shutdown
+    async fn run_session(
+        &self,
+        subdomain: Option<String>,
+        shutdown: &watch::Receiver<bool>,
+    ) -> Result<()> {
+        let mut stop = shutdown.clone();
+        let connection = tokio::select! {
+            connection = self.open_session(subdomain) => connection?,
+            _ = stop.wait_for(|stop| *stop) => return Ok(()),
+        };
+
+        // Run the connection (processes messages until disconnection)
+        connection.with_shutdown(shutdown.clone()).run().await
+    }
+
+    /// Connect to the server and request a tunnel for `subdomain`
+    async fn open_session(&self, subdomain: Option<String>) -> Result<TunnelConnection> {
+        // Connect to server
+        let stream = TcpStream::connect(&self.server_addr).await?;
+        self.tunnel.socket_options.apply(&stream)?;
+
+        // Perform TLS handshake
+        let tls_stream = self
+            .connector
+            .connect(self.server_name.clone(), stream)
+            .await?;
+
+        // Create tunnel connection handler
+        let mut connection = TunnelConnection::new(
+            tls_stream,
+            self.tunnel.local_target.clone(),
+            self.metrics.clone(),
+            self.tunnel.tunnel_type.clone(),
+        )
+        .with_http_forwarder(self.tunnel.http_forwarder.clone())
+        .with_socket_options(self.tunnel.socket_options)
+        .with_forward_host(self.tunnel.forward_host.clone())
+        .with_read_buffer(self.tunnel.read_buffer)
+        .with_local_concurrency(self.tunnel.local_concurrency.clone())
+        .with_assigned_subdomain(self.assigned.clone());
+
+        if self.tunnel.server_info {
+            connection.send_hello().await?;
+        }
+
+        // Request tunnel
+        connection
+            .request_tunnel(
+                subdomain,
+                self.tunnel.base_domain.clone(),
+                self.tunnel.tunnel_type.clone(),
+                self.tunnel.http_policy.clone(),
+            )
+            .await?;
+
+        Ok(connection)
+    }
+}
+
+/// Whether the handle asked the tunnel to stop (a dropped handle counts as
+/// a shutdown request)
+fn stop_requested(shutdown: &watch::Receiver<bool>) -> bool {
+    *shutdown.borrow() || shutdown.has_changed().is_err()
+}
+
+/// Handle to a running tunnel
+///
+/// Dropping the handle stops the tunnel.
+pub struct TunnelHandle {
+    metrics: MetricsCollector,
+    assigned: AssignedSubdomain,
+    shutdown_tx: watch::Sender<bool>,
+    /// Taken once the task's result has been returned
+    task: Option<JoinHandle<TunnelExit>>,
+}
+
+impl TunnelHandle {
+    /// Live metrics of the tunnel
+    pub fn metrics(&self) -> &MetricsCollector {
+        &self.metrics
+    }
+
+    /// Subdomain assigned by the server, once the tunnel is established
+    pub fn subdomain(&self) -> Option<String> {
+        self.assigned.get()
+    }
+
+    /// Wait for the tunnel to end on its own
+    ///
+    /// Returns the error that stopped it, if any; later calls return `Ok`.
+    /// Cancel-safe, so it can be raced against other events and followed by
+    /// [`TunnelHandle::shutdown`].
+    pub async fn wait(&mut self) -> Result<()> {
+        self.wait_for_exit().await.into_result()
+    }
+
+    /// Wait for the tunnel to end on its own, reporting why
+    ///
+    /// Like [`TunnelHandle::wait`], later calls return [`TunnelExit::Closed`].
+    pub async fn wait_for_exit(&mut self) -> TunnelExit {
+        let Some(task) = self.task.as_mut() else {
+            return TunnelExit::Closed;
+        };
+        let result = task.await;
+        self.task = None;
+        result.unwrap_or_else(|e| {
+            TunnelExit::Fatal(anyhow::Error::new(e).context("Tunnel task panicked"))
+        })
+    }
+
+    /// Close the tunnel and wait for it to stop
+    pub async fn shutdown(self) -> Result<()> {
+        self.stop().await.into_result()
+    }
+
+    /// Close the tunnel and wait for it to stop, reporting why it ended
+    ///
+    /// Usually [`TunnelExit::Shutdown`], unless it had already stopped on its own.
+    pub async fn stop(mut self) -> TunnelExit {
+        let _ = self.shutdown_tx.send(true);
+        self.wait_for_exit().await
+    }
+}
@@ -1,76 +1,217 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
 use anyhow::Result;
 use bytes::BytesMut;
 use siphon_tui::metrics::{MetricsCollector, TunnelInfo};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::time::MissedTickBehavior;
 use tokio_rustls::client::TlsStream;
 use tokio_util::codec::{Decoder, Encoder};
 
-use siphon_protocol::{ClientMessage, ServerMessage, TunnelCodec, TunnelType};
+use siphon_common::SocketOptions;
+use siphon_protocol::{
+    ClientMessage, HttpPolicy, ReadBufferPolicy, ServerMessage, TunnelCodec, TunnelType,
+    PROTOCOL_VERSION,
+};
 
-use crate::forwarder::HttpForwarder;
+use crate::forwarder::{set_forwarded_proto, ForwardErrorKind, ForwardHost, HttpForwarder};
+use crate::local_concurrency::LocalConcurrency;
+use crate::local_target::LocalTarget;
 use crate::tcp_forwarder::TcpForwarder;
+use crate::ws_forwarder::WsForwarder;
+
+/// Time between two pings measuring the control connection's round trip
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long to wait, after saying goodbye, for the server to release the
+/// tunnels and close the connection
+const GOODBYE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The server refused to open the requested tunnel
+#[derive(Debug, thiserror::Error)]
+#[error("Tunnel denied: {reason}")]
+pub struct TunnelDenied {
+    pub reason: String,
+}
+
+/// The server released the tunnel once it reached its maximum lifetime,
+/// expecting the client to reconnect for a fresh one
+#[derive(Debug, thiserror::Error)]
+#[error("Tunnel expired after {lifetime_secs}s")]
+pub struct TunnelExpired {
+    pub lifetime_secs: u64,
+}
+
+/// Subdomain assigned by the server, remembered across reconnects
+///
+/// Re-requesting it keeps auto-generated URLs stable when the connection drops.
+#[derive(Debug, Clone, Default)]
+pub struct AssignedSubdomain(Arc<Mutex<Option<String>>>);
+
+impl AssignedSubdomain {
+    pub fn get(&self) -> Option<String> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Store a newly assigned subdomain, returning the previous one
+    fn replace(&self, subdomain: String) -> Option<String> {
+        self.0.lock().unwrap().replace(subdomain)
+    }
+}
+
+/// Resolve once a stop is requested (never, without a shutdown signal)
+async fn stop_requested(shutdown: &mut Option<watch::Receiver<bool>>) {
+    match shutdown {
+        Some(shutdown) => {
+            let _ = shutdown.wait_for(|stop| *stop).await;
+        }
+        None => std::future::pending().await,
+    }
+}
 
 /// Manages the connection to the tunnel server
 pub struct TunnelConnection {
     tls_stream: TlsStream<TcpStream>,
-    local_addr: String,
+    local_target: LocalTarget,
     metrics: MetricsCollector,
     tunnel_type: TunnelType,
+    /// Shared with the other sessions of a client, so its pool stays warm
+    http_forwarder: HttpForwarder,
+    assigned_subdomain: AssignedSubdomain,
+    socket_options: SocketOptions,
+    forward_host: ForwardHost,
+    read_buffer: ReadBufferPolicy,
+    local_concurrency: LocalConcurrency,
+    shutdown: Option<watch::Receiver<bool>>,
 }
 
 impl TunnelConnection {
     pub fn new(
         tls_stream: TlsStream<TcpStream>,
-        local_addr: String,
+        local_target: LocalTarget,
         metrics: MetricsCollector,
         tunnel_type: TunnelType,
     ) -> Self {
         Self {
+            http_forwarder: HttpForwarder::new(local_target.clone()),
             tls_stream,
-            local_addr,
+            local_target,
             metrics,
             tunnel_type,
+            assigned_subdomain: AssignedSubdomain::default(),
+            socket_options: SocketOptions::default(),
+            forward_host: ForwardHost::default(),
+            read_buffer: ReadBufferPolicy::default(),
+            local_concurrency: LocalConcurrency::default(),
+            shutdown: None,
         }
     }
 
+    /// Record the subdomain the server assigns in `assigned`
+    pub fn with_assigned_subdomain(mut self, assigned: AssignedSubdomain) -> Self {
+        self.assigned_subdomain = assigned;
+        self
+    }
+
+    /// Forward HTTP requests with `forwarder`, and its pool of local connections
+    pub fn with_http_forwarder(mut self, forwarder: HttpForwarder) -> Self {
+        self.http_forwarder = forwarder;
+        self
+    }
+
+    /// Apply `options` to TCP and WebSocket connections to the local service
+    pub fn with_socket_options(mut self, options: SocketOptions) -> Self {
+        self.socket_options = options;
+        self
+    }
+
+    /// Host header sent to the local service on WebSocket upgrades
+    pub fn with_forward_host(mut self, forward_host: ForwardHost) -> Self {
+        self.forward_host = forward_host;
+        self
+    }
+
+    /// Size the buffer messages from the server are read into
+    pub fn with_read_buffer(mut self, policy: ReadBufferPolicy) -> Self {
+        self.read_buffer = policy;
+        self
+    }
+
+    /// Bound the requests forwarded to the local service at once
+    pub fn with_local_concurrency(mut self, limit: LocalConcurrency) -> Self {
+        self.local_concurrency = limit;
+        self
+    }
+
+    /// Say goodbye to the server and end the session once `shutdown` turns
+    /// true or its sender is dropped
+    pub fn with_shutdown(mut self, shutdown: watch::Receiver<bool>) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
     /// Request a tunnel from the server
     pub async fn request_tunnel(
         &mut self,
         subdomain: Option<String>,
+        base_domain: Option<String>,
         tunnel_type: TunnelType,
+        http_policy: Option<HttpPolicy>,
     ) -> Result<()> {
-        // Parse local port from address
-        let local_port: u16 = self
-            .local_addr
-            .split(':')
-            .next_back()
-            .and_then(|s| s.parse().ok())
-            .unwrap_or(0);
+        let local_port = self.local_target.port();
+
+        let websocket =
+            tunnel_type == TunnelType::Http && WsForwarder::supports(&self.local_target);
+        let flow_control = tunnel_type == TunnelType::Tcp;
 
         let msg = ClientMessage::RequestTunnel {
             subdomain,
             tunnel_type,
             local_port,
+            http_policy,
+            websocket,
+            base_domain,
+            flow_control,
         };
+        self.send(msg).await?;
+
+        tracing::debug!("Sent tunnel request");
+        Ok(())
+    }
+
+    /// Ask the server to describe itself (answered with `ServerInfo`, logged by `run`)
+    pub async fn send_hello(&mut self) -> Result<()> {
+        self.send(ClientMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+        })
+        .await
+    }
 
-        // Encode and send
+    /// Encode and send a message before the read/write tasks take over the stream
+    async fn send(&mut self, msg: ClientMessage) -> Result<()> {
         let mut codec = TunnelCodec::<ClientMessage>::new();
         let mut buf = BytesMut::new();
         codec.encode(msg, &mut buf)?;
 
         self.tls_stream.write_all(&buf).await?;
         self.tls_stream.flush().await?;
-
-        tracing::debug!("Sent tunnel request");
         Ok(())
     }
 
     /// Run the tunnel connection, processing messages until disconnection
     pub async fn run(self) -> Result<()> {
-        let local_addr = self.local_addr.clone();
+        let local_target = self.local_target.clone();
         let metrics = self.metrics.clone();
         let tunnel_type = self.tunnel_type.clone();
+        let assigned_subdomain = self.assigned_subdomain.clone();
+        let socket_options = self.socket_options;
+        let forward_host = self.forward_host;
+        let read_buffer = self.read_buffer;
+        let local_concurrency = self.local_concurrency;
+        let mut shutdown = self.shutdown;
         let (read_half, write_half) = tokio::io::split(self.tls_stream);
 
         // Channel for sending responses back to server
@@ -105,22 +246,50 @@ impl TunnelConnection {
         });
 
         // Read loop
+        let mut read_error = None;
+        let mut said_goodbye = false;
         let mut read_half = read_half;
         let mut codec = TunnelCodec::<ServerMessage>::new();
-        let mut read_buf = BytesMut::with_capacity(8192);
-        let http_forwarder = HttpForwarder::new(local_addr.clone());
-        let tcp_forwarder = TcpForwarder::new(local_addr, response_tx.clone());
+        let mut read_buf = read_buffer.new_buffer();
+        let http_forwarder = self.http_forwarder;
+        let mut tcp_forwarder =
+            TcpForwarder::new(local_target.clone(), response_tx.clone(), metrics.clone())
+                .with_socket_options(socket_options);
+        let ws_forwarder = Arc::new(
+            WsForwarder::new(local_target, response_tx.clone(), metrics.clone())
+                .with_socket_options(socket_options)
+                .with_forward_host(forward_host),
+        );
+
+        // Ping timestamps are microseconds since the session started
+        let epoch = Instant::now();
+        let mut ping = tokio::time::interval(PING_INTERVAL);
+        ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
         loop {
-            // Read more data
-            match read_half.read_buf(&mut read_buf).await {
+            // Read more data, pinging the server while it's quiet
+            let read = tokio::select! {
+                read = read_half.read_buf(&mut read_buf) => read,
+                _ = ping.tick() => {
+                    let timestamp = epoch.elapsed().as_micros() as u64;
+                    let _ = response_tx.send(ClientMessage::Ping { timestamp }).await;
+                    continue;
+                }
+                _ = stop_requested(&mut shutdown) => {
+                    let reason = "client shutting down".to_string();
+                    let _ = response_tx.send(ClientMessage::Goodbye { reason }).await;
+                    said_goodbye = true;
+                    break;
+                }
+            };
+            match read {
                 Ok(0) => {
                     tracing::info!("Server disconnected");
                     break;
                 }
                 Ok(_) => {}
                 Err(e) => {
-                    tracing::error!("Read error: {}", e);
+                    read_error = Some(e);
                     break;
                 }
             }
@@ -129,21 +298,64 @@ impl TunnelConnection {
             loop {
                 match codec.decode(&mut read_buf) {
                     Ok(Some(msg)) => {
+                        metrics.record_server_message();
                         match msg {
+                            ServerMessage::ServerInfo {
+                                base_domain,
+                                other_base_domains,
+                                protocol_version,
+                                supported_types,
+                                max_tunnels,
+                            } => {
+                                let types: Vec<String> =
+                                    supported_types.iter().map(|t| t.to_string()).collect();
+                                let base_domains: Vec<String> = std::iter::once(base_domain)
+                                    .chain(other_base_domains)
+                                    .collect();
+                                tracing::info!(
+                                    "Server info: base domains {}, protocol v{}, tunnel types [{}], max tunnels {}",
+                                    base_domains.join(", "),
+                                    protocol_version,
+                                    types.join(", "),
+                                    max_tunnels.map_or("unlimited".to_string(), |n| n.to_string())
+                                );
+                                if protocol_version != PROTOCOL_VERSION {
+                                    tracing::warn!(
+                                        "Server speaks protocol v{}, this client v{}",
+                                        protocol_version,
+                                        PROTOCOL_VERSION
+                                    );
+                                }
+                            }
                             ServerMessage::TunnelEstablished {
                                 subdomain,
                                 url,
                                 port,
+                                flow_control,
                             } => {
+                                tcp_forwarder.set_flow_control(flow_control);
                                 tracing::info!(
                                     "Tunnel established: {} -> {}",
                                     url,
-                                    http_forwarder.local_addr()
+                                    http_forwarder.target()
                                 );
                                 if let Some(p) = port {
                                     tracing::debug!("  TCP Port: {}", p);
                                 }
 
+                                if let Some(previous) =
+                                    assigned_subdomain.replace(subdomain.clone())
+                                {
+                                    if previous != subdomain {
+                                        let msg = format!(
+                                            "Subdomain changed from {} to {} after reconnecting; links to the old URL no longer work",
+                                            previous, subdomain
+                                        );
+                                        tracing::warn!("{}", msg);
+                                        metrics.record_error(msg);
+                                    }
+                                }
+
                                 // Update metrics with tunnel info for TUI
                                 metrics.set_tunnel_info(TunnelInfo {
                                     subdomain: subdomain.clone(),
@@ -154,20 +366,23 @@ impl TunnelConnection {
                             }
                             ServerMessage::TunnelDenied { reason } => {
                                 tracing::error!("Tunnel denied: {}", reason);
-                                anyhow::bail!("Tunnel denied: {}", reason);
+                                return Err(TunnelDenied { reason }.into());
                             }
                             ServerMessage::HttpRequest {
                                 stream_id,
                                 method,
                                 uri,
-                                headers,
+                                mut headers,
                                 body,
+                                scheme,
                             } => {
                                 tracing::debug!("HTTP request {}: {} {}", stream_id, method, uri);
+                                set_forwarded_proto(&mut headers, scheme.as_deref());
 
                                 // Forward request to local service
                                 let tx = response_tx.clone();
                                 let fwd = http_forwarder.clone();
+                                let local_concurrency = local_concurrency.clone();
                                 let metrics_clone = metrics.clone();
                                 let method_clone = method.clone();
                                 let uri_clone = uri.clone();
@@ -176,6 +391,38 @@ impl TunnelConnection {
                                 let start = std::time::Instant::now();
 
                                 tokio::spawn(async move {
+                                    // Held until the response is in, bounding local connections
+                                    let Some(_slot) = local_concurrency.acquire().await else {
+                                        let err_msg = "Local service is busy, try again later";
+                                        tracing::warn!(
+                                            "Too many requests in flight to the local service, rejecting {} {}",
+                                            method_clone,
+                                            uri_clone
+                                        );
+                                        metrics_clone.record_forward_error(
+                                            "saturated",
+                                            format!(
+                                                "Rejected {} {}: local concurrency limit reached",
+                                                method_clone, uri_clone
+                                            ),
+                                        );
+                                        metrics_clone.record_request_complete(
+                                            503,
+                                            start.elapsed(),
+                                            err_msg.len(),
+                                            method_clone,
+                                            uri_clone,
+                                        );
+                                        let msg = ClientMessage::HttpResponse {
+                                            stream_id,
+                                            status: 503,
+                                            headers: vec![],
+                                            body: err_msg.as_bytes().to_vec(),
+                                        };
+                                        let _ = tx.send(msg).await;
+                                        return;
+                                    };
+
                                     match fwd.forward_http(method, uri, headers, body).await {
                                         Ok((status, resp_headers, resp_body)) => {
                                             let duration = start.elapsed();
@@ -198,13 +445,17 @@ impl TunnelConnection {
                                         }
                                         Err(e) => {
                                             let duration = start.elapsed();
-                                            let err_msg = format!("Forwarding error: {}", e);
-                                            metrics_clone.record_error(format!(
-                                                "Failed to forward {} {}: {}",
-                                                method_clone, uri_clone, e
-                                            ));
+                                            let kind = ForwardErrorKind::classify(&e);
+                                            let err_msg = format!("{}: {}", kind.message(), e);
+                                            metrics_clone.record_forward_error(
+                                                kind.as_str(),
+                                                format!(
+                                                    "Failed to forward {} {}: {}",
+                                                    method_clone, uri_clone, e
+                                                ),
+                                            );
                                             metrics_clone.record_request_complete(
-                                                502,
+                                                kind.status(),
                                                 duration,
                                                 err_msg.len(),
                                                 method_clone,
@@ -212,14 +463,15 @@ impl TunnelConnection {
                                             );
 
                                             tracing::warn!(
-                                                "Failed to forward request to local service: {}",
+                                                "Failed to forward request to local service ({}): {}",
+                                                kind.as_str(),
                                                 e
                                             );
 
                                             // Send error response
                                             let msg = ClientMessage::HttpResponse {
                                                 stream_id,
-                                                status: 502,
+                                                status: kind.status(),
                                                 headers: vec![],
                                                 body: err_msg.into_bytes(),
                                             };
@@ -228,9 +480,12 @@ impl TunnelConnection {
                                     }
                                 });
                             }
-                            ServerMessage::TcpConnect { stream_id } => {
+                            ServerMessage::TcpConnect {
+                                stream_id,
+                                peer_addr,
+                            } => {
                                 tracing::debug!("TCP connect: {}", stream_id);
-                                tcp_forwarder.handle_connect(stream_id).await;
+                                tcp_forwarder.handle_connect(stream_id, peer_addr).await;
                             }
                             ServerMessage::TcpData { stream_id, data } => {
                                 tracing::debug!("TCP data {}: {} bytes", stream_id, data.len());
@@ -240,8 +495,44 @@ impl TunnelConnection {
                                 tracing::debug!("TCP close: {}", stream_id);
                                 tcp_forwarder.handle_close(stream_id);
                             }
+                            ServerMessage::TcpWindowUpdate { stream_id, bytes } => {
+                                tcp_forwarder.handle_window_update(stream_id, bytes);
+                            }
+                            ServerMessage::WsUpgrade {
+                                stream_id,
+                                uri,
+                                headers,
+                                scheme,
+                            } => {
+                                tracing::debug!("WebSocket upgrade {}: {}", stream_id, uri);
+                                // The local handshake can take a while; don't hold up other streams
+                                let fwd = ws_forwarder.clone();
+                                tokio::spawn(async move {
+                                    fwd.handle_upgrade(stream_id, uri, headers, scheme).await;
+                                });
+                            }
+                            ServerMessage::WsData { stream_id, data } => {
+                                tracing::debug!(
+                                    "WebSocket data {}: {} bytes",
+                                    stream_id,
+                                    data.len()
+                                );
+                                ws_forwarder.handle_data(stream_id, data).await;
+                            }
+                            ServerMessage::WsClose { stream_id } => {
+                                tracing::debug!("WebSocket close: {}", stream_id);
+                                ws_forwarder.handle_close(stream_id);
+                            }
                             ServerMessage::Pong { timestamp } => {
-                                tracing::debug!("Pong: {}", timestamp);
+                                let rtt = epoch
+                                    .elapsed()
+                                    .saturating_sub(Duration::from_micros(timestamp));
+                                tracing::debug!("Pong: {:?} round trip", rtt);
+                                metrics.record_ping_rtt(rtt);
+                            }
+                            ServerMessage::TunnelExpired { lifetime_secs } => {
+                                tracing::info!("Tunnel expired after {}s", lifetime_secs);
+                                return Err(TunnelExpired { lifetime_secs }.into());
                             }
                         }
                     }
@@ -252,14 +543,40 @@ impl TunnelConnection {
                     }
                 }
             }
+
+            // Give back memory grown for a burst of large frames
+            read_buffer.reclaim(&mut read_buf);
         }
 
-        // Drop the sender to signal the write task to shutdown gracefully
+        // Drop the senders to signal the write task to shutdown gracefully
+        drop(tcp_forwarder);
+        drop(ws_forwarder);
         drop(response_tx);
 
+        // A connection lost without a clean close is worth reconnecting over,
+        // and has nothing left to flush
+        if let Some(e) = read_error {
+            write_handle.abort();
+            return Err(anyhow::Error::new(e).context("Control connection lost"));
+        }
+
         // Wait for the write task to complete (sends TLS close_notify)
         let _ = write_handle.await;
 
+        // The server closes its side once it has released our tunnels, after
+        // which they can be requested again
+        if said_goodbye {
+            let released = tokio::time::timeout(GOODBYE_TIMEOUT, async {
+                while matches!(read_half.read_buf(&mut read_buf).await, Ok(n) if n > 0) {
+                    read_buf.clear();
+                }
+            })
+            .await;
+            if released.is_err() {
+                tracing::debug!("Server did not close the connection after goodbye");
+            }
+        }
+
         Ok(())
     }
 }
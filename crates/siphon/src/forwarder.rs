@@ -1,25 +1,363 @@
-use anyhow::Result;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+#[cfg(feature = "otel")]
+use siphon_common::otel;
+use siphon_common::otel::OTEL_TARGET;
+use siphon_common::SocketOptions;
+
+use crate::body_dump::BodyDump;
+use crate::local_concurrency::DEFAULT_MAX_LOCAL_CONCURRENCY;
+use crate::local_target::LocalTarget;
+
+/// Methods that are safe to send twice
+const IDEMPOTENT_METHODS: &[&str] = &["GET", "HEAD", "OPTIONS", "PUT", "DELETE", "TRACE"];
+
+/// Check whether an HTTP method is idempotent (RFC 9110 section 9.2.2)
+pub fn is_idempotent(method: &str) -> bool {
+    IDEMPOTENT_METHODS
+        .iter()
+        .any(|m| m.eq_ignore_ascii_case(method))
+}
+
+/// Retry behavior for requests that fail to reach the local service
+///
+/// Only connection failures are retried; a response from the local service,
+/// even a 5xx, is passed through as is.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt (0 disables retrying)
+    pub max_retries: u32,
+    /// Methods eligible for retry (non-idempotent methods are never retried)
+    pub methods: Vec<String>,
+    /// Delay before the first retry, doubled for each subsequent one
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 0,
+            methods: vec!["GET".to_string(), "HEAD".to_string(), "OPTIONS".to_string()],
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Number of retries allowed for a request with this method
+    fn retries_for(&self, method: &str) -> u32 {
+        let eligible =
+            is_idempotent(method) && self.methods.iter().any(|m| m.eq_ignore_ascii_case(method));
+        if eligible {
+            self.max_retries
+        } else {
+            0
+        }
+    }
+}
+
+/// Idle connections to the local service kept open for reuse
+///
+/// Requests beyond `max_idle` in flight at once still get their own
+/// connection, but it's closed instead of pooled afterwards. The default
+/// matches the local concurrency limit so a busy tunnel doesn't keep opening
+/// and closing connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalPool {
+    /// Idle connections kept per local target (0 = a new connection per request)
+    pub max_idle: usize,
+    /// How long an idle connection is kept before it's closed
+    pub idle_timeout: Duration,
+}
+
+/// Idle connections kept to the local service by default
+pub const DEFAULT_LOCAL_POOL_MAX_IDLE: usize = DEFAULT_MAX_LOCAL_CONCURRENCY;
+
+/// How long idle connections to the local service are kept by default
+pub const DEFAULT_LOCAL_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+impl Default for LocalPool {
+    fn default() -> Self {
+        Self {
+            max_idle: DEFAULT_LOCAL_POOL_MAX_IDLE,
+            idle_timeout: DEFAULT_LOCAL_POOL_IDLE_TIMEOUT,
+        }
+    }
+}
+
+/// Host header sent to the local service
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ForwardHost {
+    /// Drop the visitor's Host; the local service sees its own address
+    #[default]
+    Strip,
+    /// Forward the public Host the visitor used (for virtual-host routing)
+    Preserve,
+    /// Always send this Host
+    Rewrite(String),
+}
+
+impl ForwardHost {
+    /// Host to send, given the one the visitor sent (`None` leaves it to the target)
+    pub(crate) fn host<'a>(&'a self, public: Option<&'a str>) -> Option<&'a str> {
+        match self {
+            Self::Strip => None,
+            Self::Preserve => public,
+            Self::Rewrite(host) => Some(host),
+        }
+    }
+}
+
+impl std::str::FromStr for ForwardHost {
+    type Err = anyhow::Error;
+
+    /// Parse `strip`, `preserve` or `rewrite:<host>`
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "strip" => Ok(Self::Strip),
+            "preserve" => Ok(Self::Preserve),
+            _ => match s.strip_prefix("rewrite:") {
+                Some(host) if !host.is_empty() => Ok(Self::Rewrite(host.to_string())),
+                Some(_) => anyhow::bail!("rewrite:<host> needs a host"),
+                None => anyhow::bail!(
+                    "Invalid forward host mode '{}' (expected strip, preserve or rewrite:<host>)",
+                    s
+                ),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for ForwardHost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Strip => f.write_str("strip"),
+            Self::Preserve => f.write_str("preserve"),
+            Self::Rewrite(host) => write!(f, "rewrite:{}", host),
+        }
+    }
+}
+
+/// Value of the `Host` header among `headers`
+pub(crate) fn host_header(headers: &[(String, String)]) -> Option<&str> {
+    headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("host"))
+        .map(|(_, value)| value.as_str())
+}
+
+/// The local service sent response headers but the body was cut off
+#[derive(Debug)]
+pub(crate) struct IncompleteBody;
+
+impl std::fmt::Display for IncompleteBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("response body was cut off")
+    }
+}
+
+/// Why a request couldn't be forwarded to the local service
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardErrorKind {
+    /// Nothing accepted the connection (service down or restarting)
+    Refused,
+    /// The connection was reset or closed before a response arrived
+    Reset,
+    /// The local service didn't answer in time
+    Timeout,
+    /// The response started but the connection dropped mid-body
+    IncompleteBody,
+    /// Anything else (malformed request, TLS failure, ...)
+    Other,
+}
+
+impl ForwardErrorKind {
+    /// Classify an error returned by [`HttpForwarder::forward_http`]
+    pub fn classify(err: &anyhow::Error) -> Self {
+        if err.downcast_ref::<IncompleteBody>().is_some() {
+            return Self::IncompleteBody;
+        }
+
+        let mut connect = false;
+        let mut reset = false;
+        for cause in err.chain() {
+            if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+                if e.is_timeout() {
+                    return Self::Timeout;
+                }
+                connect |= e.is_connect();
+            } else if let Some(e) = cause.downcast_ref::<hyper::Error>() {
+                if e.is_timeout() {
+                    return Self::Timeout;
+                }
+                reset |= e.is_incomplete_message() || e.is_closed() || e.is_canceled();
+            } else if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+                use std::io::ErrorKind;
+                match e.kind() {
+                    ErrorKind::TimedOut => return Self::Timeout,
+                    // A missing Unix socket is as good as a refused connection
+                    ErrorKind::ConnectionRefused | ErrorKind::NotFound => connect = true,
+                    ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::UnexpectedEof => reset = true,
+                    _ => {}
+                }
+            }
+        }
+
+        if connect {
+            Self::Refused
+        } else if reset {
+            Self::Reset
+        } else {
+            Self::Other
+        }
+    }
+
+    /// Status sent back to the visitor: 504 for timeouts, 502 otherwise
+    pub fn status(self) -> u16 {
+        match self {
+            Self::Timeout => 504,
+            _ => 502,
+        }
+    }
+
+    /// Short name used as the metrics category
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Refused => "refused",
+            Self::Reset => "reset",
+            Self::Timeout => "timeout",
+            Self::IncompleteBody => "incomplete_body",
+            Self::Other => "other",
+        }
+    }
+
+    /// Explanation for the visitor
+    pub fn message(self) -> &'static str {
+        match self {
+            Self::Refused => "Local service is not accepting connections",
+            Self::Reset => "Local service closed the connection without responding",
+            Self::Timeout => "Local service did not respond in time",
+            Self::IncompleteBody => "Local service closed the connection mid-response",
+            Self::Other => "Failed to forward the request to the local service",
+        }
+    }
+}
+
+/// Hop-by-hop headers that must not be forwarded in either direction
+fn is_hop_by_hop(name: &str) -> bool {
+    matches!(
+        name,
+        "connection"
+            | "keep-alive"
+            | "proxy-authenticate"
+            | "proxy-authorization"
+            | "te"
+            | "trailers"
+            | "transfer-encoding"
+            | "upgrade"
+    )
+}
+
+/// Replace any client-supplied `X-Forwarded-Proto` with the scheme reported by the server
+pub fn set_forwarded_proto(headers: &mut Vec<(String, String)>, scheme: Option<&str>) {
+    let Some(scheme) = scheme else {
+        return;
+    };
+    headers.retain(|(name, _)| !name.eq_ignore_ascii_case("x-forwarded-proto"));
+    headers.push(("X-Forwarded-Proto".to_string(), scheme.to_string()));
+}
+
+/// HTTP client for the local service, keeping idle connections as `pool` says
+///
+/// `insecure` turns off certificate verification; this client only ever talks
+/// to the local target, never to the tunnel server.
+fn build_client(options: &SocketOptions, insecure: bool, pool: LocalPool) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder()
+        .pool_max_idle_per_host(pool.max_idle)
+        .pool_idle_timeout(pool.idle_timeout)
+        .tcp_nodelay(options.nodelay)
+        .danger_accept_invalid_certs(insecure);
+    if let Some(keepalive) = &options.keepalive {
+        builder = builder
+            .tcp_keepalive(keepalive.idle)
+            .tcp_keepalive_interval(keepalive.interval)
+            .tcp_keepalive_retries(keepalive.count);
+    }
+    builder.build().expect("Failed to create HTTP client")
+}
 
 /// Forwards incoming tunnel requests to a local service
 #[derive(Clone)]
 pub struct HttpForwarder {
-    local_addr: String,
+    target: LocalTarget,
     client: reqwest::Client,
+    retry: RetryPolicy,
+    body_dump: Option<BodyDump>,
+    socket_options: SocketOptions,
+    insecure: bool,
+    pool: LocalPool,
+    forward_host: ForwardHost,
 }
 
 impl HttpForwarder {
-    pub fn new(local_addr: String) -> Self {
+    pub fn new(target: LocalTarget) -> Self {
         Self {
-            local_addr,
-            client: reqwest::Client::builder()
-                .pool_max_idle_per_host(10)
-                .build()
-                .expect("Failed to create HTTP client"),
+            target,
+            client: build_client(&SocketOptions::default(), false, LocalPool::default()),
+            retry: RetryPolicy::default(),
+            body_dump: None,
+            socket_options: SocketOptions::default(),
+            insecure: false,
+            pool: LocalPool::default(),
+            forward_host: ForwardHost::default(),
         }
     }
 
-    pub fn local_addr(&self) -> &str {
-        &self.local_addr
+    /// Apply `options` to connections to the local service
+    pub fn with_socket_options(mut self, options: SocketOptions) -> Self {
+        self.socket_options = options;
+        self.client = build_client(&self.socket_options, self.insecure, self.pool);
+        self
+    }
+
+    /// Accept any certificate from an `https://` local target (self-signed dev certs)
+    pub fn with_insecure_local_tls(mut self, insecure: bool) -> Self {
+        self.insecure = insecure;
+        self.client = build_client(&self.socket_options, self.insecure, self.pool);
+        self
+    }
+
+    /// Keep idle connections to the local service as `pool` says
+    pub fn with_local_pool(mut self, pool: LocalPool) -> Self {
+        self.pool = pool;
+        self.client = build_client(&self.socket_options, self.insecure, self.pool);
+        self
+    }
+
+    /// Retry idempotent requests that fail to connect to the local service
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Host header sent to the local service (stripped by default)
+    pub fn with_forward_host(mut self, forward_host: ForwardHost) -> Self {
+        self.forward_host = forward_host;
+        self
+    }
+
+    /// Log redacted request and response bodies at debug level
+    pub fn with_body_dump(mut self, body_dump: Option<BodyDump>) -> Self {
+        self.body_dump = body_dump;
+        self
+    }
+
+    pub fn target(&self) -> &LocalTarget {
+        &self.target
     }
 
     /// Forward an HTTP request to the local service
@@ -29,9 +367,136 @@ impl HttpForwarder {
         uri: String,
         headers: Vec<(String, String)>,
         body: Vec<u8>,
+    ) -> Result<(u16, Vec<(String, String)>, Vec<u8>)> {
+        let span = tracing::info_span!(
+            target: OTEL_TARGET,
+            "forward_http",
+            http.request.method = %method,
+            url.path = uri.split('?').next().unwrap_or_default(),
+            http.response.status_code = tracing::field::Empty,
+        );
+        // Continues the server's span, from the context it sent along
+        #[cfg(feature = "otel")]
+        otel::set_parent_from(
+            &span,
+            headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        );
+        // Held but not entered: pooled connections to the local service are
+        // spawned in the current span, and would keep it open while idle
+        self.forward_http_in_span(&span, method, uri, headers, body)
+            .await
+    }
+
+    async fn forward_http_in_span(
+        &self,
+        span: &tracing::Span,
+        method: String,
+        uri: String,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    ) -> Result<(u16, Vec<(String, String)>, Vec<u8>)> {
+        // Filter out hop-by-hop headers (and Host, which is set per target
+        // unless the forward host mode says otherwise)
+        let host = self
+            .forward_host
+            .host(host_header(&headers))
+            .map(str::to_string);
+        let mut headers: Vec<(String, String)> = headers
+            .into_iter()
+            .filter(|(name, _)| {
+                let name_lower = name.to_lowercase();
+                name_lower != "host" && !is_hop_by_hop(&name_lower)
+            })
+            .collect();
+        if let Some(host) = host {
+            headers.push(("Host".to_string(), host));
+        }
+        // The local service's spans, if it traces, belong under this one
+        #[cfg(feature = "otel")]
+        otel::inject_context(span, &mut headers);
+
+        if let Some(dump) = &self.body_dump {
+            dump.log_request(&method, &uri, &headers, &body);
+        }
+
+        let retries = self.retry.retries_for(&method);
+        let (status, resp_headers, resp_body) = if retries == 0 {
+            self.send(method.clone(), uri.clone(), headers, body)
+                .await?
+        } else {
+            let mut attempt = 0;
+            loop {
+                let result = self
+                    .send(method.clone(), uri.clone(), headers.clone(), body.clone())
+                    .await;
+                match result {
+                    Err(e)
+                        if attempt < retries
+                            && ForwardErrorKind::classify(&e) == ForwardErrorKind::Refused =>
+                    {
+                        let delay = self.retry.backoff * 2u32.saturating_pow(attempt);
+                        attempt += 1;
+                        tracing::debug!(
+                            "Retrying {} {} in {:?} ({}/{}): {}",
+                            method,
+                            uri,
+                            delay,
+                            attempt,
+                            retries,
+                            e
+                        );
+                        tokio::time::sleep(delay).await;
+                    }
+                    result => break result?,
+                }
+            }
+        };
+
+        tracing::debug!("Response: {} ({} bytes)", status, resp_body.len());
+        span.record("http.response.status_code", status);
+        if let Some(dump) = &self.body_dump {
+            dump.log_response(&method, &uri, status, &resp_headers, &resp_body);
+        }
+
+        Ok((status, resp_headers, resp_body))
+    }
+
+    /// Send a request once to the local target
+    async fn send(
+        &self,
+        method: String,
+        uri: String,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    ) -> Result<(u16, Vec<(String, String)>, Vec<u8>)> {
+        match &self.target {
+            LocalTarget::Tcp(addr) => {
+                self.forward_tcp("http", addr, method, uri, headers, body)
+                    .await
+            }
+            LocalTarget::Https(addr) => {
+                self.forward_tcp("https", addr, method, uri, headers, body)
+                    .await
+            }
+            #[cfg(unix)]
+            LocalTarget::Unix(path) => forward_unix(path, method, uri, headers, body).await,
+        }
+    }
+
+    /// Forward over TCP using the pooled reqwest client
+    async fn forward_tcp(
+        &self,
+        scheme: &str,
+        addr: &str,
+        method: String,
+        uri: String,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
     ) -> Result<(u16, Vec<(String, String)>, Vec<u8>)> {
         // Build the local URL
-        let local_url = format!("http://{}{}", self.local_addr, uri);
+        let local_url = format!("{}://{}{}", scheme, addr, uri);
 
         tracing::debug!("Forwarding {} {} -> {}", method, uri, local_url);
 
@@ -39,24 +504,7 @@ impl HttpForwarder {
         let method = reqwest::Method::from_bytes(method.as_bytes())?;
         let mut request = self.client.request(method, &local_url);
 
-        // Add headers (filtering out hop-by-hop headers)
         for (name, value) in headers {
-            let name_lower = name.to_lowercase();
-            // Skip hop-by-hop headers
-            if matches!(
-                name_lower.as_str(),
-                "host"
-                    | "connection"
-                    | "keep-alive"
-                    | "proxy-authenticate"
-                    | "proxy-authorization"
-                    | "te"
-                    | "trailers"
-                    | "transfer-encoding"
-                    | "upgrade"
-            ) {
-                continue;
-            }
             request = request.header(&name, &value);
         }
 
@@ -70,35 +518,360 @@ impl HttpForwarder {
 
         // Extract response
         let status = response.status().as_u16();
+        let resp_headers = filter_response_headers(response.headers());
+        // The whole body is read before anything is sent back, so a connection
+        // dropped mid-body becomes an error response, never a truncated success
+        let resp_body = response.bytes().await.context(IncompleteBody)?.to_vec();
 
-        let resp_headers: Vec<(String, String)> = response
-            .headers()
-            .iter()
-            .filter_map(|(name, value)| {
-                let name_str = name.to_string();
-                let name_lower = name_str.to_lowercase();
-                // Skip hop-by-hop headers
-                if matches!(
-                    name_lower.as_str(),
-                    "connection"
-                        | "keep-alive"
-                        | "proxy-authenticate"
-                        | "proxy-authorization"
-                        | "te"
-                        | "trailers"
-                        | "transfer-encoding"
-                        | "upgrade"
-                ) {
-                    return None;
-                }
-                value.to_str().ok().map(|v| (name_str, v.to_string()))
-            })
-            .collect();
+        Ok((status, resp_headers, resp_body))
+    }
+}
 
-        let resp_body = response.bytes().await?.to_vec();
+/// Forward over a Unix domain socket
+///
+/// reqwest can't dial Unix sockets, so this drives a hyper HTTP/1.1
+/// connection directly. A new connection is opened for each request.
+#[cfg(unix)]
+async fn forward_unix(
+    path: &std::path::Path,
+    method: String,
+    uri: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+) -> Result<(u16, Vec<(String, String)>, Vec<u8>)> {
+    use http_body_util::{BodyExt, Full};
+    use hyper_util::rt::TokioIo;
 
-        tracing::debug!("Response: {} ({} bytes)", status, resp_body.len());
+    tracing::debug!("Forwarding {} {} -> unix:{}", method, uri, path.display());
 
-        Ok((status, resp_headers, resp_body))
+    let stream = tokio::net::UnixStream::connect(path).await?;
+    let (mut sender, connection) =
+        hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            tracing::debug!("Unix socket connection closed: {}", e);
+        }
+    });
+
+    let mut request = hyper::Request::builder().method(method.as_str()).uri(&uri);
+    if host_header(&headers).is_none() {
+        request = request.header(hyper::header::HOST, "localhost");
+    }
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    let request = request.body(Full::new(bytes::Bytes::from(body)))?;
+
+    let response = sender.send_request(request).await?;
+
+    let status = response.status().as_u16();
+    let resp_headers = filter_response_headers(response.headers());
+    let resp_body = response
+        .into_body()
+        .collect()
+        .await
+        .context(IncompleteBody)?
+        .to_bytes()
+        .to_vec();
+
+    Ok((status, resp_headers, resp_body))
+}
+
+/// Convert response headers, skipping hop-by-hop headers
+pub(crate) fn filter_response_headers(headers: &hyper::HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter(|(name, _)| !is_hop_by_hop(name.as_str()))
+        .filter_map(|(name, value)| {
+            value
+                .to_str()
+                .ok()
+                .map(|v| (name.to_string(), v.to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::Infallible;
+
+    use http_body_util::{BodyExt, Full};
+    use hyper::body::Incoming;
+    use hyper::service::service_fn;
+    use hyper_util::rt::TokioIo;
+
+    fn retry_policy(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            backoff: Duration::from_millis(50),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_set_forwarded_proto() {
+        let mut headers = vec![
+            ("x-forwarded-proto".to_string(), "http".to_string()),
+            ("accept".to_string(), "*/*".to_string()),
+        ];
+        set_forwarded_proto(&mut headers, None);
+        assert_eq!(headers.len(), 2);
+
+        set_forwarded_proto(&mut headers, Some("https"));
+        assert_eq!(
+            headers,
+            vec![
+                ("accept".to_string(), "*/*".to_string()),
+                ("X-Forwarded-Proto".to_string(), "https".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_forward_host() {
+        for mode in ["strip", "preserve", "rewrite:app.localhost:3000"] {
+            assert_eq!(mode.parse::<ForwardHost>().unwrap().to_string(), mode);
+        }
+        assert!("rewrite:".parse::<ForwardHost>().is_err());
+        assert!("keep".parse::<ForwardHost>().is_err());
+
+        let public = Some("myapp.tunnel.example.com");
+        assert_eq!(ForwardHost::Strip.host(public), None);
+        assert_eq!(ForwardHost::Preserve.host(public), public);
+        assert_eq!(
+            ForwardHost::Rewrite("app.internal".to_string()).host(public),
+            Some("app.internal")
+        );
+    }
+
+    #[test]
+    fn test_retries_only_idempotent_methods() {
+        let policy = RetryPolicy {
+            methods: vec!["GET".to_string(), "POST".to_string()],
+            ..retry_policy(3)
+        };
+        assert_eq!(policy.retries_for("get"), 3);
+        assert_eq!(policy.retries_for("POST"), 0);
+        assert_eq!(policy.retries_for("DELETE"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_refused_connection() {
+        // Reserve a port, then leave it closed so the first attempt is refused
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(120)).await;
+            let listener = tokio::net::TcpListener::bind(("127.0.0.1", port))
+                .await
+                .unwrap();
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let service = service_fn(|_req: hyper::Request<Incoming>| async {
+                    Ok::<_, Infallible>(hyper::Response::new(Full::new(bytes::Bytes::from(
+                        "recovered",
+                    ))))
+                });
+                tokio::spawn(
+                    hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+
+        let target = LocalTarget::Tcp(format!("127.0.0.1:{}", port));
+        let forwarder = HttpForwarder::new(target).with_retry(retry_policy(5));
+
+        // Non-idempotent requests fail straight away
+        let post = forwarder
+            .forward_http("POST".to_string(), "/".to_string(), vec![], vec![])
+            .await;
+        assert!(post.is_err());
+
+        let (status, _, body) = forwarder
+            .forward_http("GET".to_string(), "/".to_string(), vec![], vec![])
+            .await
+            .unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(body, b"recovered");
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn test_forward_http_over_unix_socket() {
+        use tokio::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("siphon-http-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // Mock service echoing method, path, a header and the body
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service = service_fn(|req: hyper::Request<Incoming>| async move {
+                let summary = format!(
+                    "{} {} {}",
+                    req.method(),
+                    req.uri(),
+                    req.headers()["x-test"].to_str().unwrap()
+                );
+                let body = req.into_body().collect().await.unwrap().to_bytes();
+                let mut echoed = summary.into_bytes();
+                echoed.extend_from_slice(&body);
+                Ok::<_, Infallible>(
+                    hyper::Response::builder()
+                        .header("x-mock", "unix")
+                        .body(Full::new(bytes::Bytes::from(echoed)))
+                        .unwrap(),
+                )
+            });
+            let _ = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
+
+        let forwarder = HttpForwarder::new(LocalTarget::Unix(path.clone()));
+        let (status, headers, body) = forwarder
+            .forward_http(
+                "POST".to_string(),
+                "/api?x=1".to_string(),
+                vec![
+                    ("X-Test".to_string(), "yes".to_string()),
+                    ("Connection".to_string(), "close".to_string()),
+                ],
+                b" payload".to_vec(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(status, 200);
+        assert!(headers.iter().any(|(k, v)| k == "x-mock" && v == "unix"));
+        assert_eq!(body, b"POST /api?x=1 yes payload");
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    /// Serve each connection with `respond`: raw bytes, then close
+    async fn raw_mock(respond: &'static [u8]) -> LocalTarget {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream.write_all(respond).await;
+            }
+        });
+        LocalTarget::Tcp(addr.to_string())
+    }
+
+    async fn forward_error(target: LocalTarget) -> ForwardErrorKind {
+        let err = HttpForwarder::new(target)
+            .forward_http("GET".to_string(), "/".to_string(), vec![], vec![])
+            .await
+            .unwrap_err();
+        ForwardErrorKind::classify(&err)
+    }
+
+    #[tokio::test]
+    async fn test_classify_refused() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let kind = forward_error(LocalTarget::Tcp(format!("127.0.0.1:{}", port))).await;
+        assert_eq!(kind, ForwardErrorKind::Refused);
+        assert_eq!(kind.status(), 502);
+    }
+
+    #[tokio::test]
+    async fn test_classify_closed_before_response() {
+        let kind = forward_error(raw_mock(b"").await).await;
+        assert_eq!(kind, ForwardErrorKind::Reset);
+        assert_eq!(kind.status(), 502);
+    }
+
+    #[tokio::test]
+    async fn test_classify_mid_stream_reset() {
+        // Promises 100 bytes, sends 5, then hangs up
+        let target = raw_mock(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\nhello").await;
+        let kind = forward_error(target).await;
+        assert_eq!(kind, ForwardErrorKind::IncompleteBody);
+        assert_eq!(kind.status(), 502);
+    }
+
+    #[test]
+    fn test_timeout_maps_to_504() {
+        let err = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::TimedOut));
+        let kind = ForwardErrorKind::classify(&err);
+        assert_eq!(kind, ForwardErrorKind::Timeout);
+        assert_eq!(kind.status(), 504);
+        assert_eq!(kind.as_str(), "timeout");
+    }
+
+    #[tokio::test]
+    async fn test_forward_to_self_signed_https() {
+        use std::sync::Arc;
+        use tokio_rustls::rustls::pki_types::PrivateKeyDer;
+
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let cert = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+        let tls_config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.cert.der().clone()],
+                PrivateKeyDer::Pkcs8(cert.signing_key.serialize_der().into()),
+            )
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls_config));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    // Verifying clients abort the handshake
+                    let Ok(stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let service = service_fn(|req: hyper::Request<Incoming>| async move {
+                        let body = format!("secure {}", req.uri().path());
+                        Ok::<_, Infallible>(hyper::Response::new(Full::new(bytes::Bytes::from(
+                            body,
+                        ))))
+                    });
+                    let _ = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+
+        let target: LocalTarget = format!("https://{}", addr).parse().unwrap();
+        let get = |forwarder: HttpForwarder| async move {
+            forwarder
+                .forward_http("GET".to_string(), "/hello".to_string(), vec![], vec![])
+                .await
+        };
+
+        // The self-signed certificate is rejected unless verification is off
+        assert!(get(HttpForwarder::new(target.clone())).await.is_err());
+
+        let insecure = HttpForwarder::new(target).with_insecure_local_tls(true);
+        let (status, _, body) = get(insecure).await.unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(body, b"secure /hello");
     }
 }
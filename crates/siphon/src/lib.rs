@@ -0,0 +1,83 @@
+//! Siphon tunnel client library
+//!
+//! Runs the same tunnel as the `siphon` binary, without the CLI:
+//!
+//! ```no_run
+//! # async fn example(tls: rustls::ClientConfig) -> anyhow::Result<()> {
+//! use siphon::TunnelClient;
+//!
+//! let mut handle = TunnelClient::builder()
+//!     .server("tunnel.example.com:4443")
+//!     .local("127.0.0.1:3000".parse()?)
+//!     .tls(tls)
+//!     .build()?
+//!     .run();
+//!
+//! // Runs (and reconnects) until the tunnel closes or fails for good
+//! handle.wait().await
+//! # }
+//! ```
+//!
+//! Against the e2e test server:
+//!
+//! ```
+//! # #[tokio::main]
+//! # async fn main() -> anyhow::Result<()> {
+//! # let _ = rustls::crypto::ring::default_provider().install_default();
+//! use siphon::TunnelClient;
+//! use siphon_e2e::{MockHttpService, TestServer};
+//!
+//! let server = TestServer::start().await;
+//! let local = MockHttpService::start().await;
+//!
+//! let handle = TunnelClient::builder()
+//!     .server(server.control_addr.to_string())
+//!     .server_name("localhost")
+//!     .local(local.addr_string().parse()?)
+//!     .tls(server.client_tls_config())
+//!     .build()?
+//!     .run();
+//!
+//! tokio::time::timeout(std::time::Duration::from_secs(5), async {
+//!     while handle.metrics().snapshot().tunnel_info.is_none() {
+//!         tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+//!     }
+//! })
+//! .await?;
+//! assert!(handle.subdomain().is_some());
+//!
+//! handle.shutdown().await
+//! # }
+//! ```
+
+mod body_dump;
+mod client;
+mod connector;
+mod forwarder;
+mod local_allowlist;
+mod local_concurrency;
+mod local_scheme;
+mod local_target;
+mod metrics_endpoint;
+mod server_name;
+mod tcp_forwarder;
+mod ws_forwarder;
+
+pub use body_dump::BodyDump;
+pub use client::{
+    ReconnectPolicy, TunnelClient, TunnelClientBuilder, TunnelExit, TunnelHandle,
+    DEFAULT_RECONNECT_DELAY,
+};
+pub use connector::{TunnelDenied, TunnelExpired};
+pub use forwarder::{
+    is_idempotent, ForwardErrorKind, ForwardHost, LocalPool, RetryPolicy,
+    DEFAULT_LOCAL_POOL_IDLE_TIMEOUT, DEFAULT_LOCAL_POOL_MAX_IDLE,
+};
+pub use local_allowlist::LocalAllowlist;
+pub use local_concurrency::{LocalConcurrency, DEFAULT_MAX_LOCAL_CONCURRENCY};
+pub use local_scheme::{detect_local_scheme, probe_tls, LocalScheme, LOCAL_SCHEME_PROBE_TIMEOUT};
+pub use local_target::LocalTarget;
+pub use metrics_endpoint::{serve_metrics, spawn_ticker};
+pub use siphon_common::{KeepaliveOptions, SocketOptions};
+pub use siphon_protocol::ReadBufferPolicy;
+pub use siphon_tui::MetricsCollector;
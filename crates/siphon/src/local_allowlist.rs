@@ -0,0 +1,213 @@
+//! Restricting which local addresses a tunnel may forward to
+//!
+//! Entries of `allowed_local_targets` are either networks in CIDR notation
+//! (`10.0.0.0/8`, `::1/128`, or a bare IP for a single address) or globs on the
+//! `--local` value where `*` matches any sequence (`localhost:*`,
+//! `[::1]:3000`, `unix:/run/*.sock`). An empty list allows any target.
+
+use std::net::{IpAddr, ToSocketAddrs};
+
+use anyhow::Context;
+use siphon_common::{IpRange, IpRangeError};
+use siphon_protocol::glob_match;
+
+use crate::local_target::LocalTarget;
+
+/// Parsed `allowed_local_targets`
+#[derive(Debug, Clone, Default)]
+pub struct LocalAllowlist {
+    networks: Vec<IpRange>,
+    patterns: Vec<String>,
+}
+
+impl LocalAllowlist {
+    /// Parse allowlist entries, rejecting malformed CIDRs
+    pub fn parse(entries: &[String]) -> anyhow::Result<Self> {
+        let mut allowlist = Self::default();
+        for entry in entries {
+            let entry = entry.trim();
+            if entry.is_empty() {
+                anyhow::bail!("Empty entry in allowed_local_targets");
+            }
+            // Anything that isn't an address is a glob
+            match entry.parse::<IpRange>() {
+                Ok(network) => allowlist.networks.push(network),
+                Err(IpRangeError::InvalidAddress(_)) => allowlist.patterns.push(entry.to_string()),
+                Err(e) => return Err(e).context("Invalid allowed_local_targets entry"),
+            }
+        }
+        Ok(allowlist)
+    }
+
+    /// Whether the list allows everything (no entries)
+    pub fn is_empty(&self) -> bool {
+        self.networks.is_empty() && self.patterns.is_empty()
+    }
+
+    /// Check that `target` is allowed
+    ///
+    /// A target matching a glob is allowed as is. Otherwise the host of a TCP
+    /// target must lie in one of the networks; a hostname is resolved and
+    /// every address it resolves to must be covered, so a name can't be used
+    /// to smuggle in an address outside the list.
+    pub fn check(&self, target: &LocalTarget) -> anyhow::Result<()> {
+        if self.is_empty() || self.matches_pattern(&target.to_string()) {
+            return Ok(());
+        }
+
+        let allowed = match target {
+            LocalTarget::Tcp(addr) | LocalTarget::Https(addr) => self.allows_tcp(addr)?,
+            #[cfg(unix)]
+            LocalTarget::Unix(_) => false,
+        };
+        if !allowed {
+            anyhow::bail!(
+                "Local target '{}' is not allowed by allowed_local_targets ({})",
+                target,
+                self.describe()
+            );
+        }
+        Ok(())
+    }
+
+    fn matches_pattern(&self, text: &str) -> bool {
+        self.patterns.iter().any(|p| glob_match(p, text))
+    }
+
+    fn allows_tcp(&self, addr: &str) -> anyhow::Result<bool> {
+        if self.networks.is_empty() {
+            return Ok(false);
+        }
+        let host = match addr.rsplit_once(':') {
+            Some((host, _)) => host,
+            None => addr,
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(self.contains(ip));
+        }
+
+        let resolved: Vec<IpAddr> = addr
+            .to_socket_addrs()
+            .with_context(|| format!("Failed to resolve local target '{}'", addr))?
+            .map(|a| a.ip())
+            .collect();
+        Ok(!resolved.is_empty() && resolved.iter().all(|ip| self.contains(*ip)))
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|n| n.contains(ip))
+    }
+
+    fn describe(&self) -> String {
+        self.networks
+            .iter()
+            .map(IpRange::to_string)
+            .chain(self.patterns.iter().cloned())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowlist(entries: &[&str]) -> LocalAllowlist {
+        let entries: Vec<String> = entries.iter().map(|e| e.to_string()).collect();
+        LocalAllowlist::parse(&entries).unwrap()
+    }
+
+    fn allowed(list: &LocalAllowlist, target: &str) -> bool {
+        list.check(&target.parse().unwrap()).is_ok()
+    }
+
+    #[test]
+    fn test_empty_allows_everything() {
+        let list = allowlist(&[]);
+        assert!(allowed(&list, "10.1.2.3:80"));
+        assert!(allowed(&list, "internal.example:443"));
+    }
+
+    #[test]
+    fn test_ipv4_cidr_membership() {
+        let list = allowlist(&["127.0.0.0/8", "192.168.1.10"]);
+        assert!(allowed(&list, "127.0.0.1:3000"));
+        assert!(allowed(&list, "127.255.0.1:1"));
+        assert!(allowed(&list, "192.168.1.10:8080"));
+        assert!(!allowed(&list, "192.168.1.11:8080"));
+        assert!(!allowed(&list, "10.0.0.1:3000"));
+    }
+
+    #[test]
+    fn test_ipv6_cidr_membership() {
+        let list = allowlist(&["::1/128", "fd00::/8"]);
+        assert!(allowed(&list, "[::1]:3000"));
+        assert!(allowed(&list, "[fd12:3456::1]:80"));
+        assert!(!allowed(&list, "[fe80::1]:80"));
+        assert!(!allowed(&list, "127.0.0.1:3000"));
+    }
+
+    #[test]
+    fn test_ipv4_mapped_ipv6_matches_ipv4_network() {
+        let list = allowlist(&["10.0.0.0/8"]);
+        assert!(allowed(&list, "[::ffff:10.1.2.3]:80"));
+        assert!(!allowed(&list, "[::ffff:11.1.2.3]:80"));
+    }
+
+    #[test]
+    fn test_zero_prefix_matches_whole_family() {
+        let list = allowlist(&["0.0.0.0/0"]);
+        assert!(allowed(&list, "8.8.8.8:53"));
+        assert!(!allowed(&list, "[2001:db8::1]:53"));
+    }
+
+    #[test]
+    fn test_glob_matching() {
+        let list = allowlist(&["localhost:*", "app-*.internal:8080", "[::1]:3000"]);
+        assert!(allowed(&list, "localhost:3000"));
+        assert!(allowed(&list, "app-web.internal:8080"));
+        assert!(!allowed(&list, "app-web.internal:9090"));
+        assert!(allowed(&list, "[::1]:3000"));
+        assert!(!allowed(&list, "[::1]:3001"));
+        assert!(!allowed(&list, "db.internal:5432"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket_glob() {
+        let list = allowlist(&["unix:/run/*.sock", "127.0.0.1"]);
+        assert!(allowed(&list, "unix:/run/app.sock"));
+        assert!(!allowed(&list, "unix:/var/run/app.sock"));
+    }
+
+    #[test]
+    fn test_hostname_must_resolve_inside_networks() {
+        let list = allowlist(&["127.0.0.0/8", "::1/128"]);
+        assert!(allowed(&list, "localhost:3000"));
+
+        let list = allowlist(&["10.0.0.0/8"]);
+        assert!(!allowed(&list, "localhost:3000"));
+    }
+
+    #[test]
+    fn test_invalid_entries() {
+        let parse = |entry: &str| LocalAllowlist::parse(&[entry.to_string()]);
+        assert!(parse("10.0.0.0/33").is_err());
+        assert!(parse("::1/129").is_err());
+        assert!(parse("10.0.0.0/x").is_err());
+        assert!(parse(" ").is_err());
+    }
+
+    #[test]
+    fn test_error_names_target() {
+        let list = allowlist(&["127.0.0.1"]);
+        let err = list
+            .check(&"10.0.0.1:22".parse().unwrap())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("10.0.0.1:22"), "{}", err);
+        assert!(err.contains("127.0.0.1/32"), "{}", err);
+    }
+}
@@ -0,0 +1,110 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Default cap on requests forwarded to the local service at once
+pub const DEFAULT_MAX_LOCAL_CONCURRENCY: usize = 64;
+
+/// How long a queued request waits for a slot before it is answered with 503
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Bounds how many requests are forwarded to the local service at once
+///
+/// Requests over the limit queue (up to as many again as the limit) instead
+/// of opening yet another connection to a dev server that can't keep up;
+/// once the queue is full too, or a slot doesn't free up in time, they are
+/// turned away. Clones share the same slots, so the limit holds across
+/// reconnects.
+#[derive(Debug, Clone)]
+pub struct LocalConcurrency {
+    /// `None` when unlimited
+    slots: Option<Arc<Semaphore>>,
+    queued: Arc<AtomicUsize>,
+    max_queued: usize,
+}
+
+/// A forwarding slot, released when dropped
+#[must_use]
+pub struct LocalSlot {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Default for LocalConcurrency {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_LOCAL_CONCURRENCY)
+    }
+}
+
+impl LocalConcurrency {
+    /// Allow `max` requests at once (0 = unlimited)
+    pub fn new(max: usize) -> Self {
+        Self {
+            slots: (max > 0).then(|| Arc::new(Semaphore::new(max))),
+            queued: Arc::new(AtomicUsize::new(0)),
+            max_queued: max,
+        }
+    }
+
+    /// Wait for a slot, or `None` if the queue is full or the wait timed out
+    pub async fn acquire(&self) -> Option<LocalSlot> {
+        let Some(slots) = &self.slots else {
+            return Some(LocalSlot { _permit: None });
+        };
+        if let Ok(permit) = slots.clone().try_acquire_owned() {
+            return Some(LocalSlot {
+                _permit: Some(permit),
+            });
+        }
+
+        if self.queued.fetch_add(1, Ordering::AcqRel) >= self.max_queued {
+            self.queued.fetch_sub(1, Ordering::AcqRel);
+            return None;
+        }
+        let permit = tokio::time::timeout(QUEUE_TIMEOUT, slots.clone().acquire_owned()).await;
+        self.queued.fetch_sub(1, Ordering::AcqRel);
+
+        match permit {
+            Ok(Ok(permit)) => Some(LocalSlot {
+                _permit: Some(permit),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_queues_then_rejects() {
+        let limit = LocalConcurrency::new(1);
+        let first = limit.acquire().await.expect("free slot");
+
+        // One request may wait for the slot...
+        let waiter = tokio::spawn({
+            let limit = limit.clone();
+            async move { limit.acquire().await.is_some() }
+        });
+        while limit.queued.load(Ordering::Acquire) == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        // ...but the queue is full after that
+        assert!(limit.acquire().await.is_none());
+
+        drop(first);
+        assert!(waiter.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_zero_is_unlimited() {
+        let limit = LocalConcurrency::new(0);
+        let mut slots = Vec::new();
+        for _ in 0..200 {
+            slots.push(limit.acquire().await.expect("unlimited"));
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! Detecting whether a local service speaks HTTP or HTTPS
+//!
+//! A bare `--local 127.0.0.1:8443` is taken as plain HTTP, which fails in
+//! confusing ways against a dev server only listening for TLS. Unless told
+//! otherwise, the client tries a TLS handshake with the service once at
+//! startup and picks the scheme from how it answers.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::pki_types::ServerName;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+use crate::LocalTarget;
+
+/// How long the probe waits for the local service before giving up
+pub const LOCAL_SCHEME_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Scheme of a `--local` target given without one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LocalScheme {
+    /// Probe the service for TLS at startup
+    #[default]
+    Auto,
+    Http,
+    Https,
+}
+
+impl FromStr for LocalScheme {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(LocalScheme::Auto),
+            "http" => Ok(LocalScheme::Http),
+            "https" => Ok(LocalScheme::Https),
+            _ => anyhow::bail!(
+                "Invalid local scheme '{}'. Use 'auto', 'http' or 'https'",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for LocalScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LocalScheme::Auto => "auto",
+            LocalScheme::Http => "http",
+            LocalScheme::Https => "https",
+        })
+    }
+}
+
+/// Whether the service at `addr` (host:port) speaks TLS, None if it
+/// couldn't be reached within `timeout`
+///
+/// The certificate isn't checked: any TLS answer, even an alert, counts.
+pub async fn probe_tls(addr: &str, timeout: Duration) -> Option<bool> {
+    let handshake = async {
+        let stream = TcpStream::connect(addr).await.ok()?;
+        let connector = TlsConnector::from(Arc::new(siphon_common::tls_probe_client_config()));
+        let Err(e) = connector.connect(probe_server_name(addr), stream).await else {
+            return Some(true);
+        };
+        // A plain HTTP server answers the ClientHello with an error page or
+        // hangs up, neither of which parses as TLS
+        let alert = e
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+            .is_some_and(|e| matches!(e, rustls::Error::AlertReceived(_)));
+        Some(alert)
+    };
+    tokio::time::timeout(timeout, handshake)
+        .await
+        .ok()
+        .flatten()
+}
+
+/// Name sent in the probe's ClientHello
+fn probe_server_name(addr: &str) -> ServerName<'static> {
+    let host = addr
+        .rsplit_once(':')
+        .map_or(addr, |(host, _)| host)
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    ServerName::try_from(host.to_string())
+        .unwrap_or_else(|_| ServerName::try_from("localhost").expect("valid DNS name"))
+}
+
+/// `target` with its scheme detected: a TCP target speaking TLS becomes an
+/// HTTPS one, anything else is returned as is
+pub async fn detect_local_scheme(target: LocalTarget, timeout: Duration) -> LocalTarget {
+    let LocalTarget::Tcp(addr) = target else {
+        return target;
+    };
+    match probe_tls(&addr, timeout).await {
+        Some(true) => {
+            tracing::info!("Local service at {} speaks HTTPS", addr);
+            LocalTarget::Https(addr)
+        }
+        Some(false) => {
+            tracing::info!("Local service at {} speaks HTTP", addr);
+            LocalTarget::Tcp(addr)
+        }
+        None => {
+            tracing::warn!(
+                "Could not reach local service at {} to detect its scheme, assuming HTTP \
+                 (set --local-scheme to choose)",
+                addr
+            );
+            LocalTarget::Tcp(addr)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rustls::pki_types::PrivateKeyDer;
+    use siphon_e2e::MockHttpService;
+    use tokio::io::AsyncWriteExt;
+
+    /// Address of a TLS server with a self-signed certificate
+    async fn start_https_mock() -> String {
+        let cert = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+        let tls_config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.cert.der().clone()],
+                PrivateKeyDer::Pkcs8(cert.signing_key.serialize_der().into()),
+            )
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls_config));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(mut stream) = acceptor.accept(stream).await {
+                        let _ = stream.shutdown().await;
+                    }
+                });
+            }
+        });
+        addr.to_string()
+    }
+
+    #[test]
+    fn test_parse_local_scheme() {
+        assert_eq!("auto".parse::<LocalScheme>().unwrap(), LocalScheme::Auto);
+        assert_eq!("HTTP".parse::<LocalScheme>().unwrap(), LocalScheme::Http);
+        assert_eq!("https".parse::<LocalScheme>().unwrap(), LocalScheme::Https);
+        assert!("ftp".parse::<LocalScheme>().is_err());
+        assert_eq!(LocalScheme::default().to_string(), "auto");
+    }
+
+    #[tokio::test]
+    async fn test_detects_http_service() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let mock = MockHttpService::start().await;
+
+        let target = LocalTarget::Tcp(mock.addr_string());
+        assert_eq!(
+            detect_local_scheme(target.clone(), LOCAL_SCHEME_PROBE_TIMEOUT).await,
+            target
+        );
+    }
+
+    #[tokio::test]
+    async fn test_detects_https_service() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let addr = start_https_mock().await;
+
+        let target = LocalTarget::Tcp(addr.clone());
+        assert_eq!(
+            detect_local_scheme(target, LOCAL_SCHEME_PROBE_TIMEOUT).await,
+            LocalTarget::Https(addr)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_service_is_undetected() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        assert_eq!(probe_tls(&addr, LOCAL_SCHEME_PROBE_TIMEOUT).await, None);
+        assert_eq!(
+            detect_local_scheme(LocalTarget::Tcp(addr.clone()), LOCAL_SCHEME_PROBE_TIMEOUT).await,
+            LocalTarget::Tcp(addr)
+        );
+    }
+}
@@ -0,0 +1,139 @@
+use std::fmt;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Prefix marking a Unix domain socket path in `--local`
+const UNIX_PREFIX: &str = "unix:";
+
+/// Prefix marking a local service that speaks HTTPS
+const HTTPS_PREFIX: &str = "https://";
+
+/// Optional prefix of a plain HTTP target (the default)
+const HTTP_PREFIX: &str = "http://";
+
+/// Where the client forwards tunneled traffic to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LocalTarget {
+    /// TCP address (host:port), spoken to in plain HTTP by HTTP tunnels
+    Tcp(String),
+    /// TCP address (host:port) of a service speaking HTTPS (`https://127.0.0.1:8443`)
+    Https(String),
+    /// Unix domain socket path (`unix:/run/app.sock`)
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl LocalTarget {
+    /// Whether `s` spells out its scheme (`http://`, `https://` or `unix:`)
+    pub fn has_scheme(s: &str) -> bool {
+        [HTTP_PREFIX, HTTPS_PREFIX, UNIX_PREFIX]
+            .iter()
+            .any(|prefix| s.starts_with(prefix))
+    }
+
+    /// Local port reported to the server (0 for Unix sockets)
+    pub fn port(&self) -> u16 {
+        match self {
+            LocalTarget::Tcp(addr) | LocalTarget::Https(addr) => addr
+                .split(':')
+                .next_back()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(0),
+            #[cfg(unix)]
+            LocalTarget::Unix(_) => 0,
+        }
+    }
+}
+
+impl FromStr for LocalTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(addr) = s.strip_prefix(HTTPS_PREFIX) {
+            let addr = addr.trim_end_matches('/');
+            if addr.is_empty() || addr.contains('/') {
+                anyhow::bail!(
+                    "Invalid HTTPS target '{}'. Use --local https://host:port",
+                    s
+                );
+            }
+            return Ok(LocalTarget::Https(addr.to_string()));
+        }
+        let s = match s.strip_prefix(HTTP_PREFIX) {
+            Some(addr) => addr.trim_end_matches('/'),
+            None => s,
+        };
+
+        match s.strip_prefix(UNIX_PREFIX) {
+            #[cfg(unix)]
+            Some(path) => {
+                if path.is_empty() {
+                    anyhow::bail!("Unix socket path is empty. Use --local unix:/path/to/app.sock");
+                }
+                Ok(LocalTarget::Unix(PathBuf::from(path)))
+            }
+            #[cfg(not(unix))]
+            Some(_) => anyhow::bail!("Unix sockets are not supported on this platform"),
+            None => {
+                if s.is_empty() {
+                    anyhow::bail!("Local address is empty. Use --local 127.0.0.1:3000");
+                }
+                Ok(LocalTarget::Tcp(s.to_string()))
+            }
+        }
+    }
+}
+
+impl fmt::Display for LocalTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LocalTarget::Tcp(addr) => write!(f, "{}", addr),
+            LocalTarget::Https(addr) => write!(f, "{}{}", HTTPS_PREFIX, addr),
+            #[cfg(unix)]
+            LocalTarget::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tcp() {
+        let target: LocalTarget = "127.0.0.1:3000".parse().unwrap();
+        assert_eq!(target, LocalTarget::Tcp("127.0.0.1:3000".to_string()));
+        assert_eq!(target.port(), 3000);
+        assert_eq!(target.to_string(), "127.0.0.1:3000");
+    }
+
+    #[test]
+    fn test_parse_schemes() {
+        let target: LocalTarget = "https://127.0.0.1:8443".parse().unwrap();
+        assert_eq!(target, LocalTarget::Https("127.0.0.1:8443".to_string()));
+        assert_eq!(target.port(), 8443);
+        assert_eq!(target.to_string(), "https://127.0.0.1:8443");
+
+        let target: LocalTarget = "http://localhost:3000/".parse().unwrap();
+        assert_eq!(target, LocalTarget::Tcp("localhost:3000".to_string()));
+
+        assert!("https://".parse::<LocalTarget>().is_err());
+        assert!("https://localhost:8443/api".parse::<LocalTarget>().is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_parse_unix() {
+        let target: LocalTarget = "unix:/run/app.sock".parse().unwrap();
+        assert_eq!(target, LocalTarget::Unix(PathBuf::from("/run/app.sock")));
+        assert_eq!(target.port(), 0);
+        assert_eq!(target.to_string(), "unix:/run/app.sock");
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!("".parse::<LocalTarget>().is_err());
+        assert!("unix:".parse::<LocalTarget>().is_err());
+    }
+}
@@ -1,23 +1,26 @@
-use std::sync::Arc;
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
-use siphon_secrets::{SecretResolver, SecretUri};
-use tokio::net::TcpStream;
-use tokio::sync::mpsc;
-use tokio_rustls::rustls::pki_types::ServerName;
-use tokio_rustls::TlsConnector;
-use tracing_subscriber::EnvFilter;
-
-use siphon_tui::{MetricsCollector, SetupWizard, SiphonConfig, TuiApp};
-
-mod connector;
-mod forwarder;
-mod tcp_forwarder;
-
-use connector::TunnelConnection;
-use siphon_protocol::TunnelType;
+use siphon_secrets::{SecretError, SecretResolver, SecretUri};
+use tokio::sync::{mpsc, watch};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+use siphon::{
+    BodyDump, ForwardHost, LocalAllowlist, LocalPool, LocalScheme, LocalTarget, ReconnectPolicy,
+    RetryPolicy, TunnelClient, TunnelExit, TunnelHandle, DEFAULT_LOCAL_POOL_IDLE_TIMEOUT,
+    DEFAULT_LOCAL_POOL_MAX_IDLE, DEFAULT_MAX_LOCAL_CONCURRENCY, LOCAL_SCHEME_PROBE_TIMEOUT,
+};
+use siphon_common::{KeepaliveOptions, SocketOptions, TlsPolicy};
+use siphon_protocol::{HttpPolicy, TunnelType};
+use siphon_tui::{
+    AnomalyThresholds, FatalError, MetricsCollector, SetupWizard, SiphonConfig, TuiApp,
+};
 
 /// Siphon - Secure tunnel client for exposing local services
 #[derive(Parser, Debug)]
@@ -27,18 +30,54 @@ struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
 
+    /// Path to config file (defaults to ~/.config/siphon/config.toml, or SIPHON_CONFIG if set;
+    /// SIPHON_CONFIG_DIR moves the directory)
+    #[arg(short, long, conflicts_with = "profile")]
+    config: Option<PathBuf>,
+
+    /// Named profile to use (~/.config/siphon/profiles/<name>.toml)
+    #[arg(short, long, global = true)]
+    profile: Option<String>,
+
     /// Tunnel server address (host:port)
     #[arg(short, long)]
     server: Option<String>,
 
-    /// Local address to forward to (e.g., 127.0.0.1:3000)
+    /// Name to verify the server certificate against (defaults to the --server host),
+    /// e.g. when connecting by IP or through a bastion
+    #[arg(long)]
+    server_name: Option<String>,
+
+    /// ALPN protocols to offer the server, in order of preference (e.g. --alpn siphon/1),
+    /// for proxies in front of it that route by ALPN
+    #[arg(long, value_delimiter = ',')]
+    alpn: Vec<String>,
+
+    /// DANGER: accept any server certificate, like `curl -k`, for bring-up before the
+    /// CA is set up. Anyone between you and the server can impersonate it and read
+    /// the tunnel's traffic; --ca is then not needed
+    #[arg(long)]
+    danger_skip_server_verify: bool,
+
+    /// Local address to forward to (e.g., 127.0.0.1:3000, https://127.0.0.1:8443 or unix:/run/app.sock)
     #[arg(short, long)]
     local: Option<String>,
 
-    /// Requested subdomain (optional, auto-generated if not specified)
+    /// Scheme of a --local address given without one: auto (default) tries a TLS
+    /// handshake with it at startup, http or https skip the probe
+    #[arg(long, value_name = "SCHEME")]
+    local_scheme: Option<String>,
+
+    /// Requested subdomain (optional, auto-generated if not specified; `*` for
+    /// the catch-all tunnel, serving every subdomain no other tunnel holds)
     #[arg(long)]
     subdomain: Option<String>,
 
+    /// Base domain to serve the tunnel under, for servers with several
+    /// (defaults to the server's first)
+    #[arg(long)]
+    base_domain: Option<String>,
+
     /// Client certificate (file path, keychain://, op://, env://)
     #[arg(long)]
     cert: Option<String>,
@@ -47,47 +86,222 @@ struct Cli {
     #[arg(long)]
     key: Option<String>,
 
-    /// CA certificate (file path, keychain://, op://, env://)
+    /// CA certificate (file path, keychain://, op://, env://); comma-separate several sources
     #[arg(long)]
     ca: Option<String>,
 
+    /// Passphrase for an encrypted private key (keychain://, op://, env://)
+    #[arg(long)]
+    key_passphrase: Option<String>,
+
     /// Tunnel type: http or tcp
     #[arg(long)]
     tunnel_type: Option<String>,
 
+    /// Only forward these HTTP methods, others get 403 (e.g., --allow-method GET,HEAD)
+    #[arg(long = "allow-method", value_delimiter = ',')]
+    allow_methods: Vec<String>,
+
+    /// Only forward paths matching these globs, others get 403 (e.g., --allow-path '/api/*')
+    #[arg(long = "allow-path")]
+    allow_paths: Vec<String>,
+
+    /// Retry idempotent requests up to N times when the local service can't be reached
+    #[arg(long, default_value_t = 0)]
+    retry: u32,
+
+    /// Methods eligible for --retry (idempotent only, default: GET,HEAD,OPTIONS)
+    #[arg(long = "retry-method", value_delimiter = ',')]
+    retry_methods: Vec<String>,
+
+    /// Log request/response headers and bodies with --no-tui, secrets redacted
+    #[arg(long)]
+    dump_bodies: bool,
+
+    /// Also redact this header in --dump-bodies output (Authorization, Cookie, ... always are)
+    #[arg(
+        long = "redact-header",
+        value_delimiter = ',',
+        requires = "dump_bodies"
+    )]
+    redact_headers: Vec<String>,
+
+    /// Also redact this JSON, form or query key in --dump-bodies output (password, token, ... always are)
+    #[arg(long = "redact-key", value_delimiter = ',', requires = "dump_bodies")]
+    redact_keys: Vec<String>,
+
+    /// Don't verify the certificate of an https:// --local target (self-signed dev
+    /// certs); a --local address without a scheme is then taken as https.
+    /// INSECURE: anyone able to intercept local traffic can read it
+    #[arg(long)]
+    local_insecure: bool,
+
+    /// Host header sent to the local service: strip (default), preserve the public
+    /// one (virtual-host routing), or rewrite:<host> to send a fixed value
+    #[arg(long, value_name = "MODE")]
+    forward_host: Option<String>,
+
+    /// Forward at most N requests to the local service at once; as many again
+    /// wait briefly, the rest get 503 (default: 64, 0 = unlimited)
+    #[arg(long, value_name = "N")]
+    max_local_concurrency: Option<usize>,
+
+    /// Keep up to N idle connections to the local service for reuse
+    /// (default: 64, 0 = a new connection per request)
+    #[arg(long, value_name = "N")]
+    local_pool_max_idle: Option<usize>,
+
+    /// Close idle connections to the local service after this many seconds (default: 90)
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    local_pool_idle_timeout: Option<u64>,
+
+    /// Leave Nagle's algorithm on for tunnel sockets (TCP_NODELAY is set by default)
+    #[arg(long)]
+    no_tcp_nodelay: bool,
+
+    /// Send TCP keepalive probes after this many idle seconds (OS interval and count)
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    tcp_keepalive: Option<u64>,
+
+    /// Disable colored output (also honors the NO_COLOR environment variable)
+    #[arg(long, global = true)]
+    no_color: bool,
+
     /// Disable TUI dashboard (run in CLI mode)
     #[arg(long)]
     no_tui: bool,
+
+    /// Serve Prometheus metrics on http://ADDR/metrics (with --no-tui), e.g. 127.0.0.1:9109
+    #[arg(long, value_name = "ADDR", requires = "no_tui")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Ask the server for its base domains, tunnel types and limits, and log them
+    /// (servers older than this client drop the connection)
+    #[arg(long)]
+    server_info: bool,
+
+    /// More log output with --no-tui (-v debug, -vv trace); RUST_LOG takes precedence
+    #[arg(short, long, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Only log warnings and errors with --no-tui
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Number of samples kept for the dashboard graphs
+    #[arg(
+        long,
+        default_value_t = siphon_tui::metrics::DEFAULT_HISTORY_SIZE,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    history: usize,
+
+    /// Seconds between two dashboard graph samples (graphs cover history x interval)
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    sample_interval: u64,
+
+    /// Flag a dashboard sample whose request count is this many standard
+    /// deviations above the usual rate (0 disables)
+    #[arg(long, value_name = "SIGMAS", default_value_t = 3.0)]
+    alert_spike_sigma: f64,
+
+    /// Flag the dashboard when this percentage of recent requests got a 5xx
+    /// (0 disables)
+    #[arg(long, value_name = "PERCENT", default_value_t = 25.0)]
+    alert_5xx_percent: f64,
+
+    /// Exit after the first disconnect instead of reconnecting
+    /// (exit code 0 on normal close; see the README for the others)
+    #[arg(long)]
+    once: bool,
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
-    /// Run interactive setup wizard
+    /// Run interactive setup wizard (use --profile to save as a named profile)
     Setup,
 
+    /// List available profiles
+    Profiles,
+
+    /// Inspect the client configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommand,
+    },
+
     /// Encode a file as base64 for use in config
     Encode {
         /// Path to the file to encode (certificate, key, etc.)
         file: String,
+
+        /// Break the encoded data into lines of at most this many characters
+        #[arg(
+            long,
+            value_name = "COLS",
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        wrap: Option<usize>,
+    },
+
+    /// Generate a private CA, a client certificate and optionally a server certificate
+    GenCerts {
+        /// Directory to write the PEM files to (existing files are never overwritten)
+        #[arg(long, default_value = "siphon-certs")]
+        out: PathBuf,
+
+        /// Common name of the client certificate
+        #[arg(long, default_value = "siphon-client")]
+        client_name: String,
+
+        /// Names the server certificate is valid for (DNS names or IPs, comma-separated);
+        /// no server certificate is generated without them
+        #[arg(long, value_delimiter = ',')]
+        server_name: Vec<String>,
     },
 }
 
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Check that every secret in the config can be resolved (values are never printed)
+    Check,
+
+    /// Print the effective settings and where each came from (flag, file or default);
+    /// secrets show only their backend
+    Show,
+}
+
 /// Resolved configuration from CLI args and/or config file
 struct ResolvedConfig {
     server_addr: String,
-    local_addr: String,
+    server_name: Option<String>,
+    alpn: Vec<String>,
+    local_target: LocalTarget,
+    /// Detect whether the TCP `local_target` speaks HTTPS before connecting
+    probe_local_scheme: bool,
     subdomain: Option<String>,
+    base_domain: Option<String>,
     tunnel_type: TunnelType,
+    http_policy: Option<HttpPolicy>,
+    retry: RetryPolicy,
+    body_dump: Option<BodyDump>,
+    socket_options: SocketOptions,
+    local_insecure: bool,
+    forward_host: ForwardHost,
+    max_local_concurrency: usize,
+    local_pool: LocalPool,
     cert: String,
     key: String,
-    ca: String,
+    /// None only when server verification is skipped
+    ca: Option<String>,
+    key_passphrase: Option<String>,
+    skip_server_verify: bool,
 }
 
 impl ResolvedConfig {
     /// Resolve configuration from CLI args, falling back to config file for connection settings
     fn resolve(cli: &Cli) -> Result<Self> {
-        // Try to load config file for connection settings
-        let config_file = SiphonConfig::load_default().ok();
+        let config_file = load_config_file(cli)?;
 
         // Server address (from CLI or config)
         let server_addr = cli
@@ -96,28 +310,145 @@ impl ResolvedConfig {
             .or_else(|| config_file.as_ref().map(|c| c.server_addr.clone()))
             .context("Server address required. Use --server or run 'siphon setup'")?;
 
+        // TLS server name override (from CLI or config - optional)
+        let server_name = cli
+            .server_name
+            .clone()
+            .or_else(|| config_file.as_ref().and_then(|c| c.server_name.clone()));
+
+        // ALPN protocols offered to the server (CLI only - none by default)
+        let alpn: Vec<String> = cli
+            .alpn
+            .iter()
+            .map(|protocol| protocol.trim().to_string())
+            .filter(|protocol| !protocol.is_empty())
+            .collect();
+        if let Some(protocol) = alpn.iter().find(|protocol| protocol.len() > 255) {
+            anyhow::bail!("ALPN protocol too long (max 255 bytes): {}", protocol);
+        }
+
         // Local address (CLI only - required at runtime)
-        let local_addr = cli
+        let local = cli
             .local
-            .clone()
+            .as_deref()
             .context("Local address required. Use --local (e.g., --local 127.0.0.1:3000)")?;
+        let mut local_target: LocalTarget = local.parse()?;
+        if let Some(config) = &config_file {
+            LocalAllowlist::parse(&config.allowed_local_targets)?.check(&local_target)?;
+        }
 
-        // Subdomain (CLI only - optional)
+        // Subdomain and base domain (CLI only - optional)
         let subdomain = cli.subdomain.clone();
+        let base_domain = cli.base_domain.clone();
 
         // Tunnel type (CLI only - defaults to http)
-        let tunnel_type_str = cli
-            .tunnel_type
-            .clone()
-            .unwrap_or_else(|| "http".to_string());
-
-        let tunnel_type = match tunnel_type_str.as_str() {
-            "http" => TunnelType::Http,
-            "tcp" => TunnelType::Tcp,
-            _ => anyhow::bail!(
-                "Invalid tunnel type: {}. Use 'http' or 'tcp'",
-                tunnel_type_str
-            ),
+        let tunnel_type = match &cli.tunnel_type {
+            Some(tunnel_type) => tunnel_type.parse()?,
+            None => TunnelType::Http,
+        };
+
+        // Scheme of a bare local address (CLI only - probed by default)
+        let local_scheme = match &cli.local_scheme {
+            Some(scheme) => scheme.parse()?,
+            // Skipping certificate checks only makes sense for an HTTPS service
+            None if cli.local_insecure && !LocalTarget::has_scheme(local) => LocalScheme::Https,
+            None => LocalScheme::default(),
+        };
+        if cli.local_scheme.is_some() && LocalTarget::has_scheme(local) {
+            anyhow::bail!(
+                "--local-scheme only applies to a --local address without a scheme, got '{}'",
+                local
+            );
+        }
+        let bare_tcp =
+            matches!(local_target, LocalTarget::Tcp(_)) && !LocalTarget::has_scheme(local);
+        if let (LocalScheme::Https, LocalTarget::Tcp(addr)) = (local_scheme, &local_target) {
+            local_target = LocalTarget::Https(addr.clone());
+        }
+        // Only HTTP tunnels speak to the local service in HTTP(S)
+        let probe_local_scheme =
+            bare_tcp && local_scheme == LocalScheme::Auto && tunnel_type == TunnelType::Http;
+
+        // HTTP method/path allowlist (CLI only - optional)
+        let http_policy = if cli.allow_methods.is_empty() && cli.allow_paths.is_empty() {
+            None
+        } else {
+            if tunnel_type != TunnelType::Http {
+                anyhow::bail!("--allow-method and --allow-path only apply to HTTP tunnels");
+            }
+            Some(HttpPolicy {
+                allowed_methods: cli.allow_methods.clone(),
+                allowed_paths: cli.allow_paths.clone(),
+            })
+        };
+
+        // Retry of idempotent requests (CLI only - disabled by default)
+        let mut retry = RetryPolicy {
+            max_retries: cli.retry,
+            ..Default::default()
+        };
+        if !cli.retry_methods.is_empty() {
+            if let Some(method) = cli.retry_methods.iter().find(|m| !siphon::is_idempotent(m)) {
+                anyhow::bail!(
+                    "Cannot retry {}: only idempotent methods can be retried",
+                    method
+                );
+            }
+            retry.methods = cli.retry_methods.clone();
+        }
+
+        // Body logging (CLI only - disabled by default)
+        let body_dump = cli.dump_bodies.then(|| {
+            let mut body_dump = BodyDump::default();
+            body_dump
+                .redact_headers
+                .extend(cli.redact_headers.iter().cloned());
+            body_dump
+                .redact_keys
+                .extend(cli.redact_keys.iter().cloned());
+            body_dump
+        });
+
+        // Socket tuning (CLI only - nodelay on, keepalive off by default)
+        let socket_options = SocketOptions {
+            nodelay: !cli.no_tcp_nodelay,
+            keepalive: cli.tcp_keepalive.map(|idle| KeepaliveOptions {
+                idle: Duration::from_secs(idle),
+                interval: None,
+                count: None,
+            }),
+        };
+
+        // Host header sent to the local service (from CLI or config - strip by default)
+        let forward_host = match cli
+            .forward_host
+            .as_deref()
+            .or_else(|| config_file.as_ref().and_then(|c| c.forward_host.as_deref()))
+        {
+            Some(mode) => mode.parse().context("Invalid forward_host")?,
+            None => ForwardHost::default(),
+        };
+
+        // Concurrent forwards to the local service (from CLI or config - 64 by default)
+        let max_local_concurrency = cli
+            .max_local_concurrency
+            .or_else(|| config_file.as_ref().and_then(|c| c.max_local_concurrency))
+            .unwrap_or(DEFAULT_MAX_LOCAL_CONCURRENCY);
+
+        // Idle connections kept to the local service (from CLI or config)
+        let local_pool = LocalPool {
+            max_idle: cli
+                .local_pool_max_idle
+                .or_else(|| config_file.as_ref().and_then(|c| c.local_pool_max_idle))
+                .unwrap_or(DEFAULT_LOCAL_POOL_MAX_IDLE),
+            idle_timeout: cli
+                .local_pool_idle_timeout
+                .or_else(|| {
+                    config_file
+                        .as_ref()
+                        .and_then(|c| c.local_pool_idle_timeout_secs)
+                })
+                .map_or(DEFAULT_LOCAL_POOL_IDLE_TIMEOUT, Duration::from_secs),
         };
 
         // Certificates (from CLI or config)
@@ -133,24 +464,89 @@ impl ResolvedConfig {
             .or_else(|| config_file.as_ref().map(|c| c.key.clone()))
             .context("Private key required. Use --key or run 'siphon setup'")?;
 
+        // Not needed to skip server verification
         let ca = cli
             .ca
             .clone()
-            .or_else(|| config_file.as_ref().map(|c| c.ca_cert.clone()))
-            .context("CA certificate required. Use --ca or run 'siphon setup'")?;
+            .or_else(|| config_file.as_ref().map(|c| c.ca_cert.clone()));
+        if ca.is_none() && !cli.danger_skip_server_verify {
+            anyhow::bail!("CA certificate required. Use --ca or run 'siphon setup'");
+        }
+
+        // Key passphrase (from CLI or config - optional)
+        let key_passphrase = cli
+            .key_passphrase
+            .clone()
+            .or_else(|| config_file.as_ref().and_then(|c| c.key_passphrase.clone()));
 
         Ok(Self {
             server_addr,
-            local_addr,
+            server_name,
+            alpn,
+            local_target,
+            probe_local_scheme,
             subdomain,
+            base_domain,
             tunnel_type,
+            http_policy,
+            retry,
+            body_dump,
+            socket_options,
+            local_insecure: cli.local_insecure,
+            forward_host,
+            max_local_concurrency,
+            local_pool,
             cert,
             key,
             ca,
+            key_passphrase,
+            skip_server_verify: cli.danger_skip_server_verify,
         })
     }
 }
 
+/// Load the config file for connection settings
+///
+/// An explicit path (--config), profile (--profile) or SIPHON_CONFIG must
+/// exist and be valid, the default location is optional.
+/// Log level for our crates: -q warn, default info, -v debug, -vv trace
+fn log_level(verbose: u8, quiet: bool) -> &'static str {
+    match (quiet, verbose) {
+        (true, _) => "warn",
+        (false, 0) => "info",
+        (false, 1) => "debug",
+        (false, _) => "trace",
+    }
+}
+
+fn load_config_file(cli: &Cli) -> Result<Option<SiphonConfig>> {
+    match explicit_config_path(cli)? {
+        Some(path) => Ok(Some(load_explicit_config(&path)?)),
+        None => Ok(SiphonConfig::load_default().ok()),
+    }
+}
+
+/// Config file from --config, --profile or SIPHON_CONFIG, if any
+fn explicit_config_path(cli: &Cli) -> Result<Option<PathBuf>> {
+    Ok(match (&cli.config, &cli.profile) {
+        (Some(path), _) => Some(path.clone()),
+        (None, Some(profile)) => Some(SiphonConfig::profile_path(profile)?),
+        (None, None) => std::env::var_os("SIPHON_CONFIG").map(PathBuf::from),
+    })
+}
+
+/// Load and validate a config file the user pointed at explicitly
+fn load_explicit_config(path: &PathBuf) -> Result<SiphonConfig> {
+    let config = SiphonConfig::load(path)
+        .with_context(|| format!("Failed to load config from {}", path.display()))?;
+
+    if let Err(errors) = config.validate() {
+        anyhow::bail!("Invalid config {}: {}", path.display(), errors.join(", "));
+    }
+
+    Ok(config)
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // Install rustls crypto provider before any TLS operations
@@ -160,10 +556,26 @@ async fn main() -> Result<()> {
 
     let cli = Cli::parse();
 
+    // Colors apply to the wizard, the dashboard and CLI-mode logs alike
+    let color = siphon_tui::color::color_enabled(cli.no_color);
+    siphon_tui::color::set_color_output(color);
+
     // Handle subcommands
     match &cli.command {
-        Some(Commands::Setup) => return run_setup(),
-        Some(Commands::Encode { file }) => return run_encode(file),
+        Some(Commands::Setup) => return run_setup(cli.profile.as_deref()),
+        Some(Commands::Profiles) => return run_profiles(),
+        Some(Commands::Config {
+            action: ConfigCommand::Check,
+        }) => return run_config_check(&cli),
+        Some(Commands::Config {
+            action: ConfigCommand::Show,
+        }) => return run_config_show(&cli),
+        Some(Commands::Encode { file, wrap }) => return run_encode(file, *wrap),
+        Some(Commands::GenCerts {
+            out,
+            client_name,
+            server_name,
+        }) => return run_gen_certs(out, client_name, server_name),
         None => {}
     }
 
@@ -175,27 +587,234 @@ async fn main() -> Result<()> {
             eprintln!(
                 "\nRun 'siphon setup' to configure interactively, or provide all required options."
             );
-            std::process::exit(1);
+            std::process::exit(ExitStatus::Config as i32);
         }
     };
 
     // Initialize logging (only in no-tui mode, TUI has its own display)
+    let fmt_layer = cli.no_tui.then(|| {
+        // Use RUST_LOG if set, otherwise the level picked with -v/-q for our crates
+        let level = log_level(cli.verbose, cli.quiet);
+        let mut directives = format!("siphon={level},siphon_common={level}");
+        if cli.dump_bodies {
+            // Bodies are logged at debug level, whatever -v/-q says
+            directives.push_str(",siphon::body_dump=debug");
+        }
+        let env_filter =
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(directives));
+        tracing_subscriber::fmt::layer()
+            .with_ansi(color)
+            .with_filter(env_filter)
+    });
+    let subscriber = tracing_subscriber::registry().with(fmt_layer);
+    // Spans go to the collector named by OTEL_EXPORTER_OTLP_ENDPOINT, if any
+    #[cfg(feature = "otel")]
+    let telemetry = match siphon_common::otel::Telemetry::from_env("siphon") {
+        Ok(telemetry) => telemetry,
+        Err(e) => {
+            eprintln!("Configuration error: OpenTelemetry export: {}", e);
+            std::process::exit(ExitStatus::Config as i32);
+        }
+    };
+    #[cfg(feature = "otel")]
+    let (subscriber, export_spans) = (
+        subscriber.with(telemetry.as_ref().map(|telemetry| telemetry.layer())),
+        telemetry.is_some(),
+    );
+    #[cfg(not(feature = "otel"))]
+    let export_spans = false;
+    if cli.no_tui || export_spans {
+        subscriber.init();
+    }
+
+    let local_target = if config.probe_local_scheme {
+        siphon::detect_local_scheme(config.local_target.clone(), LOCAL_SCHEME_PROBE_TIMEOUT).await
+    } else {
+        config.local_target.clone()
+    };
+
+    let tls_config = match load_tls_config_unlocking(&cli, &config).await {
+        Ok(tls_config) => tls_config,
+        Err(e) => {
+            eprintln!("Configuration error: {:#}", e);
+            std::process::exit(ExitStatus::Config as i32);
+        }
+    };
+
+    // Create metrics collector
+    let metrics =
+        MetricsCollector::with_window(cli.history, Duration::from_secs(cli.sample_interval));
+    metrics.set_anomaly_thresholds(AnomalyThresholds {
+        spike_sigma: cli.alert_spike_sigma.max(0.0),
+        max_5xx_ratio: cli.alert_5xx_percent.max(0.0) / 100.0,
+    });
+
+    let mut builder = TunnelClient::builder()
+        .server(config.server_addr)
+        .local(local_target)
+        .tunnel_type(config.tunnel_type)
+        .retry(config.retry)
+        .socket_options(config.socket_options)
+        .insecure_local_tls(config.local_insecure)
+        .server_info(cli.server_info)
+        .forward_host(config.forward_host)
+        .max_local_concurrency(config.max_local_concurrency)
+        .local_pool(config.local_pool)
+        .tls(tls_config)
+        .metrics(metrics.clone())
+        // Includes TLS failures only recognizable from their message
+        .fatal_error(|e| analyze_tls_error(e).is_some());
+    // Server hostname for TLS (--server-name, or the host of --server)
+    if let Some(server_name) = config.server_name {
+        builder = builder.server_name(server_name);
+    }
+    if let Some(subdomain) = config.subdomain {
+        builder = builder.subdomain(subdomain);
+    }
+    if let Some(base_domain) = config.base_domain {
+        builder = builder.base_domain(base_domain);
+    }
+    if let Some(body_dump) = config.body_dump {
+        builder = builder.dump_bodies(body_dump);
+    }
+    if let Some(http_policy) = config.http_policy {
+        builder = builder.http_policy(http_policy);
+    }
+    if cli.once {
+        builder = builder.reconnect(ReconnectPolicy::never());
+    }
+    let client = match builder.build() {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Configuration error: {:#}", e);
+            std::process::exit(ExitStatus::Config as i32);
+        }
+    };
+    let handle = client.run();
+
     if cli.no_tui {
-        tracing_subscriber::fmt()
-            .with_env_filter(
-                EnvFilter::from_default_env()
-                    .add_directive("siphon=info".parse()?)
-                    .add_directive("siphon_common=info".parse()?),
-            )
-            .init();
+        // No dashboard to sample the metrics, keep rates and histories current
+        siphon::spawn_ticker(metrics.clone(), metrics.sample_interval());
+        if let Some(addr) = cli.metrics_addr {
+            let listener = match tokio::net::TcpListener::bind(addr).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    eprintln!("Configuration error: cannot listen on {}: {}", addr, e);
+                    std::process::exit(ExitStatus::Config as i32);
+                }
+            };
+            tracing::info!("Serving metrics on http://{}/metrics", addr);
+            tokio::spawn(siphon::serve_metrics(listener, metrics.clone()));
+        }
     }
 
+    let exit = if cli.no_tui {
+        // CLI mode - run tunnel without TUI
+        run_cli_mode(handle).await
+    } else {
+        // TUI mode - run dashboard alongside tunnel
+        run_tui_mode(handle, metrics).await
+    };
+
+    #[cfg(feature = "otel")]
+    if let Some(telemetry) = telemetry {
+        telemetry.shutdown();
+    }
+
+    let status = ExitStatus::of(&exit);
+    eprintln!(
+        "siphon: {} (exit code {})",
+        exit_summary(&exit),
+        status as i32
+    );
+    std::process::exit(status as i32);
+}
+
+/// Process exit codes, so scripts and CI can tell why the client stopped
+///
+/// Documented in the README: keep them stable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExitStatus {
+    /// The tunnel was closed by the server or shut down
+    Normal = 0,
+    /// Any other error, e.g. the server stayed unreachable until reconnects ran out
+    Error = 1,
+    /// The configuration, secrets or certificates couldn't be loaded
+    Config = 2,
+    /// A TLS failure reconnecting won't fix
+    Tls = 3,
+    /// The server refused the tunnel
+    Denied = 4,
+}
+
+impl ExitStatus {
+    fn of(exit: &TunnelExit) -> Self {
+        match exit.error() {
+            None => Self::Normal,
+            Some(e) if analyze_tls_error(e).is_some() => Self::Tls,
+            Some(_) if exit.is_denied() => Self::Denied,
+            Some(_) => Self::Error,
+        }
+    }
+}
+
+/// One-line account of why the tunnel stopped
+fn exit_summary(exit: &TunnelExit) -> String {
+    match exit {
+        TunnelExit::Closed => "tunnel closed by the server".to_string(),
+        TunnelExit::Shutdown => "shut down on request".to_string(),
+        TunnelExit::Fatal(e) => format!("stopped on a fatal error: {:#}", e),
+        TunnelExit::GaveUp { attempts: 0, error } => format!("stopped: {:#}", error),
+        TunnelExit::GaveUp { attempts, error } => {
+            format!("gave up after {} reconnect attempts: {:#}", attempts, error)
+        }
+    }
+}
+
+/// How long to give the user to unlock the keychain before the single retry
+const KEYCHAIN_UNLOCK_DELAY: Duration = Duration::from_secs(3);
+
+/// [`load_tls_config`], giving the user one chance to unlock a locked keychain
+/// when attached to a terminal
+async fn load_tls_config_unlocking(
+    cli: &Cli,
+    config: &ResolvedConfig,
+) -> Result<rustls::ClientConfig> {
+    match load_tls_config(cli, config) {
+        Err(e) if is_keychain_unavailable(&e) && is_interactive() => {
+            tracing::warn!(
+                "{:#}. Unlock the keychain now; retrying in {}s...",
+                e,
+                KEYCHAIN_UNLOCK_DELAY.as_secs()
+            );
+            tokio::time::sleep(KEYCHAIN_UNLOCK_DELAY).await;
+            load_tls_config(cli, config)
+        }
+        result => result,
+    }
+}
+
+/// Whether a secret failed to resolve because the keychain couldn't be read
+fn is_keychain_unavailable(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<SecretError>()
+            .is_some_and(SecretError::is_keychain_unavailable)
+    })
+}
+
+/// Whether someone is at a terminal to act on prompts
+fn is_interactive() -> bool {
+    std::io::stdin().is_terminal() && std::io::stderr().is_terminal()
+}
+
+/// Resolve the certificate, key and CA secrets into the client TLS configuration
+fn load_tls_config(cli: &Cli, config: &ResolvedConfig) -> Result<rustls::ClientConfig> {
     // Resolve secrets
     let resolver = SecretResolver::new();
 
     let cert_uri: SecretUri = config.cert.parse().context("Invalid cert URI")?;
     let key_uri: SecretUri = config.key.parse().context("Invalid key URI")?;
-    let ca_uri: SecretUri = config.ca.parse().context("Invalid CA URI")?;
 
     if cli.no_tui {
         tracing::info!("Resolving secrets...");
@@ -203,66 +822,66 @@ async fn main() -> Result<()> {
 
     let cert_pem = resolver
         .resolve_trimmed(&cert_uri)
-        .map_err(|e| anyhow::anyhow!("Failed to resolve certificate: {}", e))?;
+        .context("Failed to resolve certificate")?;
     let key_pem = resolver
         .resolve_trimmed(&key_uri)
-        .map_err(|e| anyhow::anyhow!("Failed to resolve private key: {}", e))?;
-    let ca_pem = resolver
-        .resolve_trimmed(&ca_uri)
-        .map_err(|e| anyhow::anyhow!("Failed to resolve CA certificate: {}", e))?;
+        .context("Failed to resolve private key")?;
+
+    // Decrypt the private key if it is passphrase-protected
+    let key_passphrase = match &config.key_passphrase {
+        Some(source) => {
+            let uri: SecretUri = source.parse().context("Invalid key passphrase URI")?;
+            let passphrase = resolver
+                .resolve_trimmed(&uri)
+                .context("Failed to resolve key passphrase")?;
+            Some(passphrase)
+        }
+        None => None,
+    };
+    let key_pem = siphon_common::decrypt_private_key_pem(&key_pem, key_passphrase.as_deref())
+        .context("Failed to load private key (set --key-passphrase for encrypted keys)")?;
 
     if cli.no_tui {
         tracing::info!("Secrets resolved successfully");
     }
 
-    // Load TLS configuration
-    let tls_config = siphon_common::load_client_config_from_pem(&cert_pem, &key_pem, &ca_pem)
-        .context("Failed to load TLS configuration")?;
-
-    let tls_connector = TlsConnector::from(Arc::new(tls_config));
-
-    // Extract server hostname for TLS
-    let server_host = config
-        .server_addr
-        .split(':')
-        .next()
-        .context("Invalid server address")?;
-
-    let server_name = ServerName::try_from(server_host.to_string())
-        .map_err(|_| anyhow::anyhow!("Invalid server hostname: {}", server_host))?;
-
-    // Create metrics collector
-    let metrics = MetricsCollector::new();
-
-    if cli.no_tui {
-        // CLI mode - run tunnel without TUI
-        run_cli_mode(
-            config.server_addr,
-            config.local_addr,
-            config.subdomain,
-            config.tunnel_type,
-            tls_connector,
-            server_name,
-            metrics,
+    // Catch mismatched cert/key pairs before they surface as handshake failures
+    siphon_common::verify_cert_key_pair(&cert_pem, &key_pem)
+        .context("Invalid client certificate/key pair")?;
+
+    let policy = TlsPolicy::default().with_alpn(config.alpn.iter().cloned());
+
+    // Separate path, so the verified one never sees the skipping verifier
+    if config.skip_server_verify {
+        eprintln!(
+            "WARNING: --danger-skip-server-verify: the server certificate is NOT verified. \
+             Anyone between you and the server can impersonate it and read the tunnel's \
+             traffic. Only use this to test before the CA is set up."
+        );
+        return siphon_common::load_client_config_from_pem_skip_server_verify(
+            &cert_pem, &key_pem, &policy,
         )
-        .await
-    } else {
-        // TUI mode - run dashboard alongside tunnel
-        run_tui_mode(
-            config.server_addr,
-            config.local_addr,
-            config.subdomain,
-            config.tunnel_type,
-            tls_connector,
-            server_name,
-            metrics,
-        )
-        .await
+        .context("Failed to load TLS configuration");
     }
+
+    let ca = config
+        .ca
+        .as_deref()
+        .context("CA certificate required. Use --ca or run 'siphon setup'")?;
+    let ca_pem = resolver
+        .resolve_bundle(ca)
+        .context("Failed to resolve CA certificate")?;
+
+    // Load TLS configuration
+    siphon_common::load_client_config_from_pem_with_policy(&cert_pem, &key_pem, &ca_pem, &policy)
+        .context("Failed to load TLS configuration")
 }
 
-fn run_setup() -> Result<()> {
-    let mut wizard = SetupWizard::new();
+fn run_setup(profile: Option<&str>) -> Result<()> {
+    let mut wizard = match profile {
+        Some(profile) => SetupWizard::with_profile(profile),
+        None => SetupWizard::new(),
+    };
 
     match wizard.run()? {
         Some(_config) => {
@@ -276,142 +895,346 @@ fn run_setup() -> Result<()> {
     }
 }
 
-fn run_encode(file_path: &str) -> Result<()> {
+fn run_profiles() -> Result<()> {
+    let profiles = SiphonConfig::list_profiles().context("Failed to list profiles")?;
+
+    if profiles.is_empty() {
+        println!("No profiles found. Create one with 'siphon setup --profile <name>'.");
+        return Ok(());
+    }
+
+    for profile in profiles {
+        println!("{}", profile);
+    }
+
+    Ok(())
+}
+
+fn run_config_check(cli: &Cli) -> Result<()> {
+    let config = load_config_file(cli)?
+        .context("No config found. Run 'siphon setup' or pass --config / --profile")?;
+
+    let mut secrets = vec![("cert", config.cert.as_str()), ("key", config.key.as_str())];
+    // The CA may be a comma-separated bundle; check each source
+    secrets.extend(config.ca_cert.split(',').map(|s| ("ca_cert", s.trim())));
+    if let Some(passphrase) = &config.key_passphrase {
+        secrets.push(("key_passphrase", passphrase.as_str()));
+    }
+
+    let resolver = SecretResolver::new();
+    let mut failures = 0;
+    for (name, source) in secrets {
+        let result = source
+            .parse::<SecretUri>()
+            .map_err(|e| e.to_string())
+            .and_then(|uri| {
+                let backend = uri.backend_name();
+                resolver
+                    .check(&uri)
+                    .map(|_| backend)
+                    .map_err(|e| format!("{}: {}", backend, e))
+            });
+
+        match result {
+            Ok(backend) => println!("  ok    {} ({})", name, backend),
+            Err(e) => {
+                failures += 1;
+                println!("  FAIL  {} ({})", name, e);
+            }
+        }
+    }
+
+    if failures > 0 {
+        anyhow::bail!("{} secret(s) could not be resolved", failures);
+    }
+    println!("All secrets resolved");
+    Ok(())
+}
+
+fn run_config_show(cli: &Cli) -> Result<()> {
+    let config_file = load_config_file(cli)?;
+    let config_path = match explicit_config_path(cli)? {
+        Some(path) => path.display().to_string(),
+        None if config_file.is_some() => SiphonConfig::default_path().display().to_string(),
+        None => "none".to_string(),
+    };
+    let file = config_file.unwrap_or_default();
+
+    // Flag > file > default, as in ResolvedConfig::resolve
+    let pick =
+        |flag: Option<String>, from_file: Option<String>, default: &str| match (flag, from_file) {
+            (Some(value), _) => ("flag", value),
+            (None, Some(value)) => ("file", value),
+            (None, None) => ("default", default.to_string()),
+        };
+    let non_empty = |value: &str| (!value.is_empty()).then(|| value.to_string());
+    let flag_or = |flag: Option<String>, default: &str| pick(flag, None, default);
+    let secret = |value: Option<String>| value.map(|v| siphon_secrets::redact_sources(&v));
+
+    let rows = [
+        (
+            "server",
+            pick(cli.server.clone(), non_empty(&file.server_addr), "unset"),
+        ),
+        (
+            "server_name",
+            pick(
+                cli.server_name.clone(),
+                file.server_name.clone(),
+                "host of server",
+            ),
+        ),
+        (
+            "alpn",
+            flag_or((!cli.alpn.is_empty()).then(|| cli.alpn.join(", ")), "none"),
+        ),
+        (
+            "danger_skip_server_verify",
+            flag_or(
+                cli.danger_skip_server_verify.then(|| "true".to_string()),
+                "false",
+            ),
+        ),
+        (
+            "cert",
+            pick(
+                secret(cli.cert.clone()),
+                secret(non_empty(&file.cert)),
+                "unset",
+            ),
+        ),
+        (
+            "key",
+            pick(
+                secret(cli.key.clone()),
+                secret(non_empty(&file.key)),
+                "unset",
+            ),
+        ),
+        (
+            "ca_cert",
+            pick(
+                secret(cli.ca.clone()),
+                secret(non_empty(&file.ca_cert)),
+                "unset",
+            ),
+        ),
+        (
+            "key_passphrase",
+            pick(
+                secret(cli.key_passphrase.clone()),
+                secret(file.key_passphrase.clone()),
+                "unset",
+            ),
+        ),
+        (
+            "allowed_local_targets",
+            pick(
+                None,
+                (!file.allowed_local_targets.is_empty())
+                    .then(|| file.allowed_local_targets.join(", ")),
+                "any",
+            ),
+        ),
+        ("local", flag_or(cli.local.clone(), "unset")),
+        ("local_scheme", flag_or(cli.local_scheme.clone(), "auto")),
+        (
+            "subdomain",
+            flag_or(cli.subdomain.clone(), "auto-generated"),
+        ),
+        (
+            "base_domain",
+            flag_or(cli.base_domain.clone(), "server default"),
+        ),
+        ("tunnel_type", flag_or(cli.tunnel_type.clone(), "http")),
+        (
+            "retry",
+            flag_or((cli.retry > 0).then(|| cli.retry.to_string()), "0"),
+        ),
+        (
+            "tcp_nodelay",
+            flag_or(cli.no_tcp_nodelay.then(|| "false".to_string()), "true"),
+        ),
+        (
+            "tcp_keepalive",
+            flag_or(cli.tcp_keepalive.map(|secs| format!("{}s", secs)), "off"),
+        ),
+        (
+            "metrics_addr",
+            flag_or(cli.metrics_addr.map(|addr| addr.to_string()), "off"),
+        ),
+        (
+            "local_insecure",
+            flag_or(cli.local_insecure.then(|| "true".to_string()), "false"),
+        ),
+        (
+            "server_info",
+            flag_or(cli.server_info.then(|| "true".to_string()), "false"),
+        ),
+        (
+            "forward_host",
+            pick(cli.forward_host.clone(), file.forward_host.clone(), "strip"),
+        ),
+        (
+            "max_local_concurrency",
+            pick(
+                cli.max_local_concurrency.map(|n| n.to_string()),
+                file.max_local_concurrency.map(|n| n.to_string()),
+                &DEFAULT_MAX_LOCAL_CONCURRENCY.to_string(),
+            ),
+        ),
+        (
+            "local_pool_max_idle",
+            pick(
+                cli.local_pool_max_idle.map(|n| n.to_string()),
+                file.local_pool_max_idle.map(|n| n.to_string()),
+                &DEFAULT_LOCAL_POOL_MAX_IDLE.to_string(),
+            ),
+        ),
+        (
+            "local_pool_idle_timeout",
+            pick(
+                cli.local_pool_idle_timeout.map(|secs| format!("{}s", secs)),
+                file.local_pool_idle_timeout_secs
+                    .map(|secs| format!("{}s", secs)),
+                &format!("{}s", DEFAULT_LOCAL_POOL_IDLE_TIMEOUT.as_secs()),
+            ),
+        ),
+    ];
+
+    println!("Config file: {}", config_path);
+    for (name, (source, value)) in rows {
+        println!("{:<8} {:<24} {}", source, name, value);
+    }
+    Ok(())
+}
+
+fn run_encode(file_path: &str, wrap: Option<usize>) -> Result<()> {
     use base64::Engine;
 
     let content =
         std::fs::read(file_path).with_context(|| format!("Failed to read file: {}", file_path))?;
 
-    let encoded = base64::engine::general_purpose::STANDARD.encode(&content);
+    let mut encoded = base64::engine::general_purpose::STANDARD.encode(&content);
+
+    // Whitespace in the data is ignored when decoding, so the lines can go
+    // in a multi-line TOML string as is
+    if let Some(cols) = wrap {
+        encoded = encoded
+            .as_bytes()
+            .chunks(cols)
+            .map(|line| std::str::from_utf8(line).expect("base64 is ASCII"))
+            .collect::<Vec<_>>()
+            .join("\n");
+    }
 
     println!("base64://{}", encoded);
 
     Ok(())
 }
 
-async fn run_cli_mode(
-    server_addr: String,
-    local_addr: String,
-    subdomain: Option<String>,
-    tunnel_type: TunnelType,
-    tls_connector: TlsConnector,
-    server_name: ServerName<'static>,
-    metrics: MetricsCollector,
-) -> Result<()> {
-    tracing::info!("Connecting to {} to expose {}", server_addr, local_addr);
-
-    // Reconnection loop
-    let mut shutdown = false;
-    loop {
-        if shutdown {
-            break;
-        }
+fn run_gen_certs(out: &Path, client_name: &str, server_names: &[String]) -> Result<()> {
+    let certs = siphon_common::GeneratedCerts::generate(client_name, server_names)?;
+    siphon_common::load_client_config_from_pem(
+        &certs.client_cert_pem,
+        &certs.client_key_pem,
+        &certs.ca_cert_pem,
+    )
+    .context("Generated client certificate does not load")?;
+    if let Some(server) = &certs.server {
+        siphon_common::load_server_config_from_pem(
+            &server.cert_pem,
+            &server.key_pem,
+            &certs.ca_cert_pem,
+        )
+        .context("Generated server certificate does not load")?;
+    }
 
-        tracing::info!("Connecting to {}...", server_addr);
-
-        tokio::select! {
-            result = run_tunnel(
-                &server_addr,
-                &local_addr,
-                subdomain.clone(),
-                tunnel_type.clone(),
-                tls_connector.clone(),
-                server_name.clone(),
-                metrics.clone(),
-            ) => {
-                match result {
-                    Ok(_) => {
-                        tracing::info!("Tunnel closed normally");
-                        break;
-                    }
-                    Err(e) => {
-                        if let Some(tls_diagnostic) = analyze_tls_error(&e) {
-                            display_tls_error(tls_diagnostic.as_ref());
-                            return Err(e);
-                        }
-                        tracing::error!("Tunnel error: {}", e);
-                        tracing::info!("Reconnecting in 5 seconds...");
-                        tokio::time::sleep(Duration::from_secs(5)).await;
-                    }
-                }
-            }
-            _ = shutdown_signal() => {
-                tracing::info!("Shutdown signal received");
-                shutdown = true;
-            }
-        }
+    let mut files = certs.server_files();
+    files.extend(certs.client_files());
+    let paths = siphon_common::write_pem_files(out, &files)?;
+
+    println!("Wrote:");
+    for path in &paths {
+        println!("  {}", path.display());
     }
+    println!();
+    println!("CA certificate (the server's CA_CERT, and the client's CA):");
+    print!("{}", certs.ca_cert_pem);
+    println!();
+    println!("Next steps:");
+    println!("  - Point the server's CA_CERT at ca.crt");
+    if certs.server.is_some() {
+        println!("  - Use server.crt and server.key as the server's CERT and KEY");
+    }
+    println!("  - Run 'siphon setup' with client.crt, client.key and ca.crt");
+    println!("  - Keep ca.key offline; it can issue more client certificates");
 
-    tracing::info!("Client shutdown complete");
     Ok(())
 }
 
-async fn run_tui_mode(
-    server_addr: String,
-    local_addr: String,
-    subdomain: Option<String>,
-    tunnel_type: TunnelType,
-    tls_connector: TlsConnector,
-    server_name: ServerName<'static>,
-    metrics: MetricsCollector,
-) -> Result<()> {
+async fn run_cli_mode(mut handle: TunnelHandle) -> TunnelExit {
+    tokio::select! {
+        exit = handle.wait_for_exit() => {
+            if let Some(tls_diagnostic) = exit.error().and_then(analyze_tls_error) {
+                display_tls_error(tls_diagnostic.as_ref());
+            }
+            exit
+        }
+        _ = shutdown_signal() => {
+            tracing::info!("Shutdown signal received");
+            let exit = handle.stop().await;
+            tracing::info!("Client shutdown complete");
+            exit
+        }
+    }
+}
+
+async fn run_tui_mode(mut handle: TunnelHandle, metrics: MetricsCollector) -> TunnelExit {
     // Create shutdown channel
     let (shutdown_tx, mut shutdown_rx) = mpsc::channel::<()>(1);
 
+    // Channel to close the TUI when the tunnel ends on its own
+    let (stop_tx, stop_rx) = watch::channel(false);
+
     // Clone metrics for TUI
     let tui_metrics = metrics.clone();
 
     // Spawn TUI in its own task
     let tui_handle = tokio::spawn(async move {
-        let app = TuiApp::new(tui_metrics, shutdown_tx);
+        let app = TuiApp::new(tui_metrics, shutdown_tx).with_stop_signal(stop_rx);
         app.run().await
     });
 
-    // Reconnection loop with TUI
-    loop {
-        tokio::select! {
-            result = run_tunnel(
-                &server_addr,
-                &local_addr,
-                subdomain.clone(),
-                tunnel_type.clone(),
-                tls_connector.clone(),
-                server_name.clone(),
-                metrics.clone(),
-            ) => {
-                match result {
-                    Ok(_) => {
-                        // Tunnel closed normally
-                        break;
-                    }
-                    Err(e) => {
-                        if let Some(tls_diagnostic) = analyze_tls_error(&e) {
-                            metrics.record_error(format!("Fatal: {}", tls_diagnostic));
-                            // Give TUI a moment to display the error, then exit
-                            tokio::time::sleep(Duration::from_millis(500)).await;
-                            display_tls_error(tls_diagnostic.as_ref());
-                            break;
-                        }
-                        metrics.record_error(format!("Tunnel error: {}", e));
-                        tokio::time::sleep(Duration::from_secs(5)).await;
-                    }
-                }
-            }
-            _ = shutdown_rx.recv() => {
-                // TUI requested shutdown
-                break;
-            }
-            _ = shutdown_signal() => {
-                // OS signal received
-                break;
+    let mut fatal_tls = None;
+
+    let exit = tokio::select! {
+        exit = handle.wait_for_exit() => {
+            if let Some(tls_diagnostic) = exit.error().and_then(analyze_tls_error) {
+                // Show the diagnostic in the TUI until the user dismisses it
+                metrics.set_fatal_error(fatal_error_from_diagnostic(tls_diagnostic.as_ref()));
+                fatal_tls = Some(tls_diagnostic);
             }
+            exit
         }
-    }
+        // TUI requested shutdown
+        _ = shutdown_rx.recv() => handle.stop().await,
+        // OS signal received
+        _ = shutdown_signal() => handle.stop().await,
+    };
 
-    // Wait for TUI to finish
+    // Close the TUI and wait for it to restore the terminal. On fatal errors
+    // the TUI keeps the error on screen until the user presses a key.
+    if fatal_tls.is_none() {
+        let _ = stop_tx.send(true);
+    }
     let _ = tui_handle.await;
 
-    Ok(())
+    if let Some(tls_diagnostic) = fatal_tls {
+        display_tls_error(tls_diagnostic.as_ref());
+    }
+
+    exit
 }
 
 #[allow(unused_assignments)]
@@ -481,6 +1304,16 @@ mod tls_diagnostics {
 
 use tls_diagnostics::*;
 
+/// Convert a diagnostic into the form the TUI renders in its fatal error overlay
+fn fatal_error_from_diagnostic(diagnostic: &(dyn miette::Diagnostic + Send + Sync)) -> FatalError {
+    FatalError {
+        code: diagnostic.code().map(|c| c.to_string()),
+        message: diagnostic.to_string(),
+        help: diagnostic.help().map(|h| h.to_string()),
+        url: diagnostic.url().map(|u| u.to_string()),
+    }
+}
+
 /// Analyze an error and extract detailed TLS/certificate information if applicable
 fn analyze_tls_error(error: &anyhow::Error) -> Option<Box<dyn miette::Diagnostic + Send + Sync>> {
     // Check the error chain for rustls errors
@@ -518,6 +1351,12 @@ fn analyze_rustls_error(err: &rustls::Error) -> Box<dyn miette::Diagnostic + Sen
             message: "No client certificate was presented".to_string(),
             help: "Ensure your certificate file path is correct and the file exists.".to_string(),
         }),
+        Error::AlertReceived(rustls::AlertDescription::NoApplicationProtocol) => {
+            Box::new(GenericTlsDiagnostic {
+                message: "Server accepts none of the offered ALPN protocols".to_string(),
+                help: "Set --alpn to a protocol the server's control_alpn lists.".to_string(),
+            })
+        }
         Error::AlertReceived(alert) => Box::new(GenericTlsDiagnostic {
             message: format!("Server rejected connection with TLS alert: {:?}", alert),
             help: "The server doesn't trust your certificate. Check that it was signed by the correct CA.".to_string(),
@@ -725,33 +1564,3 @@ async fn shutdown_signal() {
         _ = terminate => {}
     }
 }
-
-async fn run_tunnel(
-    server_addr: &str,
-    local_addr: &str,
-    subdomain: Option<String>,
-    tunnel_type: TunnelType,
-    tls_connector: TlsConnector,
-    server_name: ServerName<'static>,
-    metrics: MetricsCollector,
-) -> Result<()> {
-    // Connect to server
-    let stream = TcpStream::connect(server_addr).await?;
-
-    // Perform TLS handshake
-    let tls_stream = tls_connector.connect(server_name, stream).await?;
-
-    // Create tunnel connection handler
-    let mut connection = TunnelConnection::new(
-        tls_stream,
-        local_addr.to_string(),
-        metrics,
-        tunnel_type.clone(),
-    );
-
-    // Request tunnel
-    connection.request_tunnel(subdomain, tunnel_type).await?;
-
-    // Run the connection (processes messages until disconnection)
-    connection.run().await
-}
@@ -0,0 +1,127 @@
+//! Prometheus `/metrics` endpoint for headless clients
+//!
+//! Serves the same counters the dashboard shows, so a client run with
+//! `--no-tui` can be scraped instead of watched.
+
+use std::convert::Infallible;
+use std::time::Duration;
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use siphon_tui::export::metrics_to_prometheus;
+use siphon_tui::MetricsCollector;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+/// Content type of the Prometheus text exposition format
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Serve `GET /metrics` from `metrics` on `listener` until the task is aborted
+pub async fn serve_metrics(listener: TcpListener, metrics: MetricsCollector) {
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("Metrics endpoint accept failed: {}", e);
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| {
+                let metrics = metrics.clone();
+                async move { Ok::<_, Infallible>(respond(&req, &metrics)) }
+            });
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::debug!("Metrics connection error from {}: {}", peer_addr, e);
+            }
+        });
+    }
+}
+
+/// Sample `metrics` every `interval` so rates and graph histories keep
+/// moving when no dashboard is ticking them
+pub fn spawn_ticker(metrics: MetricsCollector, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            metrics.tick();
+        }
+    })
+}
+
+fn respond<B>(req: &Request<B>, metrics: &MetricsCollector) -> Response<Full<Bytes>> {
+    let (status, content_type, body) = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => (
+            StatusCode::OK,
+            PROMETHEUS_CONTENT_TYPE,
+            metrics_to_prometheus(&metrics.snapshot()),
+        ),
+        (_, "/metrics") => (
+            StatusCode::METHOD_NOT_ALLOWED,
+            "text/plain",
+            "Method not allowed\n".to_string(),
+        ),
+        _ => (
+            StatusCode::NOT_FOUND,
+            "text/plain",
+            "Not found\n".to_string(),
+        ),
+    };
+    Response::builder()
+        .status(status)
+        .header("content-type", content_type)
+        .body(Full::new(Bytes::from(body)))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_metrics_endpoint_serves_prometheus_text() {
+        let metrics = MetricsCollector::new();
+        metrics.record_request_start();
+        metrics.record_request_complete(
+            404,
+            Duration::from_millis(5),
+            0,
+            "GET".into(),
+            "/missing".into(),
+        );
+        metrics.record_ping_rtt(Duration::from_millis(250));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve_metrics(listener, metrics));
+
+        let response = reqwest::get(format!("http://{}/metrics", addr))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(response.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain; version=0.0.4"));
+        let text = response.text().await.unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines.contains(&"siphon_requests_total 1"), "{}", text);
+        assert!(lines.contains(&"siphon_responses_total{class=\"4xx\"} 1"));
+        assert!(lines.contains(&"siphon_ping_rtt_seconds 0.25"));
+
+        let response = reqwest::get(format!("http://{}/", addr)).await.unwrap();
+        assert_eq!(response.status(), 404);
+
+        server.abort();
+    }
+}
@@ -0,0 +1,102 @@
+use std::net::IpAddr;
+
+use anyhow::Context;
+use tokio_rustls::rustls::pki_types::ServerName;
+
+/// Host part of a `host:port` server address (`[::1]:4443` -> `::1`)
+pub fn connect_host(server_addr: &str) -> anyhow::Result<&str> {
+    let host = match server_addr.strip_prefix('[') {
+        Some(rest) => rest
+            .split_once(']')
+            .map(|(host, _)| host)
+            .context("Invalid server address: missing ']'")?,
+        None => server_addr
+            .rsplit_once(':')
+            .map_or(server_addr, |(host, _)| host),
+    };
+
+    if host.is_empty() {
+        anyhow::bail!("Invalid server address: {}", server_addr);
+    }
+    Ok(host)
+}
+
+/// Name the server certificate is verified against
+///
+/// Uses `override_name` (`--server-name`) when set, so the TCP connection can
+/// go to a different host (an IP, an SSH-forwarded port) than the one the
+/// certificate was issued for. Falls back to the host in `server_addr`.
+///
+/// An IP address is verified against the certificate's IP SANs rather than
+/// its DNS names, so a server can be reached before it has a DNS record.
+pub fn tls_server_name(
+    server_addr: &str,
+    override_name: Option<&str>,
+) -> anyhow::Result<ServerName<'static>> {
+    let name = match override_name {
+        Some(name) => {
+            let name = name.trim();
+            if name.is_empty() {
+                anyhow::bail!("--server-name is empty");
+            }
+            name
+        }
+        None => connect_host(server_addr)?,
+    };
+
+    if let Ok(ip) = name.parse::<IpAddr>() {
+        return Ok(ServerName::IpAddress(ip.into()));
+    }
+    ServerName::try_from(name.to_string())
+        .map_err(|_| anyhow::anyhow!("Invalid server hostname: {}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dns(name: &str) -> ServerName<'static> {
+        ServerName::try_from(name.to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_server_name_from_addr() {
+        assert_eq!(
+            tls_server_name("tunnel.example.com:4443", None).unwrap(),
+            dns("tunnel.example.com")
+        );
+    }
+
+    #[test]
+    fn test_server_name_ip_address() {
+        let ip = |addr: &str| ServerName::IpAddress(addr.parse::<IpAddr>().unwrap().into());
+
+        assert_eq!(
+            tls_server_name("203.0.113.7:4443", None).unwrap(),
+            ip("203.0.113.7")
+        );
+        assert_eq!(tls_server_name("[::1]:4443", None).unwrap(), ip("::1"));
+        assert_eq!(
+            tls_server_name("tunnel.example.com:4443", Some("127.0.0.1")).unwrap(),
+            ip("127.0.0.1")
+        );
+    }
+
+    #[test]
+    fn test_server_name_differs_from_connect_host() {
+        let server_addr = "127.0.0.1:14443";
+        let name = tls_server_name(server_addr, Some("tunnel.example.com")).unwrap();
+
+        assert_eq!(connect_host(server_addr).unwrap(), "127.0.0.1");
+        assert_eq!(name, dns("tunnel.example.com"));
+    }
+
+    #[test]
+    fn test_server_name_invalid() {
+        assert!(tls_server_name("tunnel.example.com:4443", Some("")).is_err());
+        assert!(tls_server_name("tunnel.example.com:4443", Some("bad name")).is_err());
+        assert!(tls_server_name("tunnel.example.com:4443", Some("host:4443")).is_err());
+        assert!(tls_server_name(":4443", None).is_err());
+        assert!(tls_server_name("[::1:4443", None).is_err());
+    }
+}
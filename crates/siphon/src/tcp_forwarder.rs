@@ -1,106 +1,261 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 
+use bytes::{Bytes, BytesMut};
 use dashmap::DashMap;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use siphon_tui::metrics::MetricsCollector;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 
+use siphon_common::flow_control::{
+    stream_queue, ReceiveWindow, SendWindow, StreamQueue, StreamQueueError, StreamQueueReceiver,
+};
+use siphon_common::SocketOptions;
 use siphon_protocol::ClientMessage;
 
+use crate::local_target::LocalTarget;
+
+/// Most bytes read from the local service into one data frame
+const READ_CHUNK: usize = 8192;
+
+/// A connection to the local service (TCP or Unix socket)
+pub(crate) trait LocalIo: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> LocalIo for T {}
+
+pub(crate) type LocalStream = Box<dyn LocalIo>;
+
+/// Protocol messages a forwarded stream's traffic is reported with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StreamMessages {
+    /// `TcpData`/`TcpClose`, for TCP tunnels
+    Tcp,
+    /// `WsData`/`WsClose`, for upgraded WebSocket connections
+    WebSocket,
+}
+
+impl StreamMessages {
+    fn data(self, stream_id: u64, data: Bytes) -> ClientMessage {
+        match self {
+            StreamMessages::Tcp => ClientMessage::TcpData { stream_id, data },
+            StreamMessages::WebSocket => ClientMessage::WsData { stream_id, data },
+        }
+    }
+
+    fn close(self, stream_id: u64) -> ClientMessage {
+        match self {
+            StreamMessages::Tcp => ClientMessage::TcpClose { stream_id },
+            StreamMessages::WebSocket => ClientMessage::WsClose { stream_id },
+        }
+    }
+}
+
 /// Handle to a TCP connection
 struct TcpConnectionHandle {
-    writer: mpsc::Sender<Vec<u8>>,
+    writer: StreamQueue,
+    /// Credit left to send to the server (flow-controlled tunnels only)
+    send_window: Option<Arc<SendWindow>>,
+}
+
+impl Drop for TcpConnectionHandle {
+    /// A connection leaving the map is closed: stop waiting for credit
+    fn drop(&mut self) {
+        if let Some(window) = &self.send_window {
+            window.close();
+        }
+    }
+}
+
+/// Per-connection totals, reported to the metrics once when the connection ends
+struct ConnectionStats {
+    peer: Option<String>,
+    started: Instant,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    closed: AtomicBool,
+}
+
+impl ConnectionStats {
+    fn new(peer: Option<String>) -> Self {
+        Self {
+            peer,
+            started: Instant::now(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Record the closed connection (only the first call, from either task, counts)
+    fn finish(&self, metrics: &MetricsCollector) {
+        if self.closed.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        metrics.record_tcp_connection_closed(
+            self.peer.clone(),
+            self.bytes_in.load(Ordering::Relaxed),
+            self.bytes_out.load(Ordering::Relaxed),
+            self.started.elapsed(),
+        );
+    }
 }
 
 /// Manages TCP connections to the local service
 pub struct TcpForwarder {
-    local_addr: String,
+    target: LocalTarget,
     connections: Arc<DashMap<u64, TcpConnectionHandle>>,
     response_tx: mpsc::Sender<ClientMessage>,
+    metrics: MetricsCollector,
+    socket_options: SocketOptions,
+    messages: StreamMessages,
+    flow_control: bool,
+    /// Set once the forwarder is dropped, so writes to a local service that
+    /// stopped reading don't outlive the session
+    ended: watch::Sender<bool>,
 }
 
 impl TcpForwarder {
-    pub fn new(local_addr: String, response_tx: mpsc::Sender<ClientMessage>) -> Self {
+    pub fn new(
+        target: LocalTarget,
+        response_tx: mpsc::Sender<ClientMessage>,
+        metrics: MetricsCollector,
+    ) -> Self {
         Self {
-            local_addr,
+            target,
             connections: Arc::new(DashMap::new()),
             response_tx,
+            metrics,
+            socket_options: SocketOptions::default(),
+            messages: StreamMessages::Tcp,
+            flow_control: false,
+            ended: watch::channel(false).0,
+        }
+    }
+
+    /// Apply `options` to connections to the local service
+    pub fn with_socket_options(mut self, options: SocketOptions) -> Self {
+        self.socket_options = options;
+        self
+    }
+
+    /// Report this forwarder's traffic with `messages` instead of `TcpData`/`TcpClose`
+    pub(crate) fn with_messages(mut self, messages: StreamMessages) -> Self {
+        self.messages = messages;
+        self
+    }
+
+    /// Flow-control streams opened from now on with window updates, as
+    /// agreed with the server when the tunnel was established
+    pub fn set_flow_control(&mut self, enabled: bool) {
+        self.flow_control = enabled && self.messages == StreamMessages::Tcp;
+    }
+
+    /// Connect to the local service
+    pub(crate) async fn connect_stream(&self) -> std::io::Result<LocalStream> {
+        match &self.target {
+            LocalTarget::Tcp(addr) | LocalTarget::Https(addr) => {
+                let stream = TcpStream::connect(addr).await?;
+                self.socket_options.apply(&stream)?;
+                Ok(Box::new(stream))
+            }
+            #[cfg(unix)]
+            LocalTarget::Unix(path) => Ok(Box::new(tokio::net::UnixStream::connect(path).await?)),
         }
     }
 
     /// Handle a new TCP connection request from the server
-    pub async fn handle_connect(&self, stream_id: u64) {
-        tracing::debug!(
-            "Opening TCP connection {} to {}",
-            stream_id,
-            self.local_addr
-        );
+    ///
+    /// `peer` is the public client's address as reported by the server.
+    pub async fn handle_connect(&self, stream_id: u64, peer: Option<String>) {
+        tracing::debug!("Opening TCP connection {} to {}", stream_id, self.target);
 
         // Connect to local service
-        let stream = match TcpStream::connect(&self.local_addr).await {
-            Ok(s) => s,
+        let stream = match self.connect_stream().await {
+            Ok(stream) => stream,
             Err(e) => {
-                tracing::error!(
+                tracing::error!("Failed to connect to local service {}: {}", self.target, e);
+                self.metrics.record_error(format!(
                     "Failed to connect to local service {}: {}",
-                    self.local_addr,
-                    e
-                );
+                    self.target, e
+                ));
                 // Send TcpClose to indicate connection failed
-                let _ = self
-                    .response_tx
-                    .send(ClientMessage::TcpClose { stream_id })
-                    .await;
+                let _ = self.response_tx.send(self.messages.close(stream_id)).await;
                 return;
             }
         };
 
-        let (mut read_half, mut write_half) = stream.into_split();
-
-        // Create channel for writing to this connection
-        let (write_tx, mut write_rx) = mpsc::channel::<Vec<u8>>(32);
+        let write_rx = self.register(stream_id);
+        self.spawn_pipes(stream_id, stream, write_rx, peer);
+    }
 
-        // Register the connection
-        self.connections
-            .insert(stream_id, TcpConnectionHandle { writer: write_tx });
+    /// Register a connection so data from the server is queued for it
+    ///
+    /// The returned receiver is handed to [`TcpForwarder::spawn_pipes`].
+    pub(crate) fn register(&self, stream_id: u64) -> StreamQueueReceiver {
+        let (write_tx, write_rx) = stream_queue(self.flow_control);
+        self.connections.insert(
+            stream_id,
+            TcpConnectionHandle {
+                writer: write_tx,
+                send_window: self.flow_control.then(|| Arc::new(SendWindow::new())),
+            },
+        );
+        write_rx
+    }
 
-        // Spawn write task
-        let connections = self.connections.clone();
-        let response_tx = self.response_tx.clone();
-        tokio::spawn(async move {
-            while let Some(data) = write_rx.recv().await {
-                if let Err(e) = write_half.write_all(&data).await {
-                    tracing::error!("Failed to write to local TCP stream {}: {}", stream_id, e);
-                    break;
-                }
-            }
-            // Clean up
-            connections.remove(&stream_id);
-            let _ = response_tx
-                .send(ClientMessage::TcpClose { stream_id })
-                .await;
-        });
+    /// Pipe a registered connection to and from the server until either side closes
+    pub(crate) fn spawn_pipes<S>(
+        &self,
+        stream_id: u64,
+        stream: S,
+        mut write_rx: StreamQueueReceiver,
+        peer: Option<String>,
+    ) where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut read_half, mut write_half) = tokio::io::split(stream);
+        let messages = self.messages;
+        let flow_control = self.flow_control;
+        let send_window = self
+            .connections
+            .get(&stream_id)
+            .and_then(|h| h.send_window.clone());
+        self.metrics.record_tcp_connect();
+        let stats = Arc::new(ConnectionStats::new(peer));
 
         // Spawn read task - read from local service and send to server
         let connections = self.connections.clone();
         let response_tx = self.response_tx.clone();
-        tokio::spawn(async move {
-            let mut buf = vec![0u8; 8192];
+        let metrics = self.metrics.clone();
+        let read_stats = stats.clone();
+        let reader = tokio::spawn(async move {
+            // Reused across reads: frames share it without a copy, and once
+            // they have been sent `reserve` reclaims it instead of allocating another
+            let mut buf = BytesMut::with_capacity(READ_CHUNK);
             loop {
-                match read_half.read(&mut buf).await {
+                buf.reserve(READ_CHUNK);
+                match read_half.read_buf(&mut buf).await {
                     Ok(0) => {
                         // EOF - connection closed
                         tracing::debug!("Local TCP connection {} closed", stream_id);
                         break;
                     }
                     Ok(n) => {
-                        let data = buf[..n].to_vec();
-                        if let Err(e) = response_tx
-                            .send(ClientMessage::TcpData { stream_id, data })
-                            .await
-                        {
-                            tracing::error!("Failed to send TcpData: {}", e);
+                        // Out of credit: only this stream waits for the server
+                        if let Some(window) = &send_window {
+                            if !window.reserve(n).await {
+                                break;
+                            }
+                        }
+                        let data = buf.split_to(n).freeze();
+                        if let Err(e) = response_tx.send(messages.data(stream_id, data)).await {
+                            tracing::error!("Failed to send stream data: {}", e);
                             break;
                         }
+                        read_stats.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+                        metrics.record_bytes_out(n as u64);
                     }
                     Err(e) => {
                         tracing::error!("TCP read error on stream {}: {}", stream_id, e);
@@ -109,18 +264,62 @@ impl TcpForwarder {
                 }
             }
             // Clean up
+            read_stats.finish(&metrics);
             connections.remove(&stream_id);
-            let _ = response_tx
-                .send(ClientMessage::TcpClose { stream_id })
-                .await;
+            let _ = response_tx.send(messages.close(stream_id)).await;
+        });
+
+        // Spawn write task
+        let connections = self.connections.clone();
+        let response_tx = self.response_tx.clone();
+        let metrics = self.metrics.clone();
+        let mut ended = self.ended.subscribe();
+        tokio::spawn(async move {
+            let mut window = ReceiveWindow::default();
+            while let Some(data) = write_rx.recv().await {
+                let written = tokio::select! {
+                    written = write_half.write_all(&data) => written,
+                    _ = ended.wait_for(|ended| *ended) => break,
+                };
+                if let Err(e) = written {
+                    tracing::error!("Failed to write to local TCP stream {}: {}", stream_id, e);
+                    break;
+                }
+                // Let the server send more once the local service took what it sent
+                if let Some(bytes) = window.written(data.len()).filter(|_| flow_control) {
+                    let _ = response_tx
+                        .send(ClientMessage::TcpWindowUpdate { stream_id, bytes })
+                        .await;
+                }
+                let len = data.len() as u64;
+                stats.bytes_in.fetch_add(len, Ordering::Relaxed);
+                metrics.record_bytes_in(len);
+            }
+            // Clean up. The stream is closed, so stop reading from the local
+            // service too rather than wait for it to hang up
+            reader.abort();
+            stats.finish(&metrics);
+            connections.remove(&stream_id);
+            let _ = response_tx.send(messages.close(stream_id)).await;
         });
     }
 
     /// Handle incoming TCP data from the server
-    pub async fn handle_data(&self, stream_id: u64, data: Vec<u8>) {
-        if let Some(handle) = self.connections.get(&stream_id) {
-            if let Err(e) = handle.writer.send(data).await {
-                tracing::error!("Failed to forward TCP data to stream {}: {}", stream_id, e);
+    pub async fn handle_data(&self, stream_id: u64, data: Bytes) {
+        let writer = self.connections.get(&stream_id).map(|h| h.writer.clone());
+        if let Some(writer) = writer {
+            match writer.push(data).await {
+                Ok(()) => {}
+                Err(StreamQueueError::Overrun) => {
+                    tracing::warn!(
+                        "Closing TCP connection {}: the server sent more than its window",
+                        stream_id
+                    );
+                    self.handle_close(stream_id);
+                }
+                Err(e) => {
+                    tracing::error!("Failed to forward TCP data to stream {}: {}", stream_id, e);
+                }
             }
         } else {
             tracing::warn!(
@@ -130,6 +329,17 @@ impl TcpForwarder {
         }
     }
 
+    /// Credit a connection with `bytes` more it may send to the server
+    pub fn handle_window_update(&self, stream_id: u64, bytes: u64) {
+        if let Some(window) = self
+            .connections
+            .get(&stream_id)
+            .and_then(|h| h.send_window.clone())
+        {
+            window.grant(bytes);
+        }
+    }
+
     /// Handle TCP connection close from the server
     pub fn handle_close(&self, stream_id: u64) {
         if let Some((_, handle)) = self.connections.remove(&stream_id) {
@@ -139,3 +349,72 @@ impl TcpForwarder {
         }
     }
 }
+
+impl Drop for TcpForwarder {
+    /// Streams belong to the control connection the forwarder served: close
+    /// them, so none is left waiting for data or credit that can't come, or
+    /// on a local service that stopped reading
+    fn drop(&mut self) {
+        self.connections.clear();
+        self.ended.send_replace(true);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use tokio::net::UnixListener;
+
+    #[tokio::test]
+    async fn test_tcp_forwarder_over_unix_socket() {
+        let path = std::env::temp_dir().join(format!("siphon-tcp-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // Mock echo service
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 64];
+            let n = stream.read(&mut buf).await.unwrap();
+            stream.write_all(&buf[..n]).await.unwrap();
+        });
+
+        let (response_tx, mut response_rx) = mpsc::channel(8);
+        let metrics = MetricsCollector::new();
+        let forwarder = TcpForwarder::new(
+            LocalTarget::Unix(path.clone()),
+            response_tx,
+            metrics.clone(),
+        );
+
+        forwarder
+            .handle_connect(7, Some("203.0.113.7:51234".to_string()))
+            .await;
+        forwarder.handle_data(7, Bytes::from_static(b"ping")).await;
+
+        match response_rx.recv().await.unwrap() {
+            ClientMessage::TcpData { stream_id, data } => {
+                assert_eq!(stream_id, 7);
+                assert_eq!(&data[..], b"ping");
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+
+        // The echo service closes after one exchange
+        match response_rx.recv().await.unwrap() {
+            ClientMessage::TcpClose { stream_id } => assert_eq!(stream_id, 7),
+            other => panic!("unexpected message: {:?}", other),
+        }
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.total_connections, 1);
+        assert_eq!(snapshot.active_connections, 0);
+        let entry = &snapshot.recent_tcp_connections[0];
+        assert_eq!(entry.peer.as_deref(), Some("203.0.113.7:51234"));
+        assert_eq!(entry.bytes_in, 4);
+        assert_eq!(entry.bytes_out, 4);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,255 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty};
+use hyper::upgrade::Upgraded;
+use hyper::StatusCode;
+use hyper_util::rt::TokioIo;
+use siphon_tui::metrics::MetricsCollector;
+use tokio::sync::mpsc;
+
+use siphon_common::SocketOptions;
+use siphon_protocol::ClientMessage;
+
+use crate::forwarder::{
+    filter_response_headers, host_header, set_forwarded_proto, ForwardErrorKind, ForwardHost,
+    IncompleteBody,
+};
+use crate::local_target::LocalTarget;
+use crate::tcp_forwarder::{StreamMessages, TcpForwarder};
+
+/// How long the local service has to answer an upgrade (the server gives up after as long)
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How the local service answered an upgrade request
+enum Upgrade {
+    /// It switched protocols; the headers complete the visitor's handshake
+    Accepted {
+        headers: Vec<(String, String)>,
+        upgraded: Upgraded,
+    },
+    /// It answered with a final response instead
+    Refused {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    },
+}
+
+/// Relays WebSocket upgrades to the local service
+///
+/// The upgrade request is replayed on a fresh connection; once the local
+/// service switches protocols the connection is piped like a TCP tunnel
+/// connection, with `WsData`/`WsClose` instead of `TcpData`/`TcpClose`.
+pub struct WsForwarder {
+    target: LocalTarget,
+    streams: TcpForwarder,
+    response_tx: mpsc::Sender<ClientMessage>,
+    metrics: MetricsCollector,
+    forward_host: ForwardHost,
+}
+
+impl WsForwarder {
+    pub fn new(
+        target: LocalTarget,
+        response_tx: mpsc::Sender<ClientMessage>,
+        metrics: MetricsCollector,
+    ) -> Self {
+        Self {
+            streams: TcpForwarder::new(target.clone(), response_tx.clone(), metrics.clone())
+                .with_messages(StreamMessages::WebSocket),
+            target,
+            response_tx,
+            metrics,
+            forward_host: ForwardHost::default(),
+        }
+    }
+
+    /// Apply `options` to connections to the local service
+    pub fn with_socket_options(mut self, options: SocketOptions) -> Self {
+        self.streams = self.streams.with_socket_options(options);
+        self
+    }
+
+    /// Host header sent to the local service (stripped by default)
+    pub fn with_forward_host(mut self, forward_host: ForwardHost) -> Self {
+        self.forward_host = forward_host;
+        self
+    }
+
+    /// Whether upgrades can be relayed to `target`
+    ///
+    /// Upgrades are replayed in plain HTTP/1.1, so `https://` targets keep
+    /// getting them as ordinary requests through the HTTP forwarder.
+    pub fn supports(target: &LocalTarget) -> bool {
+        !matches!(target, LocalTarget::Https(_))
+    }
+
+    /// Handle an upgrade request from the server
+    ///
+    /// Always answers with an `HttpResponse`: a 101 followed by `WsData`, the
+    /// local service's refusal, or a 502/504 if it couldn't be reached.
+    pub async fn handle_upgrade(
+        &self,
+        stream_id: u64,
+        uri: String,
+        mut headers: Vec<(String, String)>,
+        scheme: Option<String>,
+    ) {
+        tracing::debug!(
+            "Opening WebSocket {} to {}: {}",
+            stream_id,
+            self.target,
+            uri
+        );
+        set_forwarded_proto(&mut headers, scheme.as_deref());
+
+        self.metrics.record_request_start();
+        let start = Instant::now();
+
+        // Registered up front so data the visitor sends right after the 101 is queued
+        let write_rx = self.streams.register(stream_id);
+
+        let upgrade = tokio::time::timeout(UPGRADE_TIMEOUT, self.upgrade(&uri, headers))
+            .await
+            .unwrap_or_else(|_| Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into()));
+        let (status, headers, body) = match upgrade {
+            Ok(Upgrade::Accepted { headers, upgraded }) => {
+                self.metrics.record_request_complete(
+                    StatusCode::SWITCHING_PROTOCOLS.as_u16(),
+                    start.elapsed(),
+                    0,
+                    "GET".to_string(),
+                    uri,
+                );
+                // Answer before piping so the server knows the stream before any data
+                self.respond(
+                    stream_id,
+                    StatusCode::SWITCHING_PROTOCOLS.as_u16(),
+                    headers,
+                    vec![],
+                )
+                .await;
+                self.streams
+                    .spawn_pipes(stream_id, TokioIo::new(upgraded), write_rx, None);
+                return;
+            }
+            Ok(Upgrade::Refused {
+                status,
+                headers,
+                body,
+            }) => {
+                tracing::debug!("Local service refused WebSocket {}: {}", stream_id, status);
+                (status, headers, body)
+            }
+            Err(e) => {
+                let kind = ForwardErrorKind::classify(&e);
+                tracing::warn!(
+                    "Failed to open WebSocket to local service ({}): {}",
+                    kind.as_str(),
+                    e
+                );
+                self.metrics.record_forward_error(
+                    kind.as_str(),
+                    format!("Failed to open WebSocket {}: {}", uri, e),
+                );
+                let body = format!("{}: {}", kind.message(), e).into_bytes();
+                (kind.status(), vec![], body)
+            }
+        };
+
+        // No connection to pipe: the response is final
+        self.streams.handle_close(stream_id);
+        self.metrics.record_request_complete(
+            status,
+            start.elapsed(),
+            body.len(),
+            "GET".to_string(),
+            uri,
+        );
+        self.respond(stream_id, status, headers, body).await;
+    }
+
+    /// Replay the upgrade request to the local service
+    async fn upgrade(&self, uri: &str, headers: Vec<(String, String)>) -> Result<Upgrade> {
+        let stream = self.streams.connect_stream().await?;
+        let (mut sender, connection) =
+            hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.with_upgrades().await {
+                tracing::debug!("Local WebSocket connection closed: {}", e);
+            }
+        });
+
+        let host = match (self.forward_host.host(host_header(&headers)), &self.target) {
+            (Some(host), _) => host,
+            (None, LocalTarget::Tcp(addr) | LocalTarget::Https(addr)) => addr.as_str(),
+            #[cfg(unix)]
+            (None, LocalTarget::Unix(_)) => "localhost",
+        };
+        let mut request = hyper::Request::get(uri).header(hyper::header::HOST, host);
+        for (name, value) in headers {
+            if !name.eq_ignore_ascii_case("host") {
+                request = request.header(name, value);
+            }
+        }
+        let mut response = sender
+            .send_request(request.body(Empty::<Bytes>::new())?)
+            .await?;
+
+        if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+            let status = response.status().as_u16();
+            let headers = filter_response_headers(response.headers());
+            let body = response
+                .into_body()
+                .collect()
+                .await
+                .context(IncompleteBody)?
+                .to_bytes()
+                .to_vec();
+            return Ok(Upgrade::Refused {
+                status,
+                headers,
+                body,
+            });
+        }
+
+        // Hop-by-hop headers are kept: `Upgrade` and `Connection` are the handshake
+        let headers = response
+            .headers()
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("").to_string()))
+            .collect();
+        let upgraded = hyper::upgrade::on(&mut response).await?;
+        Ok(Upgrade::Accepted { headers, upgraded })
+    }
+
+    async fn respond(
+        &self,
+        stream_id: u64,
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    ) {
+        let _ = self
+            .response_tx
+            .send(ClientMessage::HttpResponse {
+                stream_id,
+                status,
+                headers,
+                body,
+            })
+            .await;
+    }
+
+    /// Handle incoming WebSocket data from the server
+    pub async fn handle_data(&self, stream_id: u64, data: Bytes) {
+        self.streams.handle_data(stream_id, data).await;
+    }
+
+    /// Handle a WebSocket close from the server
+    pub fn handle_close(&self, stream_id: u64) {
+        self.streams.handle_close(stream_id);
+    }
+}
@@ -0,0 +1,79 @@
+//! Exit codes of the `siphon` binary
+//!
+//! These run the real binary against a test server and check the process
+//! exit code scripts and CI rely on (see the README).
+
+use std::process::Stdio;
+use std::time::Duration;
+
+use siphon_e2e::{MockHttpService, TestClient, TestServer};
+use siphon_protocol::TunnelType;
+use tokio::process::Command;
+
+/// Initialize tracing and crypto provider for tests
+fn init_test() {
+    // Install rustls crypto provider (ignore if already installed)
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    // Initialize tracing
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter("siphon=debug,siphon_e2e=debug")
+        .with_test_writer()
+        .try_init();
+}
+
+/// The binary in CLI mode, connecting to `server` with the test certificates
+fn siphon(server: &TestServer, local: &MockHttpService) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_siphon"));
+    command
+        .args(["--no-tui", "--quiet"])
+        .args(["--server", &server.control_addr.to_string()])
+        .args(["--server-name", "localhost"])
+        .args(["--local", &local.addr_string()])
+        .args(["--cert", "env://SIPHON_TEST_CERT"])
+        .args(["--key", "env://SIPHON_TEST_KEY"])
+        .args(["--ca", "env://SIPHON_TEST_CA"])
+        .env("SIPHON_TEST_CERT", &server.certs.client_cert_pem)
+        .env("SIPHON_TEST_KEY", &server.certs.client_key_pem)
+        .env("SIPHON_TEST_CA", &server.certs.ca_cert_pem)
+        // Keep a config file of the user running the tests out of it
+        .env("HOME", std::env::temp_dir().join("siphon-exit-codes-home"))
+        .env_remove("SIPHON_CONFIG")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    command
+}
+
+#[tokio::test]
+async fn test_denied_tunnel_exits_with_4_without_once() {
+    init_test();
+
+    let server = TestServer::start().await;
+    let mock = MockHttpService::start().await;
+
+    // Another client holds the subdomain the binary asks for
+    let _holder = TestClient::connect(
+        &server,
+        &mock.addr_string(),
+        Some("exit-code-taken".to_string()),
+        TunnelType::Http,
+    )
+    .await
+    .expect("Failed to connect holder");
+
+    let output = tokio::time::timeout(
+        Duration::from_secs(10),
+        siphon(&server, &mock)
+            .args(["--subdomain", "exit-code-taken"])
+            .output(),
+    )
+    .await
+    .expect("siphon kept retrying a denied tunnel")
+    .expect("Failed to run siphon");
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(4), "{}", stderr);
+    assert!(stderr.contains("(exit code 4)"), "{}", stderr);
+}